@@ -0,0 +1,320 @@
+//! # annotate
+//!
+//! Renders a markdown summary of failed tests and attaches it to the
+//! Buildkite build as an annotation.
+//!
+//! The summary covers every test of the run, including those uploaded early
+//! (eg the first batch, or spilled tests), which are tallied in `Results` as
+//! each batch leaves the payload.
+
+use crate::payload::{Payload, TestData};
+use minijinja::{context, Environment};
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// The maximum number of failures the built-in styles will list.
+const MAX_FAILURES: usize = 20;
+
+static COMPACT: &str = r#"**{{ failed }} of {{ total }} tests failed**{% if suite_name %} in {{ suite_name }}{% endif %}{% if url %} ([build]({{ url }})){% endif %}
+
+{% for test in failures[:max_failures] -%}
+- `{{ test.scope }}::{{ test.name }}`
+{% endfor -%}
+{% if failures|length > max_failures %}- …and {{ failures|length - max_failures }} more
+{% endif %}"#;
+
+static DETAILED: &str = r#"### {{ failed }} of {{ total }} tests failed{% if suite_name %} in {{ suite_name }}{% endif %}
+{% if url %}
+See the [build]({{ url }}) for the full output.
+{% endif %}
+{% for scope, tests in failures[:max_failures]|groupby("scope") -%}
+#### `{{ scope }}`
+
+{% for test in tests -%}
+<details>
+<summary><code>{{ test.name }}</code>{% if test.history.duration %} ({{ test.history.duration|round(3) }}s){% endif %}</summary>
+{% set reason = test.failure_reason|default("No output was captured.", true)|trim %}
+{{ reason|fence }}
+{{ reason }}
+{{ reason|fence }}
+
+</details>
+{% endfor %}
+{% endfor -%}
+{% if failures|length > max_failures %}…and {{ failures|length - max_failures }} more failures.
+{% endif %}"#;
+
+/// # AnnotateStyle
+///
+/// Which template to use when rendering the annotation.
+#[derive(Debug, PartialEq, Clone)]
+pub enum AnnotateStyle {
+    Compact,
+    Detailed,
+    Template(String),
+}
+
+impl AnnotateStyle {
+    /// Parse the value of the `--annotate-style` option.
+    ///
+    /// Accepts the name of a built-in style, or the path to a minijinja
+    /// template.
+    ///
+    /// ## Emits warnings
+    ///  - If the template file cannot be read.
+    pub fn parse(value: &str) -> Option<AnnotateStyle> {
+        match value {
+            "compact" => Some(AnnotateStyle::Compact),
+            "detailed" => Some(AnnotateStyle::Detailed),
+            path => match fs::read_to_string(path) {
+                Ok(template) => Some(AnnotateStyle::Template(template)),
+                Err(err) => {
+                    eprintln!("Unable to read annotation template {}: {}", path, err);
+                    None
+                }
+            },
+        }
+    }
+
+    fn template(&self) -> &str {
+        match self {
+            AnnotateStyle::Compact => COMPACT,
+            AnnotateStyle::Detailed => DETAILED,
+            AnnotateStyle::Template(template) => template,
+        }
+    }
+}
+
+/// # Results
+///
+/// The number of tests, and the failures, of every batch of the run.
+#[derive(Debug, Default)]
+pub struct Results {
+    total: usize,
+    failures: Vec<TestData>,
+}
+
+impl Results {
+    /// Tally the tests which have finished in `payload`.
+    pub fn record(&mut self, payload: &Payload) {
+        let tests = payload.closed_data();
+        self.total += tests.len();
+        self.failures.extend(
+            tests
+                .into_iter()
+                .filter(|test_data| test_data.is_failed())
+                .cloned(),
+        );
+    }
+}
+
+/// A markdown code fence which `text` can't close: a run of backticks longer
+/// than any in it, and at least three.
+fn fence(text: String) -> String {
+    let longest = text
+        .split(|c| c != '`')
+        .map(str::len)
+        .max()
+        .unwrap_or_default();
+
+    "`".repeat((longest + 1).max(3))
+}
+
+/// Render the annotation markdown for the failures in `results`, with the
+/// suite name and build URL of `payload`.
+///
+/// Templates are given `failures` (a list of tests, sorted by scope and
+/// name), `failed`, `total`, `max_failures`, `suite_name` and `url`, and a
+/// `fence` filter which gives a code fence that the text can't close.
+///
+/// Returns `None` if there are no failures.
+pub fn render(
+    results: &Results,
+    payload: &Payload,
+    style: &AnnotateStyle,
+) -> Option<Result<String, minijinja::Error>> {
+    let mut failures = results.failures.iter().collect::<Vec<&TestData>>();
+
+    if failures.is_empty() {
+        return None;
+    }
+
+    failures.sort_by_key(|test_data| (test_data.scope(), test_data.name()));
+
+    let mut env = Environment::new();
+    env.add_filter("fence", fence);
+    let result = env.render_str(
+        style.template(),
+        context! {
+            failures => failures,
+            failed => failures.len(),
+            total => results.total,
+            max_failures => MAX_FAILURES,
+            suite_name => payload.suite_name(),
+            url => payload.run_env().url(),
+        },
+    );
+
+    Some(result)
+}
+
+/// Annotate the Buildkite build with a summary of the failures in `results`.
+///
+/// Does nothing outside of Buildkite, or if there are no failures.
+///
+/// ## Emits warnings
+///  - If the template fails to render.
+///  - If `buildkite-agent annotate` cannot be run.
+pub fn annotate(results: &Results, payload: &Payload, style: &AnnotateStyle) {
+    if payload.run_env().ci() != "buildkite" {
+        return;
+    }
+
+    let markdown = match render(results, payload, style) {
+        Some(Ok(markdown)) => markdown,
+        Some(Err(err)) => {
+            eprintln!("Unable to render annotation template: {}", err);
+            return;
+        }
+        None => return,
+    };
+
+    let context = match payload.suite_name() {
+        Some(suite_name) => format!("buildkite-test-collector-{}", suite_name),
+        None => "buildkite-test-collector".to_string(),
+    };
+
+    let child = Command::new("buildkite-agent")
+        .args(["annotate", "--style", "error", "--context", &context])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn();
+
+    let result = child.and_then(|mut child| {
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(markdown.as_bytes())?;
+        }
+        child.wait()
+    });
+
+    match result {
+        Ok(status) if status.success() => {}
+        Ok(status) => eprintln!("buildkite-agent annotate exited with {}", status),
+        Err(err) => eprintln!("Unable to run buildkite-agent annotate: {}", err),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::input::{Event, SuiteEvent, TestEvent};
+    use crate::run_env::RuntimeEnvironment;
+
+    #[test]
+    fn renders_the_built_in_styles() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+
+        payload.push(Event::Suite {
+            event: SuiteEvent::Started { test_count: 2 },
+        });
+        for name in ["api::test::ok", "api::test::broken"] {
+            payload.push(Event::Test {
+                event: TestEvent::Started {
+                    name: name.to_string(),
+                },
+                nextest: None,
+                extra: Default::default(),
+            });
+        }
+        payload.push(Event::Test {
+            event: TestEvent::Ok {
+                name: "api::test::ok".to_string(),
+                exec_time: Some(0.1),
+                stdout: None,
+            },
+            nextest: None,
+            extra: Default::default(),
+        });
+        payload.push(Event::Test {
+            event: TestEvent::Failed {
+                name: "api::test::broken".to_string(),
+                exec_time: Some(0.2),
+                stdout: Some("assertion failed".to_string()),
+                stderr: None,
+                message: None,
+            },
+            nextest: None,
+            extra: Default::default(),
+        });
+
+        let mut results = Results::default();
+        results.record(&payload);
+
+        let compact = render(&results, &payload, &AnnotateStyle::Compact)
+            .unwrap()
+            .unwrap();
+        assert!(compact.starts_with("**1 of 2 tests failed**"));
+        assert!(compact.contains("- `api::test::broken`"));
+
+        let detailed = render(&results, &payload, &AnnotateStyle::Detailed)
+            .unwrap()
+            .unwrap();
+        assert!(detailed.contains("#### `api::test`"));
+        assert!(detailed.contains("```\nassertion failed\n```"));
+
+        let custom = AnnotateStyle::Template("{{ failures[0].name }}".to_string());
+        assert_eq!(
+            render(&results, &payload, &custom).unwrap().unwrap(),
+            "broken"
+        );
+    }
+
+    #[test]
+    fn covers_tests_which_left_the_payload_early() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+        let mut results = Results::default();
+        for (name, event) in [
+            ("a::one", "failed"),
+            ("a::two", "ok"),
+            ("a::three", "failed"),
+        ] {
+            for line in [
+                format!(
+                    r#"{{ "type": "test", "event": "started", "name": "{}" }}"#,
+                    name
+                ),
+                format!(
+                    r#"{{ "type": "test", "name": "{}", "event": "{}", "stdout": "thread '{}' panicked at src/a.rs:1:5:\nboom\n" }}"#,
+                    name, event, name
+                ),
+            ] {
+                crate::input::parse_line(&line, &mut payload).unwrap();
+            }
+            // Eg the first batch.
+            if name == "a::one" {
+                results.record(&payload.take_finished());
+            }
+        }
+        results.record(&payload);
+
+        let compact = render(&results, &payload, &AnnotateStyle::Compact)
+            .unwrap()
+            .unwrap();
+        assert!(
+            compact.starts_with("**2 of 3 tests failed**"),
+            "{}",
+            compact
+        );
+        assert!(compact.contains("- `a::one`"));
+        assert!(compact.contains("- `a::three`"));
+    }
+
+    #[test]
+    fn fences_outlast_backticks_in_the_failure() {
+        assert_eq!(fence("boom".to_string()), "```");
+        assert_eq!(fence("expected `a`".to_string()), "```");
+        assert_eq!(fence("```rust\nlet a;\n```".to_string()), "````");
+        assert_eq!(fence("``````".to_string()), "```````");
+    }
+}
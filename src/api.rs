@@ -1,86 +1,548 @@
 //! # api
 //!
 //! Deals with submitting payloads to the API and handling the response.
+//!
+//! Uploads are asynchronous, so that several can be in flight alongside
+//! parsing, with `submit` to wait for one from synchronous code.
 
-use crate::payload::Payload;
-use serde::Deserialize;
+use crate::failure;
+use crate::http::{self, block_on, client, HttpError};
+use crate::payload::{fnv1a, parse_var};
+use crate::retry::RetryPolicy;
+use crate::run_env::repository_slug;
+use crate::test_list::cache_dir;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use reqwest::Response;
+use serde::{Deserialize, Serialize};
 use std::env;
-use ureq::post;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::Command;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime};
+
+/// How long a token from the token service is cached, by default.
+const DEFAULT_TOKEN_TTL: u64 = 300;
 
+/// # ApiResponse
+///
+/// The API's response to a successful upload.
 #[derive(Deserialize, Debug, PartialEq)]
-struct ApiResponse {
-    id: String,
-    run_id: String,
-    queued: usize,
-    skipped: usize,
-    errors: Vec<String>,
+pub struct ApiResponse {
+    pub id: String,
+    pub run_id: String,
+    pub queued: usize,
+    pub skipped: usize,
+    pub errors: Vec<String>,
+    #[serde(default)]
+    pub run_url: Option<String>,
+}
+
+/// The longest response body kept in an `ApiError`.
+const MAX_ERROR_BODY_BYTES: usize = 1024;
+
+/// # ApiError
+///
+/// Why a payload couldn't be submitted, so that callers can tell a bad token
+/// (which won't fix itself) from the API being unreachable (which might).
+#[derive(Debug, PartialEq)]
+pub enum ApiError {
+    /// There's no token to upload with.
+    MissingToken,
+    /// The payload couldn't be serialised.
+    Serialise(String),
+    /// The API couldn't be reached, eg a connection error or timeout.
+    Transport(String),
+    /// The API rejected the token, with a 401 or 403.
+    Unauthorized(u16),
+    /// The API responded with another error status, and this body.
+    Status(u16, String),
+    /// The response couldn't be read, or wasn't what we expected.
+    InvalidResponse(String),
+    /// The API accepted the request, but reported errors with the payload.
+    Rejected(Vec<String>),
+}
+
+impl ApiError {
+    /// Might the same request succeed later, eg once the API has recovered?
+    pub fn is_transient(&self) -> bool {
+        match self {
+            ApiError::Transport(_) => true,
+            ApiError::Status(status, _) => *status == 429 || (500..600).contains(status),
+            _ => false,
+        }
+    }
+
+    /// Is the collector misconfigured, so that no request can succeed?
+    pub fn is_configuration(&self) -> bool {
+        matches!(self, ApiError::MissingToken | ApiError::Unauthorized(_))
+    }
+}
+
+/// The `ApiError` for a failed request, with (the start of) the body of any
+/// error response.
+async fn api_error(err: HttpError) -> ApiError {
+    match err {
+        HttpError::Status(status @ (401 | 403), _) => ApiError::Unauthorized(status),
+        HttpError::Status(status, response) => {
+            let mut body = response.text().await.unwrap_or_default();
+            failure::truncate(&mut body, MAX_ERROR_BODY_BYTES);
+            ApiError::Status(status, body.trim().to_string())
+        }
+        transport @ HttpError::Transport(_) => ApiError::Transport(transport.to_string()),
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiError::MissingToken => write!(f, "no BUILDKITE_ANALYTICS_TOKEN to upload with"),
+            ApiError::Serialise(err) => write!(f, "unable to serialise the payload: {}", err),
+            ApiError::Transport(err) => write!(f, "unable to reach the API: {}", err),
+            ApiError::Unauthorized(status) => write!(
+                f,
+                "the API rejected the token (HTTP {}), check BUILDKITE_ANALYTICS_TOKEN",
+                status
+            ),
+            ApiError::Status(status, body) if body.is_empty() => {
+                write!(f, "the API responded with HTTP {}", status)
+            }
+            ApiError::Status(status, body) => {
+                write!(f, "the API responded with HTTP {}: {}", status, body)
+            }
+            ApiError::InvalidResponse(err) => {
+                write!(f, "unable to parse the API response: {}", err)
+            }
+            ApiError::Rejected(errors) => {
+                write!(f, "the API reported errors: {}", errors.join("; "))
+            }
+        }
+    }
 }
 
+impl std::error::Error for ApiError {}
+
 /// Submit the payload to the provided endpoint.
 ///
-/// Attempt to serialse the `payload` and submit it to the Buildkite test analytics API.
+/// Attempt to serialse the `payload` and submit it to the Buildkite test analytics API,
+/// with an `Idempotency-Key` header so that retries and replays of it aren't
+/// counted twice.
 ///
 /// ## Emits warnings if:
-///  - If the `BUILDKITE_ANALYTICS_TOKEN` is not set.
-///  - If the API response cannot be parsed as JSON.
-///  - If the response contains a non-zero number of errors.
-pub fn submit(payload: Payload, endpoint: &str) -> Option<()> {
-    let auth_header = get_auth_header()?;
-    let response = send_request(payload, endpoint, &auth_header)?;
-    let response = get_response_body(response)?;
+///  - If the `BUILDKITE_ANALYTICS_TOKEN` is not set, and cannot be fetched
+///    from the Buildkite secret named by `BUILDKITE_ANALYTICS_TOKEN_SECRET`,
+///    or the token service at `BUILDKITE_ANALYTICS_TOKEN_SERVICE_URL` (only
+///    once).
+///  - Before each retry (see `retry`).
+pub fn submit<T: Serialize>(
+    payload: &T,
+    endpoint: &str,
+    idempotency_key: &str,
+) -> Result<ApiResponse, ApiError> {
+    block_on(submit_async(payload, endpoint, idempotency_key))
+}
+
+/// As `submit`, without waiting for the response.
+pub async fn submit_async<T: Serialize>(
+    payload: &T,
+    endpoint: &str,
+    idempotency_key: &str,
+) -> Result<ApiResponse, ApiError> {
+    let auth_header = get_auth_header().ok_or(ApiError::MissingToken)?;
+    let response = send_request(
+        payload,
+        endpoint,
+        &auth_header,
+        idempotency_key,
+        &RetryPolicy::from_env(),
+    )
+    .await?;
+    let response = get_response_body(response).await?;
     let response = get_api_response(&response)?;
 
     if !response.errors.is_empty() {
-        eprintln!("Error response from API: {:?}", response.errors);
-        None
+        Err(ApiError::Rejected(response.errors))
     } else {
-        Some(())
+        Ok(response)
     }
 }
 
-fn send_request(payload: Payload, endpoint: &str, auth: &str) -> Option<ureq::Response> {
-    let maybe_response = post(endpoint)
-        .set("Content-Type", "application/json")
-        .set("Authorization", auth)
-        .send_json(payload);
+/// Is there a token to upload with?
+///
+/// ## Emits warnings if:
+///  - If the token cannot be found (only once).
+pub fn is_configured() -> bool {
+    get_auth_header().is_some()
+}
 
-    match maybe_response {
-        Ok(response) => Some(response),
-        Err(err) => {
-            eprintln!("HTTP Error sending API request: {:?}", err);
-            None
+/// Send the request, retrying it according to `retry`.  The body is gzipped
+/// unless that's been disabled.
+///
+/// ## Emits warnings if:
+///  - The body cannot be gzipped, in which case it's sent as is.
+async fn send_request<T: Serialize>(
+    payload: &T,
+    endpoint: &str,
+    auth: &str,
+    idempotency_key: &str,
+    retry: &RetryPolicy,
+) -> Result<Response, ApiError> {
+    let body = serde_json::to_vec(payload).map_err(|err| ApiError::Serialise(err.to_string()))?;
+    let (body, gzipped) = if http::config().gzip {
+        match gzip(&body) {
+            Ok(gzipped) => (gzipped, true),
+            Err(err) => {
+                eprintln!(
+                    "Unable to gzip the payload: {}  Sending it uncompressed.",
+                    err
+                );
+                (body, false)
+            }
         }
+    } else {
+        (body, false)
+    };
+
+    let response = retry
+        .send(|| {
+            let mut request = client(endpoint)
+                .post(endpoint)
+                .header("Content-Type", "application/json")
+                .header("Authorization", auth)
+                .header("Idempotency-Key", idempotency_key);
+            if gzipped {
+                request = request.header("Content-Encoding", "gzip");
+            }
+            http::send(request.body(body.clone()))
+        })
+        .await;
+
+    match response {
+        Ok(response) => Ok(response),
+        Err(err) => Err(api_error(err).await),
     }
 }
 
-fn get_response_body(response: ureq::Response) -> Option<String> {
-    match response.into_string() {
-        Ok(json) => Some(json),
+fn gzip(body: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(body)?;
+    encoder.finish()
+}
+
+async fn get_response_body(response: Response) -> Result<String, ApiError> {
+    response
+        .text()
+        .await
+        .map_err(|err| ApiError::InvalidResponse(err.to_string()))
+}
+
+fn get_api_response(json: &str) -> Result<ApiResponse, ApiError> {
+    serde_json::from_str(json).map_err(|err| ApiError::InvalidResponse(err.to_string()))
+}
+
+/// The suite's token, however it was found.
+///
+/// ## Emits warnings if:
+///  - The token cannot be found (only once).
+pub fn token() -> Option<&'static str> {
+    static TOKEN: OnceLock<Option<String>> = OnceLock::new();
+
+    TOKEN.get_or_init(get_token).as_deref()
+}
+
+fn get_auth_header() -> Option<String> {
+    Some(format!("Token token=\"{}\"", token()?))
+}
+
+fn get_token() -> Option<String> {
+    if let Ok(token) = env::var("BUILDKITE_ANALYTICS_TOKEN") {
+        return Some(token);
+    }
+
+    if let Ok(key) = env::var("BUILDKITE_ANALYTICS_TOKEN_SECRET") {
+        return get_secret(&key);
+    }
+
+    match env::var("BUILDKITE_ANALYTICS_TOKEN_SERVICE_URL") {
+        Ok(url) => lookup_token(&url),
         Err(_) => {
-            eprintln!("Failed to parse JSON response");
+            eprintln!("Missing BUILDKITE_ANALYTICS_TOKEN environment variable.  No analytics will be sent.");
             None
         }
     }
 }
 
-fn get_api_response(json: &str) -> Option<ApiResponse> {
-    let maybe_response: serde_json::Result<ApiResponse> = serde_json::from_str(json);
+/// # TokenResponse
+///
+/// The token service's response, eg `{"token": "..."}`.
+#[derive(Deserialize, Debug, PartialEq)]
+struct TokenResponse {
+    token: String,
+}
+
+/// Look up the suite token for this repository from the token service at
+/// `url`, which is called as `GET <url>?repository=<owner/name>`.
+///
+/// The token is cached in the cache directory for
+/// `BUILDKITE_ANALYTICS_TOKEN_SERVICE_TTL` seconds (default 300), so that
+/// several steps of a build share one lookup.  The service's own credentials
+/// may be given in `BUILDKITE_ANALYTICS_TOKEN_SERVICE_AUTH`, which is sent as
+/// the `Authorization` header.
+///
+/// ## Emits warnings if:
+///  - The repository cannot be determined.
+///  - The service cannot be reached, has no token for the repository, or
+///    responds with something other than a token.
+pub fn lookup_token(url: &str) -> Option<String> {
+    let Some(repository) = repository_slug() else {
+        eprintln!("Unable to determine the repository to look up a token for.  Set BUILDKITE_ANALYTICS_REPOSITORY to its owner/name.  No analytics will be sent.");
+        return None;
+    };
 
-    match maybe_response {
-        Ok(response) => Some(response),
-        Err(_) => {
-            eprintln!("Failed to parse JSON response");
-            None
+    let ttl = Duration::from_secs(
+        parse_var("BUILDKITE_ANALYTICS_TOKEN_SERVICE_TTL").unwrap_or(DEFAULT_TOKEN_TTL),
+    );
+    let cache_path = cache_dir().join(format!(
+        "token-{:016x}",
+        fnv1a(&format!("{}\n{}", url, repository))
+    ));
+    if let Some(token) = read_cached_token(&cache_path, ttl) {
+        return Some(token);
+    }
+
+    let mut request = client(url).get(url).query(&[("repository", &repository)]);
+    if let Ok(auth) = env::var("BUILDKITE_ANALYTICS_TOKEN_SERVICE_AUTH") {
+        request = request.header("Authorization", auth);
+    }
+
+    let body = block_on(async {
+        match http::send(request).await {
+            Ok(response) => Ok(response.text().await),
+            Err(err) => Err(err),
+        }
+    });
+    let body = match body {
+        Ok(body) => body,
+        Err(HttpError::Status(404, _)) => {
+            eprintln!(
+                "The token service has no suite token for {}.  No analytics will be sent.",
+                repository
+            );
+            return None;
+        }
+        Err(HttpError::Status(status, _)) => {
+            eprintln!(
+                "The token service responded with HTTP {} looking up {}.  No analytics will be sent.",
+                status, repository
+            );
+            return None;
+        }
+        Err(err) => {
+            eprintln!(
+                "Unable to reach the token service: {}  No analytics will be sent.",
+                err
+            );
+            return None;
         }
+    };
+
+    let token = body
+        .ok()
+        .and_then(|body| serde_json::from_str::<TokenResponse>(&body).ok())
+        .map(|response| response.token)
+        .filter(|token| !token.trim().is_empty());
+    let Some(token) = token else {
+        eprintln!(
+            "The token service's response didn't contain a token.  No analytics will be sent."
+        );
+        return None;
+    };
+
+    if let Err(err) = write_cached_token(&cache_path, &token) {
+        eprintln!("Unable to cache the suite token: {}", err);
     }
+    Some(token)
 }
 
-fn get_auth_header() -> Option<String> {
-    match env::var("BUILDKITE_ANALYTICS_TOKEN") {
-        Ok(token) => Some(format!("Token token=\"{}\"", token)),
-        Err(_) => {
-            eprintln!("Missing BUILDKITE_ANALYTICS_TOKEN environment variable.  No analytics will be sent.");
+fn read_cached_token(path: &Path, ttl: Duration) -> Option<String> {
+    let age = fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())?;
+    if age > ttl {
+        return None;
+    }
+
+    let token = fs::read_to_string(path).ok()?;
+    Some(token.trim().to_string()).filter(|token| !token.is_empty())
+}
+
+/// Write the token where only this user can read it.
+fn write_cached_token(path: &Path, token: &str) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    io::Write::write_all(&mut options.open(path)?, token.as_bytes())
+}
+
+/// Fetch a secret using `buildkite-agent secret get`.
+pub fn get_secret(key: &str) -> Option<String> {
+    let output = Command::new("buildkite-agent")
+        .args(["secret", "get", key])
+        .output();
+
+    match output {
+        Ok(output) if output.status.success() => {
+            let secret = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            Some(secret).filter(|secret| !secret.is_empty())
+        }
+        Ok(output) => {
+            eprintln!(
+                "Unable to fetch the {} secret: {}  No analytics will be sent.",
+                key,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
             None
         }
+        Err(err) => {
+            eprintln!(
+                "Unable to run buildkite-agent to fetch the {} secret: {}  No analytics will be sent.",
+                key, err
+            );
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use serial_test::serial;
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpListener;
+    use std::thread;
+
+    type Request = (Vec<String>, Vec<u8>);
+
+    /// Accept one request at the returned endpoint, and respond to it with
+    /// `response`.  The server thread returns the request's headers
+    /// (lowercase) and body.
+    fn serve_once(response: &'static str) -> (String, thread::JoinHandle<Request>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/v1/uploads", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut headers = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                headers.push(line.trim().to_ascii_lowercase());
+            }
+            let length = headers
+                .iter()
+                .find_map(|header| header.strip_prefix("content-length: "))
+                .unwrap()
+                .parse()
+                .unwrap();
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            (&stream).write_all(response.as_bytes()).unwrap();
+            (headers, body)
+        });
+
+        (endpoint, server)
+    }
+
+    #[test]
+    #[serial]
+    fn uploads_are_gzipped() {
+        let (endpoint, server) =
+            serve_once("HTTP/1.1 202 Accepted\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+
+        let payload = serde_json::json!({ "data": vec!["boom"; 100] });
+        let response = block_on(send_request(
+            &payload,
+            &endpoint,
+            "Token",
+            "run-0",
+            &RetryPolicy::default(),
+        ));
+        assert_eq!(response.unwrap().status(), 202);
+
+        let (headers, body) = server.join().unwrap();
+        assert!(headers.contains(&"content-encoding: gzip".to_string()));
+        assert!(headers.contains(&"idempotency-key: run-0".to_string()));
+        assert!(body.len() < serde_json::to_vec(&payload).unwrap().len());
+        let mut json = String::new();
+        GzDecoder::new(&body[..]).read_to_string(&mut json).unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&json).unwrap(),
+            payload
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn failures_say_what_went_wrong() {
+        let send = |response| {
+            let (endpoint, server) = serve_once(response);
+            let result = block_on(async {
+                let response =
+                    send_request(&"{}", &endpoint, "Token", "run-0", &RetryPolicy::default())
+                        .await?;
+                get_api_response(&get_response_body(response).await?)
+            });
+            server.join().unwrap();
+            result.unwrap_err()
+        };
+
+        let err =
+            send("HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+        assert_eq!(err, ApiError::Unauthorized(401));
+        assert!(err.is_configuration());
+
+        let err = send("HTTP/1.1 422 Unprocessable Entity\r\nContent-Length: 16\r\nConnection: close\r\n\r\n{\"error\":\"bad\"}\n");
+        assert_eq!(err, ApiError::Status(422, r#"{"error":"bad"}"#.to_string()));
+        assert!(!err.is_transient() && !err.is_configuration());
+        assert_eq!(
+            err.to_string(),
+            r#"the API responded with HTTP 422: {"error":"bad"}"#
+        );
+
+        let err = send("HTTP/1.1 202 Accepted\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok");
+        assert!(matches!(err, ApiError::InvalidResponse(_)));
+
+        assert!(ApiError::Transport("refused".to_string()).is_transient());
+        assert!(ApiError::Status(503, String::new()).is_transient());
+    }
+
+    #[test]
+    fn cached_tokens_expire() {
+        let path = env::temp_dir().join(format!("token-test-{}", uuid::Uuid::new_v4()));
+
+        assert_eq!(read_cached_token(&path, Duration::from_secs(60)), None);
+
+        write_cached_token(&path, "abc123").unwrap();
+        assert_eq!(
+            read_cached_token(&path, Duration::from_secs(60)).as_deref(),
+            Some("abc123")
+        );
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(read_cached_token(&path, Duration::ZERO), None);
+
+        fs::remove_file(path).unwrap();
     }
 }
@@ -0,0 +1,132 @@
+//! # brownout
+//!
+//! Detects when the API is degrading (slow or failing responses) so that we
+//! can progressively back off and shed the remaining batches into the spool,
+//! rather than holding up the test pipeline.
+
+use std::time::Duration;
+
+/// Responses slower than this are considered degraded.
+const SLOW_RESPONSE: Duration = Duration::from_secs(5);
+
+/// Responses are only slow relative to the baseline if they're over three
+/// times this, so a first response which happens to be very fast doesn't make
+/// normal ones look slow.
+const MIN_BASELINE: Duration = Duration::from_millis(250);
+
+/// Once the pressure reaches this level all remaining batches are spooled.
+const SHED_PRESSURE: u32 = 4;
+
+/// # Action
+///
+/// What to do with the next batch.
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    /// Upload the batch, after waiting for the given backoff.
+    Upload(Duration),
+    /// Don't try to upload the batch, spool it instead.
+    Spool,
+}
+
+/// # Brownout
+///
+/// Tracks the health of the API across batch uploads.
+#[derive(Debug, Default)]
+pub struct Brownout {
+    pressure: u32,
+    /// A moving average of the latency of healthy responses.
+    baseline: Option<Duration>,
+}
+
+impl Brownout {
+    /// Decide what to do with the next batch.
+    pub fn next_action(&self) -> Action {
+        if self.is_shedding() {
+            Action::Spool
+        } else {
+            Action::Upload(Duration::from_secs(self.pressure as u64))
+        }
+    }
+
+    /// Have we given up on uploading directly?
+    pub fn is_shedding(&self) -> bool {
+        self.pressure >= SHED_PRESSURE
+    }
+
+    /// Record the outcome of an upload which took `latency`.
+    ///
+    /// Failures raise the pressure the most, responses which are slow or
+    /// much slower than the usual healthy response raise it a little, and
+    /// healthy responses let it recover.
+    pub fn record(&mut self, succeeded: bool, latency: Duration) {
+        let baseline = self.baseline.unwrap_or(latency).max(MIN_BASELINE);
+        let slow = latency >= SLOW_RESPONSE || latency > baseline * 3;
+
+        if !succeeded {
+            self.pressure += 2;
+        } else if slow {
+            self.pressure += 1;
+        } else {
+            self.pressure = self.pressure.saturating_sub(1);
+            // Weighted towards the recent responses.
+            self.baseline = Some(match self.baseline {
+                Some(baseline) => (baseline * 3 + latency) / 4,
+                None => latency,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn backs_off_then_sheds_as_the_api_degrades() {
+        let mut brownout = Brownout::default();
+        let fast = Duration::from_millis(100);
+
+        brownout.record(true, fast);
+        assert_eq!(brownout.next_action(), Action::Upload(Duration::ZERO));
+
+        brownout.record(true, Duration::from_secs(1));
+        assert_eq!(
+            brownout.next_action(),
+            Action::Upload(Duration::from_secs(1))
+        );
+
+        brownout.record(true, fast);
+        assert_eq!(brownout.next_action(), Action::Upload(Duration::ZERO));
+
+        brownout.record(false, fast);
+        brownout.record(false, fast);
+        assert_eq!(brownout.next_action(), Action::Spool);
+    }
+
+    #[test]
+    fn an_unusually_fast_first_response_is_not_the_baseline() {
+        let mut brownout = Brownout::default();
+
+        brownout.record(true, Duration::from_millis(20));
+        for _ in 0..10 {
+            brownout.record(true, Duration::from_millis(70));
+            assert_eq!(brownout.next_action(), Action::Upload(Duration::ZERO));
+        }
+    }
+
+    #[test]
+    fn responses_are_slow_relative_to_the_usual_response() {
+        let mut brownout = Brownout::default();
+        for _ in 0..10 {
+            brownout.record(true, Duration::from_millis(400));
+        }
+        brownout.record(true, Duration::from_millis(1000));
+        assert_eq!(brownout.next_action(), Action::Upload(Duration::ZERO));
+
+        brownout.record(true, Duration::from_millis(2000));
+        assert_eq!(
+            brownout.next_action(),
+            Action::Upload(Duration::from_secs(1))
+        );
+    }
+}
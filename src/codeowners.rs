@@ -0,0 +1,200 @@
+//! # codeowners
+//!
+//! Tags each test with the owners of its source file, according to the
+//! repository's CODEOWNERS file, so that results can be filtered by team.
+
+use crate::payload::Payload;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+/// Where CODEOWNERS files are conventionally kept, in order of precedence.
+const PATHS: [&str; 4] = [
+    ".github/CODEOWNERS",
+    "CODEOWNERS",
+    "docs/CODEOWNERS",
+    ".gitlab/CODEOWNERS",
+];
+
+/// # CodeOwners
+///
+/// The rules from a CODEOWNERS file.  Later rules take precedence.
+#[derive(Debug, PartialEq)]
+pub struct CodeOwners {
+    rules: Vec<(String, Vec<String>)>,
+}
+
+impl CodeOwners {
+    /// Find and parse the CODEOWNERS file.
+    ///
+    /// Taken from `BUILDKITE_ANALYTICS_CODEOWNERS`, or the first of the
+    /// conventional locations which exists.
+    pub fn discover() -> Option<Self> {
+        let path = match env::var_os("BUILDKITE_ANALYTICS_CODEOWNERS") {
+            Some(path) => PathBuf::from(path),
+            None => PATHS
+                .iter()
+                .map(PathBuf::from)
+                .find(|path| path.is_file())?,
+        };
+
+        match fs::read_to_string(&path) {
+            Ok(contents) => Some(Self::parse(&contents)),
+            Err(err) => {
+                eprintln!("Unable to read {}: {}", path.display(), err);
+                None
+            }
+        }
+    }
+
+    /// Parse the contents of a CODEOWNERS file.
+    pub fn parse(contents: &str) -> Self {
+        let rules = contents
+            .lines()
+            .map(|line| line.split_once('#').map_or(line, |(rule, _)| rule).trim())
+            .filter_map(|line| {
+                let mut parts = line.split_whitespace();
+                let pattern = parts.next()?;
+                Some((pattern.to_string(), parts.map(String::from).collect()))
+            })
+            .collect();
+
+        CodeOwners { rules }
+    }
+
+    /// The owners of the file at `path`, relative to the repository root.
+    ///
+    /// A matching rule with no owners means the file is unowned.
+    pub fn owners(&self, path: &str) -> Option<&[String]> {
+        let path = path.trim_start_matches("./");
+
+        self.rules
+            .iter()
+            .rev()
+            .find(|(pattern, _)| matches(pattern, path))
+            .map(|(_, owners)| owners.as_slice())
+            .filter(|owners| !owners.is_empty())
+    }
+
+    /// Tag every test in `payload` whose file has owners with an `owner` tag.
+    pub fn tag(&self, payload: &mut Payload) {
+        for test_data in payload.data_mut() {
+            let owners = test_data
+                .file_name()
+                .and_then(|file_name| self.owners(file_name))
+                .map(|owners| owners.join(","));
+
+            if let Some(owners) = owners {
+                test_data.insert_tag("owner", &owners);
+            }
+        }
+    }
+}
+
+/// Does the gitignore style `pattern` match `path`, or one of its parent
+/// directories?
+fn matches(pattern: &str, path: &str) -> bool {
+    let anchored = pattern.starts_with('/') || pattern.trim_end_matches('/').contains('/');
+    let pattern = pattern.trim_start_matches('/');
+
+    let mut segments = Vec::new();
+    if !anchored {
+        segments.push("**");
+    }
+    segments.extend(pattern.split('/').filter(|segment| !segment.is_empty()));
+    if pattern.ends_with('/') {
+        segments.push("**");
+    }
+
+    let path = path.split('/').collect::<Vec<&str>>();
+
+    (1..=path.len()).any(|len| match_segments(&segments, &path[..len]))
+}
+
+fn match_segments(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((&"**", rest)) => (0..=path.len()).any(|skip| match_segments(rest, &path[skip..])),
+        Some((segment, rest)) => match path.split_first() {
+            Some((first, path_rest)) => {
+                match_glob(segment.as_bytes(), first.as_bytes()) && match_segments(rest, path_rest)
+            }
+            None => false,
+        },
+    }
+}
+
+/// Does the glob `pattern` (with `*` and `?` wildcards) match all of `name`?
+pub fn match_glob(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|skip| match_glob(rest, &name[skip..])),
+        Some((b'?', rest)) => !name.is_empty() && match_glob(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && match_glob(rest, &name[1..]),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn patterns_match_like_gitignore() {
+        assert!(matches("*", "src/lib.rs"));
+        assert!(matches("*.rs", "src/lib.rs"));
+        assert!(!matches("*.rs", "README.md"));
+        assert!(matches("/src/", "src/payload.rs"));
+        assert!(!matches("/src/", "crates/foo/src/lib.rs"));
+        assert!(matches("src/", "crates/foo/src/lib.rs"));
+        assert!(matches("docs", "docs/index.md"));
+        assert!(matches("crates/*/tests/", "crates/api/tests/upload.rs"));
+        assert!(!matches("crates/*/tests/", "crates/api/src/upload.rs"));
+        assert!(matches("crates/**/upload.rs", "crates/api/src/upload.rs"));
+        assert!(matches("src/lib.r?", "src/lib.rs"));
+    }
+
+    #[test]
+    fn later_rules_take_precedence() {
+        let owners = CodeOwners::parse(
+            "# Default owners
+*       @buildkite/everyone
+/src/   @buildkite/collectors  # the collector itself
+/src/api.rs @buildkite/api @octocat
+/src/generated.rs
+",
+        );
+
+        assert_eq!(
+            owners.owners("README.md"),
+            Some(&["@buildkite/everyone".to_string()][..])
+        );
+        assert_eq!(
+            owners.owners("./src/payload.rs"),
+            Some(&["@buildkite/collectors".to_string()][..])
+        );
+        assert_eq!(
+            owners.owners("src/api.rs"),
+            Some(&["@buildkite/api".to_string(), "@octocat".to_string()][..])
+        );
+        assert_eq!(owners.owners("src/generated.rs"), None);
+    }
+
+    #[test]
+    fn tests_are_tagged_with_their_owners() {
+        use crate::run_env::RuntimeEnvironment;
+
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+        for line in [
+            r#"{ "type": "suite", "event": "started", "test_count": 1 }"#,
+            r#"{ "type": "test", "event": "started", "name": "src/api.rs - submit (line 10)" }"#,
+            r#"{ "type": "test", "name": "src/api.rs - submit (line 10)", "event": "ok", "exec_time": 0.1 }"#,
+        ] {
+            crate::input::parse_line(line, &mut payload).unwrap();
+        }
+
+        CodeOwners::parse("/src/api.rs @buildkite/api @octocat").tag(&mut payload);
+
+        let json = serde_json::to_value(&payload).unwrap();
+        assert_eq!(json["data"][0]["tags"]["owner"], "@buildkite/api,@octocat");
+    }
+}
@@ -0,0 +1,460 @@
+//! # compare
+//!
+//! Compares the local results with the latest run on a baseline branch,
+//! fetched from the Test Engine REST API, and reports new failures, fixed
+//! tests and significant duration regressions as markdown.
+//!
+//! Every execution of the baseline run is fetched, passed or failed, a page
+//! at a time, so that slow-downs of tests which passed on the baseline are
+//! reported too.
+
+use crate::http;
+use crate::payload::{Payload, TestData};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::fmt::Write;
+
+static API: &str = "https://api.buildkite.com/v2/analytics/organizations";
+
+/// Durations which grow by more than this factor are regressions.
+const REGRESSION_FACTOR: f64 = 1.5;
+
+/// Durations shorter than this are too noisy to compare.
+const MIN_DURATION: f64 = 0.1;
+
+/// How many items to ask for in each page of results.
+const PER_PAGE: usize = 100;
+
+/// The most pages of runs to look through for one on the baseline branch,
+/// and of its executions to fetch.
+const MAX_PAGES: usize = 50;
+
+#[derive(Deserialize, Debug, PartialEq)]
+struct Run {
+    id: String,
+    branch: Option<String>,
+    url: Option<String>,
+}
+
+/// # BaseExecution
+///
+/// An execution from the baseline run.
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+pub struct BaseExecution {
+    #[serde(default)]
+    scope: Option<String>,
+    #[serde(alias = "test_name")]
+    name: String,
+    #[serde(default)]
+    duration: Option<f64>,
+    /// Eg `passed` or `failed`.
+    #[serde(default)]
+    result: Option<String>,
+}
+
+impl BaseExecution {
+    fn is_failed(&self) -> bool {
+        self.result.as_deref() == Some("failed")
+    }
+}
+
+/// # Comparison
+///
+/// The differences between the baseline run and the local results.
+#[derive(Debug, Default, PartialEq)]
+pub struct Comparison {
+    base_url: Option<String>,
+    new_failures: Vec<String>,
+    fixed: Vec<String>,
+    regressions: Vec<(String, f64, f64)>,
+}
+
+impl Comparison {
+    /// Are there any new failures?
+    pub fn has_new_failures(&self) -> bool {
+        !self.new_failures.is_empty()
+    }
+
+    /// Render the comparison as markdown, suitable for a PR comment.
+    pub fn to_markdown(&self, base: &str) -> String {
+        let mut markdown = String::new();
+
+        match &self.base_url {
+            Some(url) => writeln!(markdown, "### Compared with [`{}`]({})\n", base, url),
+            None => writeln!(markdown, "### Compared with `{}`\n", base),
+        }
+        .unwrap();
+
+        if self.new_failures.is_empty() && self.fixed.is_empty() && self.regressions.is_empty() {
+            writeln!(markdown, "No changes.").unwrap();
+        }
+
+        write_list(&mut markdown, "New failures", &self.new_failures);
+        write_list(&mut markdown, "Fixed", &self.fixed);
+
+        if !self.regressions.is_empty() {
+            writeln!(markdown, "#### Duration regressions\n").unwrap();
+            writeln!(markdown, "| Test | {} | Local |", base).unwrap();
+            writeln!(markdown, "| --- | --- | --- |").unwrap();
+            for (name, before, after) in self.regressions.iter() {
+                writeln!(markdown, "| `{}` | {:.3}s | {:.3}s |", name, before, after).unwrap();
+            }
+        }
+
+        markdown
+    }
+}
+
+fn write_list(markdown: &mut String, heading: &str, names: &[String]) {
+    if names.is_empty() {
+        return;
+    }
+
+    writeln!(markdown, "#### {} ({})\n", heading, names.len()).unwrap();
+    for name in names {
+        writeln!(markdown, "- `{}`", name).unwrap();
+    }
+    writeln!(markdown).unwrap();
+}
+
+/// Compare the local results in `payload` with the baseline's executions.
+pub fn compare(base: &[BaseExecution], base_url: Option<String>, payload: &Payload) -> Comparison {
+    let local = payload
+        .closed_data()
+        .into_iter()
+        .map(|test_data| (test_data.full_name(), test_data))
+        .collect::<HashMap<String, &TestData>>();
+
+    let base = base
+        .iter()
+        .map(|execution| {
+            let name = match &execution.scope {
+                Some(scope) if !scope.is_empty() => format!("{}::{}", scope, execution.name),
+                _ => execution.name.clone(),
+            };
+            (name, execution)
+        })
+        .collect::<HashMap<String, &BaseExecution>>();
+
+    let mut comparison = Comparison {
+        base_url,
+        ..Comparison::default()
+    };
+
+    for (name, test_data) in local.iter() {
+        let execution = base.get(name);
+        if test_data.is_failed() && !execution.is_some_and(|execution| execution.is_failed()) {
+            comparison.new_failures.push(name.clone());
+        }
+
+        let durations = execution
+            .and_then(|execution| execution.duration)
+            .zip(test_data.duration());
+        if let Some((before, after)) = durations {
+            if before >= MIN_DURATION && after > before * REGRESSION_FACTOR {
+                comparison.regressions.push((name.clone(), before, after));
+            }
+        }
+    }
+
+    for (name, execution) in base.iter() {
+        if execution.is_failed()
+            && local
+                .get(name)
+                .is_some_and(|test_data| !test_data.is_failed())
+        {
+            comparison.fixed.push(name.clone());
+        }
+    }
+
+    comparison.new_failures.sort();
+    comparison.fixed.sort();
+    comparison.regressions.sort_by(|a, b| a.0.cmp(&b.0));
+
+    comparison
+}
+
+/// Fetch every execution of the latest run on the `base` branch.
+///
+/// Needs `BUILDKITE_API_TOKEN` (with the `read_suites` scope),
+/// `BUILDKITE_ORGANIZATION_SLUG` and `BUILDKITE_ANALYTICS_SUITE_SLUG`.
+///
+/// ## Emits warnings if:
+///  - Any of the required environment variables are missing.
+///  - There's no run on the `base` branch in the first `MAX_PAGES` pages.
+///  - The API request fails.
+pub fn fetch_base(base: &str) -> Option<(Vec<BaseExecution>, Option<String>)> {
+    let token = required_var("BUILDKITE_API_TOKEN")?;
+    let org = required_var("BUILDKITE_ORGANIZATION_SLUG")?;
+    let suite = required_var("BUILDKITE_ANALYTICS_SUITE_SLUG")?;
+    let auth = format!("Bearer {}", token);
+    let suite_url = format!("{}/{}/suites/{}", API, org, suite);
+
+    let runs_url = format!("{}/runs", suite_url);
+    let Some(run) = find_run(base, |page| {
+        get_json(&runs_url, &auth, &[("branch", base)], page)
+    })?
+    else {
+        eprintln!("Unable to find a recent run on the {} branch.", base);
+        return None;
+    };
+
+    let executions_url = format!("{}/runs/{}/executions", suite_url, run.id);
+    let executions = get_all(|page| get_json(&executions_url, &auth, &[], page))?;
+
+    Some((executions, run.url))
+}
+
+/// The first run on the `base` branch, looking through the pages of runs
+/// returned by `get_page` (counting from 1) until there are no more.
+///
+/// Returns `None` if a page couldn't be fetched.
+fn find_run<F>(base: &str, mut get_page: F) -> Option<Option<Run>>
+where
+    F: FnMut(usize) -> Option<Vec<Run>>,
+{
+    for page in 1..=MAX_PAGES {
+        let runs = get_page(page)?;
+        let last_page = runs.len() < PER_PAGE;
+        // The branch filter is applied here too, in case the API ignores it.
+        if let Some(run) = runs
+            .into_iter()
+            .find(|run| run.branch.as_deref() == Some(base))
+        {
+            return Some(Some(run));
+        }
+        if last_page {
+            break;
+        }
+    }
+
+    Some(None)
+}
+
+/// Every item in the pages returned by `get_page` (counting from 1).
+///
+/// Returns `None` if a page couldn't be fetched.
+///
+/// ## Emits warnings if:
+///  - There are more than `MAX_PAGES` pages, in which case only those are
+///    returned.
+fn get_all<T, F>(mut get_page: F) -> Option<Vec<T>>
+where
+    F: FnMut(usize) -> Option<Vec<T>>,
+{
+    let mut all = Vec::new();
+    for page in 1..=MAX_PAGES {
+        let items = get_page(page)?;
+        let last_page = items.len() < PER_PAGE;
+        all.extend(items);
+        if last_page {
+            return Some(all);
+        }
+    }
+
+    eprintln!(
+        "Only the first {} executions of the base run were compared.",
+        all.len()
+    );
+    Some(all)
+}
+
+/// Fetch the `page`th page of JSON from `url`.
+fn get_json<T: serde::de::DeserializeOwned>(
+    url: &str,
+    auth: &str,
+    query: &[(&str, &str)],
+    page: usize,
+) -> Option<T> {
+    let request = http::client(url)
+        .get(url)
+        .header("Authorization", auth)
+        .query(query)
+        .query(&[("page", page), ("per_page", PER_PAGE)]);
+    let response = http::block_on(async {
+        match http::send(request).await {
+            Ok(response) => response.text().await.map_err(http::HttpError::Transport),
+            Err(err) => Err(err),
+        }
+    });
+
+    match response.map(|body| serde_json::from_str::<T>(&body)) {
+        Ok(Ok(json)) => Some(json),
+        Ok(Err(err)) => {
+            eprintln!("Failed to parse JSON response from {}: {}", url, err);
+            None
+        }
+        Err(err) => {
+            eprintln!("HTTP Error fetching {}: {}", url, err);
+            None
+        }
+    }
+}
+
+fn required_var(key: &str) -> Option<String> {
+    match env::var(key) {
+        Ok(value) => Some(value),
+        Err(_) => {
+            eprintln!("Missing {} environment variable, which compare needs.", key);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::input::{Event, SuiteEvent, TestEvent};
+    use crate::run_env::RuntimeEnvironment;
+
+    #[test]
+    fn compare_finds_new_failures_fixes_and_regressions() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+        payload.push(Event::Suite {
+            event: SuiteEvent::Started { test_count: 5 },
+        });
+        for (name, failed, exec_time) in [
+            ("api::test::new_failure", true, 0.1),
+            ("api::test::fixed", false, 0.1),
+            ("api::test::slow", true, 2.0),
+            ("api::test::slower", false, 1.0),
+            ("api::test::broken", true, 0.1),
+        ] {
+            payload.push(Event::Test {
+                event: TestEvent::Started {
+                    name: name.to_string(),
+                },
+                nextest: None,
+                extra: Default::default(),
+            });
+            payload.push(Event::Test {
+                event: if failed {
+                    TestEvent::Failed {
+                        name: name.to_string(),
+                        exec_time: Some(exec_time),
+                        stdout: None,
+                        stderr: None,
+                        message: None,
+                    }
+                } else {
+                    TestEvent::Ok {
+                        name: name.to_string(),
+                        exec_time: Some(exec_time),
+                        stdout: None,
+                    }
+                },
+                nextest: None,
+                extra: Default::default(),
+            });
+        }
+
+        let base = vec![
+            BaseExecution {
+                scope: Some("api::test".to_string()),
+                name: "fixed".to_string(),
+                duration: Some(0.1),
+                result: Some("failed".to_string()),
+            },
+            BaseExecution {
+                scope: Some("api::test".to_string()),
+                name: "slow".to_string(),
+                duration: Some(1.0),
+                result: Some("failed".to_string()),
+            },
+            BaseExecution {
+                scope: Some("api::test".to_string()),
+                name: "slower".to_string(),
+                duration: Some(0.5),
+                result: Some("passed".to_string()),
+            },
+            BaseExecution {
+                scope: Some("api::test".to_string()),
+                name: "broken".to_string(),
+                duration: Some(0.1),
+                result: Some("passed".to_string()),
+            },
+        ];
+
+        let comparison = compare(&base, None, &payload);
+
+        assert_eq!(
+            comparison.new_failures,
+            vec!["api::test::broken", "api::test::new_failure"]
+        );
+        assert_eq!(comparison.fixed, vec!["api::test::fixed"]);
+        assert_eq!(
+            comparison.regressions,
+            vec![
+                ("api::test::slow".to_string(), 1.0, 2.0),
+                ("api::test::slower".to_string(), 0.5, 1.0),
+            ]
+        );
+        assert!(comparison.has_new_failures());
+
+        let markdown = comparison.to_markdown("main");
+        assert!(markdown.contains("#### New failures (2)"));
+        assert!(markdown.contains("| `api::test::slow` | 1.000s | 2.000s |"));
+    }
+
+    fn run(id: usize, branch: &str) -> Run {
+        Run {
+            id: id.to_string(),
+            branch: Some(branch.to_string()),
+            url: None,
+        }
+    }
+
+    #[test]
+    fn looks_past_the_first_page_for_a_base_run() {
+        let mut pages = Vec::new();
+        let found = find_run("main", |page| {
+            pages.push(page);
+            let branch = if page == 3 { "main" } else { "feature" };
+            Some(
+                (0..PER_PAGE)
+                    .map(|id| run(page * 1000 + id, branch))
+                    .collect(),
+            )
+        });
+        assert_eq!(found, Some(Some(run(3000, "main"))));
+        assert_eq!(pages, [1, 2, 3]);
+
+        let mut pages = 0;
+        let found = find_run("main", |_| {
+            pages += 1;
+            Some(vec![run(1, "feature")])
+        });
+        assert_eq!(found, Some(None));
+        assert_eq!(pages, 1, "stops after a short page");
+
+        let mut pages = 0;
+        let found = find_run("main", |_| {
+            pages += 1;
+            Some((0..PER_PAGE).map(|id| run(id, "feature")).collect())
+        });
+        assert_eq!(found, Some(None));
+        assert_eq!(pages, MAX_PAGES);
+
+        assert_eq!(find_run("main", |_| None), None);
+    }
+
+    #[test]
+    fn fetches_every_page_of_executions() {
+        let executions = get_all(|page| Some(vec![page; if page < 3 { PER_PAGE } else { 1 }]));
+        assert_eq!(executions.unwrap().len(), 2 * PER_PAGE + 1);
+
+        let mut pages = 0;
+        let executions = get_all(|_| {
+            pages += 1;
+            Some(vec![0; PER_PAGE])
+        });
+        assert_eq!(executions.unwrap().len(), MAX_PAGES * PER_PAGE);
+        assert_eq!(pages, MAX_PAGES);
+
+        assert_eq!(
+            get_all::<usize, _>(|page| (page < 2).then(|| vec![0; PER_PAGE])),
+            None
+        );
+    }
+}
@@ -0,0 +1,178 @@
+//! # compress
+//!
+//! Optional zstd compression of the files we write to disk, eg spooled
+//! payloads.  Compressed files get a `.zst` extension and are decompressed
+//! transparently when read back.
+//!
+//! Compression needs the `zstd` feature, and is enabled by setting
+//! `BUILDKITE_ANALYTICS_COMPRESSION=zstd` (with an optional
+//! `BUILDKITE_ANALYTICS_COMPRESSION_LEVEL`, default `3`).
+
+use crate::payload::parse_var;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The extension of compressed files.
+pub const EXTENSION: &str = "zst";
+
+const DEFAULT_LEVEL: i32 = 3;
+
+/// # Compression
+///
+/// How to compress files written to disk.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd {
+        level: i32,
+    },
+}
+
+impl Compression {
+    /// Read the compression settings from the environment.
+    ///
+    /// ## Emits warnings
+    ///  - If the compression is not recognised.
+    ///  - If zstd compression is requested without the `zstd` feature.
+    pub fn from_env() -> Self {
+        match std::env::var("BUILDKITE_ANALYTICS_COMPRESSION").as_deref() {
+            Err(_) | Ok("") | Ok("none") => Compression::None,
+            Ok("zstd") if cfg!(feature = "zstd") => Compression::Zstd {
+                level: parse_var("BUILDKITE_ANALYTICS_COMPRESSION_LEVEL").unwrap_or(DEFAULT_LEVEL),
+            },
+            Ok("zstd") => {
+                eprintln!("This build doesn't support zstd compression, so files will be written uncompressed.");
+                Compression::None
+            }
+            Ok(other) => {
+                eprintln!(
+                    "Unknown BUILDKITE_ANALYTICS_COMPRESSION {:?}, files will be written uncompressed.",
+                    other
+                );
+                Compression::None
+            }
+        }
+    }
+
+    /// Write `contents` to `path`, compressed if need be.
+    ///
+    /// Returns the path actually written, which has an extra `.zst` extension
+    /// if it was compressed.
+    pub fn write(&self, path: &Path, contents: &[u8]) -> io::Result<PathBuf> {
+        match self {
+            Compression::None => {
+                fs::write(path, contents)?;
+                Ok(path.to_path_buf())
+            }
+            Compression::Zstd { level } => {
+                let mut compressed_path = OsString::from(path);
+                compressed_path.push(".");
+                compressed_path.push(EXTENSION);
+                let compressed_path = PathBuf::from(compressed_path);
+
+                fs::write(&compressed_path, zstd_encode(contents, *level)?)?;
+                Ok(compressed_path)
+            }
+        }
+    }
+}
+
+/// Read the file at `path`, decompressing it if it has a `.zst` extension.
+pub fn read(path: &Path) -> io::Result<Vec<u8>> {
+    let contents = fs::read(path)?;
+
+    if is_compressed(path) {
+        zstd_decode(&contents)
+    } else {
+        Ok(contents)
+    }
+}
+
+/// Does `path` have a `.zst` extension?
+pub fn is_compressed(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == EXTENSION)
+}
+
+/// The path with any `.zst` extension removed, eg to check the underlying
+/// file type.
+pub fn uncompressed_path(path: &Path) -> PathBuf {
+    if is_compressed(path) {
+        path.with_extension("")
+    } else {
+        path.to_path_buf()
+    }
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_encode(contents: &[u8], level: i32) -> io::Result<Vec<u8>> {
+    zstd::encode_all(contents, level)
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_encode(_contents: &[u8], _level: i32) -> io::Result<Vec<u8>> {
+    Err(unsupported())
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_decode(contents: &[u8]) -> io::Result<Vec<u8>> {
+    zstd::decode_all(contents)
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_decode(_contents: &[u8]) -> io::Result<Vec<u8>> {
+    Err(unsupported())
+}
+
+#[cfg(not(feature = "zstd"))]
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "zstd support requires the zstd feature",
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use uuid::Uuid;
+
+    #[test]
+    fn uncompressed_files_are_written_as_is() {
+        let path = env::temp_dir().join(format!("compress-test-{}.json", Uuid::new_v4()));
+
+        let written = Compression::None.write(&path, b"{}").unwrap();
+
+        assert_eq!(written, path);
+        assert_eq!(read(&written).unwrap(), b"{}");
+        fs::remove_file(written).unwrap();
+    }
+
+    #[test]
+    fn compressed_paths_are_recognised() {
+        let path = Path::new("spool/batch.json.zst");
+
+        assert!(is_compressed(path));
+        assert_eq!(uncompressed_path(path), Path::new("spool/batch.json"));
+        assert!(!is_compressed(Path::new("spool/batch.json")));
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn compressed_files_round_trip() {
+        let path = env::temp_dir().join(format!("compress-test-{}.json", Uuid::new_v4()));
+        let contents = "{\"data\":[]}".repeat(100);
+
+        let written = Compression::Zstd { level: 3 }
+            .write(&path, contents.as_bytes())
+            .unwrap();
+
+        assert_eq!(written.extension().unwrap(), "zst");
+        assert!(fs::metadata(&written).unwrap().len() < contents.len() as u64);
+        assert_eq!(read(&written).unwrap(), contents.as_bytes());
+        fs::remove_file(written).unwrap();
+    }
+}
@@ -0,0 +1,266 @@
+//! # criterion
+//!
+//! Imports [Criterion] benchmark results, so that performance trends appear
+//! alongside test results.  Results are read either from the JSON messages of
+//! `cargo criterion --message-format=json`, or from the `estimates.json`
+//! files Criterion leaves in `target/criterion`.
+//!
+//! Each benchmark is recorded like a libtest `#[bench]`: its duration is the
+//! median time per iteration.
+//!
+//! [Criterion]: https://github.com/bheisler/criterion.rs
+
+use crate::input::Event;
+use crate::payload::Payload;
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// # Estimate
+///
+/// A statistic with its confidence interval, as reported by cargo-criterion.
+#[derive(Deserialize, Debug, PartialEq)]
+struct Estimate {
+    estimate: f64,
+    unit: String,
+}
+
+impl Estimate {
+    /// The estimate in nanoseconds.
+    fn nanoseconds(&self) -> Option<f64> {
+        let scale = match self.unit.as_str() {
+            "ns" => 1.0,
+            "us" | "µs" => 1_000.0,
+            "ms" => 1_000_000.0,
+            "s" => 1_000_000_000.0,
+            _ => return None,
+        };
+        Some(self.estimate * scale)
+    }
+}
+
+/// # Throughput
+///
+/// How much each iteration processes, if the benchmark says.
+#[derive(Deserialize, Debug, PartialEq)]
+struct Throughput {
+    per_iteration: u64,
+    unit: String,
+}
+
+/// # BenchmarkComplete
+///
+/// The message cargo-criterion prints as each benchmark finishes.
+#[derive(Deserialize, Debug, PartialEq)]
+struct BenchmarkComplete {
+    id: String,
+    median: Estimate,
+    median_abs_dev: Estimate,
+    #[serde(default)]
+    throughput: Vec<Throughput>,
+}
+
+/// # PointEstimate
+///
+/// A statistic from an `estimates.json` file, always in nanoseconds.
+#[derive(Deserialize, Debug, PartialEq)]
+struct PointEstimate {
+    point_estimate: f64,
+}
+
+/// # Estimates
+///
+/// The statistics Criterion writes to `<benchmark>/new/estimates.json`.
+#[derive(Deserialize, Debug, PartialEq)]
+struct Estimates {
+    median: PointEstimate,
+    median_abs_dev: PointEstimate,
+}
+
+/// # BenchmarkInfo
+///
+/// The description Criterion writes to `<benchmark>/new/benchmark.json`.
+#[derive(Deserialize, Debug, PartialEq)]
+struct BenchmarkInfo {
+    full_id: String,
+}
+
+/// Parse a single line of `cargo criterion --message-format=json` output.
+///
+/// Lines other than finished benchmarks are ignored.
+pub fn parse_line(line: &str, payload: &mut Payload) {
+    let Ok(message) = serde_json::from_str::<serde_json::Value>(line) else {
+        return;
+    };
+    if message["reason"] != "benchmark-complete" {
+        return;
+    }
+
+    let complete = match serde_json::from_value::<BenchmarkComplete>(message) {
+        Ok(complete) => complete,
+        Err(err) => {
+            eprintln!("Unable to parse Criterion benchmark: {}", err);
+            return;
+        }
+    };
+
+    let (Some(median), Some(deviation)) = (
+        complete.median.nanoseconds(),
+        complete.median_abs_dev.nanoseconds(),
+    ) else {
+        eprintln!(
+            "Unable to record Criterion benchmark {}: unknown unit {:?}",
+            complete.id, complete.median.unit
+        );
+        return;
+    };
+
+    let mib_per_second = complete
+        .throughput
+        .iter()
+        .find(|throughput| throughput.unit == "bytes")
+        .filter(|_| median > 0.0)
+        .map(|throughput| {
+            (throughput.per_iteration as f64 / (median / 1_000_000_000.0) / 1_048_576.0) as u64
+        });
+
+    payload.push(Event::Bench {
+        name: complete.id,
+        median,
+        deviation,
+        mib_per_second,
+    });
+}
+
+/// Read every benchmark's latest estimates from a Criterion output directory,
+/// eg `target/criterion`.
+///
+/// Returns the number of benchmarks read.
+///
+/// ## Emits warnings
+///  - For each `estimates.json` which cannot be read or parsed.
+pub fn read_dir(dir: &Path, payload: &mut Payload) -> io::Result<usize> {
+    let mut estimates = Vec::new();
+    find_estimates(dir, &mut estimates)?;
+    estimates.sort();
+
+    let mut count = 0;
+    for path in estimates {
+        match read_estimates(dir, &path) {
+            Ok((name, estimates)) => {
+                payload.push(Event::Bench {
+                    name,
+                    median: estimates.median.point_estimate,
+                    deviation: estimates.median_abs_dev.point_estimate,
+                    mib_per_second: None,
+                });
+                count += 1;
+            }
+            Err(err) => eprintln!("Unable to read {}: {}", path.display(), err),
+        }
+    }
+
+    Ok(count)
+}
+
+/// The benchmark name and estimates from `<benchmark>/new/estimates.json`.
+///
+/// The name is taken from the neighbouring `benchmark.json`, or the
+/// benchmark's directory relative to `dir` if that's missing.
+fn read_estimates(dir: &Path, path: &Path) -> io::Result<(String, Estimates)> {
+    let estimates = serde_json::from_slice::<Estimates>(&fs::read(path)?)?;
+
+    let new_dir = path.parent().unwrap_or(dir);
+    let name = fs::read(new_dir.join("benchmark.json"))
+        .ok()
+        .and_then(|info| serde_json::from_slice::<BenchmarkInfo>(&info).ok())
+        .map(|info| info.full_id)
+        .unwrap_or_else(|| {
+            let benchmark_dir = new_dir.parent().unwrap_or(dir);
+            benchmark_dir
+                .strip_prefix(dir)
+                .unwrap_or(benchmark_dir)
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/")
+        });
+
+    Ok((name, estimates))
+}
+
+fn find_estimates(dir: &Path, found: &mut Vec<std::path::PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            // Criterion's HTML reports don't contain estimates.
+            if path.file_name().is_some_and(|name| name != "report") {
+                find_estimates(&path, found)?;
+            }
+        } else if path.ends_with("new/estimates.json") {
+            found.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::run_env::RuntimeEnvironment;
+    use std::env;
+
+    #[test]
+    fn parses_cargo_criterion_messages() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+
+        for line in [
+            r#"{"reason":"group-complete","group_name":"fib","benchmarks":["fib/20"],"report_directory":"target/criterion/reports/fib"}"#,
+            r#"{"reason":"benchmark-complete","id":"fib/20","report_directory":"target/criterion/reports/fib/20","iteration_count":[1],"measured_values":[1.0],"unit":"ns","throughput":[{"per_iteration":1048576,"unit":"bytes"}],"typical":{"estimate":2.0,"lower_bound":1.9,"upper_bound":2.1,"unit":"ms"},"mean":{"estimate":2.0,"lower_bound":1.9,"upper_bound":2.1,"unit":"ms"},"median":{"estimate":2.0,"lower_bound":1.9,"upper_bound":2.1,"unit":"ms"},"median_abs_dev":{"estimate":50.0,"lower_bound":40.0,"upper_bound":60.0,"unit":"us"},"slope":null,"change":null}"#,
+        ] {
+            parse_line(line, &mut payload);
+        }
+
+        let json = serde_json::to_value(&payload).unwrap();
+        let bench = &json["data"][0];
+        assert_eq!(json["data"].as_array().unwrap().len(), 1);
+        assert_eq!(bench["name"], "fib/20");
+        assert_eq!(bench["history"]["duration"], 0.002);
+        assert_eq!(bench["tags"]["bench.median_ns"], "2000000");
+        assert_eq!(bench["tags"]["bench.deviation_ns"], "50000");
+        assert_eq!(bench["tags"]["bench.mib_per_second"], "500");
+    }
+
+    #[test]
+    fn reads_estimates_from_the_output_directory() {
+        let dir = env::temp_dir().join(format!("criterion-{}", uuid::Uuid::new_v4()));
+        let estimates = r#"{"mean":{"confidence_interval":{"confidence_level":0.95,"lower_bound":1.0,"upper_bound":2.0},"point_estimate":1500.0,"standard_error":1.0},"median":{"confidence_interval":{"confidence_level":0.95,"lower_bound":1.0,"upper_bound":2.0},"point_estimate":1000.0,"standard_error":1.0},"median_abs_dev":{"confidence_interval":{"confidence_level":0.95,"lower_bound":1.0,"upper_bound":2.0},"point_estimate":10.0,"standard_error":1.0},"slope":null,"std_dev":{"confidence_interval":{"confidence_level":0.95,"lower_bound":1.0,"upper_bound":2.0},"point_estimate":12.0,"standard_error":1.0}}"#;
+
+        for benchmark in ["fib/20", "parse"] {
+            let new_dir = dir.join(benchmark).join("new");
+            fs::create_dir_all(&new_dir).unwrap();
+            fs::write(new_dir.join("estimates.json"), estimates).unwrap();
+        }
+        fs::write(
+            dir.join("parse/new/benchmark.json"),
+            r#"{"group_id":"parse","function_id":null,"value_str":null,"throughput":null,"full_id":"parse json","directory_name":"parse","title":"parse json"}"#,
+        )
+        .unwrap();
+        fs::create_dir_all(dir.join("report")).unwrap();
+
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+        assert_eq!(read_dir(&dir, &mut payload).unwrap(), 2);
+
+        let mut names = payload
+            .closed_data()
+            .iter()
+            .map(|test_data| test_data.name().to_string())
+            .collect::<Vec<String>>();
+        names.sort();
+        assert_eq!(names, vec!["fib/20", "parse json"]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,226 @@
+//! # doctor
+//!
+//! Diagnoses the collection environment and reports any misconfiguration
+//! along with hints on how to fix it.
+
+use crate::api;
+use crate::http;
+use crate::input::{self, Event, ParseError, TestEvent};
+use crate::run_env::{RuntimeEnvironment, REQUIRED_VARS};
+use std::env;
+use std::io::{BufRead, IsTerminal};
+
+/// # InputReport
+///
+/// A summary of the lines read from `stdin`.
+#[derive(Debug, Default, PartialEq)]
+pub struct InputReport {
+    lines: usize,
+    events: usize,
+    unrecognised_json: usize,
+    unknown_events: usize,
+    finished_tests: usize,
+    missing_exec_time: usize,
+}
+
+/// Run the diagnostics and print the report to `stdout`.
+pub fn run(endpoint: &str) {
+    println!("CI environment:");
+    check_environment();
+
+    println!("\nAPI token:");
+    check_token();
+
+    println!("\nEndpoint:");
+    println!("  ✓ Results will be sent to {}", endpoint);
+    if let Some(proxy) = &http::config().proxy {
+        if http::config().is_proxied(endpoint) {
+            println!(
+                "  ✓ Requests will go through the proxy {}",
+                http::without_credentials(proxy)
+            );
+        } else {
+            println!("  ✓ Requests will bypass the proxy, as NO_PROXY lists the endpoint");
+        }
+    }
+
+    println!("\nInput:");
+    let stdin = std::io::stdin();
+    if stdin.is_terminal() {
+        println!("  ! Nothing was piped to stdin, so the input format was not checked.");
+        println!("    Hint: pipe your test output into `doctor` to check it too.");
+    } else {
+        let report = check_input(stdin.lock().lines().map_while(Result::ok));
+        print_input_report(&report);
+    }
+}
+
+fn check_environment() {
+    match RuntimeEnvironment::detect() {
+        Some(run_env) => println!("  ✓ Detected the {} environment", run_env.ci()),
+        None => {
+            println!("  ✗ Unable to detect a CI environment, no analytics would be sent.");
+            println!("    Hint: set CI to any non-empty value to use a generic environment.");
+        }
+    }
+
+    for (ci, vars) in REQUIRED_VARS {
+        let missing = vars
+            .iter()
+            .filter(|var| env::var(var).is_err())
+            .copied()
+            .collect::<Vec<&str>>();
+
+        if missing.is_empty() {
+            println!("    {}: all required variables are present", ci);
+        } else {
+            println!("    {}: missing {}", ci, missing.join(", "));
+        }
+    }
+}
+
+fn check_token() {
+    if let Ok(url) = env::var("BUILDKITE_ANALYTICS_TOKEN_SERVICE_URL") {
+        let other_sources = [
+            "BUILDKITE_ANALYTICS_TOKEN",
+            "BUILDKITE_ANALYTICS_TOKEN_SECRET",
+        ];
+        if other_sources.iter().all(|key| env::var(key).is_err()) {
+            match api::lookup_token(&url) {
+                Some(_) => println!("  ✓ The token was fetched from the token service"),
+                None => {
+                    println!("  ✗ The token could not be fetched from the token service");
+                    println!("    Hint: check the service maps this repository to a suite.");
+                }
+            }
+            return;
+        }
+    }
+
+    if let Ok(key) = env::var("BUILDKITE_ANALYTICS_TOKEN_SECRET") {
+        if env::var("BUILDKITE_ANALYTICS_TOKEN").is_err() {
+            match api::get_secret(&key) {
+                Some(_) => println!("  ✓ The token was fetched from the {} secret", key),
+                None => {
+                    println!("  ✗ The token could not be fetched from the {} secret", key);
+                    println!("    Hint: check the secret exists and this agent may access it.");
+                }
+            }
+            return;
+        }
+    }
+
+    match env::var("BUILDKITE_ANALYTICS_TOKEN") {
+        Ok(token) if !token.trim().is_empty() => println!("  ✓ BUILDKITE_ANALYTICS_TOKEN is set"),
+        Ok(_) => {
+            println!("  ✗ BUILDKITE_ANALYTICS_TOKEN is empty");
+            println!("    Hint: copy the token from your Test Engine suite settings.");
+        }
+        Err(_) => {
+            println!("  ✗ BUILDKITE_ANALYTICS_TOKEN is not set");
+            println!("    Hint: copy the token from your Test Engine suite settings, or set");
+            println!("    BUILDKITE_ANALYTICS_TOKEN_SECRET to the name of a Buildkite secret.");
+        }
+    }
+}
+
+/// Read the input lines and summarise what we found.
+pub fn check_input<I: Iterator<Item = String>>(lines: I) -> InputReport {
+    let mut report = InputReport::default();
+
+    for line in lines {
+        report.lines += 1;
+
+        match input::parse_event(&line) {
+            Ok(Event::Test {
+                event: TestEvent::Ok { exec_time, .. } | TestEvent::Failed { exec_time, .. },
+                ..
+            }) => {
+                report.events += 1;
+                report.finished_tests += 1;
+                if exec_time.is_none() {
+                    report.missing_exec_time += 1;
+                }
+            }
+            Ok(Event::Bench { .. }) => {
+                report.events += 1;
+                report.finished_tests += 1;
+            }
+            Ok(Event::Unknown { .. }) => {
+                report.events += 1;
+                report.unknown_events += 1;
+            }
+            Ok(_) => report.events += 1,
+            Err(ParseError::Unrecognised(_)) => report.unrecognised_json += 1,
+            Err(ParseError::NotJson | ParseError::Cargo) => {}
+        }
+    }
+
+    report
+}
+
+fn print_input_report(report: &InputReport) {
+    println!(
+        "    Read {} lines containing {} test events",
+        report.lines, report.events
+    );
+
+    if report.events == 0 && report.unrecognised_json == 0 {
+        println!("  ✗ No JSON test events were found.");
+        println!("    Hint: run `cargo test -- -Z unstable-options --format json --report-time`.");
+    } else if report.unrecognised_json > 0 {
+        println!(
+            "  ✗ {} JSON lines were not recognised as test events.",
+            report.unrecognised_json
+        );
+        println!("    Hint: check the test harness's output format.");
+    } else if report.unknown_events > 0 {
+        println!(
+            "  ! {} events are of kinds this version doesn't know, and will be ignored.",
+            report.unknown_events
+        );
+        println!("    Hint: a newer buildkite-test-collector may understand them.");
+    } else if report.finished_tests == 0 {
+        println!("  ! No finished tests were found, so nothing would be uploaded.");
+    } else if report.missing_exec_time > 0 {
+        println!(
+            "  ! {} test results are missing exec_time, so durations will be approximate.",
+            report.missing_exec_time
+        );
+        println!("    Hint: make sure `--report-time` is passed to the test harness.");
+    } else {
+        println!("  ✓ Input looks like libtest JSON output");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn check_input_summarises_the_stream() {
+        let lines = vec![
+            "running 2 tests",
+            r#"{ "type": "suite", "event": "started", "test_count": 2 }"#,
+            r#"{ "type": "test", "event": "started", "name": "a::b" }"#,
+            r#"{ "type": "test", "name": "a::b", "event": "ok", "exec_time": 0.1 }"#,
+            r#"{ "type": "test", "name": "a::c", "event": "ok" }"#,
+            r#"{ "type": "test", "name": "a::d", "event": "exploded" }"#,
+            r#"{ "type": "test", "event": "ok" }"#,
+        ];
+
+        let report = check_input(lines.into_iter().map(String::from));
+
+        assert_eq!(
+            report,
+            InputReport {
+                lines: 7,
+                events: 5,
+                unrecognised_json: 1,
+                unknown_events: 1,
+                finished_tests: 2,
+                missing_exec_time: 1,
+            }
+        );
+    }
+}
@@ -0,0 +1,146 @@
+//! # duration
+//!
+//! Choosing between the available sources of a test's duration.
+
+use std::env;
+
+/// # DurationSource
+///
+/// Where a test's duration came from.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum DurationSource {
+    /// The `exec_time` reported by libtest.
+    Libtest,
+    /// The wall time measured by the collector between the start and finish
+    /// events.
+    Collector,
+    /// The `time` attribute of a JUnit test case.
+    Junit,
+    /// The `duration` attribute of a Visual Studio TRX test result.
+    Trx,
+}
+
+impl DurationSource {
+    /// The name of the source, as recorded in the `duration_source` tag.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DurationSource::Libtest => "libtest",
+            DurationSource::Collector => "collector",
+            DurationSource::Junit => "junit",
+            DurationSource::Trx => "trx",
+        }
+    }
+
+    fn parse(value: &str) -> Option<DurationSource> {
+        match value.trim() {
+            "libtest" => Some(DurationSource::Libtest),
+            "collector" => Some(DurationSource::Collector),
+            "junit" => Some(DurationSource::Junit),
+            "trx" => Some(DurationSource::Trx),
+            _ => None,
+        }
+    }
+}
+
+/// # DurationPolicy
+///
+/// The order in which duration sources are preferred.  The first source
+/// which has a duration for a test wins.
+#[derive(Debug, PartialEq, Clone)]
+pub struct DurationPolicy {
+    precedence: Vec<DurationSource>,
+}
+
+impl Default for DurationPolicy {
+    fn default() -> Self {
+        DurationPolicy {
+            precedence: vec![
+                DurationSource::Junit,
+                DurationSource::Trx,
+                DurationSource::Libtest,
+                DurationSource::Collector,
+            ],
+        }
+    }
+}
+
+impl DurationPolicy {
+    /// Read the precedence from `BUILDKITE_ANALYTICS_DURATION_SOURCES`, a
+    /// comma separated list of `libtest`, `collector`, `junit` and `trx`.
+    ///
+    /// Sources which aren't listed are still used as a last resort, in their
+    /// default order.
+    ///
+    /// ## Emits warnings
+    ///  - If the list contains an unknown source.
+    pub fn from_env() -> Self {
+        match env::var("BUILDKITE_ANALYTICS_DURATION_SOURCES") {
+            Ok(value) => DurationPolicy::parse(&value).unwrap_or_else(|| {
+                eprintln!(
+                    "Unable to parse BUILDKITE_ANALYTICS_DURATION_SOURCES value {:?}, using the default.",
+                    value
+                );
+                DurationPolicy::default()
+            }),
+            Err(_) => DurationPolicy::default(),
+        }
+    }
+
+    fn parse(value: &str) -> Option<DurationPolicy> {
+        let mut precedence = value
+            .split(',')
+            .map(DurationSource::parse)
+            .collect::<Option<Vec<DurationSource>>>()?;
+
+        for source in DurationPolicy::default().precedence {
+            if !precedence.contains(&source) {
+                precedence.push(source);
+            }
+        }
+
+        Some(DurationPolicy { precedence })
+    }
+
+    /// Choose a duration from the candidates according to the policy.
+    pub fn choose(
+        &self,
+        candidates: &[(DurationSource, Option<f64>)],
+    ) -> Option<(DurationSource, f64)> {
+        self.precedence.iter().find_map(|source| {
+            candidates
+                .iter()
+                .find(|(candidate, _)| candidate == source)
+                .and_then(|(_, duration)| duration.map(|duration| (*source, duration)))
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn choose_follows_the_precedence() {
+        let candidates = [
+            (DurationSource::Libtest, Some(1.0)),
+            (DurationSource::Collector, Some(2.0)),
+        ];
+
+        assert_eq!(
+            DurationPolicy::default().choose(&candidates),
+            Some((DurationSource::Libtest, 1.0))
+        );
+
+        let policy = DurationPolicy::parse("collector").unwrap();
+        assert_eq!(
+            policy.choose(&candidates),
+            Some((DurationSource::Collector, 2.0))
+        );
+        assert_eq!(
+            policy.choose(&[(DurationSource::Libtest, Some(1.0))]),
+            Some((DurationSource::Libtest, 1.0))
+        );
+
+        assert_eq!(DurationPolicy::parse("libtest,bogus"), None);
+    }
+}
@@ -0,0 +1,101 @@
+//! # env_file
+//!
+//! Loading environment variables from a dotenv style file.
+
+use std::env;
+use std::fs;
+
+/// Load the variables in the file at `path` into the environment.
+///
+/// Variables which are already set in the environment are not overridden.
+///
+/// ## Emits warnings
+///  - If the file cannot be read.
+///  - If a line cannot be parsed.
+pub fn load(path: &str) {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) => {
+            eprintln!("Unable to read env file {}: {}", path, err);
+            return;
+        }
+    };
+
+    for (key, value) in parse(&contents, path) {
+        if env::var_os(&key).is_none() {
+            env::set_var(key, value);
+        }
+    }
+}
+
+/// Parse the contents of a dotenv file into key/value pairs.
+///
+/// Blank lines and lines starting with `#` are ignored, as is a leading
+/// `export`.  Values may be wrapped in single or double quotes.
+fn parse(contents: &str, path: &str) -> Vec<(String, String)> {
+    let mut vars = Vec::new();
+
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let line = line.strip_prefix("export ").unwrap_or(line);
+
+        match line.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => {
+                vars.push((key.trim().to_string(), unquote(value.trim()).to_string()))
+            }
+            _ => eprintln!("Unable to parse line {} of env file {}", index + 1, path),
+        }
+    }
+
+    vars
+}
+
+fn unquote(value: &str) -> &str {
+    for quote in ['"', '\''] {
+        if value.len() >= 2 && value.starts_with(quote) && value.ends_with(quote) {
+            return &value[1..value.len() - 1];
+        }
+    }
+
+    value
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_handles_comments_exports_and_quotes() {
+        let contents = "
+# The token
+BUILDKITE_ANALYTICS_TOKEN=abc123
+export CI=true
+BUILDKITE_MESSAGE=\"Be excellent to each other\"
+BUILDKITE_BRANCH = 'marty'
+not a variable
+EMPTY=
+";
+
+        assert_eq!(
+            parse(contents, ".env"),
+            vec![
+                (
+                    "BUILDKITE_ANALYTICS_TOKEN".to_string(),
+                    "abc123".to_string()
+                ),
+                ("CI".to_string(), "true".to_string()),
+                (
+                    "BUILDKITE_MESSAGE".to_string(),
+                    "Be excellent to each other".to_string()
+                ),
+                ("BUILDKITE_BRANCH".to_string(), "marty".to_string()),
+                ("EMPTY".to_string(), "".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,252 @@
+//! # exit
+//!
+//! The exit code policy used with `--exit-codes`, so that pipelines can
+//! `soft_fail` on specific outcomes (eg tolerate upload failures, but not
+//! test failures).
+//!
+//! | Code | Outcome                                  |
+//! | ---- | ---------------------------------------- |
+//! | 0    | Success                                  |
+//! | 1    | One or more tests failed                 |
+//! | 2    | Configuration error (no CI or bad token) |
+//! | 3    | One or more batches could not be sent    |
+//! | 4    | Too many lines failed to parse           |
+//! | 5    | Expected tests are missing (strict mode) |
+//!
+//! When several apply, the most severe wins: configuration errors, then parse
+//! errors, then missing tests, then test failures, then upload failures.
+
+use crate::payload::{parse_var, Completeness};
+
+/// # Outcome
+///
+/// The overall result of a collector invocation.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Outcome {
+    Success,
+    TestsFailed,
+    Configuration,
+    UploadFailed,
+    ParseErrors,
+    Incomplete,
+}
+
+impl Outcome {
+    /// The process exit code for this outcome.
+    pub fn code(&self) -> i32 {
+        match self {
+            Outcome::Success => 0,
+            Outcome::TestsFailed => 1,
+            Outcome::Configuration => 2,
+            Outcome::UploadFailed => 3,
+            Outcome::ParseErrors => 4,
+            Outcome::Incomplete => 5,
+        }
+    }
+
+    fn severity(&self) -> u8 {
+        match self {
+            Outcome::Success => 0,
+            Outcome::UploadFailed => 1,
+            Outcome::TestsFailed => 2,
+            Outcome::Incomplete => 3,
+            Outcome::ParseErrors => 4,
+            Outcome::Configuration => 5,
+        }
+    }
+}
+
+/// # Summary
+///
+/// What happened during the run, for the exit summary and code.
+#[derive(Debug, Default, PartialEq)]
+pub struct Summary {
+    pub configured: bool,
+    pub tests: usize,
+    pub failed: usize,
+    /// Failures of informational tests, which don't affect the outcome.
+    pub informational_failed: usize,
+    /// Failures of quarantined tests, when they're ignored.
+    pub quarantined_failed: usize,
+    pub parse_errors: usize,
+    pub batches: usize,
+    pub uploaded: usize,
+    pub spooled: usize,
+    /// How many of the tests the suites expected to run finished, if known.
+    pub completeness: Option<Completeness>,
+}
+
+impl Summary {
+    /// The outcome, given the maximum number of lines allowed to fail to
+    /// parse, and whether missing tests are an error.
+    pub fn outcome(&self, max_parse_errors: usize, strict_completeness: bool) -> Outcome {
+        let mut outcomes = vec![Outcome::Success];
+
+        if !self.configured {
+            outcomes.push(Outcome::Configuration);
+        }
+        if self.parse_errors > max_parse_errors {
+            outcomes.push(Outcome::ParseErrors);
+        }
+        if strict_completeness && self.completeness.is_some_and(|c| !c.is_complete()) {
+            outcomes.push(Outcome::Incomplete);
+        }
+        if self.failed > 0 {
+            outcomes.push(Outcome::TestsFailed);
+        }
+        if self.uploaded + self.spooled < self.batches {
+            outcomes.push(Outcome::UploadFailed);
+        }
+
+        outcomes
+            .into_iter()
+            .max_by_key(Outcome::severity)
+            .unwrap_or(Outcome::Success)
+    }
+
+    /// Add the counts from `other`, eg a partial upload made during the run.
+    pub fn merge(&mut self, other: &Summary) {
+        self.tests += other.tests;
+        self.failed += other.failed;
+        self.informational_failed += other.informational_failed;
+        self.quarantined_failed += other.quarantined_failed;
+        self.parse_errors += other.parse_errors;
+        self.batches += other.batches;
+        self.uploaded += other.uploaded;
+        self.spooled += other.spooled;
+    }
+
+    /// A one line summary of the run.
+    pub fn message(&self) -> String {
+        let mut message = format!("{} of {} tests failed", self.failed, self.tests);
+        let ignored = [
+            (self.informational_failed, "informational"),
+            (self.quarantined_failed, "quarantined"),
+        ]
+        .iter()
+        .filter(|(count, _)| *count > 0)
+        .map(|(count, kind)| format!("{} {}", count, kind))
+        .collect::<Vec<String>>();
+        if !ignored.is_empty() {
+            message.push_str(&format!(" (and {})", ignored.join(", ")));
+        }
+
+        if self.configured {
+            message.push_str(&format!(
+                ", {} of {} batches uploaded",
+                self.uploaded, self.batches
+            ));
+            if self.spooled > 0 {
+                message.push_str(&format!(" ({} spooled)", self.spooled));
+            }
+        } else {
+            message.push_str(", nothing was uploaded");
+        }
+
+        if self.parse_errors > 0 {
+            message.push_str(&format!(", {} lines failed to parse", self.parse_errors));
+        }
+
+        if let Some(completeness) = self.completeness.filter(|c| !c.is_complete()) {
+            message.push_str(&format!(
+                ", only {} of {} expected tests finished ({:.1}%)",
+                completeness.finished,
+                completeness.expected,
+                completeness.ratio() * 100.0
+            ));
+        }
+
+        message
+    }
+
+    /// Print the summary and exit with the code for its outcome.
+    ///
+    /// The parse error threshold is taken from
+    /// `BUILDKITE_ANALYTICS_MAX_PARSE_ERRORS` (default `0`), and missing tests
+    /// are an error if `BUILDKITE_ANALYTICS_STRICT_COMPLETENESS` is `true`.
+    pub fn exit(&self) -> ! {
+        let outcome = self.outcome(
+            parse_var("BUILDKITE_ANALYTICS_MAX_PARSE_ERRORS").unwrap_or(0),
+            parse_var("BUILDKITE_ANALYTICS_STRICT_COMPLETENESS").unwrap_or(false),
+        );
+
+        eprintln!(
+            "{}: {} (exit code {})",
+            env!("CARGO_PKG_NAME"),
+            self.message(),
+            outcome.code()
+        );
+        std::process::exit(outcome.code())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn the_most_severe_outcome_wins() {
+        let mut summary = Summary {
+            configured: true,
+            tests: 10,
+            batches: 2,
+            uploaded: 2,
+            ..Summary::default()
+        };
+        assert_eq!(summary.outcome(0, false), Outcome::Success);
+
+        summary.uploaded = 1;
+        assert_eq!(summary.outcome(0, false), Outcome::UploadFailed);
+
+        summary.spooled = 1;
+        assert_eq!(summary.outcome(0, false), Outcome::Success);
+
+        summary.informational_failed = 1;
+        assert_eq!(summary.outcome(0, false), Outcome::Success);
+
+        summary.quarantined_failed = 1;
+        assert_eq!(summary.outcome(0, false), Outcome::Success);
+
+        summary.failed = 1;
+        assert_eq!(summary.outcome(0, false), Outcome::TestsFailed);
+
+        summary.parse_errors = 3;
+        assert_eq!(summary.outcome(5, false), Outcome::TestsFailed);
+        assert_eq!(summary.outcome(2, false), Outcome::ParseErrors);
+
+        summary.completeness = Some(Completeness {
+            expected: 12,
+            finished: 10,
+        });
+        assert_eq!(summary.outcome(5, false), Outcome::TestsFailed);
+        assert_eq!(summary.outcome(5, true), Outcome::Incomplete);
+
+        summary.configured = false;
+        assert_eq!(summary.outcome(2, false), Outcome::Configuration);
+        assert_eq!(Outcome::Configuration.code(), 2);
+    }
+
+    #[test]
+    fn summarises_the_run() {
+        let summary = Summary {
+            configured: true,
+            tests: 10,
+            failed: 2,
+            informational_failed: 1,
+            quarantined_failed: 3,
+            parse_errors: 1,
+            batches: 3,
+            uploaded: 2,
+            spooled: 1,
+            completeness: Some(Completeness {
+                expected: 12,
+                finished: 10,
+            }),
+        };
+
+        assert_eq!(
+            summary.message(),
+            "2 of 10 tests failed (and 1 informational, 3 quarantined), 2 of 3 batches uploaded (1 spooled), 1 lines failed to parse, only 10 of 12 expected tests finished (83.3%)"
+        );
+    }
+}
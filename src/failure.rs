@@ -0,0 +1,347 @@
+//! # failure
+//!
+//! Splits a failed test's output into a short failure reason (the panic
+//! message) and the structured `failure_expanded` detail Test Engine shows
+//! alongside it.
+
+use serde::{Deserialize, Serialize};
+
+/// The default limit on each part of a failure's output, in bytes.
+pub const MAX_BYTES: usize = 64 * 1024;
+
+/// Room for the note saying how much was trimmed.
+const TRIMMED_NOTE_LEN: usize = 40;
+
+/// # FailureExpanded
+///
+/// The full output of a failure, with any backtrace separated out.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct FailureExpanded {
+    pub expanded: Vec<String>,
+    pub backtrace: Vec<String>,
+}
+
+impl FailureExpanded {
+    /// Trim the output and backtrace to at most `max_bytes` each (see
+    /// `truncate`).
+    pub fn truncate(&mut self, max_bytes: usize) {
+        truncate_lines(&mut self.expanded, max_bytes);
+        truncate_lines(&mut self.backtrace, max_bytes);
+    }
+}
+
+/// # Failure
+///
+/// A failed test's output, parsed.
+#[derive(Debug, PartialEq)]
+pub struct Failure {
+    pub reason: Option<String>,
+    pub expanded: Vec<FailureExpanded>,
+    pub location: Option<PanicLocation>,
+}
+
+impl Failure {
+    /// A failure with only a reason, eg one the collector inferred itself.
+    pub fn with_reason(reason: String) -> Failure {
+        Failure {
+            reason: Some(reason),
+            expanded: Vec::new(),
+            location: None,
+        }
+    }
+}
+
+/// # PanicLocation
+///
+/// Where the test panicked, eg `src/lib.rs:10:5`.
+#[derive(Debug, PartialEq)]
+pub struct PanicLocation {
+    pub file: String,
+    pub line: usize,
+}
+
+impl PanicLocation {
+    /// Parse a `file:line:column` location.
+    fn parse(location: &str) -> Option<PanicLocation> {
+        let (rest, column) = location.rsplit_once(':')?;
+        let (file, line) = rest.rsplit_once(':')?;
+        column.parse::<usize>().ok()?;
+
+        Some(PanicLocation {
+            file: file.to_string(),
+            line: line.parse().ok()?,
+        })
+    }
+}
+
+/// Parse the captured output of a failed test.
+///
+/// Understands both the current panic format:
+///
+/// ```text
+/// thread 'tests::it_fails' panicked at src/lib.rs:10:5:
+/// assertion `left == right` failed
+/// ```
+///
+/// and the format used before Rust 1.73:
+///
+/// ```text
+/// thread 'tests::it_fails' panicked at 'boom', src/lib.rs:10:5
+/// ```
+///
+/// Output without a panic uses its first line as the reason.
+pub fn parse(output: &str) -> Failure {
+    let lines = output.lines().collect::<Vec<&str>>();
+    if lines.iter().all(|line| line.trim().is_empty()) {
+        return Failure {
+            reason: None,
+            expanded: Vec::new(),
+            location: None,
+        };
+    }
+
+    let backtrace_at = lines
+        .iter()
+        .position(|line| line.trim_start().starts_with("stack backtrace:"));
+    let (expanded, backtrace) = match backtrace_at {
+        Some(index) => {
+            let backtrace = lines[index + 1..]
+                .iter()
+                .take_while(|line| !is_note(line))
+                .map(|line| line.trim_end().to_string())
+                .collect();
+            (&lines[..index], backtrace)
+        }
+        None => (&lines[..], Vec::new()),
+    };
+
+    let (reason, location) = panic(&lines).unwrap_or((None, None));
+    let reason = reason.or_else(|| {
+        lines
+            .iter()
+            .map(|line| line.trim())
+            .find(|line| !line.is_empty())
+            .map(String::from)
+    });
+
+    Failure {
+        reason,
+        expanded: vec![FailureExpanded {
+            expanded: expanded.iter().map(|line| line.to_string()).collect(),
+            backtrace,
+        }],
+        location,
+    }
+}
+
+/// The message and location of the first panic in `lines`, if any.
+///
+/// Panics reported by `console_error_panic_hook` under wasm-bindgen-test
+/// don't name their thread, and may be indented.
+fn panic(lines: &[&str]) -> Option<(Option<String>, Option<PanicLocation>)> {
+    let (index, rest) = lines.iter().enumerate().find_map(|(index, line)| {
+        let line = line.trim_start();
+        let rest = match line.strip_prefix("thread '") {
+            Some(rest) => rest.split_once("' panicked at ")?.1,
+            None => line.strip_prefix("panicked at ")?,
+        };
+        Some((index, rest))
+    })?;
+    let indent = lines[index].len() - lines[index].trim_start().len();
+
+    // Before Rust 1.73: `panicked at 'message', src/lib.rs:10:5`
+    if let Some(quoted) = rest.strip_prefix('\'') {
+        let (message, location) = quoted.rsplit_once("', ")?;
+        return Some((
+            Some(message.to_string()),
+            PanicLocation::parse(location.trim()),
+        ));
+    }
+
+    let location = PanicLocation::parse(rest.trim().trim_end_matches(':'));
+    let message = lines[index + 1..]
+        .iter()
+        .take_while(|line| {
+            let line = line.trim_start();
+            !is_note(line) && !line.starts_with("stack backtrace:") && line != "Stack:"
+        })
+        .map(|line| match line.get(..indent) {
+            Some(prefix) if prefix.trim().is_empty() => &line[indent..],
+            _ => line.trim_start(),
+        })
+        .collect::<Vec<&str>>()
+        .join("\n");
+    let message = Some(message.trim_end().to_string()).filter(|message| !message.is_empty());
+
+    Some((message, location))
+}
+
+/// A failed test's `stdout` and `stderr` as one output, with a `---- stderr
+/// ----` line between them if there's both, since assertion output (eg from
+/// test frameworks which don't panic) often lands on `stderr`.
+pub fn combine(stdout: Option<&str>, stderr: Option<&str>) -> String {
+    let stdout = stdout.unwrap_or_default().trim_end();
+    let stderr = stderr.unwrap_or_default().trim_end();
+
+    match (stdout.is_empty(), stderr.is_empty()) {
+        (_, true) => stdout.to_string(),
+        (true, false) => stderr.to_string(),
+        (false, false) => format!("{}\n---- stderr ----\n{}", stdout, stderr),
+    }
+}
+
+/// Trim the middle out of `text` if it's longer than `max_bytes`, keeping its
+/// start (usually the panic) and end (usually the last thing the test did),
+/// with a note of how many bytes were trimmed in between.
+pub fn truncate(text: &mut String, max_bytes: usize) {
+    if text.len() <= max_bytes {
+        return;
+    }
+
+    let keep = max_bytes.saturating_sub(TRIMMED_NOTE_LEN);
+    let mut head = keep / 2;
+    while !text.is_char_boundary(head) {
+        head -= 1;
+    }
+    let mut tail = text.len() - (keep - keep / 2);
+    while !text.is_char_boundary(tail) {
+        tail += 1;
+    }
+
+    let note = format!("\n… {} bytes trimmed …\n", tail - head);
+    text.replace_range(head..tail, &note);
+}
+
+/// `truncate` for output split into lines.
+fn truncate_lines(lines: &mut Vec<String>, max_bytes: usize) {
+    if lines.iter().map(|line| line.len() + 1).sum::<usize>() <= max_bytes {
+        return;
+    }
+
+    let mut text = lines.join("\n");
+    truncate(&mut text, max_bytes);
+    *lines = text.lines().map(String::from).collect();
+}
+
+fn is_note(line: &str) -> bool {
+    line.starts_with("note: ")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn splits_panics_into_reason_and_expanded_output() {
+        let output = "some println output
+thread 'tests::it_fails' panicked at src/lib.rs:10:5:
+assertion `left == right` failed
+  left: 1
+ right: 2
+stack backtrace:
+   0: rust_begin_unwind
+   1: core::panicking::panic_fmt
+note: Some details are omitted, run with `RUST_BACKTRACE=full` for a verbose backtrace.
+";
+
+        let failure = parse(output);
+
+        assert_eq!(
+            failure.reason.as_deref(),
+            Some("assertion `left == right` failed\n  left: 1\n right: 2")
+        );
+        assert_eq!(
+            failure.location,
+            Some(PanicLocation {
+                file: "src/lib.rs".to_string(),
+                line: 10,
+            })
+        );
+        assert_eq!(
+            failure.expanded,
+            vec![FailureExpanded {
+                expanded: vec![
+                    "some println output".to_string(),
+                    "thread 'tests::it_fails' panicked at src/lib.rs:10:5:".to_string(),
+                    "assertion `left == right` failed".to_string(),
+                    "  left: 1".to_string(),
+                    " right: 2".to_string(),
+                ],
+                backtrace: vec![
+                    "   0: rust_begin_unwind".to_string(),
+                    "   1: core::panicking::panic_fmt".to_string(),
+                ],
+            }]
+        );
+    }
+
+    #[test]
+    fn understands_the_old_panic_format() {
+        let output = "thread 'tests::it_fails' panicked at 'boom, again', src/lib.rs:10:5
+note: run with `RUST_BACKTRACE=1` environment variable to display a backtrace
+";
+
+        let failure = parse(output);
+
+        assert_eq!(failure.reason.as_deref(), Some("boom, again"));
+        assert_eq!(
+            failure.location,
+            Some(PanicLocation {
+                file: "src/lib.rs".to_string(),
+                line: 10,
+            })
+        );
+        assert!(failure.expanded[0].backtrace.is_empty());
+    }
+
+    #[test]
+    fn long_output_is_trimmed_from_the_middle() {
+        let mut text = format!("panicked{}finally", "x".repeat(1000));
+        truncate(&mut text, 100);
+        assert!(text.len() <= 100);
+        assert!(text.starts_with("panickedxxx"));
+        assert!(text.ends_with("xxxfinally"));
+        assert!(text.contains("\n… 955 bytes trimmed …\n"));
+
+        let mut multibyte = "é".repeat(100);
+        truncate(&mut multibyte, 60);
+        assert!(multibyte.starts_with("éé") && multibyte.ends_with("éé"));
+
+        let mut short = "boom".to_string();
+        truncate(&mut short, 100);
+        assert_eq!(short, "boom");
+
+        let mut expanded = FailureExpanded {
+            expanded: (0..100).map(|line| format!("line {}", line)).collect(),
+            backtrace: vec!["   0: rust_begin_unwind".to_string()],
+        };
+        expanded.truncate(200);
+        assert_eq!(expanded.expanded.first().unwrap(), "line 0");
+        assert_eq!(expanded.expanded.last().unwrap(), "line 99");
+        assert!(expanded
+            .expanded
+            .iter()
+            .any(|line| line.contains("trimmed")));
+        assert_eq!(expanded.backtrace.len(), 1);
+    }
+
+    #[test]
+    fn stderr_follows_stdout() {
+        assert_eq!(
+            combine(Some("out\n"), Some("err\n")),
+            "out\n---- stderr ----\nerr"
+        );
+        assert_eq!(combine(None, Some("err")), "err");
+        assert_eq!(combine(Some("out"), Some("")), "out");
+        assert_eq!(combine(None, None), "");
+    }
+
+    #[test]
+    fn falls_back_to_the_first_line() {
+        assert_eq!(
+            parse("\nError: Custom { kind: Other }\n").reason.as_deref(),
+            Some("Error: Custom { kind: Other }")
+        );
+        assert_eq!(parse("").expanded, Vec::new());
+    }
+}
@@ -0,0 +1,282 @@
+//! # first_batch
+//!
+//! Uploads an early first batch, so that Test Engine shows some signal from a
+//! long suite well before it finishes.  The batch is sent in the background as
+//! soon as either enough tests have finished or enough time has passed,
+//! whichever comes first, and the remaining results follow at the end.
+//!
+//! When streaming, each full batch of finished tests after the first is sent
+//! in the background too, so a cancelled job keeps most of its results and
+//! there's little left to upload at the end.  Batches are sent one at a time,
+//! in order.
+//!
+//! Multi-hour suites can also flush the tests which have finished every so
+//! many seconds, however few there are, so that a job killed late in the run
+//! still has recent results.  It's checked as each line of test output
+//! arrives.
+//!
+//! Enabled by setting `BUILDKITE_ANALYTICS_FIRST_BATCH_TESTS` and/or
+//! `BUILDKITE_ANALYTICS_FIRST_BATCH_SECONDS`, `BUILDKITE_ANALYTICS_STREAM`, or
+//! `BUILDKITE_ANALYTICS_FLUSH_SECONDS`.
+
+use crate::exit::Summary;
+use crate::http;
+use crate::payload::{parse_var, seconds_var, Payload};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+
+/// # FirstBatch
+///
+/// Decides when to send the first batch (and, when streaming, each batch
+/// after it), and sends it.
+#[derive(Debug)]
+pub struct FirstBatch {
+    tests: usize,
+    after: Duration,
+    /// Once the first batch is sent, send each batch of this many finished
+    /// tests too.
+    stream: Option<usize>,
+    /// Send the finished tests whenever this long has passed since the last
+    /// batch was sent.
+    flush_interval: Option<Duration>,
+    started_at: Instant,
+    last_sent_at: Instant,
+    sent: usize,
+    /// The latest upload, which waits for the one before it.
+    upload: Option<JoinHandle<Summary>>,
+}
+
+impl FirstBatch {
+    pub fn new(tests: usize, after: Duration) -> Self {
+        let started_at = Instant::now();
+        FirstBatch {
+            tests,
+            after,
+            stream: None,
+            flush_interval: None,
+            started_at,
+            last_sent_at: started_at,
+            sent: 0,
+            upload: None,
+        }
+    }
+
+    /// Keep sending a batch whenever `batch_size` more tests have finished.
+    pub fn with_stream(mut self, batch_size: usize) -> Self {
+        self.stream = Some(batch_size.max(1));
+        self
+    }
+
+    /// Also send the tests which have finished whenever `interval` has
+    /// passed since the last batch was sent (or the run started).
+    pub fn with_flush_interval(mut self, interval: Duration) -> Self {
+        self.flush_interval = Some(interval);
+        self
+    }
+
+    /// Read the thresholds from the environment, if any are set.
+    ///
+    /// Streaming sends batches of `batch_size` tests, starting with the
+    /// first unless its thresholds are set too.
+    pub fn from_env(batch_size: usize) -> Option<Self> {
+        let tests = parse_var::<usize>("BUILDKITE_ANALYTICS_FIRST_BATCH_TESTS");
+        let seconds = seconds_var("BUILDKITE_ANALYTICS_FIRST_BATCH_SECONDS");
+        let stream = parse_var::<bool>("BUILDKITE_ANALYTICS_STREAM").unwrap_or(false);
+        let flush_interval = seconds_var("BUILDKITE_ANALYTICS_FLUSH_SECONDS");
+
+        let mut first_batch = match (tests, seconds) {
+            (None, None) if !stream && flush_interval.is_none() => return None,
+            (None, None) if stream => FirstBatch::new(batch_size, Duration::MAX),
+            _ => FirstBatch::new(
+                tests.unwrap_or(usize::MAX),
+                seconds.unwrap_or(Duration::MAX),
+            ),
+        };
+
+        if stream {
+            first_batch = first_batch.with_stream(batch_size);
+        }
+        if let Some(interval) = flush_interval {
+            first_batch = first_batch.with_flush_interval(interval);
+        }
+        Some(first_batch)
+    }
+
+    /// Has the first batch been sent?
+    pub fn is_sent(&self) -> bool {
+        self.sent > 0
+    }
+
+    /// Is it time to send a batch of the tests which have finished in
+    /// `payload`?
+    pub fn is_due(&self, payload: &Payload) -> bool {
+        let finished = payload.closed_data().len();
+        let flush = self
+            .flush_interval
+            .is_some_and(|interval| self.last_sent_at.elapsed() >= interval);
+        match (self.is_sent(), self.stream) {
+            _ if finished == 0 => false,
+            _ if flush => true,
+            (false, _) => finished >= self.tests || self.started_at.elapsed() >= self.after,
+            (true, Some(batch_size)) => finished >= batch_size,
+            (true, None) => false,
+        }
+    }
+
+    /// Send the tests which have finished in `payload` with `upload`, as a
+    /// blocking task on the HTTP runtime, if it's time.  Unless streaming or flushing, does
+    /// nothing once the first batch is sent.
+    pub fn poll<F>(&mut self, payload: &mut Payload, upload: F)
+    where
+        F: FnOnce(Payload) -> Summary + Send + 'static,
+    {
+        if !self.is_due(payload) {
+            return;
+        }
+
+        let batch = payload.take_finished();
+        let previous = self.upload.take();
+        self.sent += 1;
+        self.last_sent_at = Instant::now();
+        self.upload = Some(http::runtime().spawn_blocking(move || {
+            let mut summary = previous.map(join).unwrap_or_default();
+            summary.merge(&upload(batch));
+            summary
+        }));
+    }
+
+    /// Wait for the batches to be sent.
+    ///
+    /// Returns their combined summary, which is empty if none were sent.
+    ///
+    /// ## Emits warnings
+    ///  - If an upload task panicked.
+    pub fn join(self) -> Summary {
+        self.upload.map(join).unwrap_or_default()
+    }
+}
+
+/// Wait for `upload`'s summary.
+fn join(upload: JoinHandle<Summary>) -> Summary {
+    http::block_on(upload).unwrap_or_else(|_| {
+        eprintln!("Unable to upload an early batch.");
+        Summary::default()
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::input::parse_line;
+    use crate::run_env::RuntimeEnvironment;
+
+    fn counting_upload(payload: Payload) -> Summary {
+        Summary {
+            tests: payload.closed_data().len(),
+            ..Summary::default()
+        }
+    }
+
+    fn finish_test(payload: &mut Payload, name: &str) {
+        for line in [
+            format!(
+                r#"{{ "type": "test", "event": "started", "name": "{}" }}"#,
+                name
+            ),
+            format!(
+                r#"{{ "type": "test", "name": "{}", "event": "ok", "exec_time": 0.1 }}"#,
+                name
+            ),
+        ] {
+            parse_line(&line, payload).unwrap();
+        }
+    }
+
+    #[test]
+    fn sends_once_enough_tests_finish() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+        let mut first_batch = FirstBatch::new(2, Duration::MAX);
+
+        finish_test(&mut payload, "a::one");
+        first_batch.poll(&mut payload, counting_upload);
+        assert!(!first_batch.is_sent());
+
+        finish_test(&mut payload, "a::two");
+        parse_line(
+            r#"{ "type": "test", "event": "started", "name": "a::three" }"#,
+            &mut payload,
+        )
+        .unwrap();
+        first_batch.poll(&mut payload, counting_upload);
+        assert!(first_batch.is_sent());
+
+        // Later tests are left for the end.
+        finish_test(&mut payload, "a::four");
+        first_batch.poll(&mut payload, counting_upload);
+
+        assert_eq!(first_batch.join().tests, 2);
+        assert_eq!(payload.closed_data().len(), 1);
+        assert_eq!(payload.unfinished_data().len(), 1);
+    }
+
+    #[test]
+    fn streams_each_batch_in_order() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+        let mut first_batch = FirstBatch::new(1, Duration::MAX).with_stream(2);
+        let order = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recording_upload = |order: &std::sync::Arc<std::sync::Mutex<Vec<usize>>>| {
+            let order = order.clone();
+            move |payload: Payload| {
+                let summary = counting_upload(payload);
+                order.lock().unwrap().push(summary.tests);
+                summary
+            }
+        };
+
+        for name in ["a::1", "a::2", "a::3", "a::4", "a::5", "a::6"] {
+            finish_test(&mut payload, name);
+            first_batch.poll(&mut payload, recording_upload(&order));
+        }
+
+        // The first test on its own, then two at a time, leaving the last.
+        assert_eq!(first_batch.join().tests, 5);
+        assert_eq!(*order.lock().unwrap(), [1, 2, 2]);
+        assert_eq!(payload.closed_data().len(), 1);
+    }
+
+    #[test]
+    fn sends_once_enough_time_passes() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+        let mut first_batch = FirstBatch::new(usize::MAX, Duration::ZERO);
+
+        first_batch.poll(&mut payload, counting_upload);
+        assert!(!first_batch.is_sent(), "there's nothing to send yet");
+
+        finish_test(&mut payload, "a::one");
+        first_batch.poll(&mut payload, counting_upload);
+
+        assert_eq!(first_batch.join().tests, 1);
+    }
+
+    #[test]
+    fn flushes_whenever_the_interval_passes() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+        let mut first_batch =
+            FirstBatch::new(usize::MAX, Duration::MAX).with_flush_interval(Duration::ZERO);
+
+        for name in ["a::1", "a::2", "a::3"] {
+            finish_test(&mut payload, name);
+            first_batch.poll(&mut payload, counting_upload);
+        }
+
+        assert_eq!(first_batch.sent, 3);
+        assert_eq!(first_batch.join().tests, 3);
+        assert!(payload.closed_data().is_empty());
+
+        let mut first_batch =
+            FirstBatch::new(usize::MAX, Duration::MAX).with_flush_interval(Duration::MAX);
+        finish_test(&mut payload, "a::4");
+        first_batch.poll(&mut payload, counting_upload);
+        assert!(!first_batch.is_sent());
+    }
+}
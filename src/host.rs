@@ -0,0 +1,109 @@
+//! # host
+//!
+//! Tags the run with the host it ran on and the Rust toolchain it used, so
+//! that test timings can be compared across runner types.
+//!
+//! Enabled by default, and disabled by setting
+//! `BUILDKITE_ANALYTICS_HOST_TAGS=false`.
+
+use crate::payload::parse_var;
+use std::env;
+use std::fs;
+use std::process::Command;
+
+/// The run tags describing this host, eg `host.os=linux`.
+///
+/// Tags which can't be found out (eg because `rustc` isn't on the `PATH`)
+/// are left out.
+pub fn tags() -> Vec<(&'static str, String)> {
+    if !parse_var::<bool>("BUILDKITE_ANALYTICS_HOST_TAGS").unwrap_or(true) {
+        return Vec::new();
+    }
+
+    let mut tags = vec![
+        ("host.os", env::consts::OS.to_string()),
+        ("host.arch", env::consts::ARCH.to_string()),
+    ];
+    if let Some(hostname) = hostname() {
+        tags.push(("host.name", hostname));
+    }
+    // cargo tells the programs it runs which rustc it's using.
+    let rustc = env::var("RUSTC").unwrap_or("rustc".to_string());
+    if let Some(version) = version(&rustc, "rustc") {
+        tags.push(("rust.version", version));
+    }
+    let cargo = env::var("CARGO").unwrap_or("cargo".to_string());
+    if let Some(version) = version(&cargo, "cargo") {
+        tags.push(("cargo.version", version));
+    }
+
+    tags
+}
+
+/// The name of this host, from the environment or the system.
+fn hostname() -> Option<String> {
+    env::var("HOSTNAME")
+        .ok()
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .or_else(|| {
+            let output = Command::new("hostname").output().ok()?;
+            Some(String::from_utf8_lossy(&output.stdout).into_owned())
+        })
+        .map(|hostname| hostname.trim().to_string())
+        .filter(|hostname| !hostname.is_empty())
+}
+
+/// The version `program -V` reports, without its name, eg `1.80.0 (051478957
+/// 2024-07-21)` from `rustc 1.80.0 (051478957 2024-07-21)`.
+fn version(program: &str, name: &str) -> Option<String> {
+    let output = Command::new(program).arg("-V").output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    parse_version(&String::from_utf8_lossy(&output.stdout), name)
+}
+
+fn parse_version(output: &str, name: &str) -> Option<String> {
+    let version = output.trim();
+    let version = version
+        .strip_prefix(name)
+        .map_or(version, |version| version.trim_start());
+
+    Some(version.to_string()).filter(|version| !version.is_empty())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serial_test::serial;
+
+    #[test]
+    fn versions_are_reported_without_the_program_name() {
+        assert_eq!(
+            parse_version("rustc 1.80.0 (051478957 2024-07-21)\n", "rustc"),
+            Some("1.80.0 (051478957 2024-07-21)".to_string())
+        );
+        assert_eq!(
+            parse_version("cargo 1.80.0 (376290515 2024-07-16)", "cargo"),
+            Some("1.80.0 (376290515 2024-07-16)".to_string())
+        );
+        assert_eq!(parse_version("\n", "rustc"), None);
+    }
+
+    #[test]
+    #[serial]
+    fn tags_describe_the_host() {
+        env::set_var("RUSTC", "definitely-not-rustc");
+        let tags = tags();
+        env::remove_var("RUSTC");
+
+        assert!(tags.contains(&("host.os", env::consts::OS.to_string())));
+        assert!(tags.contains(&("host.arch", env::consts::ARCH.to_string())));
+        assert!(!tags.iter().any(|(key, _)| *key == "rust.version"));
+
+        env::set_var("BUILDKITE_ANALYTICS_HOST_TAGS", "false");
+        assert!(super::tags().is_empty());
+        env::remove_var("BUILDKITE_ANALYTICS_HOST_TAGS");
+    }
+}
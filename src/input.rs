@@ -2,95 +2,1094 @@
 //!
 //! Deserialisation of JSON input from Rust.
 
+use crate::criterion;
+use crate::miri::MiriReports;
 use crate::payload::Payload;
+use crate::tap::TapParser;
+use crate::text::TextParser;
 use serde::Deserialize;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::io::BufRead;
+
+/// # InputFormat
+///
+/// The format of the test output on `stdin`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum InputFormat {
+    /// libtest's JSON output, from `cargo test` or `cargo nextest`.
+    Libtest,
+    /// The Test Anything Protocol.
+    Tap,
+    /// `cargo criterion --message-format=json` benchmark results.
+    Criterion,
+    /// Work it out from the first lines of output.
+    Auto,
+}
+
+impl InputFormat {
+    /// Parse the value of the `--input-format` option.
+    pub fn parse(value: &str) -> Option<InputFormat> {
+        match value {
+            "libtest" | "json" => Some(InputFormat::Libtest),
+            "tap" => Some(InputFormat::Tap),
+            "criterion" => Some(InputFormat::Criterion),
+            "auto" => Some(InputFormat::Auto),
+            _ => None,
+        }
+    }
+
+    /// Guess the format from a single line of output, if it's distinctive.
+    pub fn sniff(line: &str) -> Option<InputFormat> {
+        let line = line.trim();
+
+        if is_cargo_message(line) {
+            return None;
+        }
+
+        if is_json_object(line) {
+            let message = serde_json::from_str::<serde_json::Value>(line).ok()?;
+            // cargo-criterion's messages say why they were sent, libtest's don't.
+            return Some(if message.get("reason").is_some() {
+                InputFormat::Criterion
+            } else {
+                InputFormat::Libtest
+            });
+        }
+
+        let tap_plan = line
+            .strip_prefix("1..")
+            .is_some_and(|count| count.starts_with(|c: char| c.is_ascii_digit()));
+        if tap_plan
+            || line.starts_with("TAP version")
+            || line.starts_with("not ok")
+            || line.starts_with("ok ")
+            || line == "ok"
+        {
+            return Some(InputFormat::Tap);
+        }
+
+        // libtest's human-readable output.
+        let running =
+            line.starts_with("running ") && (line.ends_with(" tests") || line.ends_with(" test"));
+        if running || (line.starts_with("test ") && line.contains(" ... ")) {
+            return Some(InputFormat::Libtest);
+        }
+
+        None
+    }
+}
+
+/// How many non-empty lines `InputFormat::Auto` looks at before giving up and
+/// assuming libtest.
+const SNIFF_LINES: usize = 10;
+
+/// # InputParser
+///
+/// Parses test output in the chosen format.  With `InputFormat::Auto`, the
+/// first lines are held back until one of them gives the format away, and
+/// then parsed in that format.
+#[derive(Debug)]
+pub struct InputParser {
+    format: InputFormat,
+    sniffed: Vec<String>,
+    tap: TapParser,
+    text: TextParser,
+    miri: MiriReports,
+    pub parse_errors: ParseErrors,
+}
+
+impl InputParser {
+    pub fn new(format: InputFormat, debug: bool) -> Self {
+        InputParser {
+            format,
+            sniffed: Vec::new(),
+            tap: TapParser::default(),
+            text: TextParser::default(),
+            miri: MiriReports::default(),
+            parse_errors: ParseErrors::with_debug(debug),
+        }
+    }
+
+    /// Parse a single line of output and push any results into `payload`.
+    pub fn parse_line(&mut self, line: &str, payload: &mut Payload) {
+        match self.format {
+            InputFormat::Libtest => {
+                // Lines which aren't events are otherwise ignored, unless
+                // there are no events at all.
+                if let Some(binary) = cargo_test_binary(line) {
+                    payload.set_binary(binary);
+                }
+                self.parse_errors.parse_line(line, payload);
+                self.text.parse_line(line, payload);
+                self.miri.parse_line(line);
+            }
+            InputFormat::Tap => self.tap.parse_line(line, payload),
+            InputFormat::Criterion => criterion::parse_line(line, payload),
+            InputFormat::Auto => {
+                self.sniffed.push(line.to_string());
+                let seen = self
+                    .sniffed
+                    .iter()
+                    .filter(|line| !line.trim().is_empty() && !is_cargo_message(line))
+                    .count();
+
+                match InputFormat::sniff(line) {
+                    Some(format) => self.detect(format, payload),
+                    None if seen >= SNIFF_LINES => self.detect(InputFormat::Libtest, payload),
+                    None => {}
+                }
+            }
+        }
+    }
+
+    /// Push anything still held back into `payload`, once the output has
+    /// ended.
+    pub fn finish(&mut self, payload: &mut Payload) {
+        if self.format == InputFormat::Auto {
+            self.detect(InputFormat::Libtest, payload);
+        }
+        self.tap.finish(payload);
+        self.text.finish(payload);
+        self.miri.finish(payload);
+    }
+
+    fn detect(&mut self, format: InputFormat, payload: &mut Payload) {
+        if self.parse_errors.debug {
+            eprintln!("Detected {:?} input.", format);
+        }
+
+        self.format = format;
+        for line in std::mem::take(&mut self.sniffed) {
+            self.parse_line(&line, payload);
+        }
+    }
+}
 
 /// # SuiteEvent
 ///
 /// An event relating to the entire test suite.
-#[derive(Deserialize, Debug, PartialEq)]
-#[serde(tag = "event")]
+#[derive(Debug, PartialEq)]
 pub enum SuiteEvent {
-    #[serde(rename = "started")]
     Started { test_count: usize },
-    #[serde(rename = "ok")]
-    Ok {
-        #[serde(flatten)]
-        results: SuiteResults,
-    },
-    #[serde(rename = "failed")]
-    Failed {
-        #[serde(flatten)]
-        results: SuiteResults,
-    },
+    Ok { results: SuiteResults },
+    Failed { results: SuiteResults },
 }
 
 /// # SuiteResults
 ///
 /// When a suite is finished Rust tells us how many tests passed and failed and
-/// how long it took.
-#[derive(Deserialize, Debug, PartialEq)]
+/// how long it took.  Custom harnesses (eg libtest-mimic) don't always report
+/// every count.
+#[derive(Deserialize, Debug, Default, PartialEq, Clone, Copy)]
+#[serde(default)]
 pub struct SuiteResults {
-    passed: usize,
-    failed: usize,
-    ignored: usize,
-    measured: usize,
-    filtered_out: usize,
-    exec_time: f64,
+    pub passed: usize,
+    pub failed: usize,
+    pub ignored: usize,
+    pub measured: usize,
+    pub filtered_out: usize,
+    pub exec_time: Option<f64>,
 }
 
 /// # TestEvent
 ///
-/// An event relating to an individual test.
-#[derive(Deserialize, Debug, PartialEq)]
-#[serde(tag = "event")]
+/// An event relating to an individual test.  `exec_time` is only present when
+/// libtest is run with `--report-time`, and passing tests only have `stdout`
+/// with `--show-output`.
+#[derive(Debug, PartialEq)]
 pub enum TestEvent {
-    #[serde(rename = "started")]
-    Started { name: String },
-    #[serde(rename = "ok")]
-    Ok { name: String, exec_time: f64 },
-    #[serde(rename = "failed")]
+    Started {
+        name: String,
+    },
+    Ok {
+        name: String,
+        exec_time: Option<f64>,
+        stdout: Option<String>,
+    },
     Failed {
         name: String,
-        exec_time: f64,
+        exec_time: Option<f64>,
         stdout: Option<String>,
         stderr: Option<String>,
+        /// Why the test failed, when it wasn't a panic.
+        message: Option<String>,
+    },
+    Ignored {
+        name: String,
+    },
+    Timeout {
+        name: String,
     },
-    #[serde(rename = "ignored")]
-    Ignored { name: String },
-    #[serde(rename = "timeout")]
-    Timeout { name: String },
+}
+
+impl TestEvent {
+    /// The name of the test this event relates to.
+    pub fn name(&self) -> &str {
+        match self {
+            TestEvent::Started { name }
+            | TestEvent::Ok { name, .. }
+            | TestEvent::Failed { name, .. }
+            | TestEvent::Ignored { name }
+            | TestEvent::Timeout { name } => name,
+        }
+    }
+}
+
+/// # NextestInfo
+///
+/// cargo-nextest's `libtest-json-plus` format adds information about the
+/// test binary to each event.
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+pub struct NextestInfo {
+    #[serde(rename = "crate")]
+    pub crate_name: String,
+    pub test_binary: String,
+    pub kind: String,
 }
 
 /// # Event
 ///
-/// Incoming events can either be `SuiteEvent` or `TestEvent`.
-#[derive(Deserialize, Debug, PartialEq)]
-#[serde(tag = "type")]
+/// Incoming events can either be `SuiteEvent`, `TestEvent` or the result of a
+/// `#[bench]` test.  Bench results are measured in nanoseconds per iteration.
+///
+/// Events of a kind we don't know (eg added by a newer libtest) are kept as
+/// `Unknown`, rather than treated as garbage.
+#[derive(Debug, PartialEq)]
 pub enum Event {
-    #[serde(rename = "suite")]
     Suite {
-        #[serde(flatten)]
         event: SuiteEvent,
     },
-    #[serde(rename = "test")]
     Test {
-        #[serde(flatten)]
         event: TestEvent,
+        nextest: Option<NextestInfo>,
+        /// Fields we don't know, eg added by a newer libtest.
+        extra: serde_json::Map<String, serde_json::Value>,
+    },
+    Bench {
+        name: String,
+        median: f64,
+        deviation: f64,
+        mib_per_second: Option<u64>,
+    },
+    Unknown {
+        /// The event's `type` and `event`, eg `test/retried`.
+        kind: String,
+        raw: serde_json::Value,
     },
 }
 
+/// The `(type, event)` pairs understood by `Event`.  `bench` has no `event`.
+const KNOWN_EVENTS: [(&str, &str); 8] = [
+    ("suite", "started"),
+    ("suite", "ok"),
+    ("suite", "failed"),
+    ("test", "started"),
+    ("test", "ok"),
+    ("test", "failed"),
+    ("test", "ignored"),
+    ("test", "timeout"),
+];
+
+impl Event {
+    /// Keep `raw` as an `Unknown` event if its kind isn't one we understand.
+    ///
+    /// Returns `None` for events of a known kind, which must have failed to
+    /// parse for some other reason (eg a missing field).
+    fn unknown(raw: serde_json::Value) -> Option<Event> {
+        let kind = raw.get("type")?.as_str()?;
+        let event = raw.get("event").and_then(serde_json::Value::as_str);
+
+        let known = match event {
+            Some(event) => KNOWN_EVENTS.contains(&(kind, event)),
+            None => kind == "bench",
+        };
+        if known {
+            return None;
+        }
+
+        let kind = match event {
+            Some(event) => format!("{}/{}", kind, event),
+            None => kind.to_string(),
+        };
+        Some(Event::Unknown { kind, raw })
+    }
+}
+
+/// # DoctestName
+///
+/// The parts of a doctest's name, eg `src/lib.rs - module::func (line 42)`.
+#[derive(Debug, PartialEq)]
+pub struct DoctestName<'a> {
+    pub file: &'a str,
+    pub item: &'a str,
+    pub line: usize,
+}
+
+impl<'a> DoctestName<'a> {
+    /// Attempt to parse a test name as a doctest name.
+    ///
+    /// Also accepts the trailing ` - compile fail` style annotations added by
+    /// newer versions of rustdoc, and the empty item path of crate level
+    /// documentation (eg `src/lib.rs - (line 1)`).
+    pub fn parse(name: &'a str) -> Option<DoctestName<'a>> {
+        let (file, rest) = name.split_once(" - ")?;
+        let (item, rest) = rest.split_once("(line ")?;
+        let (line, _) = rest.split_once(')')?;
+
+        Some(DoctestName {
+            file,
+            item: item.trim(),
+            line: line.parse().ok()?,
+        })
+    }
+}
+
+/// # CaseName
+///
+/// The parts of the name of one case of an rstest parameterised test, eg
+/// `tests::my_test::case_3_negative_input`.
+#[derive(Debug, PartialEq)]
+pub struct CaseName<'a> {
+    /// The path of the parameterised test, eg `tests::my_test`.
+    pub test: &'a str,
+    pub index: usize,
+    pub description: Option<&'a str>,
+}
+
+impl<'a> CaseName<'a> {
+    /// Attempt to parse a test's path as a case of a parameterised test.
+    pub fn parse(path: &'a str) -> Option<CaseName<'a>> {
+        let (test, case) = path.rsplit_once("::")?;
+        let case = case.strip_prefix("case_")?;
+        let (index, description) = match case.split_once('_') {
+            Some((index, description)) => (index, Some(description)),
+            None => (case, None),
+        };
+
+        Some(CaseName {
+            test,
+            index: index.parse().ok()?,
+            description: description.filter(|description| !description.is_empty()),
+        })
+    }
+}
+
+/// # ParseError
+///
+/// Why a line couldn't be turned into an `Event`.
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    /// The line isn't a JSON object, eg plain text output from a test.
+    NotJson,
+    /// The line is a JSON object, but not an event we recognise.
+    Unrecognised(String),
+    /// The line is one of cargo's own messages, from `--message-format json`.
+    Cargo,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::NotJson => write!(f, "not a JSON object"),
+            ParseError::Unrecognised(err) => write!(f, "unrecognised event: {}", err),
+            ParseError::Cargo => write!(f, "a message from cargo"),
+        }
+    }
+}
+
+/// # ParseErrors
+///
+/// Counts the lines which looked like JSON but weren't events we recognise,
+/// so that users can tell when the format has changed underneath them.
+/// Events of an unknown kind are counted separately: they're well formed, so
+/// are tolerated rather than treated as errors.
+#[derive(Debug, Default)]
+pub struct ParseErrors {
+    pub count: usize,
+    pub unknown_events: BTreeMap<String, usize>,
+    debug: bool,
+}
+
+impl ParseErrors {
+    /// Also print each offending line as it's found.
+    pub fn with_debug(debug: bool) -> Self {
+        ParseErrors {
+            debug,
+            ..ParseErrors::default()
+        }
+    }
+
+    /// Parse `line` and push it into `payload`, recording any error.
+    ///
+    /// Lines which aren't JSON are expected (eg output from tests) and not
+    /// counted.
+    pub fn parse_line(&mut self, line: &str, payload: &mut Payload) {
+        match parse_event(line) {
+            Ok(Event::Unknown { kind, raw }) => {
+                if self.debug {
+                    eprintln!("Ignoring unknown event ({}): {}", kind, raw);
+                }
+                *self.unknown_events.entry(kind).or_default() += 1;
+            }
+            Ok(event) => payload.push(event),
+            Err(err) => self.record(line, err),
+        }
+    }
+
+    fn record(&mut self, line: &str, err: ParseError) {
+        if let ParseError::Unrecognised(_) = err {
+            self.count += 1;
+            if self.debug {
+                eprintln!("Unable to parse line ({}): {}", err, line);
+            }
+        }
+    }
+
+    /// Add the errors `other` recorded, eg while parsing another writer's
+    /// output.
+    pub fn merge(&mut self, other: ParseErrors) {
+        self.count += other.count;
+        for (kind, count) in other.unknown_events {
+            *self.unknown_events.entry(kind).or_default() += count;
+        }
+    }
+
+    /// Report the number of lines which failed to parse, and the events of
+    /// unknown kinds, if any.
+    ///
+    /// ## Emits warnings
+    ///  - If any lines failed to parse.
+    ///  - If any events were of an unknown kind.
+    pub fn report(&self) {
+        if self.count > 0 {
+            eprintln!(
+                "{} JSON lines weren't recognised as test events and were ignored.  Run with --debug to see them.",
+                self.count
+            );
+        }
+
+        if !self.unknown_events.is_empty() {
+            let kinds = self
+                .unknown_events
+                .iter()
+                .map(|(kind, count)| format!("{} ({})", kind, count))
+                .collect::<Vec<String>>();
+            eprintln!(
+                "Ignored events of kinds this version doesn't know: {}.  A newer buildkite-test-collector may understand them.",
+                kinds.join(", ")
+            );
+        }
+    }
+}
+
 /// Attempt to parse a single line if JSON.
 ///
 /// Attempts to convert `line` into an `Event` and push it into `Payload`.
-pub fn parse_line(line: &str, payload: &mut Payload) {
-    if line.chars().find(|c| !c.is_whitespace()) != Some('{') {
-        return;
+/// Outside of tests, use `ParseErrors::parse_line` so that errors and unknown
+/// events are counted.
+#[cfg(test)]
+pub fn parse_line(line: &str, payload: &mut Payload) -> Result<(), ParseError> {
+    payload.push(parse_event(line)?);
+    Ok(())
+}
+
+/// Attempt to convert a single line into an `Event`.
+///
+/// The line is read into a `RawEvent` which borrows its strings from `line`,
+/// rather than through serde's internally tagged enums, which buffer every
+/// field of every event before they can be deserialised.
+pub fn parse_event(line: &str) -> Result<Event, ParseError> {
+    if !is_json_object(line) {
+        return Err(ParseError::NotJson);
+    }
+    if is_cargo_message(line) {
+        return Err(ParseError::Cargo);
+    }
+
+    let unknown = || serde_json::from_str(line).ok().and_then(Event::unknown);
+
+    match serde_json::from_str::<RawEvent>(line) {
+        Ok(raw) => match raw.into_event() {
+            Some(event) => event,
+            None => {
+                unknown().ok_or_else(|| ParseError::Unrecognised("unknown variant".to_string()))
+            }
+        },
+        Err(err) => unknown().ok_or_else(|| ParseError::Unrecognised(err.to_string())),
+    }
+}
+
+/// # RawEvent
+///
+/// Every field of every kind of event libtest emits.  Strings are borrowed
+/// from the line being parsed unless they contain escapes.
+#[derive(Deserialize)]
+struct RawEvent<'a> {
+    #[serde(rename = "type", borrow)]
+    kind: Cow<'a, str>,
+    #[serde(default, borrow)]
+    event: Option<Cow<'a, str>>,
+    #[serde(default, borrow)]
+    name: Option<Cow<'a, str>>,
+    #[serde(default)]
+    exec_time: Option<f64>,
+    #[serde(default, borrow)]
+    stdout: Option<Cow<'a, str>>,
+    #[serde(default, borrow)]
+    stderr: Option<Cow<'a, str>>,
+    #[serde(default, borrow)]
+    message: Option<Cow<'a, str>>,
+    #[serde(default)]
+    test_count: Option<usize>,
+    #[serde(default)]
+    passed: Option<usize>,
+    #[serde(default)]
+    failed: Option<usize>,
+    #[serde(default)]
+    ignored: Option<usize>,
+    #[serde(default)]
+    measured: Option<usize>,
+    #[serde(default)]
+    filtered_out: Option<usize>,
+    #[serde(default)]
+    median: Option<f64>,
+    #[serde(default)]
+    deviation: Option<f64>,
+    #[serde(default)]
+    mib_per_second: Option<u64>,
+    #[serde(default)]
+    nextest: Option<NextestInfo>,
+    /// Only the fields not named above are buffered here.
+    #[serde(flatten)]
+    extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl RawEvent<'_> {
+    /// Convert into an `Event`, or `None` if its kind isn't one we know.
+    fn into_event(self) -> Option<Result<Event, ParseError>> {
+        let event = match (self.kind.as_ref(), self.event.as_deref()) {
+            ("suite", Some("started")) => required(self.test_count, "test_count")
+                .map(|test_count| SuiteEvent::Started { test_count })
+                .map(|event| Event::Suite { event }),
+            ("suite", Some("ok")) => Ok(Event::Suite {
+                event: SuiteEvent::Ok {
+                    results: self.results(),
+                },
+            }),
+            ("suite", Some("failed")) => Ok(Event::Suite {
+                event: SuiteEvent::Failed {
+                    results: self.results(),
+                },
+            }),
+            ("test", Some(event)) => {
+                let name = required(self.name, "name").map(Cow::into_owned);
+                let event = match event {
+                    "started" => name.map(|name| TestEvent::Started { name }),
+                    "ok" => name.map(|name| TestEvent::Ok {
+                        name,
+                        exec_time: self.exec_time,
+                        stdout: self.stdout.map(Cow::into_owned),
+                    }),
+                    "failed" => name.map(|name| TestEvent::Failed {
+                        name,
+                        exec_time: self.exec_time,
+                        stdout: self.stdout.map(Cow::into_owned),
+                        stderr: self.stderr.map(Cow::into_owned),
+                        message: self.message.map(Cow::into_owned),
+                    }),
+                    "ignored" => name.map(|name| TestEvent::Ignored { name }),
+                    "timeout" => name.map(|name| TestEvent::Timeout { name }),
+                    _ => return None,
+                };
+                event.map(|event| Event::Test {
+                    event,
+                    nextest: self.nextest,
+                    extra: self.extra,
+                })
+            }
+            ("bench", _) => required(self.name, "name").and_then(|name| {
+                Ok(Event::Bench {
+                    name: name.into_owned(),
+                    median: required(self.median, "median")?,
+                    deviation: required(self.deviation, "deviation")?,
+                    mib_per_second: self.mib_per_second,
+                })
+            }),
+            _ => return None,
+        };
+
+        Some(event)
+    }
+
+    fn results(&self) -> SuiteResults {
+        SuiteResults {
+            passed: self.passed.unwrap_or_default(),
+            failed: self.failed.unwrap_or_default(),
+            ignored: self.ignored.unwrap_or_default(),
+            measured: self.measured.unwrap_or_default(),
+            filtered_out: self.filtered_out.unwrap_or_default(),
+            exec_time: self.exec_time,
+        }
+    }
+}
+
+fn required<T>(field: Option<T>, name: &str) -> Result<T, ParseError> {
+    field.ok_or_else(|| ParseError::Unrecognised(format!("missing field `{}`", name)))
+}
+
+/// Call `f` with each line read from `reader`, without its line ending.
+///
+/// A single buffer is reused for every line, rather than allocating a new
+/// `String` for each like `BufRead::lines`.  Stops at the end of the input or
+/// the first line which can't be read (eg isn't UTF-8).
+pub fn for_each_line(mut reader: impl BufRead, mut f: impl FnMut(&str)) {
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match reader.read_line(&mut line) {
+            Ok(0) | Err(_) => return,
+            Ok(_) => {
+                let trimmed = line.strip_suffix('\n').unwrap_or(&line);
+                f(trimmed.strip_suffix('\r').unwrap_or(trimmed));
+            }
+        }
+    }
+}
+
+/// Split the binary id cargo-nextest prefixes test names with, eg
+/// `my-crate::my_binary$tests::it_works`, from the test's path.
+pub fn split_binary_id(name: &str) -> (Option<&str>, &str) {
+    match name.split_once('$') {
+        Some((binary_id, path)) => (Some(binary_id), path),
+        None => (None, name),
+    }
+}
+
+/// Split the kind libtest-mimic prefixes some test names with, eg
+/// `[fixture] parses_empty_file`, from the rest of the name.
+pub fn split_kind(name: &str) -> (Option<&str>, &str) {
+    match name
+        .strip_prefix('[')
+        .and_then(|rest| rest.split_once("] "))
+    {
+        Some((kind, name)) => (Some(kind), name),
+        None => (None, name),
+    }
+}
+
+/// The test binary cargo says it's about to run, eg `my_crate` from
+/// `Running unittests src/lib.rs (target/debug/deps/my_crate-0123456789abcdef)`,
+/// or the crate whose doctests it's about to run, from `Doc-tests my_crate`.
+///
+/// cargo prints these on `stderr`.
+pub fn cargo_test_binary(line: &str) -> Option<&str> {
+    let line = line.trim();
+    if let Some(crate_name) = line.strip_prefix("Doc-tests ") {
+        return Some(crate_name);
     }
 
-    let maybe_event: serde_json::Result<Event> = serde_json::from_str(line);
+    let (_, path) = line.strip_prefix("Running ")?.rsplit_once(" (")?;
+    let file = path.strip_suffix(')')?.rsplit(['/', '\\']).next()?;
+    let file = file.strip_suffix(".exe").unwrap_or(file);
+
+    // Binaries are suffixed with a hash of their build.
+    Some(match file.rsplit_once('-') {
+        Some((binary, hash)) if hash.len() == 16 && hash.chars().all(|c| c.is_ascii_hexdigit()) => {
+            binary
+        }
+        _ => file,
+    })
+}
+
+/// Split a test's path into its scope (module path) and name.
+pub fn split_scope(path: &str) -> (&str, &str) {
+    path.rsplit_once("::").unwrap_or(("", path))
+}
+
+/// The `reason`s of the messages cargo itself prints with
+/// `--message-format json`, interleaved with the test output.
+const CARGO_REASONS: [&str; 4] = [
+    "compiler-artifact",
+    "compiler-message",
+    "build-script-executed",
+    "build-finished",
+];
+
+/// Is the line one of cargo's own JSON messages, rather than test output?
+pub fn is_cargo_message(line: &str) -> bool {
+    line.trim_start()
+        .strip_prefix(r#"{"reason":""#)
+        .and_then(|rest| rest.split_once('"'))
+        .is_some_and(|(reason, _)| CARGO_REASONS.contains(&reason))
+}
+
+/// Does the line look like it contains a JSON object?
+pub fn is_json_object(line: &str) -> bool {
+    line.chars().find(|c| !c.is_whitespace()) == Some('{')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_doctest_names() {
+        assert_eq!(
+            DoctestName::parse("src/lib.rs - module::func (line 42)"),
+            Some(DoctestName {
+                file: "src/lib.rs",
+                item: "module::func",
+                line: 42,
+            })
+        );
+        assert_eq!(
+            DoctestName::parse("src/lib.rs - Foo::bar (line 7) - compile fail"),
+            Some(DoctestName {
+                file: "src/lib.rs",
+                item: "Foo::bar",
+                line: 7,
+            })
+        );
+        assert_eq!(
+            DoctestName::parse("src/lib.rs - (line 1)"),
+            Some(DoctestName {
+                file: "src/lib.rs",
+                item: "",
+                line: 1,
+            })
+        );
+        assert_eq!(DoctestName::parse("payload::test::batchify"), None);
+    }
+
+    #[test]
+    fn parses_cargo_test_binaries() {
+        assert_eq!(
+            cargo_test_binary(
+                "     Running unittests src/lib.rs (target/debug/deps/my_crate-0123456789abcdef)"
+            ),
+            Some("my_crate")
+        );
+        assert_eq!(
+            cargo_test_binary(
+                r"     Running tests\api.rs (target\debug\deps\api-0123456789abcdef.exe)"
+            ),
+            Some("api")
+        );
+        assert_eq!(cargo_test_binary("   Doc-tests my_crate"), Some("my_crate"));
+        assert_eq!(cargo_test_binary("running 3 tests"), None);
+    }
+
+    #[test]
+    fn counts_unrecognised_json_lines() {
+        let mut payload = Payload::new(crate::run_env::RuntimeEnvironment::generic());
+        let mut errors = ParseErrors::default();
+
+        for line in [
+            r#"{ "type": "suite", "event": "started", "test_count": 1 }"#,
+            "running 1 test",
+            r#"{ "type": "test", "event": "ok" }"#,
+            r#"{ "type": "suite", "event": "started", "test_count": "many" }"#,
+        ] {
+            errors.parse_line(line, &mut payload);
+        }
+
+        assert_eq!(errors.count, 2);
+        assert!(errors.unknown_events.is_empty());
+    }
+
+    #[test]
+    fn tolerates_unknown_events() {
+        let mut payload = Payload::new(crate::run_env::RuntimeEnvironment::generic());
+        let mut errors = ParseErrors::default();
+
+        for line in [
+            r#"{ "type": "suite", "event": "started", "test_count": 1 }"#,
+            r#"{ "type": "test", "event": "started", "name": "a::b" }"#,
+            r#"{ "type": "test", "event": "retried", "name": "a::b", "attempt": 2 }"#,
+            r#"{ "type": "test", "event": "retried", "name": "a::b", "attempt": 3 }"#,
+            r#"{ "type": "report", "total_time": 1.0 }"#,
+            r#"{ "type": "test", "event": "ok", "name": "a::b", "exec_time": 0.1 }"#,
+        ] {
+            errors.parse_line(line, &mut payload);
+        }
+
+        assert_eq!(errors.count, 0);
+        assert_eq!(
+            errors.unknown_events,
+            BTreeMap::from([("report".to_string(), 1), ("test/retried".to_string(), 2)])
+        );
+        assert_eq!(payload.closed_data().len(), 1);
+
+        assert_eq!(
+            parse_event(r#"{ "type": "test", "event": "retried", "name": "a::b" }"#),
+            Ok(Event::Unknown {
+                kind: "test/retried".to_string(),
+                raw: serde_json::json!({ "type": "test", "event": "retried", "name": "a::b" }),
+            })
+        );
+    }
+
+    #[test]
+    fn keeps_unknown_fields_of_test_events() {
+        let event = parse_event(
+            r#"{ "type": "test", "event": "failed", "name": "a::b", "exec_time": 0.1, "stdout": "boom", "nextest": { "crate": "a", "test_binary": "b", "kind": "lib" }, "failure": { "kind": "panic" } }"#,
+        );
+
+        let Ok(Event::Test { extra, .. }) = event else {
+            panic!("expected a test event, got {:?}", event);
+        };
+        assert_eq!(
+            serde_json::Value::Object(extra),
+            serde_json::json!({ "failure": { "kind": "panic" } })
+        );
+    }
+
+    #[test]
+    fn unescapes_strings_which_cant_be_borrowed() {
+        assert_eq!(
+            parse_event(
+                r#"{ "type": "test", "event": "failed", "name": "a::b", "stdout": "left: \"1\"\nright: \"2\"" }"#
+            ),
+            Ok(Event::Test {
+                event: TestEvent::Failed {
+                    name: "a::b".to_string(),
+                    exec_time: None,
+                    stdout: Some("left: \"1\"\nright: \"2\"".to_string()),
+                    stderr: None,
+                    message: None,
+                },
+                nextest: None,
+                extra: Default::default(),
+            })
+        );
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture` to see how
+    /// quickly events are parsed.
+    #[test]
+    #[ignore]
+    fn parsing_throughput() {
+        let lines = (0..100_000)
+            .flat_map(|i| {
+                [
+                    format!(r#"{{ "type": "test", "event": "started", "name": "tests::case_{}" }}"#, i),
+                    format!(r#"{{ "type": "test", "name": "tests::case_{}", "event": "ok", "exec_time": 0.001 }}"#, i),
+                ]
+            })
+            .collect::<Vec<String>>();
+        let bytes = lines.iter().map(String::len).sum::<usize>();
+
+        let started = std::time::Instant::now();
+        for line in &lines {
+            assert!(parse_event(line).is_ok());
+        }
+        let elapsed = started.elapsed();
+
+        eprintln!(
+            "Parsed {} events in {:?} ({:.0} MiB/s)",
+            lines.len(),
+            elapsed,
+            bytes as f64 / elapsed.as_secs_f64() / 1024.0 / 1024.0
+        );
+    }
+
+    #[test]
+    fn parses_bench_events() {
+        assert_eq!(
+            parse_event(
+                r#"{ "type": "bench", "name": "bench_parse", "median": 1234, "deviation": 56, "mib_per_second": 78 }"#
+            ),
+            Ok(Event::Bench {
+                name: "bench_parse".to_string(),
+                median: 1234.0,
+                deviation: 56.0,
+                mib_per_second: Some(78),
+            })
+        );
+        assert_eq!(
+            parse_event(
+                r#"{ "type": "bench", "name": "bench_parse", "median": 1234, "deviation": 56 }"#
+            ),
+            Ok(Event::Bench {
+                name: "bench_parse".to_string(),
+                median: 1234.0,
+                deviation: 56.0,
+                mib_per_second: None,
+            })
+        );
+    }
+
+    #[test]
+    fn parses_case_names() {
+        assert_eq!(
+            CaseName::parse("tests::my_test::case_3_negative_input"),
+            Some(CaseName {
+                test: "tests::my_test",
+                index: 3,
+                description: Some("negative_input"),
+            })
+        );
+        assert_eq!(
+            CaseName::parse("my_test::case_01"),
+            Some(CaseName {
+                test: "my_test",
+                index: 1,
+                description: None,
+            })
+        );
+        assert_eq!(CaseName::parse("case_1"), None);
+        assert_eq!(CaseName::parse("tests::case_insensitive"), None);
+    }
+
+    #[test]
+    fn sniffs_input_formats() {
+        for (line, format) in [
+            (
+                r#"{ "type": "suite", "event": "started", "test_count": 1 }"#,
+                Some(InputFormat::Libtest),
+            ),
+            (
+                r#"{"reason":"benchmark-complete","id":"fib"}"#,
+                Some(InputFormat::Criterion),
+            ),
+            ("TAP version 13", Some(InputFormat::Tap)),
+            ("1..4", Some(InputFormat::Tap)),
+            ("not ok 2 - talks to the sensor", Some(InputFormat::Tap)),
+            ("running 12 tests", Some(InputFormat::Libtest)),
+            ("test tests::adds ... ok", Some(InputFormat::Libtest)),
+            ("   Compiling my-crate v0.1.0", None),
+            ("{ not json", None),
+            (
+                r#"{"reason":"compiler-artifact","package_id":"my-crate 0.1.0"}"#,
+                None,
+            ),
+        ] {
+            assert_eq!(InputFormat::sniff(line), format, "{}", line);
+        }
+    }
+
+    #[test]
+    fn skips_cargos_messages() {
+        let mut payload = Payload::new(crate::run_env::RuntimeEnvironment::generic());
+        let mut input = InputParser::new(InputFormat::Auto, false);
+
+        for line in [
+            r#"{"reason":"compiler-artifact","package_id":"my-crate 0.1.0","target":{"name":"my-crate"},"fresh":true}"#,
+            r#"{"reason":"compiler-message","package_id":"my-crate 0.1.0","message":{"rendered":"warning: unused variable"}}"#,
+            r#"{"reason":"build-finished","success":true}"#,
+            "",
+            "running 1 test",
+            "test tests::adds ... ok",
+            "",
+            "test result: ok. 1 passed; 0 failed; 0 ignored; 0 measured; 0 filtered out; finished in 0.00s",
+        ] {
+            input.parse_line(line, &mut payload);
+        }
+        input.finish(&mut payload);
+
+        assert_eq!(input.parse_errors.count, 0);
+        assert_eq!(payload.closed_data().len(), 1);
+    }
+
+    #[test]
+    fn replays_lines_once_the_format_is_detected() {
+        let mut payload = Payload::new(crate::run_env::RuntimeEnvironment::generic());
+        let mut input = InputParser::new(InputFormat::Auto, false);
+
+        for line in ["", "# starting", "TAP version 13", "ok 1 - boots"] {
+            input.parse_line(line, &mut payload);
+        }
+        input.finish(&mut payload);
+
+        assert_eq!(input.format, InputFormat::Tap);
+        assert_eq!(payload.closed_data().len(), 1);
+    }
+
+    #[test]
+    fn assumes_libtest_when_nothing_gives_the_format_away() {
+        let mut payload = Payload::new(crate::run_env::RuntimeEnvironment::generic());
+        let mut input = InputParser::new(InputFormat::Auto, false);
+
+        for _ in 0..SNIFF_LINES {
+            input.parse_line("hello", &mut payload);
+        }
+
+        assert_eq!(input.format, InputFormat::Libtest);
+        assert!(input.sniffed.is_empty());
+    }
+
+    mod properties {
+        use super::*;
+        use crate::run_env::RuntimeEnvironment;
+        use proptest::prelude::*;
+
+        fn event_line() -> impl Strategy<Value = String> {
+            (
+                prop::sample::select(vec!["suite", "test", "bench", "other"]),
+                prop::sample::select(vec!["started", "ok", "failed", "ignored", "timeout"]),
+                prop::sample::select(vec![
+                    "tests::a",
+                    "tests::b",
+                    "crate$tests::a",
+                    "src/lib.rs - func (line 1)",
+                    "",
+                    "::",
+                ]),
+                0.0..2.0f64,
+            )
+                .prop_map(|(kind, event, name, time)| {
+                    format!(
+                        r#"{{ "type": "{}", "event": "{}", "name": "{}", "exec_time": {}, "test_count": 1, "median": {}, "deviation": 0, "passed": 0, "failed": 0, "ignored": 0, "measured": 0, "filtered_out": 0 }}"#,
+                        kind, event, name, time, time
+                    )
+                })
+        }
+
+        proptest! {
+            #[test]
+            fn arbitrary_lines_never_panic(lines in prop::collection::vec(".*", 0..20)) {
+                let mut payload = Payload::new(RuntimeEnvironment::generic());
+                for line in lines {
+                    let _ = parse_line(&line, &mut payload);
+                }
+            }
+
+            #[test]
+            fn event_streams_keep_their_invariants(lines in prop::collection::vec(event_line(), 0..50)) {
+                let mut payload = Payload::new(RuntimeEnvironment::generic());
+                for line in lines {
+                    let _ = parse_line(&line, &mut payload);
+                }
+
+                for test_data in payload.closed_data() {
+                    let duration = test_data.duration().unwrap_or_default();
+                    prop_assert!(duration >= 0.0);
+                }
+            }
+
+            #[test]
+            fn scopes_split_losslessly(path in "[a-z_:]{0,30}") {
+                let (scope, name) = split_scope(&path);
+
+                prop_assert!(!name.contains("::"));
+                if scope.is_empty() && !path.starts_with("::") {
+                    prop_assert_eq!(name, path.as_str());
+                } else {
+                    prop_assert_eq!(format!("{}::{}", scope, name), path);
+                }
+            }
 
-    if let Ok(event) = maybe_event {
-        payload.push(event);
+            #[test]
+            fn binary_ids_split_losslessly(name in "[a-z_:$-]{0,30}") {
+                match split_binary_id(&name) {
+                    (Some(binary_id), path) => {
+                        prop_assert!(!binary_id.contains('$'));
+                        prop_assert_eq!(format!("{}${}", binary_id, path), name);
+                    }
+                    (None, path) => prop_assert_eq!(path, name.as_str()),
+                }
+            }
+        }
     }
 }
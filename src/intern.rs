@@ -0,0 +1,126 @@
+//! # intern
+//!
+//! String interning for values which repeat many times across a test run,
+//! such as scopes, tags, failure reasons and history sections, so that each
+//! distinct value is only allocated once.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+/// # Interner
+///
+/// Hands out shared copies of strings, allocating only the first time each
+/// distinct string is seen.
+#[derive(Debug, Default, PartialEq)]
+pub struct Interner {
+    strings: HashSet<Arc<str>>,
+}
+
+impl Interner {
+    /// Return the shared copy of `value`, allocating it if it's new.
+    pub fn intern(&mut self, value: &str) -> Arc<str> {
+        if let Some(existing) = self.strings.get(value) {
+            return existing.clone();
+        }
+
+        let value: Arc<str> = Arc::from(value);
+        self.strings.insert(value.clone());
+        value
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::payload::Payload;
+    use crate::run_env::RuntimeEnvironment;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    /// Counts the allocations and bytes allocated on each thread, so the
+    /// memory benchmark isn't thrown off by tests running alongside it.
+    struct CountingAllocator;
+
+    thread_local! {
+        static LIVE_BYTES: Cell<isize> = const { Cell::new(0) };
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    fn count(bytes: isize) {
+        let _ = LIVE_BYTES.try_with(|live| live.set(live.get() + bytes));
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            count(layout.size() as isize);
+            let _ = ALLOCATIONS.try_with(|allocations| allocations.set(allocations.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            count(-(layout.size() as isize));
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    /// Not a correctness test, but a benchmark of how much memory a huge
+    /// suite's results take up, and how many allocations collecting each
+    /// event takes, run with `cargo test --release interning_memory --
+    /// --ignored --nocapture`.  Every test has the run's default tags, and
+    /// one in ten fails the same way.
+    #[test]
+    #[ignore]
+    fn interning_memory() {
+        let scope = "integration::api::client::requests::retries::with_backoff";
+        let lines = (0..100_000)
+            .flat_map(|i| {
+                let finished = if i % 10 == 0 {
+                    format!(r#"{{ "type": "test", "name": "{}::case_{}", "event": "failed", "exec_time": 0.001, "stdout": "thread 'main' panicked at src/client.rs:10:5:\nconnection refused\n" }}"#, scope, i)
+                } else {
+                    format!(r#"{{ "type": "test", "name": "{}::case_{}", "event": "ok", "exec_time": 0.001 }}"#, scope, i)
+                };
+                [
+                    format!(r#"{{ "type": "test", "event": "started", "name": "{}::case_{}" }}"#, scope, i),
+                    finished,
+                ]
+            })
+            .collect::<Vec<String>>();
+        let default_tags = ["host.os", "host.arch", "host.name", "rust.version", "team"]
+            .map(|key| (key.to_string(), format!("{} of the run", key)))
+            .to_vec();
+
+        let before = LIVE_BYTES.with(Cell::get);
+        let allocations_before = ALLOCATIONS.with(Cell::get);
+        let mut payload =
+            Payload::new(RuntimeEnvironment::generic()).with_default_tags(default_tags);
+        for line in &lines {
+            crate::input::parse_line(line, &mut payload).unwrap();
+        }
+        let held = LIVE_BYTES.with(Cell::get) - before;
+        let allocations = ALLOCATIONS.with(Cell::get) - allocations_before;
+
+        assert_eq!(payload.closed_data().len(), 100_000);
+        eprintln!(
+            "Holding 100000 tests takes {:.1} MiB ({} bytes per test), and {:.1} allocations per event",
+            held as f64 / 1024.0 / 1024.0,
+            held / 100_000,
+            allocations as f64 / lines.len() as f64
+        );
+    }
+
+    #[test]
+    fn repeated_strings_share_an_allocation() {
+        let mut interner = Interner::default();
+
+        let first = interner.intern("payload::test");
+        let second = interner.intern(&String::from("payload::test"));
+        let other = interner.intern("run_env::test");
+
+        assert!(Arc::ptr_eq(&first, &second));
+        assert!(!Arc::ptr_eq(&first, &other));
+        assert_eq!(&*other, "run_env::test");
+    }
+}
@@ -0,0 +1,173 @@
+//! # junit
+//!
+//! Deserialisation of JUnit XML reports, for tools which don't produce
+//! libtest's JSON output.
+
+use crate::duration::DurationSource;
+use crate::payload::{Payload, TestResult};
+use roxmltree::{Document, Node};
+use std::sync::Arc;
+
+/// Parse a JUnit XML report and push each test case into `payload`.
+///
+/// Accepts either a `<testsuites>` or a single `<testsuite>` root.  Test
+/// cases are scoped by their `classname`, or the enclosing suite's `name` if
+/// they don't have one.
+///
+/// Returns the number of test cases found.
+pub fn parse(xml: &str, payload: &mut Payload) -> Result<usize, roxmltree::Error> {
+    let document = Document::parse(xml)?;
+    let mut count = 0;
+
+    for suite in document
+        .descendants()
+        .filter(|node| node.has_tag_name("testsuite"))
+    {
+        let suite_name = suite.attribute("name").unwrap_or_default();
+        let mut offset = 0.0;
+
+        for case in suite
+            .children()
+            .filter(|node| node.has_tag_name("testcase"))
+        {
+            let scope = case.attribute("classname").unwrap_or(suite_name);
+            let name = case.attribute("name").unwrap_or_default();
+            let duration = case
+                .attribute("time")
+                .and_then(|time| time.parse::<f64>().ok())
+                .unwrap_or_default();
+
+            payload.push_finished_test(
+                scope,
+                name,
+                test_result(case),
+                offset,
+                duration,
+                DurationSource::Junit,
+            );
+
+            offset += duration;
+            count += 1;
+        }
+    }
+
+    Ok(count)
+}
+
+fn test_result(case: Node) -> TestResult {
+    for child in case.children().filter(Node::is_element) {
+        match child.tag_name().name() {
+            "failure" | "error" => {
+                let message = child.attribute("message");
+                let text = child.text().map(str::trim).filter(|text| !text.is_empty());
+
+                let failure_reason = match (message, text) {
+                    (Some(message), Some(text)) => Some(format!("{}\n{}", message, text)),
+                    (message, text) => message.or(text).map(String::from),
+                };
+
+                return TestResult::Failed {
+                    failure_reason: failure_reason.map(Arc::from),
+                };
+            }
+            "skipped" => return TestResult::Skipped,
+            _ => {}
+        }
+    }
+
+    TestResult::Passed
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::run_env::RuntimeEnvironment;
+
+    #[test]
+    fn parses_test_cases_into_the_payload() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuites>
+  <testsuite name="integration" tests="3">
+    <testcase classname="api::test" name="submits" time="0.5"/>
+    <testcase classname="api::test" name="retries" time="1.25">
+      <failure message="assertion failed">left: 1, right: 2</failure>
+    </testcase>
+    <testcase name="flaky" time="0">
+      <skipped/>
+    </testcase>
+  </testsuite>
+</testsuites>"#;
+
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+
+        assert_eq!(parse(xml, &mut payload).unwrap(), 3);
+
+        let json = serde_json::to_value(&payload).unwrap();
+        let mut data = json["data"].as_array().unwrap().clone();
+        data.sort_by_key(|test| test["name"].as_str().unwrap().to_string());
+
+        assert_eq!(data[0]["name"], "flaky");
+        assert_eq!(data[0]["scope"], "integration");
+        assert_eq!(data[0]["result"], "skipped");
+
+        assert_eq!(data[1]["name"], "retries");
+        assert_eq!(data[1]["result"], "failed");
+        assert_eq!(
+            data[1]["failure_reason"],
+            "assertion failed\nleft: 1, right: 2"
+        );
+        // Start times are measured from the first test's.
+        let start_at = |test: &serde_json::Value| test["history"]["start_at"].as_f64().unwrap();
+        assert!((start_at(&data[1]) - start_at(&data[2]) - 0.5).abs() < 1e-6);
+        assert_eq!(data[1]["history"]["duration"], 1.25);
+        assert_eq!(data[1]["tags"]["duration_source"], "junit");
+
+        assert_eq!(data[2]["name"], "submits");
+        assert_eq!(data[2]["result"], "passed");
+    }
+
+    #[test]
+    fn rejects_malformed_xml() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+
+        assert!(parse("<testsuite>", &mut payload).is_err());
+    }
+
+    mod properties {
+        use super::*;
+        use crate::run_env::RuntimeEnvironment;
+        use proptest::prelude::*;
+
+        proptest! {
+            #[test]
+            fn arbitrary_input_never_panics(xml in ".*") {
+                let mut payload = Payload::new(RuntimeEnvironment::generic());
+                let _ = parse(&xml, &mut payload);
+            }
+
+            #[test]
+            fn every_test_case_is_recorded(
+                cases in prop::collection::vec(("[a-z]{1,10}", 0.0..10.0f64, 0..3u8), 0..30)
+            ) {
+                let mut xml = String::from("<testsuite name=\"suite\">");
+                for (index, (name, time, kind)) in cases.iter().enumerate() {
+                    let body = match kind {
+                        0 => "",
+                        1 => "<failure message=\"boom\"/>",
+                        _ => "<skipped/>",
+                    };
+                    xml.push_str(&format!(
+                        "<testcase classname=\"case{}\" name=\"{}\" time=\"{}\">{}</testcase>",
+                        index, name, time, body
+                    ));
+                }
+                xml.push_str("</testsuite>");
+
+                let mut payload = Payload::new(RuntimeEnvironment::generic());
+
+                prop_assert_eq!(parse(&xml, &mut payload).unwrap(), cases.len());
+                prop_assert_eq!(payload.closed_data().len(), cases.len());
+            }
+        }
+    }
+}
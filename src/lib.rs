@@ -0,0 +1,14 @@
+//! # buildkite-test-collector
+//!
+//! The parts of the collector which tests can use themselves, to enrich their
+//! results.  Most projects only need the `buildkite-test-collector` binary;
+//! see the README.
+//!
+//!  - `marker`: the marker lines tests print to tag themselves and record
+//!    spans, which the collector finds in their captured output.
+//!  - `tracing` (with the `tracing` feature): a `tracing-subscriber` layer
+//!    which records the spans opened during each test as markers.
+
+pub mod marker;
+#[cfg(feature = "tracing")]
+pub mod tracing;
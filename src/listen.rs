@@ -0,0 +1,268 @@
+//! # listen
+//!
+//! Reads test output from a named pipe (FIFO) or Unix domain socket instead
+//! of `stdin`, so that one long-running collector (eg a sidecar container)
+//! can collect the output of several test invocations into a single run.
+//!
+//! If the path is an existing FIFO it's read from, reopening it each time a
+//! writer closes it.  Otherwise a Unix socket is created there, and any
+//! number of connections may write to it at once.  Either way, lines are
+//! collected until one of them is `FINISH`.
+//!
+//! Each connection to the socket, and each time the FIFO is opened after its
+//! last writer closed it, is a writer of its own, whose output is parsed separately, as test events don't
+//! say which invocation they came from.  Lines from several processes which
+//! have the FIFO open at once can't be told apart, so only one may write to
+//! it at a time.
+
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+/// The line which tells the collector that every test invocation has
+/// finished, so it should upload the run and exit.
+pub const FINISH: &str = r#"{"type":"collector","event":"finish"}"#;
+
+/// # Message
+///
+/// What one of the writers sent, identified by the order it connected in.
+#[derive(Debug, PartialEq)]
+pub enum Message {
+    /// A line of its output.
+    Line(usize, String),
+    /// It closed its end, so has nothing more to write.
+    Closed(usize),
+}
+
+/// # Listener
+///
+/// The messages received so far from every writer, in the order they
+/// arrived.
+#[derive(Debug)]
+pub struct Listener {
+    messages: Receiver<Message>,
+    /// The socket created, which is removed again when dropped.
+    socket: Option<PathBuf>,
+}
+
+impl Listener {
+    /// Each message received, until a writer sends `FINISH`.
+    pub fn messages(&self) -> impl Iterator<Item = Message> + '_ {
+        self.messages
+            .iter()
+            .take_while(|message| !matches!(message, Message::Line(_, line) if line == FINISH))
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Some(socket) = &self.socket {
+            let _ = std::fs::remove_file(socket);
+        }
+    }
+}
+
+/// Start reading from the FIFO at `path`, or a Unix socket created there.
+///
+/// A socket left behind by an earlier collector is replaced, but any other
+/// kind of file is an error.
+#[cfg(unix)]
+pub fn listen(path: &Path) -> io::Result<Listener> {
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::net::UnixListener;
+
+    let (sender, messages) = mpsc::channel();
+
+    let file_type = match std::fs::metadata(path) {
+        Ok(metadata) => Some(metadata.file_type()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err),
+    };
+
+    match file_type {
+        Some(file_type) if file_type.is_fifo() => {
+            let path = path.to_path_buf();
+            thread::spawn(move || {
+                // Opening blocks until there's a writer, and reading ends
+                // when the last writer closes, so open it again for the next.
+                for writer in 0.. {
+                    let Ok(fifo) = std::fs::File::open(&path) else {
+                        return;
+                    };
+                    if !forward(writer, fifo, &sender) {
+                        return;
+                    }
+                }
+            });
+
+            Ok(Listener {
+                messages,
+                socket: None,
+            })
+        }
+        Some(file_type) if !file_type.is_socket() => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "not a named pipe or socket",
+        )),
+        _ => {
+            if file_type.is_some() {
+                std::fs::remove_file(path)?;
+            }
+            let listener = UnixListener::bind(path)?;
+            thread::spawn(move || {
+                let streams = listener.incoming().map_while(Result::ok);
+                for (writer, stream) in streams.enumerate() {
+                    let sender = sender.clone();
+                    thread::spawn(move || forward(writer, stream, &sender));
+                }
+            });
+
+            Ok(Listener {
+                messages,
+                socket: Some(path.to_path_buf()),
+            })
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub fn listen(_path: &Path) -> io::Result<Listener> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "named pipes and Unix sockets are only supported on Unix",
+    ))
+}
+
+/// Send each line read from `reader`, as `writer`'s, and then that it's
+/// closed.
+///
+/// Returns `false` once the `Listener` has gone, so there's no point reading
+/// any more.
+#[cfg_attr(not(unix), allow(dead_code))]
+fn forward(writer: usize, reader: impl io::Read, sender: &Sender<Message>) -> bool {
+    for line in BufReader::new(reader).lines().map_while(Result::ok) {
+        if sender.send(Message::Line(writer, line)).is_err() {
+            return false;
+        }
+    }
+
+    sender.send(Message::Closed(writer)).is_ok()
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use std::env;
+    use std::io::Write;
+    use std::os::unix::net::UnixStream;
+    use std::process::Command;
+    use uuid::Uuid;
+
+    fn lines(listener: &Listener) -> Vec<(usize, String)> {
+        listener
+            .messages()
+            .filter_map(|message| match message {
+                Message::Line(writer, line) => Some((writer, line)),
+                Message::Closed(_) => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn collects_lines_from_every_connection_until_finished() {
+        let path = env::temp_dir().join(format!("listen-test-{}.sock", Uuid::new_v4()));
+        let listener = listen(&path).unwrap();
+
+        let mut first = UnixStream::connect(&path).unwrap();
+        writeln!(
+            first,
+            r#"{{ "type": "suite", "event": "started", "test_count": 1 }}"#
+        )
+        .unwrap();
+        drop(first);
+        assert_eq!(
+            listener.messages().nth(1),
+            Some(Message::Closed(0)),
+            "the first connection is closed after its line"
+        );
+
+        let mut second = UnixStream::connect(&path).unwrap();
+        writeln!(
+            second,
+            r#"{{ "type": "suite", "event": "started", "test_count": 2 }}"#
+        )
+        .unwrap();
+        writeln!(second, "{}", FINISH).unwrap();
+        writeln!(second, "after the end").unwrap();
+
+        let lines = lines(&listener);
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].0, 1);
+        assert!(lines[0].1.contains(r#""test_count": 2"#));
+
+        drop(listener);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn tells_apart_connections_writing_at_once() {
+        let path = env::temp_dir().join(format!("listen-test-{}.sock", Uuid::new_v4()));
+        let listener = listen(&path).unwrap();
+
+        let mut streams = [
+            UnixStream::connect(&path).unwrap(),
+            UnixStream::connect(&path).unwrap(),
+        ];
+        // Each is received before the next is written, so they interleave.
+        for (writer, line) in [(0, "first 1"), (1, "second 1"), (0, "first 2")] {
+            writeln!(streams[writer], "{}", line).unwrap();
+            assert_eq!(
+                listener.messages().next(),
+                Some(Message::Line(writer, line.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn reopens_fifos_for_each_writer() {
+        let path = env::temp_dir().join(format!("listen-test-{}.fifo", Uuid::new_v4()));
+        let created = Command::new("mkfifo").arg(&path).status();
+        if !created.is_ok_and(|status| status.success()) {
+            eprintln!("mkfifo isn't available, skipping");
+            return;
+        }
+
+        let listener = listen(&path).unwrap();
+        for (writer, line) in ["first run", "second run"].into_iter().enumerate() {
+            let mut fifo = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+            writeln!(fifo, "{}", line).unwrap();
+            drop(fifo);
+            // Waiting for it to close, as the reader only sees the end of a
+            // FIFO once nothing has it open.
+            assert_eq!(
+                listener.messages().take(2).collect::<Vec<Message>>(),
+                [
+                    Message::Line(writer, line.to_string()),
+                    Message::Closed(writer)
+                ]
+            );
+        }
+        let mut fifo = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        writeln!(fifo, "{}", FINISH).unwrap();
+        assert_eq!(listener.messages().next(), None);
+
+        drop(listener);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn refuses_to_replace_other_files() {
+        let path = env::temp_dir().join(format!("listen-test-{}.txt", Uuid::new_v4()));
+        std::fs::write(&path, "results").unwrap();
+
+        assert!(listen(&path).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
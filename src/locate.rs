@@ -0,0 +1,365 @@
+//! # locate
+//!
+//! Finds where each test is defined by scanning the source for test
+//! functions, so that passing tests link to their code in Test Engine too,
+//! not only failures (which use where they panicked).
+//!
+//! A test function is found by its `#[test]` (or `#[tokio::test]`, etc)
+//! attribute, and given the module path of its file and any inline `mod`
+//! blocks around it, eg `api::test` for a `mod test` in `src/api.rs`.  A test
+//! matches the function with its name whose module path best matches the end
+//! of its scope.  It's a heuristic: tests generated by macros aren't found.
+//!
+//! Enabled by setting `BUILDKITE_ANALYTICS_LOCATE_TESTS=true`, which searches
+//! the current directory.
+
+use crate::payload::{parse_var, Payload};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// # TestLocations
+///
+/// The test functions found in the source, by name.
+#[derive(Debug, Default, PartialEq)]
+pub struct TestLocations {
+    tests: HashMap<String, Vec<Definition>>,
+}
+
+/// Where a test function is defined.
+#[derive(Debug, PartialEq)]
+struct Definition {
+    module_path: Vec<String>,
+    file: String,
+    line: usize,
+}
+
+impl TestLocations {
+    /// Scan the current directory, if enabled.  It's only scanned once, however
+    /// many payloads (eg streamed batches) are located.
+    ///
+    /// ## Emits warnings
+    ///  - If `BUILDKITE_ANALYTICS_LOCATE_TESTS` cannot be parsed.
+    pub fn discover() -> Option<&'static Self> {
+        static LOCATIONS: OnceLock<Option<TestLocations>> = OnceLock::new();
+
+        LOCATIONS
+            .get_or_init(|| {
+                parse_var::<bool>("BUILDKITE_ANALYTICS_LOCATE_TESTS")
+                    .filter(|locate| *locate)
+                    .map(|_| Self::scan(Path::new(".")))
+            })
+            .as_ref()
+    }
+
+    /// Find the test functions in the Rust source under `root`, skipping
+    /// `target` and hidden directories.
+    pub fn scan(root: &Path) -> Self {
+        let mut locations = TestLocations::default();
+        let mut dirs = vec![root.to_path_buf()];
+
+        while let Some(dir) = dirs.pop() {
+            let Ok(entries) = fs::read_dir(&dir) else {
+                continue;
+            };
+            for entry in entries.map_while(Result::ok) {
+                let path = entry.path();
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+
+                if path.is_dir() {
+                    if name != "target" && !name.starts_with('.') {
+                        dirs.push(path);
+                    }
+                } else if name.ends_with(".rs") {
+                    let Ok(contents) = fs::read_to_string(&path) else {
+                        continue;
+                    };
+                    let relative = path.strip_prefix(root).unwrap_or(&path);
+                    let file = relative
+                        .components()
+                        .map(|component| component.as_os_str().to_string_lossy())
+                        .collect::<Vec<_>>()
+                        .join("/");
+                    locations.add_file(&file, &contents);
+                }
+            }
+        }
+
+        locations
+    }
+
+    /// Add the test functions in `contents`, the source of `file`.
+    fn add_file(&mut self, file: &str, contents: &str) {
+        let file_modules = file_module_path(file);
+        let mut inline_modules: Vec<(String, usize)> = Vec::new();
+        let mut depth = 0usize;
+        let mut is_test = false;
+
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim_start();
+            if line.starts_with("//") {
+                continue;
+            }
+
+            if line.starts_with("#[") && is_test_attribute(line) {
+                is_test = true;
+            }
+            if let Some(name) = item_name(line, "fn") {
+                if is_test {
+                    let module_path = file_modules
+                        .iter()
+                        .cloned()
+                        .chain(inline_modules.iter().map(|(name, _)| name.clone()))
+                        .collect();
+                    self.tests
+                        .entry(name.to_string())
+                        .or_default()
+                        .push(Definition {
+                            module_path,
+                            file: file.to_string(),
+                            line: index + 1,
+                        });
+                }
+                is_test = false;
+            }
+            if let Some(name) = item_name(line, "mod").filter(|_| line.contains('{')) {
+                inline_modules.push((name.to_string(), depth));
+            }
+
+            let opened = line.matches('{').count();
+            let closed = line.matches('}').count();
+            depth = (depth + opened).saturating_sub(closed);
+            while inline_modules
+                .last()
+                .is_some_and(|(_, module_depth)| *module_depth >= depth)
+            {
+                inline_modules.pop();
+            }
+        }
+    }
+
+    /// The file and line where the test named `name` in `scope` is defined.
+    ///
+    /// Parameterised cases (eg `adds::case_1`) are found by their test
+    /// function's name, the last part of their scope.
+    pub fn find(&self, scope: &str, name: &str) -> Option<(&str, usize)> {
+        let scope = scope
+            .split("::")
+            .filter(|module| !module.is_empty())
+            .collect::<Vec<&str>>();
+
+        self.find_in(&scope, name).or_else(|| {
+            let (function, parent) = scope.split_last()?;
+            self.find_in(parent, function)
+        })
+    }
+
+    /// The definition of `name` whose module path best matches the end of
+    /// `scope`, if there's exactly one.
+    fn find_in(&self, scope: &[&str], name: &str) -> Option<(&str, usize)> {
+        let mut best: Option<(usize, &Definition)> = None;
+        let mut tied = false;
+
+        for definition in self.tests.get(name)? {
+            let Some(matched) = matching_suffix(scope, &definition.module_path) else {
+                continue;
+            };
+            match best {
+                Some((best_matched, _)) if best_matched > matched => {}
+                Some((best_matched, _)) if best_matched == matched => tied = true,
+                _ => {
+                    best = Some((matched, definition));
+                    tied = false;
+                }
+            }
+        }
+
+        best.filter(|_| !tied)
+            .map(|(_, definition)| (definition.file.as_str(), definition.line))
+    }
+
+    /// Give each test in `payload` which doesn't know where it is the
+    /// location of its definition, if it's found.
+    pub fn locate(&self, payload: &mut Payload) {
+        for test_data in payload.data_mut() {
+            if test_data.file_name().is_some() {
+                continue;
+            }
+            if let Some((file, line)) = self.find(test_data.scope(), test_data.name()) {
+                test_data.set_location(file, line);
+            }
+        }
+    }
+}
+
+/// The module path of a source file, eg `api::client` for
+/// `src/api/client.rs`.  Integration tests, benchmarks and examples are
+/// crates of their own, named after their file.
+fn file_module_path(file: &str) -> Vec<String> {
+    let components = file.split('/').collect::<Vec<&str>>();
+    let root = components
+        .iter()
+        .rposition(|component| ["src", "tests", "benches", "examples"].contains(component));
+    let modules = match root {
+        Some(root) => &components[root + 1..],
+        None => &components[components.len().saturating_sub(1)..],
+    };
+
+    modules
+        .iter()
+        .map(|module| module.trim_end_matches(".rs"))
+        .enumerate()
+        .filter(|(index, module)| {
+            // `tests/api/main.rs` is the `api` test, but `src/main.rs` is
+            // the crate root.
+            let is_last = *index == modules.len() - 1;
+            !(is_last && ["lib", "main", "mod"].contains(module) && (*index > 0 || root.is_some()))
+        })
+        .map(|(_, module)| module.to_string())
+        .collect()
+}
+
+/// Is the attribute on `line` one which marks a test, eg `#[test]`,
+/// `#[tokio::test]` or `#[rstest]`, rather than eg `#[cfg(test)]`?
+fn is_test_attribute(line: &str) -> bool {
+    let attribute = line.trim_start_matches("#[");
+    let path = attribute.split(['(', ']', ' ']).next().unwrap_or_default();
+    let name = path.rsplit("::").next().unwrap_or_default();
+
+    name.contains("test") && !["cfg", "cfg_attr"].contains(&path)
+}
+
+/// The name of the item of `kind` (eg `fn`) declared on `line`, after any
+/// qualifiers such as `pub` or `async`.
+fn item_name<'a>(line: &'a str, kind: &str) -> Option<&'a str> {
+    let mut words = line.split_whitespace();
+    words.find(|word| *word == kind)?;
+    let name = words.next()?;
+    let end = name
+        .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .unwrap_or(name.len());
+
+    Some(&name[..end]).filter(|name| !name.is_empty())
+}
+
+/// How many modules match, if the end of `scope` is the end of
+/// `module_path` (or the other way round, as the scope may be missing the
+/// test binary or include it).
+fn matching_suffix(scope: &[&str], module_path: &[String]) -> Option<usize> {
+    let matched = scope
+        .iter()
+        .rev()
+        .zip(module_path.iter().rev())
+        .take_while(|(scope, module)| **scope == module.as_str())
+        .count();
+
+    Some(matched).filter(|matched| *matched == scope.len().min(module_path.len()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::run_env::RuntimeEnvironment;
+    use std::env;
+    use uuid::Uuid;
+
+    const API: &str = r#"
+pub fn get() {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn gets() {
+        get();
+    }
+
+    mod errors {
+        #[tokio::test]
+        async fn times_out() {}
+    }
+
+    #[rstest]
+    #[case(1)]
+    fn cases(#[case] n: u8) {}
+
+    // #[test]
+    fn helper() {}
+}
+
+#[test]
+fn outside() {}
+"#;
+
+    #[test]
+    fn finds_test_functions_by_module_path() {
+        let mut locations = TestLocations::default();
+        locations.add_file("crates/client/src/api.rs", API);
+        locations.add_file("tests/api.rs", "#[test]\nfn gets() {}\n");
+
+        assert_eq!(
+            locations.find("api::test", "gets"),
+            Some(("crates/client/src/api.rs", 9))
+        );
+        assert_eq!(
+            locations.find("my-crate::api::test::errors", "times_out"),
+            Some(("crates/client/src/api.rs", 15))
+        );
+        assert_eq!(
+            locations.find("api::test::cases", "case_1"),
+            Some(("crates/client/src/api.rs", 20))
+        );
+        assert_eq!(
+            locations.find("api", "outside"),
+            Some(("crates/client/src/api.rs", 27))
+        );
+        assert_eq!(locations.find("api", "gets"), Some(("tests/api.rs", 2)));
+        assert_eq!(locations.find("api::test", "helper"), None);
+        assert_eq!(locations.find("other::test", "gets"), None);
+    }
+
+    #[test]
+    fn modules_are_named_after_their_files() {
+        assert_eq!(file_module_path("src/lib.rs"), Vec::<String>::new());
+        assert_eq!(file_module_path("src/main.rs"), Vec::<String>::new());
+        assert_eq!(file_module_path("src/api/mod.rs"), ["api"]);
+        assert_eq!(
+            file_module_path("crates/a/src/api/client.rs"),
+            ["api", "client"]
+        );
+        assert_eq!(file_module_path("tests/api.rs"), ["api"]);
+        assert_eq!(file_module_path("tests/api/main.rs"), ["api"]);
+        assert_eq!(file_module_path("build.rs"), ["build"]);
+    }
+
+    #[test]
+    fn locates_tests_in_a_payload() {
+        let root = env::temp_dir().join(format!("locate-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::create_dir_all(root.join("target/debug")).unwrap();
+        fs::write(root.join("src/api.rs"), API).unwrap();
+        fs::write(root.join("target/debug/api.rs"), API).unwrap();
+
+        let locations = TestLocations::scan(&root);
+        fs::remove_dir_all(&root).unwrap();
+
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+        for line in [
+            r#"{ "type": "test", "event": "started", "name": "api::test::gets" }"#,
+            r#"{ "type": "test", "name": "api::test::gets", "event": "ok" }"#,
+        ] {
+            crate::input::parse_line(line, &mut payload).unwrap();
+        }
+        locations.locate(&mut payload);
+
+        let test_data = payload.closed_data()[0];
+        assert_eq!(test_data.file_name(), Some("src/api.rs"));
+        assert_eq!(
+            serde_json::to_value(test_data).unwrap()["location"],
+            "src/api.rs:9"
+        );
+    }
+}
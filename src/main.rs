@@ -30,7 +30,6 @@
 //! with other tools as needed.
 
 extern crate serde;
-extern crate ureq;
 extern crate uuid;
 
 #[cfg(test)]
@@ -40,31 +39,126 @@ extern crate serial_test;
 #[cfg(test)]
 extern crate rand;
 
+mod annotate;
 mod api;
+mod brownout;
+mod codeowners;
+mod compare;
+mod compress;
+mod criterion;
+mod doctor;
+mod duration;
+mod env_file;
+mod exit;
+mod failure;
+mod first_batch;
+mod host;
+mod http;
 mod input;
+mod intern;
+mod junit;
+mod listen;
+mod locate;
+mod miri;
 mod payload;
+mod preflight;
+mod property;
+mod quarantine;
+mod redact;
+mod retry;
 mod run_env;
+mod run_info;
+mod run_state;
+mod serve;
+mod severity;
+mod spill;
+mod spool;
+mod tags;
+mod tap;
+mod test_list;
+mod text;
+mod trx;
+mod validate;
+mod wrapper;
 
-use payload::Payload;
+use annotate::AnnotateStyle;
+use api::ApiError;
+use brownout::{Action, Brownout};
+use buildkite_test_collector::marker;
+use codeowners::CodeOwners;
+use duration::DurationPolicy;
+use exit::Summary;
+use failure::Failure;
+use first_batch::FirstBatch;
+use input::{InputFormat, InputParser, ParseErrors};
+use listen::{Listener, Message};
+use locate::TestLocations;
+use miri::MiriReports;
+use payload::{NameLimits, Payload, SuiteContext, TestData};
+use quarantine::Quarantine;
 use run_env::RuntimeEnvironment;
+use run_state::{RunState, Sent};
+use severity::{Severity, SeverityRules};
+use spill::Spill;
+use std::collections::HashMap;
 use std::io::*;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tags::TagRules;
 
 static BATCH_SIZE: usize = 500;
-static ENDPOINT: &str = "https://analytics-api.buildkite.com/v1/uploads";
+/// The most JSON to send in one batch, unless a single test is bigger.
+static MAX_BATCH_BYTES: usize = 4 * 1024 * 1024;
+/// How many batches are uploaded at once, by default.
+static UPLOAD_CONCURRENCY: usize = 4;
+static DEFAULT_ENDPOINT: &str = "https://analytics-api.buildkite.com/v1/uploads";
 
 // https://doc.rust-lang.org/cargo/reference/environment-variables.html#environment-variables-cargo-sets-for-crates
 const NAME: &str = env!("CARGO_PKG_NAME");
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-/// The entrypoint for the binary.  Takes no arguments.
+/// The entrypoint for the binary.  Accepts the `compare`, `doctor`, `flush`,
+/// `list`, `run`, `serve` and `upload` subcommands and the options described in `help`.
 ///
 /// ## Emits warnings
 ///  - If the CI environment cannot be detected.
 fn main() {
     let mut args = std::env::args();
     let prog = args.next().unwrap_or(NAME.to_string());
+    let mut command = None;
+    let mut env_file = None;
+    let mut suite_name = None;
+    let mut scope_prefix = None;
+    let mut expect_tests = None;
+    let mut annotate_style = None;
+    let mut run_info_file = None;
+    let mut connect_timeout = None;
+    let mut timeout = None;
+    let mut proxy = None;
+    let mut ca_bundle = None;
+    let mut client_cert = None;
+    let mut client_key = None;
+    let mut base = None;
+    let mut listen = None;
+    let mut input_socket = None;
+    let mut input_format = None;
+    let mut tags = Vec::new();
+    let mut refresh_list = false;
+    let mut no_gzip = false;
+    let mut exit_codes = false;
+    let mut debug = false;
+    let mut paths = Vec::new();
+    let mut wrapped = Vec::new();
+
     while let Some(arg) = args.next() {
-        match arg.as_str() {
+        let (flag, value) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => (flag, Some(value.to_string())),
+            _ => (arg.as_str(), None),
+        };
+
+        match flag {
             "--version" => {
                 println!("{} {}", NAME, VERSION);
                 return;
@@ -73,46 +167,866 @@ fn main() {
                 help(prog);
                 return;
             }
-            _ => {}
+            "--env-file" => env_file = value.or_else(|| args.next()),
+            "--suite-name" => suite_name = value.or_else(|| args.next()),
+            "--scope-prefix" => scope_prefix = value.or_else(|| args.next()),
+            "--expect-tests" => expect_tests = value.or_else(|| args.next()),
+            "--annotate-style" => annotate_style = value.or_else(|| args.next()),
+            "--run-info-file" => run_info_file = value.or_else(|| args.next()),
+            "--connect-timeout" => connect_timeout = value.or_else(|| args.next()),
+            "--timeout" => timeout = value.or_else(|| args.next()),
+            "--proxy" => proxy = value.or_else(|| args.next()),
+            "--ca-bundle" => ca_bundle = value.or_else(|| args.next()),
+            "--client-cert" => client_cert = value.or_else(|| args.next()),
+            "--client-key" => client_key = value.or_else(|| args.next()),
+            "--base" => base = value.or_else(|| args.next()),
+            "--listen" => listen = value.or_else(|| args.next()),
+            "--input-socket" => input_socket = value.or_else(|| args.next()),
+            "--input-format" => input_format = value.or_else(|| args.next()),
+            "--tag" => tags.extend(value.or_else(|| args.next())),
+            "--refresh-list" => refresh_list = true,
+            "--no-gzip" => no_gzip = true,
+            "--exit-codes" => exit_codes = true,
+            "--debug" => debug = true,
+            "--" => {
+                wrapped.extend(args.by_ref());
+                break;
+            }
+            _ if arg.starts_with("--") => {}
+            _ if command.is_none() => command = Some(arg.clone()),
+            _ => paths.push(arg.clone()),
+        }
+    }
+
+    if let Some(path) = env_file {
+        env_file::load(&path);
+    }
+
+    let mut http_config = http::HttpConfig::from_env();
+    for (flag, value, timeout) in [
+        (
+            "--connect-timeout",
+            connect_timeout,
+            &mut http_config.timeouts.connect,
+        ),
+        ("--timeout", timeout, &mut http_config.timeouts.request),
+    ] {
+        let Some(value) = value else {
+            continue;
+        };
+        match value.parse::<f64>() {
+            Ok(seconds) if seconds.is_finite() && seconds >= 0.0 => {
+                *timeout = Duration::from_secs_f64(seconds)
+            }
+            _ => eprintln!("Ignoring {} {:?}: not a number of seconds.", flag, value),
         }
     }
+    if proxy.is_some() {
+        http_config.proxy = proxy;
+    }
+    for (path, setting) in [
+        (ca_bundle, &mut http_config.ca_bundle),
+        (client_cert, &mut http_config.client_cert),
+        (client_key, &mut http_config.client_key),
+    ] {
+        if let Some(path) = path {
+            *setting = Some(path.into());
+        }
+    }
+    if no_gzip {
+        http_config.gzip = false;
+    }
+    http::configure(http_config);
+    let run = Arc::new(RunState::default().with_run_env_references(
+        payload::parse_var("BUILDKITE_ANALYTICS_RUN_ENV_REFERENCES").unwrap_or(false),
+    ));
+
+    let annotate_style = annotate_style.and_then(|style| AnnotateStyle::parse(&style));
+    let default_tags = tags::default_tags(&tags);
+    let expected_tests = expect_tests.and_then(|count| match count.parse::<usize>() {
+        Ok(count) => Some(count),
+        Err(_) => {
+            eprintln!(
+                "Ignoring --expect-tests {:?}: not a number of tests.",
+                count
+            );
+            None
+        }
+    });
+    let input_format = match input_format {
+        Some(format) => InputFormat::parse(&format).unwrap_or_else(|| {
+            eprintln!("Unknown input format {:?}, detecting it instead.", format);
+            InputFormat::Auto
+        }),
+        None => InputFormat::Auto,
+    };
+
+    // Each belongs to one mode, so say so rather than quietly ignore it.
+    let serving = command.as_deref() == Some("serve");
+    if listen.is_some() && !serving {
+        eprintln!("Ignoring --listen: it's the TCP address `serve` listens on.  Pass --input-socket to read test output from a named pipe or Unix socket.");
+    }
+    if input_socket.is_some() && serving {
+        eprintln!("Ignoring --input-socket: `serve` reads test output from stdin and --listen.");
+    }
+
+    match command.as_deref() {
+        Some("doctor") => return doctor::run(&endpoint()),
+        Some("flush") => return spool::flush(&endpoint()),
+        Some("list") => {
+            let Some(names) = test_list::list(&wrapped, refresh_list) else {
+                std::process::exit(1);
+            };
+            for name in names {
+                println!("{}", name);
+            }
+            return;
+        }
+        Some("compare") => {
+            let base = base.unwrap_or("main".to_string());
+            let Some(run_env) = RuntimeEnvironment::detect() else {
+                eprintln!("Unable to detect CI environment.  Nothing will be compared.");
+                return;
+            };
+
+            let mut payload = new_payload(
+                run_env,
+                suite_name,
+                scope_prefix,
+                expected_tests,
+                default_tags,
+            );
+            let mut parse_errors = ParseErrors::with_debug(debug);
+            input::for_each_line(stdin().lock(), |line| {
+                parse_errors.parse_line(line, &mut payload)
+            });
+            parse_errors.report();
+
+            let Some((executions, base_url)) = compare::fetch_base(&base) else {
+                return;
+            };
+
+            let comparison = compare::compare(&executions, base_url, &payload);
+            print!("{}", comparison.to_markdown(&base));
+
+            if comparison.has_new_failures() {
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some("upload") => {
+            let Some(run_env) = RuntimeEnvironment::detect() else {
+                eprintln!("Unable to detect CI environment.  No analytics will be sent.");
+                if exit_codes {
+                    Summary::default().exit();
+                }
+                return;
+            };
+
+            let mut payload = new_payload(
+                run_env,
+                suite_name,
+                scope_prefix,
+                expected_tests,
+                default_tags,
+            );
+            if paths.is_empty() {
+                paths.push("-".to_string());
+            }
+            for path in paths {
+                if Path::new(&path).is_dir() {
+                    if let Err(err) = criterion::read_dir(Path::new(&path), &mut payload) {
+                        eprintln!("Unable to read Criterion results from {}: {}", path, err);
+                    }
+                } else {
+                    read_report(&path, &mut payload);
+                }
+            }
+
+            let summary = finish(payload, annotate_style, &run);
+            run.finish(run_info_file.as_deref(), summary.completeness);
+            if exit_codes {
+                summary.exit();
+            }
+            return;
+        }
+        Some("serve") => {
+            let Some(run_env) = RuntimeEnvironment::detect() else {
+                eprintln!("Unable to detect CI environment.  Nothing will be served.");
+                if exit_codes {
+                    Summary::default().exit();
+                }
+                return;
+            };
+
+            let state = serve::State::new(
+                new_payload(
+                    run_env,
+                    suite_name,
+                    scope_prefix,
+                    expected_tests,
+                    default_tags,
+                ),
+                ParseErrors::with_debug(debug),
+            );
+            let flush_run = run.clone();
+            let flush: serve::Flush = Arc::new(move |payload| finish(payload, None, &flush_run));
+            let listen = listen.unwrap_or(serve::DEFAULT_ADDRESS.to_string());
+
+            let state = match serve::serve(&listen, state, flush) {
+                Ok(state) => state,
+                Err(err) => {
+                    eprintln!("Unable to listen on {}: {}", listen, err);
+                    std::process::exit(1);
+                }
+            };
+
+            state.parse_errors.report();
+            let mut summary = finish(state.payload, annotate_style, &run);
+            summary.merge(&state.flushed);
+            run.finish(run_info_file.as_deref(), summary.completeness);
+            summary.parse_errors = state.parse_errors.count;
+            if exit_codes {
+                summary.exit();
+            }
+            return;
+        }
+        Some("run") => {
+            let mut payload = RuntimeEnvironment::detect().map(|run_env| {
+                new_payload(
+                    run_env,
+                    suite_name,
+                    scope_prefix,
+                    expected_tests,
+                    default_tags,
+                )
+            });
+            if payload.is_none() {
+                eprintln!("Unable to detect CI environment.  No analytics will be sent.");
+            }
+
+            let mut input = InputParser::new(input_format, debug);
+            let mut first_batch = first_batch();
+            let mut spill = Spill::from_env();
+            // Miri reports its errors on stderr, and cargo which test binary
+            // it's running.
+            let miri = Arc::new(Mutex::new(MiriReports::default()));
+            let stderr_miri = miri.clone();
+            let binary = Arc::new(Mutex::new(None::<String>));
+            let stderr_binary = binary.clone();
+            let result = wrapper::run(
+                &wrapped,
+                |line| {
+                    if let Some(payload) = payload.as_mut() {
+                        if let Some(binary) =
+                            binary.lock().ok().and_then(|mut binary| binary.take())
+                        {
+                            payload.set_binary(&binary);
+                        }
+                        input.parse_line(line, payload);
+                        poll_first_batch(&mut first_batch, payload, &run);
+                        poll_spill(&mut spill, payload);
+                    }
+                    println!("{}", line);
+                },
+                move |line| {
+                    if let Ok(mut miri) = stderr_miri.lock() {
+                        miri.parse_line(line);
+                    }
+                    if let Some(name) = input::cargo_test_binary(line) {
+                        if let Ok(mut binary) = stderr_binary.lock() {
+                            *binary = Some(name.to_string());
+                        }
+                    }
+                },
+            );
+
+            let (status, crash) = match result {
+                Ok(result) => result,
+                Err(err) => {
+                    eprintln!("Unable to run {:?}: {}", wrapped, err);
+                    std::process::exit(1);
+                }
+            };
+
+            let mut summary = Summary::default();
+            let crashed = crash.is_some();
+            if let Some(mut payload) = payload {
+                input.finish(&mut payload);
+                input.parse_errors.report();
+                if let Ok(mut miri) = miri.lock() {
+                    miri.finish(&mut payload);
+                }
+                if let Some(crash) = crash {
+                    eprintln!("The test binary was killed by {}.", crash.signal);
+                    wrapper::record(&crash, &mut payload);
+                }
+                let early = first_batch.map(FirstBatch::join).unwrap_or_default();
+                let spilled = finish_spill(spill, &mut payload, &run);
+                summary = finish(payload, annotate_style, &run);
+                summary.merge(&early);
+                summary.merge(&spilled);
+                run.finish(run_info_file.as_deref(), summary.completeness);
+            }
+
+            if exit_codes {
+                summary.parse_errors = input.parse_errors.count;
+                summary.exit();
+            }
+            if !status.success()
+                && !crashed
+                && summary.failed == 0
+                && summary.informational_failed + summary.quarantined_failed > 0
+            {
+                eprintln!(
+                    "Only informational or quarantined tests failed, so exiting successfully."
+                );
+                std::process::exit(0);
+            }
+            std::process::exit(status.code().unwrap_or(1));
+        }
+        _ => {}
+    }
 
     let stdin = std::io::stdin();
-    let stdin = stdin.lock();
+    let mut stdin = stdin.lock();
 
-    if let Some(run_env) = RuntimeEnvironment::detect() {
-        let mut payload = Payload::new(run_env);
+    let summary = if let Some(run_env) = RuntimeEnvironment::detect() {
+        let mut payload = new_payload(
+            run_env,
+            suite_name,
+            scope_prefix,
+            expected_tests,
+            default_tags,
+        );
+        let mut input = InputParser::new(input_format, debug);
+        let mut first_batch = first_batch();
+        let mut spill = Spill::from_env();
 
-        for line in stdin.lines().flatten() {
-            input::parse_line(&line, &mut payload);
+        let mut on_line = |input: &mut InputParser, payload: &mut Payload, line: &str| {
+            input.parse_line(line, payload);
+            poll_first_batch(&mut first_batch, payload, &run);
+            poll_spill(&mut spill, payload);
             println!("{}", line);
+        };
+        match input_socket.as_deref() {
+            Some(path) => match listen::listen(Path::new(path)) {
+                Ok(listener) => {
+                    eprintln!("Collecting test output written to {}", path);
+                    let parse_errors =
+                        parse_writers(&listener, input_format, debug, &mut payload, on_line);
+                    input.parse_errors.merge(parse_errors);
+                }
+                Err(err) => {
+                    eprintln!("Unable to listen on {}: {}", path, err);
+                    std::process::exit(1);
+                }
+            },
+            None => {
+                input::for_each_line(&mut stdin, |line| on_line(&mut input, &mut payload, line));
+                input.finish(&mut payload);
+            }
         }
+        input.parse_errors.report();
 
-        for payload in payload.batchify(BATCH_SIZE) {
-            api::submit(payload, ENDPOINT);
-        }
+        let early = first_batch.map(FirstBatch::join).unwrap_or_default();
+        let spilled = finish_spill(spill, &mut payload, &run);
+        let mut summary = finish(payload, annotate_style, &run);
+        summary.merge(&early);
+        summary.merge(&spilled);
+        run.finish(run_info_file.as_deref(), summary.completeness);
+        summary.parse_errors = input.parse_errors.count;
+        summary
     } else {
         eprintln!("Unable to detect CI environment.  No analytics will be sent.");
-        for line in stdin.lines().flatten() {
-            println!("{}", line)
+        // Nothing to parse, so pass the bytes straight through.
+        if let Err(err) = std::io::copy(&mut stdin, &mut stdout().lock()) {
+            eprintln!("Unable to echo stdin: {}", err);
+        }
+        Summary::default()
+    };
+
+    if exit_codes {
+        summary.exit();
+    }
+}
+
+fn new_payload(
+    run_env: RuntimeEnvironment,
+    suite_name: Option<String>,
+    scope_prefix: Option<String>,
+    expected_tests: Option<usize>,
+    default_tags: Vec<(String, String)>,
+) -> Payload {
+    let mut payload = Payload::new(run_env)
+        .with_name_limits(NameLimits::from_env())
+        .with_suite_name(suite_name)
+        .with_scope_prefix(scope_prefix)
+        .with_expected_tests(expected_tests)
+        .with_duration_policy(DurationPolicy::from_env())
+        .with_extra_fields(payload::parse_var("BUILDKITE_ANALYTICS_EXTRA_FIELDS").unwrap_or(false))
+        .with_case_tags(payload::parse_var("BUILDKITE_ANALYTICS_CASE_TAGS").unwrap_or(false))
+        .with_random_ids(payload::parse_var("BUILDKITE_ANALYTICS_RANDOM_IDS").unwrap_or(false))
+        .with_max_failure_bytes(
+            payload::parse_var("BUILDKITE_ANALYTICS_MAX_FAILURE_BYTES")
+                .unwrap_or(failure::MAX_BYTES),
+        )
+        .with_default_tags(default_tags)
+        .with_tag_rules(TagRules::discover().unwrap_or_default());
+
+    for (key, value) in host::tags() {
+        payload.insert_tag(key, &value);
+    }
+    payload
+}
+
+/// Parse the output of each of `listener`'s writers with a parser and suite
+/// of its own, as several test invocations may write at once, and test events
+/// don't say which they came from.  Each line is passed to `on_line` with its
+/// writer's parser, and its suite switched to in `payload`.
+///
+/// Returns the lines which the writers' parsers couldn't parse.
+fn parse_writers(
+    listener: &Listener,
+    format: InputFormat,
+    debug: bool,
+    payload: &mut Payload,
+    mut on_line: impl FnMut(&mut InputParser, &mut Payload, &str),
+) -> ParseErrors {
+    let mut writers = HashMap::new();
+    let mut parse_errors = ParseErrors::with_debug(debug);
+    let mut finish = |(mut input, mut context): (InputParser, SuiteContext),
+                      payload: &mut Payload| {
+        payload.swap_context(&mut context);
+        input.finish(payload);
+        payload.swap_context(&mut context);
+        parse_errors.merge(input.parse_errors);
+    };
+
+    for message in listener.messages() {
+        match message {
+            Message::Line(writer, line) => {
+                let (input, context) = writers
+                    .entry(writer)
+                    .or_insert_with(|| (InputParser::new(format, debug), SuiteContext::default()));
+                payload.swap_context(context);
+                on_line(input, payload, &line);
+                payload.swap_context(context);
+            }
+            Message::Closed(writer) => {
+                if let Some(writer) = writers.remove(&writer) {
+                    finish(writer, payload);
+                }
+            }
+        }
+    }
+    // Those still connected when another said to finish.
+    for (_, writer) in writers {
+        finish(writer, payload);
+    }
+
+    parse_errors
+}
+
+/// Read the JUnit XML or TRX report at `path` (or `stdin` for `-`) into
+/// `payload`.
+///
+/// ## Emits warnings
+///  - If the report cannot be read or parsed.
+fn read_report(path: &str, payload: &mut Payload) {
+    let xml = if path == "-" {
+        let mut xml = String::new();
+        std::io::stdin().read_to_string(&mut xml).map(|_| xml)
+    } else {
+        std::fs::read_to_string(path)
+    };
+
+    match xml.map(|xml| (trx::is_trx(&xml), xml)) {
+        Ok((true, xml)) => {
+            if let Err(err) = trx::parse(&xml, payload) {
+                eprintln!("Unable to parse TRX from {}: {}", path, err);
+            }
+        }
+        Ok((false, xml)) => {
+            if let Err(err) = junit::parse(&xml, payload) {
+                eprintln!("Unable to parse JUnit XML from {}: {}", path, err);
+            }
+        }
+        Err(err) => eprintln!("Unable to read {}: {}", path, err),
+    }
+}
+
+/// Where to upload results: `BUILDKITE_ANALYTICS_ENDPOINT` if set, eg an
+/// on-network relay or a mock API, or Test Engine's upload API.
+fn endpoint() -> String {
+    std::env::var("BUILDKITE_ANALYTICS_ENDPOINT")
+        .ok()
+        .filter(|endpoint| !endpoint.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_ENDPOINT.to_string())
+}
+
+/// The early first batch (and streamed batches after it), if enabled and
+/// there's somewhere to send them.
+fn first_batch() -> Option<FirstBatch> {
+    FirstBatch::from_env(BATCH_SIZE).filter(|_| api::is_configured())
+}
+
+/// Send the next early batch from `payload` if it's time, without annotating
+/// the build with its partial results.
+fn poll_first_batch(
+    first_batch: &mut Option<FirstBatch>,
+    payload: &mut Payload,
+    run: &Arc<RunState>,
+) {
+    if let Some(first_batch) = first_batch.as_mut().filter(|batch| batch.is_due(payload)) {
+        let run = run.clone();
+        first_batch.poll(payload, move |batch| finish(batch, None, &run));
+    }
+}
+
+/// Move the tests which have finished in `payload` to the spill file, if
+/// spilling.
+fn poll_spill(spill: &mut Option<Spill>, payload: &mut Payload) {
+    if let Some(spill) = spill.as_mut() {
+        spill.poll(payload);
+    }
+}
+
+/// Upload the spilled tests, a batch at a time, without annotating the build
+/// with them.  The tests still running are left in `payload`.
+fn finish_spill(spill: Option<Spill>, payload: &mut Payload, run: &RunState) -> Summary {
+    spill
+        .map(|spill| spill.finish(payload, BATCH_SIZE, |batch| finish(batch, None, run)))
+        .unwrap_or_default()
+}
+
+/// Annotate the build and upload the collected results, recording the
+/// uploads in `run`.
+///
+/// Returns a summary of the run, for `--exit-codes`.
+fn finish(mut payload: Payload, annotate_style: Option<AnnotateStyle>, run: &RunState) -> Summary {
+    // Measured before the stragglers below are failed, so they still count
+    // as missing.
+    let completeness = payload.completeness();
+    let incomplete = payload.fail_unfinished(|_| {
+        Failure::with_reason(
+            "The test output ended before this test finished, eg because the test binary aborted"
+                .to_string(),
+        )
+    });
+    if incomplete > 0 {
+        eprintln!(
+            "{} tests were still running when the test output ended, so they're recorded as failed.",
+            incomplete
+        );
+    }
+
+    // Before tagging owners, who are found by the test's file.
+    if let Some(locations) = TestLocations::discover() {
+        locations.locate(&mut payload);
+    }
+    if let Some(owners) = CodeOwners::discover() {
+        owners.tag(&mut payload);
+    }
+    if let Some(rules) = SeverityRules::discover() {
+        rules.tag(&mut payload);
+    }
+    if let Some(quarantine) = Quarantine::discover() {
+        quarantine.tag(&mut payload);
+    }
+
+    // Before annotating, which publishes failures too.
+    if let Some(redactor) = redact::Redactor::from_env() {
+        let redacted = redactor.redact_payload(&mut payload);
+        if redacted > 0 {
+            eprintln!("Redacted secrets from the failures of {} tests.", redacted);
         }
     }
+
+    run.record_results(&payload);
+    if let Some(style) = annotate_style {
+        run.annotate(&payload, &style);
+    }
+
+    let closed_data = payload.closed_data();
+    let informational = Some(Severity::Informational.as_str());
+    let (informational, failed): (Vec<&TestData>, Vec<_>) = closed_data
+        .iter()
+        .copied()
+        .filter(|test_data| test_data.is_failed())
+        .partition(|test_data| test_data.tag("severity") == informational);
+    // Blocker failures always fail the run, quarantined or not.
+    let ignore_quarantined =
+        payload::parse_var("BUILDKITE_ANALYTICS_QUARANTINE_IGNORE_FAILURES").unwrap_or(false);
+    let blocker = Some(Severity::Blocker.as_str());
+    let (quarantined, failed): (Vec<&TestData>, Vec<_>) =
+        failed.into_iter().partition(|test_data| {
+            ignore_quarantined
+                && test_data.tag("quarantined").is_some()
+                && test_data.tag("severity") != blocker
+        });
+    let mut summary = Summary {
+        configured: api::is_configured(),
+        tests: closed_data.len(),
+        failed: failed.len(),
+        informational_failed: informational.len(),
+        quarantined_failed: quarantined.len(),
+        completeness,
+        ..Summary::default()
+    };
+
+    // Either is likely a misconfiguration, eg the wrong output format or
+    // only part of the output being piped in.
+    match summary.completeness {
+        Some(completeness) if !completeness.is_complete() => eprintln!(
+            "Only {} of the {} expected tests finished ({:.1}%), so some results are missing.  Check that a test binary didn't crash, and that all of its JSON output is piped to the collector.",
+            completeness.finished,
+            completeness.expected,
+            completeness.ratio() * 100.0
+        ),
+        Some(completeness) if completeness.is_exceeded() => eprintln!(
+            "{} tests finished, but only {} were expected.  Check that the output of only one run is piped to the collector.",
+            completeness.finished, completeness.expected
+        ),
+        _ => {}
+    }
+
+    if summary.configured {
+        let max_batch_bytes =
+            payload::parse_var("BUILDKITE_ANALYTICS_MAX_BATCH_BYTES").unwrap_or(MAX_BATCH_BYTES);
+        validate::check(&mut payload);
+        let batches = payload.batchify(BATCH_SIZE, max_batch_bytes);
+        preflight::check(&batches, &preflight::Limits::from_env());
+        summary.batches = batches.len();
+        upload(batches, run, &mut summary);
+    }
+
+    summary
+}
+
+/// Upload the batches, several at once, backing off as the API degrades and
+/// spooling the remaining batches once it's clearly having a bad day.
+///
+/// Records each accepted batch in `run`.
+///
+/// Records the number of batches uploaded and spooled in `summary`.  Batches
+/// which couldn't be delivered, even after retrying, are spooled to be sent
+/// later with `flush`, except those the API rejected (it would only reject
+/// them again).  If it rejected the token, the rest of the batches are spooled
+/// without trying them, and `summary` is marked as misconfigured.
+///
+/// ## Emits warnings
+///  - If a batch cannot be uploaded, with why.
+///  - If any batches were spooled, with how to send them.
+///  - If the run information cannot be written.
+fn upload(batches: Vec<Payload>, run: &RunState, summary: &mut Summary) {
+    let endpoint = endpoint();
+    let spool_dir = spool::dir();
+    let compression = compress::Compression::from_env();
+    let concurrency =
+        payload::parse_var("BUILDKITE_ANALYTICS_UPLOAD_CONCURRENCY").unwrap_or(UPLOAD_CONCURRENCY);
+    let brownout = Mutex::new(Brownout::default());
+    let total = batches.len();
+    let uploaded = AtomicUsize::new(0);
+    let spooled = AtomicUsize::new(0);
+    let unauthorized = AtomicBool::new(false);
+
+    let send = run.send_batches(batches, concurrency, async |payload: &Payload| {
+        let idempotency_key = payload.idempotency_key();
+        let action = brownout.lock().unwrap().next_action();
+        match action {
+            Action::Upload(backoff) if !unauthorized.load(Ordering::Relaxed) => {
+                tokio::time::sleep(backoff).await;
+
+                let started = Instant::now();
+                let result = api::submit_async(payload, &endpoint, &idempotency_key).await;
+                brownout.lock().unwrap().record(
+                    !result.as_ref().is_err_and(ApiError::is_transient),
+                    started.elapsed(),
+                );
+
+                match result {
+                    Ok(response) => {
+                        run.record(&response);
+                        uploaded.fetch_add(1, Ordering::Relaxed);
+                        return Sent::Accepted;
+                    }
+                    Err(err) if err.is_configuration() => {
+                        // Only say so once, though batches in flight may fail too.
+                        if !unauthorized.swap(true, Ordering::Relaxed) {
+                            eprintln!("Unable to upload: {}", err);
+                        }
+                    }
+                    Err(err) if !err.is_transient() => {
+                        eprintln!("Unable to upload a batch: {}", err);
+                        return Sent::Rejected;
+                    }
+                    Err(err) => eprintln!("Unable to upload a batch: {}", err),
+                }
+            }
+            _ => {}
+        }
+
+        match spool::write(&spool_dir, payload, &idempotency_key, compression) {
+            Ok(_) => {
+                spooled.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(err) => eprintln!("Unable to spool payload: {}", err),
+        }
+        Sent::Failed
+    });
+    http::block_on(send);
+
+    let spooled = spooled.into_inner();
+    let unauthorized = unauthorized.into_inner();
+    summary.uploaded = uploaded.into_inner();
+    summary.spooled = spooled;
+    if unauthorized {
+        summary.configured = false;
+    }
+
+    if spooled > 0 {
+        let reason = if unauthorized {
+            "The API rejected the token"
+        } else {
+            "The analytics API is unavailable or degraded"
+        };
+        eprintln!(
+            "{}, so {} of {} batches were spooled to {}.  To send them later (with a working token), run:\n\n  BUILDKITE_ANALYTICS_SPOOL_DIR={} {} flush\n",
+            reason,
+            spooled,
+            total,
+            spool_dir.display(),
+            spool_dir.display(),
+            NAME
+        );
+    }
 }
 
 fn help(prog: String) {
     println!("\n{} {}", NAME, VERSION);
     print!(
         "
-Expects BUILDKITE_ANALYTICS_TOKEN in environment, and test result JSON on stdin.
+Expects BUILDKITE_ANALYTICS_TOKEN in environment (or the name of a Buildkite
+secret containing it in BUILDKITE_ANALYTICS_TOKEN_SECRET, or the URL of a token
+service in BUILDKITE_ANALYTICS_TOKEN_SERVICE_URL), and test result JSON on
+stdin.
 Test results may be piped like:
 
   cargo test -- -Z unstable-options --format json --report-time | {}
 
+Options:
+
+  --env-file <path>     Load BUILDKITE_ANALYTICS_TOKEN and friends from a
+                        dotenv file.
+  --suite-name <name>   Tag every test with `suite.name`, to tell apart
+                        results from several invocations in one build.
+  --scope-prefix <prefix>
+                        Prefix every test's scope, eg with the crate name,
+                        to namespace results from several jobs in one suite.
+  --expect-tests <count>
+                        The number of tests the run should have, in place of
+                        the suites' own counts, to warn if results are
+                        missing.
+  --annotate-style <style>
+                        Annotate the Buildkite build with failed tests.
+                        Either `compact`, `detailed` or the path to a
+                        minijinja template.
+  --input-format <format>
+                        The format of the test output on stdin, either
+                        `libtest`, `tap`, `criterion` or `auto` (the
+                        default, which detects it from the first lines).
+  --tag <key=value>     Tag every test, unless a rule in
+                        .buildkite/test-tags (or
+                        BUILDKITE_ANALYTICS_TAGS_FILE) or the test itself
+                        says otherwise.  May be repeated, and adds to the
+                        comma separated pairs in BUILDKITE_ANALYTICS_TAGS.
+  --run-info-file <path>
+                        Write the run_id, run URL and the ids of every
+                        upload as JSON, once the last upload is done.
+  --connect-timeout <seconds>
+                        How long to wait to connect to the API (default
+                        BUILDKITE_ANALYTICS_CONNECT_TIMEOUT_SECONDS, or 10).
+  --timeout <seconds>   How long each API request may take in all (default
+                        BUILDKITE_ANALYTICS_TIMEOUT_SECONDS, or 60).
+  --proxy <url>         Send API requests through this proxy, in place of
+                        HTTPS_PROXY or HTTP_PROXY.
+  --ca-bundle <path>    A PEM file of root certificates to trust as well as
+                        the usual ones, in place of
+                        BUILDKITE_ANALYTICS_CA_BUNDLE.
+  --client-cert <path>  A PEM file of the client certificate to present to
+                        gateways which require one, in place of
+                        BUILDKITE_ANALYTICS_CLIENT_CERT.
+  --client-key <path>   A PEM file of its private key, if it isn't in the
+                        certificate's file, in place of
+                        BUILDKITE_ANALYTICS_CLIENT_KEY.
+  --no-gzip             Send uploads uncompressed, eg for a relay which
+                        can't decompress them (or set
+                        BUILDKITE_ANALYTICS_GZIP=false).
+  --listen <address>    The TCP address `serve` listens on for JSON-RPC
+                        requests (default a free port on 127.0.0.1).
+  --input-socket <path> The path of a named pipe or Unix socket to read test
+                        output from instead of stdin.  Each connection to the
+                        socket is parsed separately; write to a named pipe
+                        from one invocation at a time.
+  --debug               Print each JSON line which isn't a recognised event.
+  --exit-codes          Print a summary and exit with 1 if tests failed, 2
+                        on configuration errors, 3 if batches couldn't be
+                        sent, 4 if more than
+                        BUILDKITE_ANALYTICS_MAX_PARSE_ERRORS lines (default
+                        0) failed to parse, or 5 if expected tests are
+                        missing and BUILDKITE_ANALYTICS_STRICT_COMPLETENESS
+                        is true.
+
+To run the tests yourself, so that crashes (eg SIGSEGV or SIGABRT) are
+reported along with the tests which completed, pass the command after `--`:
+
+  {} run -- cargo test -- -Z unstable-options --format json --report-time
+
+To diagnose configuration problems, run (optionally piping test output in):
+
+  {} doctor
+
+To upload JUnit XML or Visual Studio TRX reports from other tools, or
+Criterion benchmark results from its output directory (eg target/criterion),
+pass them to the `upload` subcommand (or pipe a report in):
+
+  {} upload <path>...
+
+To compare the results with the latest run on a base branch, and print a
+markdown report of new failures, fixed tests and duration regressions:
+
+  cargo test -- -Z unstable-options --format json --report-time | {} compare --base main
+
+To let tools (eg IDEs) follow the run, answer JSON-RPC requests for its status
+and failures on a TCP socket (which also accepts libtest JSON) while reading
+stdin:
+
+  cargo test -- -Z unstable-options --format json | {} serve --listen 127.0.0.1:7357
+
+To run one collector for several test invocations (eg as a sidecar), read
+their output from a named pipe or Unix socket, until a writer sends the line
+{{\"type\":\"collector\",\"event\":\"finish\"}}:
+
+  {} --input-socket /tmp/buildkite-test-collector.sock
+
+To list the tests cargo would run (passing any cargo options after `--`),
+cached by a fingerprint of the build until it changes or --refresh-list is
+given:
+
+  {} list -- --workspace
+
+Batches which can't be delivered (eg the API is down or degraded, or rejects
+the token) are spooled to BUILDKITE_ANALYTICS_SPOOL_DIR (or a temporary
+directory) and can be sent later with:
+
+  {} flush
+
 For more help, see:
   - https://buildkite.com/docs/test-analytics/rust-collectors
   - https://github.com/buildkite/test-collector-rust
 
 ",
-        prog
+        prog, prog, prog, prog, prog, prog, prog, prog, prog
     );
 }
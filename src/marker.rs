@@ -0,0 +1,190 @@
+//! # marker
+//!
+//! Tests can enrich their results, with no dependencies, by printing marker
+//! lines which the collector finds in their captured output, eg:
+//!
+//! ```text
+//! buildkite-test-collector: tag team=search priority=p1
+//! buildkite-test-collector: span start name=db_setup at=0.002
+//! buildkite-test-collector: span end at=0.150
+//! ```
+//!
+//! Captured output has no timestamps, so spans give their own with `at`, in
+//! seconds since the test started.
+
+use std::collections::HashMap;
+
+/// The prefix of every marker line.
+pub const PREFIX: &str = "buildkite-test-collector:";
+
+/// # Span
+///
+/// A section of a test's run, from a pair of `span start` and `span end`
+/// markers.
+#[derive(Debug, PartialEq, Default)]
+pub struct Span {
+    /// The Test Engine section, from `section` (default `annotation`).
+    pub section: String,
+    /// Every other `key=value` pair of the `span start` marker.
+    pub detail: HashMap<String, String>,
+    /// Seconds since the test started.
+    pub start: Option<f64>,
+    pub end: Option<f64>,
+    pub children: Vec<Span>,
+}
+
+/// Each marker in `output`, as its directive (eg `tag` or `span start`) and
+/// `key=value` pairs.
+///
+/// Pairs without an `=` or with an empty key are ignored.
+fn markers(output: &str) -> impl Iterator<Item = (Vec<&str>, Vec<(&str, &str)>)> {
+    output
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix(PREFIX))
+        .map(|marker| {
+            let (directive, pairs): (Vec<&str>, Vec<&str>) = marker
+                .split_whitespace()
+                .partition(|word| !word.contains('='));
+            let pairs = pairs
+                .into_iter()
+                .filter_map(|pair| pair.split_once('='))
+                .filter(|(key, _)| !key.is_empty())
+                .collect();
+            (directive, pairs)
+        })
+}
+
+/// The `key=value` pairs of every `tag` marker in `output`, in order.
+pub fn tags(output: &str) -> Vec<(&str, &str)> {
+    markers(output)
+        .filter(|(directive, _)| directive[..] == ["tag"])
+        .flat_map(|(_, pairs)| pairs)
+        .collect()
+}
+
+/// The spans marked in `output`, nested as they were started and ended.
+///
+/// Spans which are never ended are kept, without an end.
+pub fn spans(output: &str) -> Vec<Span> {
+    let mut open: Vec<Span> = Vec::new();
+    let mut spans = Vec::new();
+
+    for (directive, pairs) in markers(output) {
+        let at = pairs
+            .iter()
+            .find(|(key, _)| *key == "at")
+            .and_then(|(_, at)| at.parse::<f64>().ok());
+
+        match directive[..] {
+            ["span", "start"] => {
+                let mut span = Span {
+                    section: "annotation".to_string(),
+                    start: at,
+                    ..Span::default()
+                };
+                for (key, value) in pairs {
+                    match key {
+                        "at" => {}
+                        "section" => span.section = value.to_string(),
+                        _ => {
+                            span.detail.insert(key.to_string(), value.to_string());
+                        }
+                    }
+                }
+                open.push(span);
+            }
+            ["span", "end"] => {
+                if let Some(mut span) = open.pop() {
+                    span.end = at;
+                    close(span, &mut open, &mut spans);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    while let Some(span) = open.pop() {
+        close(span, &mut open, &mut spans);
+    }
+
+    spans
+}
+
+/// Attach `span` to its parent, if any.
+fn close(span: Span, open: &mut [Span], spans: &mut Vec<Span>) {
+    match open.last_mut() {
+        Some(parent) => parent.children.push(span),
+        None => spans.push(span),
+    }
+}
+
+/// `output` without its marker lines, eg so they aren't mistaken for a
+/// failure reason.
+pub fn strip(output: &str) -> String {
+    output
+        .lines()
+        .filter(|line| !line.trim().starts_with(PREFIX))
+        .map(|line| format!("{}\n", line))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn finds_tag_markers() {
+        let output = "some println output
+buildkite-test-collector: tag team=search priority=p1
+  buildkite-test-collector: tag flaky_because=network
+buildkite-test-collector: tagged nothing=here
+buildkite-test-collector: tag =empty novalue
+";
+
+        assert_eq!(
+            tags(output),
+            vec![
+                ("team", "search"),
+                ("priority", "p1"),
+                ("flaky_because", "network"),
+            ]
+        );
+        assert_eq!(strip(output), "some println output\n");
+    }
+
+    #[test]
+    fn nests_spans() {
+        let output = "buildkite-test-collector: span start name=setup at=0.5
+buildkite-test-collector: span start section=sql query=insert at=0.75
+buildkite-test-collector: span end at=1.0
+buildkite-test-collector: span end at=2
+buildkite-test-collector: span start name=unfinished at=3
+";
+
+        assert_eq!(
+            spans(output),
+            vec![
+                Span {
+                    section: "annotation".to_string(),
+                    detail: HashMap::from([("name".to_string(), "setup".to_string())]),
+                    start: Some(0.5),
+                    end: Some(2.0),
+                    children: vec![Span {
+                        section: "sql".to_string(),
+                        detail: HashMap::from([("query".to_string(), "insert".to_string())]),
+                        start: Some(0.75),
+                        end: Some(1.0),
+                        children: Vec::new(),
+                    }],
+                },
+                Span {
+                    section: "annotation".to_string(),
+                    detail: HashMap::from([("name".to_string(), "unfinished".to_string())]),
+                    start: Some(3.0),
+                    end: None,
+                    children: Vec::new(),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,193 @@
+//! # miri
+//!
+//! Collects the errors [Miri] reports (eg Undefined Behavior or leaked
+//! memory) from the output of `cargo miri test`.  Miri stops the whole test
+//! binary when it finds one, so the report is attached to the tests which
+//! were still running, or to a test of its own if they'd all finished (as
+//! for leaks, which are only checked at exit).
+//!
+//! Miri writes its errors to `stderr`, so pipe that in too (`2>&1`), or use
+//! the `run` subcommand.
+//!
+//! [Miri]: https://github.com/rust-lang/miri
+
+use crate::failure::{self, Failure};
+use crate::input::{is_json_object, Event, TestEvent};
+use crate::payload::Payload;
+
+/// The errors which start a Miri report, after `error: `.
+const ERRORS: [&str; 7] = [
+    "Undefined Behavior",
+    "memory leaked",
+    "unsupported operation",
+    "resource exhaustion",
+    "deadlock",
+    "abnormal termination",
+    "the main thread terminated without waiting for all remaining threads",
+];
+
+/// The name of the test Miri's reports are given when no test was running.
+const NAME: &str = "miri::diagnostics";
+
+/// # MiriReports
+///
+/// The reports found so far, and the one being read.
+#[derive(Debug, Default)]
+pub struct MiriReports {
+    reports: Vec<Vec<String>>,
+    reading: bool,
+}
+
+impl MiriReports {
+    /// Parse a single line of output, which may be part of a report.
+    pub fn parse_line(&mut self, line: &str) {
+        if let Some(error) = line.strip_prefix("error: ") {
+            self.reading = ERRORS.iter().any(|prefix| error.starts_with(prefix));
+            if self.reading {
+                self.reports.push(vec![line.to_string()]);
+            }
+            return;
+        }
+
+        // Reports are followed by the test harness carrying on, or exiting.
+        if is_json_object(line) || line.starts_with("test ") || line.starts_with("running ") {
+            self.reading = false;
+        }
+
+        if let Some(report) = self.reports.last_mut().filter(|_| self.reading) {
+            report.push(line.to_string());
+        }
+    }
+
+    /// Attach the reports to the tests which were still running, or to a
+    /// test of their own if there were none.
+    ///
+    /// Returns the number of reports found.
+    pub fn finish(&mut self, payload: &mut Payload) -> usize {
+        let count = self.reports.len();
+        if count == 0 {
+            return 0;
+        }
+
+        let output = self
+            .reports
+            .drain(..)
+            .map(|report| report.join("\n").trim_end().to_string())
+            .collect::<Vec<String>>()
+            .join("\n\n");
+        let failure = failure::parse(&output);
+
+        let failed = payload.fail_unfinished(|_| Failure {
+            reason: failure.reason.clone(),
+            expanded: failure.expanded.clone(),
+            location: None,
+        });
+        if failed == 0 {
+            for event in [
+                TestEvent::Started {
+                    name: NAME.to_string(),
+                },
+                TestEvent::Failed {
+                    name: NAME.to_string(),
+                    exec_time: None,
+                    stdout: Some(output),
+                    stderr: None,
+                    message: None,
+                },
+            ] {
+                payload.push(Event::Test {
+                    event,
+                    nextest: None,
+                    extra: Default::default(),
+                });
+            }
+        }
+
+        count
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::run_env::RuntimeEnvironment;
+
+    const UNDEFINED_BEHAVIOR: &str = r#"error: Undefined Behavior: dereferencing pointer failed: alloc1 has been freed, so this pointer is dangling
+ --> src/lib.rs:8:22
+  |
+8 |         assert_eq!(unsafe { *dangling }, 1);
+  |                             ^^^^^^^^^ dereferencing pointer failed
+  |
+  = help: this indicates a bug in the program: it performed an invalid operation, and caused Undefined Behavior
+  = note: inside `tests::dangles` at src/lib.rs:8:22
+
+note: some details are omitted, run with `MIRIFLAGS=-Zmiri-backtrace=full` for a verbose backtrace
+
+error: aborting due to 1 previous error
+"#;
+
+    #[test]
+    fn reports_are_attached_to_the_running_test() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+        let mut miri = MiriReports::default();
+
+        let events = [
+            r#"{ "type": "suite", "event": "started", "test_count": 2 }"#,
+            r#"{ "type": "test", "event": "started", "name": "tests::works" }"#,
+            r#"{ "type": "test", "name": "tests::works", "event": "ok", "exec_time": 12.5 }"#,
+            r#"{ "type": "test", "event": "started", "name": "tests::dangles" }"#,
+        ];
+        for line in events.into_iter().chain(UNDEFINED_BEHAVIOR.lines()) {
+            let _ = crate::input::parse_line(line, &mut payload);
+            miri.parse_line(line);
+        }
+
+        assert_eq!(miri.finish(&mut payload), 1);
+
+        let json = serde_json::to_value(&payload).unwrap();
+        let mut data = json["data"].as_array().unwrap().clone();
+        data.sort_by_key(|test| test["name"].as_str().unwrap().to_string());
+
+        assert_eq!(data.len(), 2);
+        assert_eq!(data[0]["name"], "dangles");
+        assert_eq!(data[0]["result"], "failed");
+        assert_eq!(
+            data[0]["failure_reason"],
+            "error: Undefined Behavior: dereferencing pointer failed: alloc1 has been freed, so this pointer is dangling"
+        );
+        assert_eq!(
+            data[0]["failure_expanded"][0]["expanded"][6],
+            "  = help: this indicates a bug in the program: it performed an invalid operation, and caused Undefined Behavior"
+        );
+        assert_eq!(data[1]["name"], "works");
+        assert_eq!(data[1]["result"], "passed");
+    }
+
+    #[test]
+    fn leaks_after_the_tests_finish_get_a_test_of_their_own() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+        let mut miri = MiriReports::default();
+
+        for line in [
+            "test result: ok. 1 passed; 0 failed; 0 ignored; 0 measured; 0 filtered out; finished in 3.10s",
+            "",
+            "error: memory leaked: alloc7 (Rust heap, size: 4, align: 4), allocated here:",
+            "   --> src/lib.rs:3:9",
+            "note: set `MIRIFLAGS=-Zmiri-ignore-leaks` to disable this check",
+            "error: aborting due to 1 previous error",
+            "error: test failed, to rerun pass `--lib`",
+        ] {
+            miri.parse_line(line);
+        }
+
+        assert_eq!(miri.finish(&mut payload), 1);
+
+        let tests = payload.closed_data();
+        assert_eq!(tests.len(), 1);
+        assert_eq!(tests[0].full_name(), NAME);
+        assert_eq!(
+            tests[0].failure_reason(),
+            Some("error: memory leaked: alloc7 (Rust heap, size: 4, align: 4), allocated here:")
+        );
+    }
+}
@@ -2,11 +2,19 @@
 //!
 //! Information about the payload to send to the API.
 
-use crate::input::{Event, SuiteEvent, TestEvent};
+use crate::duration::{DurationPolicy, DurationSource};
+use crate::failure::{self, Failure, FailureExpanded};
+use crate::input::{self, CaseName, DoctestName, Event, SuiteEvent, SuiteResults, TestEvent};
+use crate::intern::Interner;
+use crate::marker::{self, Span};
+use crate::preflight;
+use crate::property;
 use crate::run_env::RuntimeEnvironment;
+use crate::tags::TagRules;
 use serde::ser::{Serialize, SerializeStruct, Serializer};
-use std::collections::HashMap;
-use std::time::Instant;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 use uuid::Uuid;
 
 /// # Payload
@@ -14,30 +22,332 @@ use uuid::Uuid;
 /// A data-structure which represents the (possibly) incomplete data to be
 /// eventually sent to the API.
 ///
+/// `cargo test --workspace` runs one suite per test binary, so tests are
+/// keyed by the suite they ran in as well as their name, and test names from
+/// different binaries never collide.
+///
 /// Impements `serde:Serialize` for serialisation into JSON.
 #[derive(Debug, PartialEq)]
 pub struct Payload {
     run_env: RuntimeEnvironment,
-    data: HashMap<String, TestData>,
-    started_at: Option<Instant>,
+    /// Whether to send only the fields of `run_env` which identify the run.
+    refer_to_run: bool,
+    /// Which of its upload's batches this is, from `batchify`.
+    batch_index: usize,
+    data: HashMap<TestKey, TestData>,
+    /// Earlier attempts at retried tests, oldest first, which are uploaded
+    /// as executions of their own.
+    attempts: HashMap<TestKey, Vec<TestData>>,
+    suite: Option<SuiteClock>,
+    next_suite: usize,
+    started_at: Option<RunStart>,
     finished_at: Option<Instant>,
+    name_limits: NameLimits,
+    suite_name: Option<String>,
+    /// Prepended to every test's scope.
+    scope_prefix: Option<String>,
+    suite_counts: BTreeMap<usize, SuiteCount>,
+    /// How many tests the whole run should have, in place of the suites'
+    /// own counts.
+    expected_tests: Option<usize>,
+    duration_policy: DurationPolicy,
+    interner: Interner,
+    /// The suite each test id was first seen in, so tests which share a
+    /// scope and name can be told apart.
+    ids: HashMap<Uuid, usize>,
+    tags: HashMap<String, String>,
+    warned_exec_time: bool,
+    extra_fields: bool,
+    case_tags: bool,
+    random_ids: bool,
+    /// The most bytes of each part of a failure's output to keep.
+    max_failure_bytes: usize,
+    /// The test binary cargo said it's running, which the tests started
+    /// since belong to.
+    binary: Option<String>,
+    default_tags: Vec<(String, String)>,
+    tag_rules: TagRules,
+}
+
+/// The index of the suite a test ran in, and its name.
+type TestKey = (usize, String);
+
+/// # SuiteCount
+///
+/// How many tests a suite said it would run, how many of those we've
+/// already handed off (eg in an early batch), and what it said when it
+/// finished.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+struct SuiteCount {
+    expected: Option<usize>,
+    taken: usize,
+    results: Option<SuiteResults>,
+}
+
+/// # Completeness
+///
+/// How many of the tests the suites said they would run have finished.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Completeness {
+    pub expected: usize,
+    pub finished: usize,
+}
+
+impl Completeness {
+    /// The fraction of expected tests which finished, at most `1.0`.
+    pub fn ratio(&self) -> f64 {
+        if self.expected == 0 {
+            1.0
+        } else {
+            (self.finished as f64 / self.expected as f64).min(1.0)
+        }
+    }
+
+    /// Did every expected test finish?
+    pub fn is_complete(&self) -> bool {
+        self.finished >= self.expected
+    }
+
+    /// Did more tests finish than were expected, eg because the output of
+    /// another run was mixed in?
+    pub fn is_exceeded(&self) -> bool {
+        self.finished > self.expected
+    }
+}
+
+/// # SuiteClock
+///
+/// The timing context of a single suite.  Created by the suite's `started`
+/// event or, if that never arrives (or a test event arrives first), by the
+/// first event which needs it.
+///
+/// Times are seconds since the Unix epoch, measured with the monotonic clock
+/// from when the run started, so they keep increasing from one suite to the
+/// next even if the system clock is adjusted.
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct SuiteClock {
+    index: usize,
+    run_start: RunStart,
+    explicit: bool,
+}
+
+impl SuiteClock {
+    /// The current time.
+    fn now(&self) -> f64 {
+        self.run_start.epoch_at(Instant::now())
+    }
+}
+
+/// # SuiteContext
+///
+/// The suite, and test binary, which one writer's events belong to, so that
+/// several writers can add their output to one payload at once (see
+/// `Payload::swap_context`).
+#[derive(Debug, Default)]
+pub struct SuiteContext {
+    suite: Option<SuiteClock>,
+    binary: Option<String>,
+}
+
+/// # RunStart
+///
+/// When the run's first event arrived, by both the monotonic clock (for
+/// measuring) and the system clock (for reporting).
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct RunStart {
+    instant: Instant,
+    epoch: f64,
+}
+
+impl RunStart {
+    fn now() -> Self {
+        let epoch = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+
+        RunStart {
+            instant: Instant::now(),
+            epoch: seconds(epoch),
+        }
+    }
+
+    /// The time of `instant`, in seconds since the Unix epoch.
+    fn epoch_at(&self, instant: Instant) -> f64 {
+        self.epoch + seconds(instant.saturating_duration_since(self.instant))
+    }
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs_f64()
+}
+
+/// # NameLimits
+///
+/// Guard rails for extremely long test names.  Names longer than
+/// `max_length` bytes are truncated and suffixed with a hash of the full name
+/// so that they remain unique.  If the full name is no longer than
+/// `max_tag_length` bytes it is retained in the `full_name` tag.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct NameLimits {
+    max_length: usize,
+    max_tag_length: usize,
+}
+
+/// The length of the `~` separator plus a 64 bit hash rendered in hex.
+const NAME_HASH_SUFFIX_LEN: usize = 17;
+
+impl Default for NameLimits {
+    fn default() -> Self {
+        NameLimits {
+            max_length: 1024,
+            max_tag_length: 4096,
+        }
+    }
+}
+
+impl NameLimits {
+    /// Read the limits from the environment.
+    ///
+    /// ## Emits warnings
+    ///  - If `BUILDKITE_ANALYTICS_MAX_NAME_LENGTH` or
+    ///    `BUILDKITE_ANALYTICS_MAX_NAME_TAG_LENGTH` cannot be parsed.
+    pub fn from_env() -> Self {
+        let default = NameLimits::default();
+
+        NameLimits {
+            max_length: parse_var("BUILDKITE_ANALYTICS_MAX_NAME_LENGTH")
+                .unwrap_or(default.max_length)
+                .max(NAME_HASH_SUFFIX_LEN + 1),
+            max_tag_length: parse_var("BUILDKITE_ANALYTICS_MAX_NAME_TAG_LENGTH")
+                .unwrap_or(default.max_tag_length),
+        }
+    }
+
+    /// Apply the limits to `name`.
+    ///
+    /// Returns the (possibly truncated) name, and the full name if it was
+    /// truncated and is short enough to be kept as a tag.
+    fn apply(&self, name: &str) -> (String, Option<String>) {
+        if name.len() <= self.max_length {
+            return (name.to_string(), None);
+        }
+
+        let mut cut = self.max_length - NAME_HASH_SUFFIX_LEN;
+        while !name.is_char_boundary(cut) {
+            cut -= 1;
+        }
+
+        let truncated = format!("{}~{:016x}", &name[..cut], fnv1a(name));
+        let full_name = Some(name.to_string()).filter(|_| name.len() <= self.max_tag_length);
+
+        (truncated, full_name)
+    }
+}
+
+/// Parse the environment variable `key`, if it's set.
+///
+/// ## Emits warnings
+///  - If the value cannot be parsed.
+pub fn parse_var<T: std::str::FromStr>(key: &str) -> Option<T> {
+    parse_value(key, std::env::var(key).ok()?)
+}
+
+/// Parse `value`, the value of the variable `key`.
+///
+/// ## Emits warnings
+///  - If the value cannot be parsed.
+pub fn parse_value<T: std::str::FromStr>(key: &str, value: String) -> Option<T> {
+    match value.parse() {
+        Ok(value) => Some(value),
+        Err(_) => {
+            eprintln!(
+                "Unable to parse {} value {:?}, using the default.",
+                key, value
+            );
+            None
+        }
+    }
+}
+
+/// Parse the environment variable `key` as a (possibly fractional) number of
+/// seconds, if it's set.
+///
+/// ## Emits warnings
+///  - If the value cannot be parsed.
+pub fn seconds_var(key: &str) -> Option<Duration> {
+    seconds_value(key, std::env::var(key).ok()?)
+}
+
+/// Parse `value`, the value of the variable `key`, as a (possibly
+/// fractional) number of seconds.
+///
+/// ## Emits warnings
+///  - If the value cannot be parsed.
+pub fn seconds_value(key: &str, value: String) -> Option<Duration> {
+    parse_value::<f64>(key, value)
+        .filter(|seconds| seconds.is_finite() && *seconds >= 0.0)
+        .map(Duration::from_secs_f64)
+}
+
+/// The namespace of test ids, the UUIDv5 of this repository's URL.
+const ID_NAMESPACE: Uuid = Uuid::from_u128(0xc7492f898cff5a90bd599d8ef1d1c93d);
+
+/// A stable id for the test named `name` in `scope`, so that its results can
+/// be correlated across runs, and repeated uploads deduplicated.
+fn test_id(suite_name: Option<&str>, scope: &str, name: &str) -> Uuid {
+    let test = format!("{}\0{}::{}", suite_name.unwrap_or_default(), scope, name);
+    Uuid::new_v5(&ID_NAMESPACE, test.as_bytes())
+}
+
+/// A stable id for the `attempt`th attempt at the test with id `test_id`,
+/// since Test Engine would otherwise deduplicate retries of a test.
+fn attempt_id(test_id: &str, attempt: usize) -> Uuid {
+    let attempt = format!("{}\0{}", test_id, attempt);
+    Uuid::new_v5(&ID_NAMESPACE, attempt.as_bytes())
+}
+
+/// A stable 64 bit FNV-1a hash, so truncated names are consistent between runs.
+pub fn fnv1a(value: &str) -> u64 {
+    value.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
 }
 
 /// # TestData
 ///
 /// Information about a specific test result.  Contains the test's unique
 /// identifier, name, etc, as well as any tracing or failure information.
-#[derive(serde::Serialize, Debug, PartialEq, Clone)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Clone)]
 pub struct TestData {
     id: String,
-    scope: String,
+    scope: Arc<str>,
     name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    location: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file_name: Option<String>,
     #[serde(flatten)]
     result: TestResult,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    failure_expanded: Vec<FailureExpanded>,
     history: TestHistory,
+    /// Shared through the payload's `Interner` when set while collecting, as
+    /// most tests have the same few.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    tags: HashMap<Arc<str>, Arc<str>>,
+    /// Fields of the test's events which the collector doesn't understand.
+    #[serde(default, skip_serializing_if = "serde_json::Map::is_empty")]
+    extra: serde_json::Map<String, serde_json::Value>,
 }
 
 impl TestData {
+    /// Start building a test which has already finished, to push with
+    /// `Payload::push_test`, eg from a tool's own results rather than
+    /// libtest's output.
+    pub fn builder() -> TestDataBuilder {
+        TestDataBuilder::default()
+    }
+
     /// Have we received a finishing event for this `TestData`?
     ///
     /// Because Rust sends separate `TestStarted`, `TestOk` and `TestFailed`
@@ -46,17 +356,266 @@ impl TestData {
     pub fn is_finished(&self) -> bool {
         self.history.is_finished()
     }
+
+    /// The scope the test belongs to, eg its module path.
+    pub fn scope(&self) -> &str {
+        &self.scope
+    }
+
+    /// The name of the test within its scope.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The scope and name, eg `payload::test::batchify_works_as_expected`.
+    pub fn full_name(&self) -> String {
+        if self.scope.is_empty() {
+            self.name.clone()
+        } else {
+            format!("{}::{}", self.scope, self.name)
+        }
+    }
+
+    /// The file the test is in, if we know it.
+    pub fn file_name(&self) -> Option<&str> {
+        self.file_name.as_deref()
+    }
+
+    /// Record where the test is defined.
+    pub fn set_location(&mut self, file: &str, line: usize) {
+        self.location = Some(format!("{}:{}", file, line));
+        self.file_name = Some(file.to_string());
+    }
+
+    /// The value of the tag `key`, if set.
+    pub fn tag(&self, key: &str) -> Option<&str> {
+        self.tags.get(key).map(|value| &**value)
+    }
+
+    /// Tag this test, replacing any existing value.
+    pub fn insert_tag(&mut self, key: &str, value: &str) {
+        self.tags.insert(Arc::from(key), Arc::from(value));
+    }
+
+    /// `insert_tag`, sharing the key and value through `interner`.
+    fn intern_tag(&mut self, key: &str, value: &str, interner: &mut Interner) {
+        self.tags
+            .insert(interner.intern(key), interner.intern(value));
+    }
+
+    /// Tag this test, and record its spans, from any markers in its captured
+    /// output.  The tags and the spans' sections are shared through
+    /// `interner`.
+    fn apply_markers(&mut self, output: &str, interner: &mut Interner) {
+        for (key, value) in marker::tags(output) {
+            self.intern_tag(key, value, interner);
+        }
+
+        let start_at = self.history.start_at;
+        self.history.children.extend(
+            marker::spans(output)
+                .into_iter()
+                .map(|span| TestHistory::from_span(span, start_at, interner)),
+        );
+    }
+
+    /// Set the duration from the first available candidate allowed by
+    /// `policy`, recording where it came from in the `duration_source` tag.
+    pub fn set_duration(
+        &mut self,
+        policy: &DurationPolicy,
+        candidates: &[(DurationSource, Option<f64>)],
+        interner: &mut Interner,
+    ) {
+        if let Some((source, duration)) = policy.choose(candidates) {
+            self.history.duration = Some(duration);
+            self.intern_tag("duration_source", source.as_str(), interner);
+        }
+    }
+
+    /// Record that the test finished at `now` (by the collector's clock),
+    /// with its duration chosen between libtest's `exec_time` and the
+    /// measured wall time.
+    ///
+    /// `end_at` is derived from `start_at` and the chosen duration, rather
+    /// than being `now`, so that the history's numbers add up.  It's only
+    /// `now` when there's no duration at all.
+    pub fn finish_at(
+        &mut self,
+        now: f64,
+        exec_time: Option<f64>,
+        policy: &DurationPolicy,
+        interner: &mut Interner,
+    ) {
+        let measured = self.history.start_at.map(|start_at| now - start_at);
+        self.set_duration(
+            policy,
+            &[
+                (DurationSource::Libtest, exec_time),
+                (DurationSource::Collector, measured),
+            ],
+            interner,
+        );
+        self.history.end_at = Some(
+            self.history
+                .start_at
+                .zip(self.history.duration)
+                .map_or(now, |(start_at, duration)| start_at + duration),
+        );
+    }
+
+    /// How long the test took, if it has finished.
+    pub fn duration(&self) -> Option<f64> {
+        self.history.duration
+    }
+
+    /// Did this test fail?
+    pub fn is_failed(&self) -> bool {
+        matches!(self.result, TestResult::Failed { .. })
+    }
+
+    /// Why the test failed, if it did and we know.
+    pub fn failure_reason(&self) -> Option<&str> {
+        match &self.result {
+            TestResult::Failed { failure_reason } => failure_reason.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Call `edit` with the failure's reason, and each line of its expanded
+    /// output and backtrace, eg to redact secrets from them.  `edit` returns
+    /// whether it changed the text, and so does this.
+    ///
+    /// The reason may be shared with other tests, so it's edited as a copy.
+    pub fn edit_failure_text<F>(&mut self, mut edit: F) -> bool
+    where
+        F: FnMut(&mut String) -> bool,
+    {
+        let mut edited = false;
+        if let TestResult::Failed {
+            failure_reason: Some(reason),
+        } = &mut self.result
+        {
+            let mut text = reason.to_string();
+            if edit(&mut text) {
+                *reason = Arc::from(text);
+                edited = true;
+            }
+        }
+
+        for text in self
+            .failure_expanded
+            .iter_mut()
+            .flat_map(|expanded| expanded.expanded.iter_mut().chain(&mut expanded.backtrace))
+        {
+            edited |= edit(text);
+        }
+        edited
+    }
+
+    /// Trim the failure's reason, expanded output and backtrace to at most
+    /// `max_bytes` each.
+    fn truncate_failure(&mut self, max_bytes: usize) {
+        if let TestResult::Failed {
+            failure_reason: Some(reason),
+        } = &mut self.result
+        {
+            if reason.len() > max_bytes {
+                let mut text = reason.to_string();
+                failure::truncate(&mut text, max_bytes);
+                *reason = Arc::from(text);
+            }
+        }
+        for expanded in &mut self.failure_expanded {
+            expanded.truncate(max_bytes);
+        }
+    }
+}
+
+/// # TestDataBuilder
+///
+/// A finished test, as built by `TestData::builder`.  It passed in no time at
+/// the start of the run unless told otherwise.
+#[derive(Debug, PartialEq, Clone)]
+pub struct TestDataBuilder {
+    scope: String,
+    name: String,
+    result: TestResult,
+    start_at: f64,
+    duration: f64,
+    duration_source: DurationSource,
+}
+
+impl Default for TestDataBuilder {
+    fn default() -> Self {
+        TestDataBuilder {
+            scope: String::new(),
+            name: String::new(),
+            result: TestResult::Passed,
+            start_at: 0.0,
+            duration: 0.0,
+            duration_source: DurationSource::Collector,
+        }
+    }
+}
+
+impl TestDataBuilder {
+    /// The scope the test belongs to, eg its module path.
+    pub fn scope(mut self, scope: &str) -> Self {
+        self.scope = scope.to_string();
+        self
+    }
+
+    /// The name of the test within its scope.
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    /// Whether the test passed, failed or was skipped.
+    pub fn result(mut self, result: TestResult) -> Self {
+        self.result = result;
+        self
+    }
+
+    /// The test failed, for `reason` if known.
+    pub fn failed(self, reason: Option<&str>) -> Self {
+        self.result(TestResult::Failed {
+            failure_reason: reason.map(Arc::from),
+        })
+    }
+
+    /// When the test started, in seconds from the start of the run.
+    pub fn start_at(mut self, start_at: f64) -> Self {
+        self.start_at = start_at;
+        self
+    }
+
+    /// How long the test took, in seconds.
+    pub fn duration(mut self, duration: f64) -> Self {
+        self.duration = duration;
+        self
+    }
+
+    /// Where the duration came from, which is recorded in the
+    /// `duration_source` tag.
+    pub fn duration_source(mut self, duration_source: DurationSource) -> Self {
+        self.duration_source = duration_source;
+        self
+    }
 }
 
 /// # TestHistory
 ///
 /// Contains timing information about the test and possibly finer tracing.
-#[derive(serde::Serialize, Debug, PartialEq, Clone)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Clone)]
 pub struct TestHistory {
-    section: String,
+    section: Arc<str>,
     start_at: Option<f64>,
     end_at: Option<f64>,
     duration: Option<f64>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    detail: HashMap<String, String>,
     children: Vec<TestHistory>,
 }
 
@@ -69,18 +628,45 @@ impl TestHistory {
     pub fn is_finished(&self) -> bool {
         self.end_at.is_some()
     }
+
+    /// The time between the start and finish events, as measured by the
+    /// collector.
+    pub fn measured_duration(&self) -> Option<f64> {
+        Some(self.end_at? - self.start_at?)
+    }
+
+    /// The history of a span marked by a test which started at `offset`.
+    fn from_span(span: Span, offset: Option<f64>, interner: &mut Interner) -> TestHistory {
+        let start_at = offset.zip(span.start).map(|(offset, start)| offset + start);
+        let end_at = offset.zip(span.end).map(|(offset, end)| offset + end);
+
+        TestHistory {
+            section: interner.intern(&span.section),
+            start_at,
+            end_at,
+            duration: span.start.zip(span.end).map(|(start, end)| end - start),
+            detail: span.detail,
+            children: span
+                .children
+                .into_iter()
+                .map(|child| TestHistory::from_span(child, offset, interner))
+                .collect(),
+        }
+    }
 }
 
 /// # TestResult
 ///
 /// Did the test in question pass?  And if not, why not?
-#[derive(serde::Serialize, Debug, PartialEq, Clone)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Clone)]
 #[serde(tag = "result")]
 pub enum TestResult {
     #[serde(rename = "passed")]
     Passed,
     #[serde(rename = "failed")]
-    Failed { failure_reason: Option<String> },
+    Failed { failure_reason: Option<Arc<str>> },
+    #[serde(rename = "skipped")]
+    Skipped,
 }
 
 impl Serialize for Payload {
@@ -88,10 +674,25 @@ impl Serialize for Payload {
     where
         S: Serializer,
     {
-        let mut state = serializer.serialize_struct("Payload", 3)?;
+        let mut state = serializer.serialize_struct("Payload", 4)?;
         state.serialize_field("format", "json")?;
-        state.serialize_field("run_env", &self.run_env)?;
-        state.serialize_field("data", &self.closed_data())?;
+        if self.refer_to_run {
+            state.serialize_field("run_env", &self.run_env.reference())?;
+        } else {
+            state.serialize_field("run_env", &self.run_env)?;
+        }
+        let tags = self
+            .tags
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .chain(self.suite_results_tags())
+            .collect::<HashMap<String, String>>();
+        if tags.is_empty() {
+            state.skip_field("tags")?;
+        } else {
+            state.serialize_field("tags", &tags)?;
+        }
+        state.serialize_field("data", &self.executions())?;
         state.end()
     }
 }
@@ -101,152 +702,1024 @@ impl Payload {
     pub fn new(run_env: RuntimeEnvironment) -> Self {
         Payload {
             run_env,
+            refer_to_run: false,
+            batch_index: 0,
             data: HashMap::new(),
+            attempts: HashMap::new(),
+            suite: None,
+            next_suite: 0,
             started_at: None,
             finished_at: None,
+            name_limits: NameLimits::default(),
+            suite_name: None,
+            scope_prefix: None,
+            suite_counts: BTreeMap::new(),
+            expected_tests: None,
+            duration_policy: DurationPolicy::default(),
+            interner: Interner::default(),
+            ids: HashMap::new(),
+            tags: HashMap::new(),
+            warned_exec_time: false,
+            extra_fields: false,
+            case_tags: false,
+            random_ids: false,
+            max_failure_bytes: failure::MAX_BYTES,
+            binary: None,
+            default_tags: Vec::new(),
+            tag_rules: TagRules::default(),
         }
     }
 
+    /// Use the provided limits for test names.
+    pub fn with_name_limits(mut self, name_limits: NameLimits) -> Self {
+        self.name_limits = name_limits;
+        self
+    }
+
+    /// Tag every test with the provided suite name, if any.
+    ///
+    /// Allows results from several collector invocations within one build
+    /// (eg unit, integration and doc tests) to be told apart.
+    pub fn with_suite_name(mut self, suite_name: Option<String>) -> Self {
+        self.suite_name = suite_name;
+        self
+    }
+
+    /// Prepend `scope_prefix` to the scope of every test, if any.
+    ///
+    /// Allows teams which send results from several jobs to one suite to
+    /// namespace them, eg by crate.
+    pub fn with_scope_prefix(mut self, scope_prefix: Option<String>) -> Self {
+        self.scope_prefix = scope_prefix.filter(|prefix| !prefix.is_empty());
+        self
+    }
+
     /// Push an event into the payload.
     pub fn push(&mut self, event: Event) {
         match event {
             Event::Suite { event: suite_event } => self.push_suite_event(suite_event),
-            Event::Test { event: test_event } => self.push_test_event(test_event),
+            Event::Test {
+                event: test_event,
+                nextest,
+                extra,
+            } => {
+                let key = self.key(test_event.name());
+                self.push_test_event(&key, test_event);
+
+                if self.extra_fields {
+                    if let Some(data) = self.data.get_mut(&key) {
+                        data.extra.extend(extra);
+                    }
+                }
+
+                if let Some((nextest, data)) = nextest.zip(self.data.get_mut(&key)) {
+                    let interner = &mut self.interner;
+                    data.intern_tag("nextest.crate", &nextest.crate_name, interner);
+                    data.intern_tag("nextest.test_binary", &nextest.test_binary, interner);
+                    data.intern_tag("nextest.kind", &nextest.kind, interner);
+                }
+            }
+            Event::Bench {
+                name,
+                median,
+                deviation,
+                mib_per_second,
+            } => self.push_bench(name, median, deviation, mib_per_second),
+            Event::Unknown { .. } => {}
+        }
+    }
+
+    /// Pass the fields of test events which the collector doesn't understand
+    /// through to the payload, under each test's `extra` key.
+    pub fn with_extra_fields(mut self, extra_fields: bool) -> Self {
+        self.extra_fields = extra_fields;
+        self
+    }
+
+    /// Tag each case of an rstest parameterised test with the test it belongs
+    /// to, so its cases can be grouped together.
+    pub fn with_case_tags(mut self, case_tags: bool) -> Self {
+        self.case_tags = case_tags;
+        self
+    }
+
+    /// Give each test a random id, as older versions did, rather than one
+    /// derived from its suite, scope and name.
+    pub fn with_random_ids(mut self, random_ids: bool) -> Self {
+        self.random_ids = random_ids;
+        self
+    }
+
+    /// Trim the middle out of each part of a failure's output (its reason,
+    /// expanded output and backtrace) which is longer than `max_bytes`, so a
+    /// test which dumps lots of output can't balloon the payload.
+    pub fn with_max_failure_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_failure_bytes = max_bytes;
+        self
+    }
+
+    /// Tag every test, unless a tag rule or the test itself says otherwise.
+    pub fn with_default_tags(mut self, default_tags: Vec<(String, String)>) -> Self {
+        self.default_tags = default_tags;
+        self
+    }
+
+    /// Tag each test according to the rules matching its full name.
+    pub fn with_tag_rules(mut self, tag_rules: TagRules) -> Self {
+        self.tag_rules = tag_rules;
+        self
+    }
+
+    /// The idempotency key to upload this payload with: the run's key, the
+    /// batch's index (see `batchify`), and a hash of its contents.  It's the
+    /// same however many times the batch is retried, sent in full rather than
+    /// referring to the run, replayed from the spool or rebuilt from the same
+    /// results, so that the API can ignore duplicates.  The hash tells apart
+    /// separate invocations in one job, which share a run key.
+    pub fn idempotency_key(&self) -> String {
+        let key = format!(
+            "{}-{}-{:016x}",
+            self.run_env.key(),
+            self.batch_index,
+            fnv1a(&self.canonical_json())
+        );
+
+        // Also used as the name of the spooled file.
+        key.chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+                _ => '_',
+            })
+            .collect()
+    }
+
+    /// The payload as JSON, without its runtime environment, and with its
+    /// tests in order.  Objects are serialised with their keys in order, as
+    /// `serde_json::Value` keeps them sorted, so it doesn't depend on the
+    /// order of the payload's maps.
+    fn canonical_json(&self) -> String {
+        let mut json = serde_json::to_value(self).unwrap_or_default();
+        if let Some(json) = json.as_object_mut() {
+            json.remove("run_env");
+        }
+        if let Some(data) = json
+            .get_mut("data")
+            .and_then(serde_json::Value::as_array_mut)
+        {
+            data.sort_by_cached_key(|execution| execution.to_string());
         }
+
+        json.to_string()
     }
 
-    /// Split the payload into batches of `batch_size`.
+    /// Split the payload into batches of at most `batch_size` tests, and
+    /// (roughly) `max_bytes` of JSON.
     ///
     /// Currently the analytics API allows a maximum of 5000 tests to be
     /// uploaded in a single call, however it is possible to upload more than
-    /// that by splitting the payload into separate batches.
+    /// that by splitting the payload into separate batches.  Tests with lots
+    /// of failure output can make even a few too big for one request, so
+    /// batches are also split by their estimated size, though a test which is
+    /// too big by itself still gets a batch of its own.
+    ///
+    /// Tests are batched in order of their suite and name, so the same tests
+    /// always make the same batches, and each batch is numbered for its
+    /// `idempotency_key`.
+    ///
+    /// Each test is in exactly one batch.  Tests which never finished have no
+    /// result to upload, so should be failed first (see `fail_unfinished`),
+    /// and any which are left are dropped.
     ///
     /// Returns a vector of payloads containing their individual batches of
     /// `TestData`.
-    pub fn batchify(self, batch_size: usize) -> Vec<Self> {
-        let (complete, incomplete): (Vec<TestData>, Vec<TestData>) = self
+    ///
+    /// ## Emits warnings
+    ///  - If any unfinished tests were dropped.
+    pub fn batchify(mut self, batch_size: usize, max_bytes: usize) -> Vec<Self> {
+        let (mut complete, incomplete): (Vec<_>, Vec<_>) = self
             .data
-            .values()
-            .cloned()
-            .partition(|test_data| test_data.is_finished());
-
-        let result = complete
-            .chunks(batch_size)
-            .map(|chunk| {
-                let mut payload = self.new_clean();
-
-                for test_data in chunk.iter() {
-                    payload
-                        .data
-                        .insert(test_data.name.clone(), test_data.clone());
-                }
-
-                if payload.data.len() < batch_size {
-                    for test_data in incomplete.iter() {
-                        payload
-                            .data
-                            .insert(test_data.name.clone(), test_data.clone());
-                    }
-                }
+            .drain()
+            .partition(|(_, test_data)| test_data.is_finished());
+        complete.sort_by(|(a, _), (b, _)| a.cmp(b));
+        if !incomplete.is_empty() {
+            eprintln!(
+                "{} tests never finished, so they won't be uploaded.",
+                incomplete.len()
+            );
+        }
 
-                payload
-            })
-            .collect();
+        let mut batches = Vec::new();
+        let mut batch = self.new_clean();
+        let (mut executions, mut bytes) = (0, 0);
+        for (key, test_data) in complete {
+            // Earlier attempts go in the same batch as the last.
+            let attempts = self.attempts.remove(&key).unwrap_or_default();
+            let test_executions = attempts.len() + 1;
+            // Each test is followed by a comma.
+            let test_bytes = attempts
+                .iter()
+                .chain([&test_data])
+                .map(|test_data| preflight::serialized_len(test_data) + 1)
+                .sum::<usize>();
+            if !batch.data.is_empty()
+                && (executions + test_executions > batch_size || bytes + test_bytes > max_bytes)
+            {
+                let mut next = self.new_clean();
+                next.batch_index = batch.batch_index + 1;
+                // The run's totals are sent once, with the first batch.
+                next.suite_counts = BTreeMap::new();
+                batches.push(std::mem::replace(&mut batch, next));
+                (executions, bytes) = (0, 0);
+            }
+            (executions, bytes) = (executions + test_executions, bytes + test_bytes);
+            batch.data.insert(key.clone(), test_data);
+            if !attempts.is_empty() {
+                batch.attempts.insert(key, attempts);
+            }
+        }
+        if !batch.data.is_empty() {
+            batches.push(batch);
+        }
+        // The attempts before one which never finished did finish.
+        if let Some(batch) = batches.last_mut() {
+            batch.attempts.extend(self.attempts.drain());
+        }
 
-        result
+        batches
     }
 
     fn new_clean(&self) -> Self {
         Payload {
             run_env: self.run_env.clone(),
+            refer_to_run: self.refer_to_run,
+            batch_index: self.batch_index,
             data: HashMap::new(),
+            attempts: HashMap::new(),
+            suite: self.suite,
+            next_suite: self.next_suite,
             started_at: self.started_at,
             finished_at: self.finished_at,
+            name_limits: self.name_limits,
+            suite_name: self.suite_name.clone(),
+            scope_prefix: self.scope_prefix.clone(),
+            suite_counts: self.suite_counts.clone(),
+            expected_tests: self.expected_tests,
+            duration_policy: self.duration_policy.clone(),
+            interner: Interner::default(),
+            ids: HashMap::new(),
+            tags: self.tags.clone(),
+            warned_exec_time: self.warned_exec_time,
+            extra_fields: self.extra_fields,
+            case_tags: self.case_tags,
+            random_ids: self.random_ids,
+            max_failure_bytes: self.max_failure_bytes,
+            binary: self.binary.clone(),
+            default_tags: self.default_tags.clone(),
+            tag_rules: self.tag_rules.clone(),
         }
     }
 
-    fn closed_data(&self) -> Vec<&TestData> {
+    /// Expect the whole run to have `expected_tests` tests, eg as counted by
+    /// a wrapper script, rather than trusting the suites' own counts.
+    pub fn with_expected_tests(mut self, expected_tests: Option<usize>) -> Self {
+        self.expected_tests = expected_tests;
+        self
+    }
+
+    /// Choose between duration sources according to `duration_policy`.
+    pub fn with_duration_policy(mut self, duration_policy: DurationPolicy) -> Self {
+        self.duration_policy = duration_policy;
+        self
+    }
+
+    /// The runtime environment this payload will be sent with.
+    pub fn run_env(&self) -> &RuntimeEnvironment {
+        &self.run_env
+    }
+
+    /// Does the payload send only the fields of its runtime environment
+    /// which identify the run?
+    #[cfg(test)]
+    pub fn refers_to_run(&self) -> bool {
+        self.refer_to_run
+    }
+
+    /// Send only the fields of the runtime environment which identify the
+    /// run, or send it in full.
+    pub fn set_refer_to_run(&mut self, refer_to_run: bool) {
+        self.refer_to_run = refer_to_run;
+    }
+
+    /// The suite name provided with `with_suite_name`, if any.
+    pub fn suite_name(&self) -> Option<&str> {
+        self.suite_name.as_deref()
+    }
+
+    /// All the `TestData` for which we have received a finishing event.
+    pub fn closed_data(&self) -> Vec<&TestData> {
         self.data
             .values()
             .filter(|event| event.history.end_at.is_some())
             .collect()
     }
 
-    fn push_suite_event(&mut self, suite_event: SuiteEvent) {
-        match suite_event {
-            SuiteEvent::Started { .. } => self.started_at = Some(Instant::now()),
-            SuiteEvent::Ok { .. } => self.finished_at = Some(Instant::now()),
-            SuiteEvent::Failed { .. } => self.finished_at = Some(Instant::now()),
-        }
+    /// Every execution of the finished tests, including earlier attempts at
+    /// retried tests, as they're uploaded.
+    pub fn executions(&self) -> Vec<&TestData> {
+        self.attempts
+            .values()
+            .flatten()
+            .chain(self.closed_data())
+            .collect()
     }
 
-    fn push_test_event(&mut self, test_event: TestEvent) {
-        match test_event {
-            TestEvent::Started { name } => {
-                let name_chunks = name.split("::").collect::<Vec<&str>>();
-
-                let data = TestData {
-                    id: Uuid::new_v4().to_string(),
-                    name: name_chunks.iter().last().unwrap().to_string(),
-                    scope: name_chunks
-                        .iter()
-                        .rev()
-                        .skip(1)
-                        .rev()
-                        .copied()
-                        .collect::<Vec<&str>>()
-                        .join("::"),
-                    result: TestResult::Passed,
-                    history: TestHistory {
-                        section: "top".to_string(),
-                        start_at: Some(
-                            Instant::now()
-                                .duration_since(self.started_at.unwrap())
-                                .as_millis() as f64
-                                / 1000000.0,
-                        ),
-                        end_at: None,
-                        duration: None,
-                        children: Vec::new(),
-                    },
-                };
+    /// Add an execution which was uploaded (or written) before, eg one read
+    /// back from disk.  It's keyed by its id, as it's already finished.
+    pub fn push_execution(&mut self, test_data: TestData) {
+        self.data.insert((0, test_data.id.clone()), test_data);
+    }
 
-                self.data.insert(name, data);
-            }
-            TestEvent::Ok { name, exec_time } => {
-                let data = self.data.get_mut(&name).unwrap();
-                data.history.end_at = Some(
-                    Instant::now()
-                        .duration_since(self.started_at.unwrap())
-                        .as_millis() as f64
-                        / 1000000.0,
-                );
-                data.history.duration = Some(exec_time);
-            }
-            TestEvent::Failed {
-                name,
-                exec_time,
-                stdout,
+    /// All the `TestData`, finished or not and including earlier attempts,
+    /// for enrichment before upload.
+    pub fn data_mut(&mut self) -> impl Iterator<Item = &mut TestData> {
+        self.data
+            .values_mut()
+            .chain(self.attempts.values_mut().flatten())
+    }
+
+    /// All the `TestData` which started but never finished, eg because the
+    /// test binary crashed.
+    pub fn unfinished_data(&self) -> Vec<&TestData> {
+        self.data
+            .values()
+            .filter(|event| event.history.end_at.is_none())
+            .collect()
+    }
+
+    /// Fail the tests which started but never finished, eg because the test
+    /// binary aborted or its output was cut short, with a failure chosen for
+    /// each.  They're tagged `incomplete`.
+    ///
+    /// Returns how many tests were failed.
+    pub fn fail_unfinished<F>(&mut self, failure: F) -> usize
+    where
+        F: Fn(&TestData) -> Failure,
+    {
+        let now = self.run_start().epoch_at(Instant::now());
+
+        let mut count = 0;
+        for data in self.data.values_mut().filter(|data| !data.is_finished()) {
+            data.history.end_at = Some(now);
+            let measured = data.history.measured_duration();
+            data.set_duration(
+                &self.duration_policy,
+                &[(DurationSource::Collector, measured)],
+                &mut self.interner,
+            );
+            let failure = failure(data);
+            data.result = TestResult::Failed {
+                failure_reason: intern_reason(
+                    failure.reason.as_deref(),
+                    self.max_failure_bytes,
+                    &mut self.interner,
+                ),
+            };
+            data.failure_expanded = failure.expanded;
+            data.truncate_failure(self.max_failure_bytes);
+            if let Some(location) = failure.location.filter(|_| data.location.is_none()) {
+                data.location = Some(format!("{}:{}", location.file, location.line));
+                data.file_name = Some(location.file);
+            }
+            data.intern_tag("incomplete", "true", &mut self.interner);
+            count += 1;
+        }
+
+        count
+    }
+
+    /// Remove the finished tests, returning them in a payload of their own, eg
+    /// to upload them before the run is over.
+    pub fn take_finished(&mut self) -> Payload {
+        let mut finished = self.new_clean();
+        finished.suite_counts = BTreeMap::new();
+        finished.expected_tests = None;
+        let (complete, incomplete) = std::mem::take(&mut self.data)
+            .into_iter()
+            .partition(|(_, test_data)| test_data.is_finished());
+        finished.data = complete;
+        self.data = incomplete;
+        // Earlier attempts have finished, even if the latest hasn't.
+        finished.attempts = std::mem::take(&mut self.attempts);
+
+        for (suite, _) in finished.data.keys() {
+            self.suite_counts.entry(*suite).or_default().taken += 1;
+        }
+        finished
+    }
+
+    /// Compare the number of tests each suite said it would run (or the
+    /// number set with `with_expected_tests`) with the number which
+    /// finished, counting ignored tests as finished.
+    ///
+    /// Returns `None` if no suite said how many tests it would run.
+    pub fn completeness(&self) -> Option<Completeness> {
+        let mut finished_by_suite = HashMap::new();
+        for ((suite, _), test_data) in &self.data {
+            if test_data.is_finished() {
+                *finished_by_suite.entry(*suite).or_insert(0) += 1;
+            }
+        }
+
+        if let Some(expected) = self.expected_tests {
+            let taken = self
+                .suite_counts
+                .values()
+                .map(|count| count.taken)
+                .sum::<usize>();
+            return Some(Completeness {
+                expected,
+                finished: taken + finished_by_suite.values().sum::<usize>(),
+            });
+        }
+
+        let mut completeness: Option<Completeness> = None;
+        for (suite, count) in &self.suite_counts {
+            let Some(expected) = count.expected else {
+                continue;
+            };
+            let completeness = completeness.get_or_insert(Completeness {
+                expected: 0,
+                finished: 0,
+            });
+            completeness.expected += expected;
+            completeness.finished += count.taken + finished_by_suite.get(suite).unwrap_or(&0);
+        }
+
+        completeness
+    }
+
+    /// Record the test binary (or crate, for doctests) cargo is about to run,
+    /// from its `Running` and `Doc-tests` lines.  It's part of the scope of
+    /// the tests which follow, so tests with the same path in different
+    /// crates can be told apart.
+    pub fn set_binary(&mut self, binary: &str) {
+        self.binary = Some(binary.to_string());
+    }
+
+    /// Remove everything collected so far, leaving an empty payload with the
+    /// same settings.
+    pub fn take(&mut self) -> Payload {
+        let clean = self.new_clean();
+        std::mem::replace(self, clean)
+    }
+
+    /// Tag the whole run, rather than an individual test.
+    pub fn insert_tag(&mut self, key: &str, value: &str) {
+        self.tags.insert(key.to_string(), value.to_string());
+    }
+
+    /// The tags of the whole run.
+    pub fn run_tags(&self) -> &HashMap<String, String> {
+        &self.tags
+    }
+
+    /// Remove a tag from the whole run.
+    pub fn remove_tag(&mut self, key: &str) {
+        self.tags.remove(key);
+    }
+
+    /// Keep only the tests, including earlier attempts, for which `keep`
+    /// returns `true`.
+    pub fn retain_tests<F>(&mut self, mut keep: F)
+    where
+        F: FnMut(&TestData) -> bool,
+    {
+        self.data.retain(|_, test_data| keep(test_data));
+        for attempts in self.attempts.values_mut() {
+            attempts.retain(|test_data| keep(test_data));
+        }
+        self.attempts.retain(|_, attempts| !attempts.is_empty());
+    }
+
+    /// Push a test which has already finished, eg from a JUnit report.
+    ///
+    /// `start_at` is in seconds from the start of the run.  The test is keyed
+    /// by its scope and name, like libtest test names.
+    pub fn push_finished_test(
+        &mut self,
+        scope: &str,
+        name: &str,
+        result: TestResult,
+        start_at: f64,
+        duration: f64,
+        source: DurationSource,
+    ) {
+        self.push_test(
+            TestData::builder()
+                .scope(scope)
+                .name(name)
+                .result(result)
+                .start_at(start_at)
+                .duration(duration)
+                .duration_source(source),
+        );
+    }
+
+    /// Push a test built with `TestData::builder`, giving it the run's id,
+    /// scope prefix, tags and name limits like any other test.
+    ///
+    /// ## Emits warnings
+    ///  - If another test in the run has the same scope and name, in which
+    ///    case this one is renamed.
+    pub fn push_test(&mut self, test: TestDataBuilder) {
+        let start_at = self.run_start().epoch + test.start_at;
+        let mut data = self.new_test_data(&test.scope, &test.name, start_at);
+        data.result = match test.result {
+            TestResult::Failed { failure_reason } => TestResult::Failed {
+                failure_reason: intern_reason(
+                    failure_reason.as_deref(),
+                    self.max_failure_bytes,
+                    &mut self.interner,
+                ),
+            },
+            result => result,
+        };
+        data.history.end_at = Some(start_at + test.duration);
+        data.set_duration(
+            &self.duration_policy,
+            &[(test.duration_source, Some(test.duration))],
+            &mut self.interner,
+        );
+
+        // Eg the same case in two JUnit reports, which would otherwise
+        // replace each other.
+        let (scope, name) = (test.scope, test.name);
+        let mut key = self.key(&format!("{}::{}", scope, name));
+        let mut copy = 1;
+        while self.data.contains_key(&key) || self.is_claimed_elsewhere(key.0, &data) {
+            copy += 1;
+            key = self.key(&format!("{}::{} ({})", scope, name, copy));
+            self.rename_duplicate(&mut data, &name, &copy.to_string());
+        }
+        self.claim(key.0, &data);
+        self.data.insert(key, data);
+    }
+
+    /// Has a test in a suite other than `suite` already got the id of
+    /// `data`?
+    fn is_claimed_elsewhere(&self, suite: usize, data: &TestData) -> bool {
+        Uuid::parse_str(&data.id)
+            .ok()
+            .and_then(|id| self.ids.get(&id))
+            .is_some_and(|claimed| *claimed != suite)
+    }
+
+    /// Record that the id of `data` belongs to a test in `suite`.
+    fn claim(&mut self, suite: usize, data: &TestData) {
+        if let Ok(id) = Uuid::parse_str(&data.id) {
+            self.ids.entry(id).or_insert(suite);
+        }
+    }
+
+    /// Rename `data`, which has the same scope and name as another test in
+    /// the run, to `name (qualifier)`, with an id to match, so that Test
+    /// Engine doesn't merge their results.
+    ///
+    /// ## Emits warnings
+    ///  - Naming both tests.
+    fn rename_duplicate(&mut self, data: &mut TestData, name: &str, qualifier: &str) {
+        let duplicate = data.full_name();
+        data.name = format!("{} ({})", name, qualifier);
+        if !self.random_ids {
+            data.id = test_id(self.suite_name.as_deref(), &data.scope, &data.name).to_string();
+        }
+        eprintln!(
+            "More than one test is named {}, so this one is uploaded as {}.",
+            duplicate,
+            data.full_name()
+        );
+    }
+
+    /// The key of the test named `name` in the current suite, qualified by
+    /// its binary like cargo-nextest's test names.
+    fn key(&mut self, name: &str) -> TestKey {
+        let index = self.clock().index;
+        match self.binary.as_deref() {
+            Some(binary) if !name.contains('$') => (index, format!("{}${}", binary, name)),
+            _ => (index, name.to_string()),
+        }
+    }
+
+    /// When the run started, which is now if nothing has happened yet.
+    fn run_start(&mut self) -> RunStart {
+        *self.started_at.get_or_insert_with(RunStart::now)
+    }
+
+    /// The current suite's clock, starting a new suite if there isn't one.
+    fn clock(&mut self) -> SuiteClock {
+        if let Some(suite) = self.suite {
+            return suite;
+        }
+
+        // Taken now, as other writers' suites may start before this ends.
+        let index = self.next_suite;
+        self.next_suite += 1;
+        let suite = SuiteClock {
+            index,
+            run_start: self.run_start(),
+            explicit: false,
+        };
+        self.suite = Some(suite);
+        suite
+    }
+
+    /// Switch to another writer's suite and test binary, leaving this
+    /// writer's in `context` until it's switched back.
+    pub fn swap_context(&mut self, context: &mut SuiteContext) {
+        std::mem::swap(&mut self.suite, &mut context.suite);
+        std::mem::swap(&mut self.binary, &mut context.binary);
+    }
+
+    fn new_test_data(&mut self, scope: &str, name: &str, start_at: f64) -> TestData {
+        // Rules match the test's own name, whatever the prefix.
+        let path = match scope {
+            "" => name.to_string(),
+            scope => format!("{}::{}", scope, name),
+        };
+        let scope = match (&self.scope_prefix, scope) {
+            (Some(prefix), "") => prefix.clone(),
+            (Some(prefix), scope) => format!("{}::{}", prefix, scope),
+            (None, scope) => scope.to_string(),
+        };
+        let id = if self.random_ids {
+            Uuid::new_v4()
+        } else {
+            test_id(self.suite_name.as_deref(), &scope, name)
+        };
+        // Defaults first, so that later rules replace them.  Every test has
+        // them, so they're shared rather than copied.
+        let mut tags = HashMap::new();
+        for (key, value) in self
+            .default_tags
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .chain(self.tag_rules.tags(&path))
+        {
+            tags.insert(self.interner.intern(key), self.interner.intern(value));
+        }
+
+        let (name, full_name) = self.name_limits.apply(name);
+        if let Some(full_name) = full_name {
+            tags.insert(self.interner.intern("full_name"), Arc::from(full_name));
+        }
+        if let Some(suite_name) = &self.suite_name {
+            tags.insert(
+                self.interner.intern("suite.name"),
+                self.interner.intern(suite_name),
+            );
+        }
+
+        TestData {
+            id: id.to_string(),
+            scope: self.interner.intern(&scope),
+            name,
+            location: None,
+            file_name: None,
+            result: TestResult::Passed,
+            failure_expanded: Vec::new(),
+            history: TestHistory {
+                section: self.interner.intern("top"),
+                start_at: Some(start_at),
+                end_at: None,
+                duration: None,
+                detail: HashMap::new(),
+                children: Vec::new(),
+            },
+            tags,
+            extra: serde_json::Map::new(),
+        }
+    }
+
+    /// Record a `#[bench]` result against the test libtest started for it,
+    /// using the median time per iteration as its duration.  It's tagged as
+    /// a benchmark (unless its harness gave it another kind), with its
+    /// statistics as both tags and the detail of its history.
+    fn push_bench(
+        &mut self,
+        name: String,
+        median: f64,
+        deviation: f64,
+        mib_per_second: Option<u64>,
+    ) {
+        let key = self.key(&name);
+        if !self.data.contains_key(&key) {
+            self.push_test_event(&key, TestEvent::Started { name });
+        }
+
+        let Some(data) = self.data.get_mut(&key) else {
+            return;
+        };
+
+        let start_at = data.history.start_at.unwrap_or_default();
+        let median_secs = median / 1_000_000_000.0;
+        data.history.end_at = Some(start_at + median_secs);
+        data.set_duration(
+            &self.duration_policy,
+            &[(DurationSource::Libtest, Some(median_secs))],
+            &mut self.interner,
+        );
+
+        let mut statistics = vec![
+            ("median_ns", median.to_string()),
+            ("deviation_ns", deviation.to_string()),
+        ];
+        if let Some(mib_per_second) = mib_per_second {
+            statistics.push(("mib_per_second", mib_per_second.to_string()));
+        }
+        for (key, value) in statistics {
+            // The statistics differ for every benchmark, so aren't shared.
+            let tag = self.interner.intern(&format!("bench.{}", key));
+            data.tags.insert(tag, Arc::from(value.as_str()));
+            data.history.detail.insert(key.to_string(), value);
+        }
+        if data.tag("kind").is_none() {
+            data.intern_tag("kind", "bench", &mut self.interner);
+        }
+    }
+
+    fn push_suite_event(&mut self, suite_event: SuiteEvent) {
+        match suite_event {
+            SuiteEvent::Started { test_count } => {
+                // Events which arrived before the suite started belong to
+                // it, but a suite which started and never finished (eg
+                // because its binary crashed) is over.
+                if self.suite.is_some_and(|suite| suite.explicit) {
+                    self.suite = None;
+                }
+                let mut suite = self.clock();
+                suite.explicit = true;
+                self.suite = Some(suite);
+                self.suite_counts.entry(suite.index).or_default().expected = Some(test_count);
+            }
+            SuiteEvent::Ok { results } | SuiteEvent::Failed { results } => {
+                let suite = self.clock();
+                self.suite_counts.entry(suite.index).or_default().results = Some(results);
+                self.finished_at = Some(Instant::now());
+                self.suite = None;
+            }
+        }
+    }
+
+    /// The totals of the counts and times the suites reported when they
+    /// finished, as run tags, so they can be checked against cargo's own
+    /// summary.
+    fn suite_results_tags(&self) -> Vec<(String, String)> {
+        let mut suites = self
+            .suite_counts
+            .values()
+            .filter_map(|count| count.results)
+            .peekable();
+        if suites.peek().is_none() {
+            return Vec::new();
+        }
+
+        let mut total = SuiteResults::default();
+        for suite in suites {
+            total.passed += suite.passed;
+            total.failed += suite.failed;
+            total.ignored += suite.ignored;
+            total.measured += suite.measured;
+            total.filtered_out += suite.filtered_out;
+            if let Some(exec_time) = suite.exec_time {
+                total.exec_time = Some(total.exec_time.unwrap_or_default() + exec_time);
+            }
+        }
+
+        let mut tags = vec![
+            ("libtest.passed", total.passed.to_string()),
+            ("libtest.failed", total.failed.to_string()),
+            ("libtest.ignored", total.ignored.to_string()),
+            ("libtest.measured", total.measured.to_string()),
+            ("libtest.filtered_out", total.filtered_out.to_string()),
+        ];
+        if let Some(exec_time) = total.exec_time {
+            tags.push(("libtest.exec_time", exec_time.to_string()));
+        }
+        tags.into_iter()
+            .map(|(key, value)| (key.to_string(), value))
+            .collect()
+    }
+
+    /// Warn (once) when libtest wasn't run with `--report-time`.
+    fn check_exec_time(&mut self, exec_time: Option<f64>) {
+        if exec_time.is_none() && !self.warned_exec_time {
+            self.warned_exec_time = true;
+            eprintln!("Test results are missing exec_time, so durations will be approximate.  Pass --report-time to libtest for accurate durations.");
+        }
+    }
+
+    /// Record `test_event` against the test at `key`, which is worked out
+    /// once by the caller rather than for every use.
+    fn push_test_event(&mut self, key: &TestKey, test_event: TestEvent) {
+        match test_event {
+            TestEvent::Started { name } => {
+                let (binary_id, path) = input::split_binary_id(&name);
+                let (kind, path) = input::split_kind(path);
+                // Tests in different binaries (eg the crates of a workspace)
+                // can share a path, so the binary is part of their scope.
+                let binary = binary_id.map(str::to_string).or(self.binary.clone());
+
+                let start_at = self.clock().now();
+
+                // Doctests are scoped by their file, since their item paths
+                // would otherwise be mangled by splitting on `::`.
+                let mut data = match DoctestName::parse(path) {
+                    Some(doctest) => {
+                        let test_name = match doctest.item {
+                            "" => format!("(line {})", doctest.line),
+                            item => format!("{} (line {})", item, doctest.line),
+                        };
+                        let scope = match &binary {
+                            Some(binary) => format!("{}::{}", binary, doctest.file),
+                            None => doctest.file.to_string(),
+                        };
+                        let mut data = self.new_test_data(&scope, &test_name, start_at);
+                        data.location = Some(format!("{}:{}", doctest.file, doctest.line));
+                        data.file_name = Some(doctest.file.to_string());
+                        data
+                    }
+                    None => {
+                        let (scope, test_name) = match (binary, input::split_scope(path)) {
+                            (Some(binary), ("", test_name)) => (binary, test_name),
+                            (Some(binary), (module, test_name)) => {
+                                (format!("{}::{}", binary, module), test_name)
+                            }
+                            (None, (module, test_name)) => (module.to_string(), test_name),
+                        };
+                        self.new_test_data(&scope, test_name, start_at)
+                    }
+                };
+
+                let interner = &mut self.interner;
+                if let Some(binary_id) = binary_id {
+                    data.intern_tag("nextest.binary_id", binary_id, interner);
+                }
+                if let Some(kind) = kind {
+                    data.intern_tag("kind", kind, interner);
+                }
+                if let Some(case) = CaseName::parse(path).filter(|_| self.case_tags) {
+                    data.intern_tag("case.test", case.test, interner);
+                    data.intern_tag("case.index", &case.index.to_string(), interner);
+                    if let Some(description) = case.description {
+                        data.insert_tag("case.description", description);
+                    }
+                }
+
+                // Tests in different suites can share a scope and name, eg
+                // when cargo's `Running` lines weren't collected so their
+                // binaries are unknown.
+                if self.is_claimed_elsewhere(key.0, &data) {
+                    let name = data.name.clone();
+                    self.rename_duplicate(&mut data, &name, &format!("suite {}", key.0 + 1));
+                }
+                self.claim(key.0, &data);
+
+                // cargo-nextest retries failed tests by starting them again,
+                // and each attempt is an execution of its own.
+                if let Some(mut previous) = self.data.remove(key).filter(|data| data.is_finished())
+                {
+                    let attempt = previous
+                        .tag("attempt")
+                        .and_then(|attempt| attempt.parse::<usize>().ok())
+                        .unwrap_or(1);
+                    let interner = &mut self.interner;
+                    previous.intern_tag("attempt", &attempt.to_string(), interner);
+                    data.intern_tag("attempt", &(attempt + 1).to_string(), interner);
+                    if !self.random_ids {
+                        data.id = attempt_id(&data.id, attempt + 1).to_string();
+                    }
+                    self.attempts.entry(key.clone()).or_default().push(previous);
+                }
+
+                self.data.insert(key.clone(), data);
+            }
+            TestEvent::Ok {
+                exec_time, stdout, ..
+            } => {
+                self.check_exec_time(exec_time);
+                let end_at = self.clock().now();
+                let Some(data) = self.data.get_mut(key) else {
+                    return;
+                };
+                let interner = &mut self.interner;
+                data.apply_markers(stdout.as_deref().unwrap_or_default(), interner);
+                if data.tags.contains_key("attempt") {
+                    data.intern_tag("flaky", "true", interner);
+                }
+                // It may have been reported as timed out before finishing.
+                data.result = TestResult::Passed;
+                data.finish_at(end_at, exec_time, &self.duration_policy, interner);
+            }
+            TestEvent::Failed {
+                exec_time,
+                stdout,
+                stderr,
+                message,
                 ..
             } => {
-                let data = self.data.get_mut(&name).unwrap();
-                data.history.end_at = Some(
-                    Instant::now()
-                        .duration_since(self.started_at.unwrap())
-                        .as_millis() as f64
-                        / 1000000.0,
-                );
-                data.history.duration = Some(exec_time);
+                self.check_exec_time(exec_time);
+                let end_at = self.clock().now();
+                let Some(data) = self.data.get_mut(key) else {
+                    return;
+                };
+                let interner = &mut self.interner;
+                data.finish_at(end_at, exec_time, &self.duration_policy, interner);
+                let output = failure::combine(stdout.as_deref(), stderr.as_deref());
+                data.apply_markers(&output, interner);
+                let output = marker::strip(&output);
+                let failure = failure::parse(&output);
+                for (key, value) in property::tags(&output) {
+                    data.insert_tag(key, &value);
+                }
+                // libtest explains failures which weren't panics, eg a
+                // panic=abort test's process exiting abnormally, separately.
+                // Many tests can fail the same way, eg when a shared fixture
+                // breaks, so the reason is shared.
                 data.result = TestResult::Failed {
-                    failure_reason: stdout,
+                    failure_reason: intern_reason(
+                        failure.reason.as_deref().or(message.as_deref()),
+                        self.max_failure_bytes,
+                        interner,
+                    ),
+                };
+                data.failure_expanded = failure.expanded;
+                data.truncate_failure(self.max_failure_bytes);
+
+                // Doctests already know where they are.
+                if let Some(location) = failure.location.filter(|_| data.location.is_none()) {
+                    data.location = Some(format!("{}:{}", location.file, location.line));
+                    data.file_name = Some(location.file);
                 }
             }
-            TestEvent::Ignored { .. } => {}
-            TestEvent::Timeout { .. } => {}
+            TestEvent::Ignored { name } => {
+                // libtest starts ignored tests too, but its text output (and
+                // some custom harnesses) only report them as ignored.
+                if !self.data.contains_key(key) {
+                    self.push_test_event(key, TestEvent::Started { name });
+                }
+                let Some(data) = self.data.get_mut(key) else {
+                    return;
+                };
+                // They never ran, so took no time.
+                data.result = TestResult::Skipped;
+                data.history.end_at = data.history.start_at;
+                data.set_duration(
+                    &self.duration_policy,
+                    &[(DurationSource::Collector, Some(0.0))],
+                    &mut self.interner,
+                );
+            }
+            TestEvent::Timeout { .. } => {
+                // libtest reports tests which run for too long, but they may
+                // still finish later, in which case that result wins.
+                let end_at = self.clock().now();
+                let Some(data) = self.data.get_mut(key) else {
+                    return;
+                };
+                let interner = &mut self.interner;
+                data.history.end_at = Some(end_at);
+                let measured = data.history.measured_duration();
+                data.set_duration(
+                    &self.duration_policy,
+                    &[(DurationSource::Collector, measured)],
+                    interner,
+                );
+                data.result = TestResult::Failed {
+                    failure_reason: Some(interner.intern("test exceeded time limit")),
+                };
+                data.intern_tag("timeout", "true", interner);
+            }
         }
     }
 }
 
+/// Trim a failure's `reason` to `max_bytes`, and share it through
+/// `interner`.
+fn intern_reason(
+    reason: Option<&str>,
+    max_bytes: usize,
+    interner: &mut Interner,
+) -> Option<Arc<str>> {
+    let reason = reason?;
+    if reason.len() <= max_bytes {
+        return Some(interner.intern(reason));
+    }
+
+    let mut reason = reason.to_string();
+    failure::truncate(&mut reason, max_bytes);
+    Some(interner.intern(&reason))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -264,36 +1737,1349 @@ mod test {
 
         for _ in 0..finished_size {
             let td = stub_test_data(true);
-            payload.data.insert(td.name.clone(), td);
+            payload.data.insert((0, td.name.clone()), td);
         }
 
         for _ in 0..unfinished_size {
             let td = stub_test_data(false);
-            payload.data.insert(td.name.clone(), td);
+            payload.data.insert((0, td.name.clone()), td);
         }
 
-        let payloads = payload.batchify(batch_size);
+        let payload_run_env = payload.run_env.clone();
+        let payloads = payload.batchify(batch_size, usize::MAX);
 
         assert_eq!(payloads.len(), 2);
         assert_eq!(payloads[0].data.len(), batch_size);
+        // Each carries the run's full runtime environment.
+        for batch in &payloads {
+            assert_eq!(batch.run_env, payload_run_env);
+            assert!(!batch.refers_to_run());
+        }
 
         for td in payloads[0].data.values() {
             assert!(td.is_finished());
         }
 
+        // Unfinished tests are dropped rather than copied into the last.
+        assert_eq!(payloads[1].data.len(), finished_size - batch_size);
+        for td in payloads[1].data.values() {
+            assert!(td.is_finished());
+        }
+    }
+
+    #[test]
+    fn each_test_is_in_exactly_one_batch() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+        for index in 0..25 {
+            let td = stub_test_data(index % 5 != 0);
+            payload.data.insert((0, td.name.clone()), td);
+        }
+        // Fail the unfinished ones, as `finish` does.
+        payload.fail_unfinished(|_| Failure::with_reason("aborted".to_string()));
+
+        let mut ids = payload
+            .batchify(7, usize::MAX)
+            .iter()
+            .flat_map(|batch| batch.data.values().map(|td| td.id.clone()))
+            .collect::<Vec<String>>();
+        assert_eq!(ids.len(), 25);
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), 25);
+    }
+
+    #[test]
+    fn run_env_is_sent_in_full_unless_referring_to_the_run() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+        let json = serde_json::to_value(&payload).unwrap();
+        // Unknown fields are sent as null.
+        assert_eq!(json["run_env"]["branch"], serde_json::Value::Null);
+        assert_eq!(json["run_env"].as_object().unwrap().len(), 10);
+
+        payload.set_refer_to_run(true);
+        let json = serde_json::to_value(&payload).unwrap();
+        let mut fields: Vec<_> = json["run_env"].as_object().unwrap().keys().collect();
+        fields.sort();
+        assert_eq!(fields, ["ci", "collector", "key", "version"]);
+        assert_eq!(json["run_env"]["key"], payload.run_env().key());
+    }
+
+    #[test]
+    fn writers_suites_are_kept_apart() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+        let mut contexts = [SuiteContext::default(), SuiteContext::default()];
+        // Two invocations writing at once, with the same test names.
+        for (writer, line) in [
+            (
+                0,
+                r#"{ "type": "suite", "event": "started", "test_count": 1 }"#,
+            ),
+            (
+                1,
+                r#"{ "type": "suite", "event": "started", "test_count": 2 }"#,
+            ),
+            (
+                0,
+                r#"{ "type": "test", "event": "started", "name": "tests::a" }"#,
+            ),
+            (
+                1,
+                r#"{ "type": "test", "event": "started", "name": "tests::a" }"#,
+            ),
+            (
+                1,
+                r#"{ "type": "test", "event": "started", "name": "tests::b" }"#,
+            ),
+            (
+                0,
+                r#"{ "type": "test", "name": "tests::a", "event": "ok" }"#,
+            ),
+            (
+                0,
+                r#"{ "type": "suite", "event": "ok", "passed": 1, "failed": 0 }"#,
+            ),
+            (
+                1,
+                r#"{ "type": "test", "name": "tests::a", "event": "failed" }"#,
+            ),
+            (
+                1,
+                r#"{ "type": "test", "name": "tests::b", "event": "ok" }"#,
+            ),
+            (
+                1,
+                r#"{ "type": "suite", "event": "failed", "passed": 1, "failed": 1 }"#,
+            ),
+        ] {
+            payload.swap_context(&mut contexts[writer]);
+            crate::input::parse_line(line, &mut payload).unwrap();
+            payload.swap_context(&mut contexts[writer]);
+        }
+
+        assert_eq!(payload.data.len(), 3);
+        assert!(!payload.data[&(0, "tests::a".to_string())].is_failed());
+        assert!(payload.data[&(1, "tests::a".to_string())].is_failed());
+        assert!(!payload.data[&(1, "tests::b".to_string())].is_failed());
+        assert_eq!(
+            payload.completeness(),
+            Some(Completeness {
+                expected: 3,
+                finished: 3
+            })
+        );
+    }
+
+    #[test]
+    fn repeated_tags_and_failures_are_shared() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic())
+            .with_default_tags(vec![("team".to_string(), "search".to_string())]);
+        for name in ["a::one", "a::two"] {
+            for line in [
+                format!(
+                    r#"{{ "type": "test", "event": "started", "name": "{}" }}"#,
+                    name
+                ),
+                format!(
+                    r#"{{ "type": "test", "name": "{}", "event": "failed", "stdout": "boom" }}"#,
+                    name
+                ),
+            ] {
+                crate::input::parse_line(&line, &mut payload).unwrap();
+            }
+        }
+
+        let one = &payload.data[&(0, "a::one".to_string())];
+        let two = &payload.data[&(0, "a::two".to_string())];
+        assert!(Arc::ptr_eq(&one.tags["team"], &two.tags["team"]));
+        match (&one.result, &two.result) {
+            (
+                TestResult::Failed {
+                    failure_reason: Some(one),
+                },
+                TestResult::Failed {
+                    failure_reason: Some(two),
+                },
+            ) => assert!(Arc::ptr_eq(one, two)),
+            results => panic!("expected both to fail, got {:?}", results),
+        }
+    }
+
+    #[test]
+    fn idempotency_keys_identify_each_batch() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+        let key = payload.idempotency_key();
+        assert_eq!(key, payload.idempotency_key());
+        assert!(key.starts_with(&format!("{}-0-", payload.run_env().key())));
+
+        for line in [
+            r#"{ "type": "test", "event": "started", "name": "a::one" }"#,
+            r#"{ "type": "test", "name": "a::one", "event": "ok" }"#,
+        ] {
+            crate::input::parse_line(line, &mut payload).unwrap();
+        }
+        assert_ne!(key, payload.idempotency_key());
+    }
+
+    #[test]
+    fn idempotency_keys_are_the_same_for_the_same_batch() {
+        let run_env = RuntimeEnvironment::generic();
+        let tests: Vec<TestData> = (0..6)
+            .map(|index| {
+                let mut td = stub_test_data(true);
+                td.name = format!("test_{}", index);
+                for tag in ["a", "b", "c", "d", "e", "f"] {
+                    td.insert_tag(tag, &index.to_string());
+                }
+                td
+            })
+            .collect();
+        // Maps are seeded differently each time, so ordered differently.
+        let batchify = |order: &mut dyn Iterator<Item = &TestData>| {
+            let mut payload = Payload::new(run_env.clone());
+            for tag in ["x", "y", "z"] {
+                payload.insert_tag(tag, "run");
+            }
+            for td in order {
+                payload.data.insert((0, td.name.clone()), td.clone());
+            }
+            payload.batchify(4, usize::MAX)
+        };
+
+        let mut batches = batchify(&mut tests.iter());
+        let rebuilt = batchify(&mut tests.iter().rev());
+        assert_eq!(batches.len(), 2);
+        let keys: Vec<String> = batches.iter().map(Payload::idempotency_key).collect();
         assert_eq!(
-            payloads[1].data.len(),
-            finished_size + unfinished_size - batch_size
+            keys,
+            rebuilt
+                .iter()
+                .map(Payload::idempotency_key)
+                .collect::<Vec<_>>()
         );
+        assert!(keys[1].starts_with(&format!("{}-1-", run_env.key())));
+
+        // Retried, or sent in full after a reference was refused.
+        batches[1].set_refer_to_run(true);
+        assert_eq!(batches[1].idempotency_key(), keys[1]);
+        batches[1].set_refer_to_run(false);
+        assert_eq!(batches[1].idempotency_key(), keys[1]);
+    }
 
-        let (finished, unfinished): (Vec<TestData>, Vec<TestData>) = payloads[1]
+    #[test]
+    fn batches_are_split_by_size() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+
+        for (index, output_len) in [10, 10, 5000, 10, 10, 10].into_iter().enumerate() {
+            let mut td = stub_test_data(true);
+            td.name = format!("test_{}", index);
+            td.result = TestResult::Failed {
+                failure_reason: Some("x".repeat(output_len).into()),
+            };
+            payload.data.insert((0, td.name.clone()), td);
+        }
+        let small = payload
             .data
             .values()
-            .cloned()
-            .partition(|td| td.is_finished());
+            .map(preflight::serialized_len)
+            .min()
+            .unwrap();
+
+        let batches = payload.batchify(100, small * 2 + 100);
+        let sizes = batches
+            .iter()
+            .map(|batch| batch.data.len())
+            .collect::<Vec<usize>>();
+
+        // The big test is alone, and the small ones in twos at most.
+        assert_eq!(sizes.iter().sum::<usize>(), 6);
+        assert!(sizes.iter().all(|size| *size <= 2));
+        assert!(batches.iter().any(|batch| batch.data.len() == 1
+            && batch.data.values().any(|td| td
+                .failure_reason()
+                .is_some_and(|reason| reason.len() == 5000))));
+    }
+
+    /// Run with `cargo test --release batching_throughput -- --ignored
+    /// --nocapture` to see how quickly a large payload is split into batches.
+    #[test]
+    #[ignore]
+    fn batching_throughput() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+        for index in 0..200_000 {
+            let mut td = stub_test_data(index % 100 != 0);
+            td.result = TestResult::Failed {
+                failure_reason: Some(format!("assertion failed in case {}", index).into()),
+            };
+            payload.data.insert((0, td.name.clone()), td);
+        }
+
+        let started = std::time::Instant::now();
+        let batches = payload.batchify(5000, usize::MAX);
+        let elapsed = started.elapsed();
 
-        assert_eq!(finished.len(), finished_size - batch_size);
-        assert_eq!(unfinished.len(), unfinished_size);
+        assert_eq!(batches.len(), 40);
+        eprintln!("Split 200000 tests into batches in {:?}", elapsed);
+    }
+
+    #[test]
+    fn long_names_are_truncated_with_a_hash_suffix() {
+        let limits = NameLimits {
+            max_length: 32,
+            max_tag_length: 64,
+        };
+
+        let (name, full_name) = limits.apply("short_name");
+        assert_eq!(name, "short_name");
+        assert_eq!(full_name, None);
+
+        let long_a = format!("{}_a", "x".repeat(40));
+        let long_b = format!("{}_b", "x".repeat(40));
+        let (name_a, full_name_a) = limits.apply(&long_a);
+        let (name_b, _) = limits.apply(&long_b);
+
+        assert_eq!(name_a.len(), 32);
+        assert_ne!(name_a, name_b);
+        assert_eq!(name_a, limits.apply(&long_a).0);
+        assert_eq!(full_name_a, Some(long_a));
+
+        let (name, full_name) = limits.apply(&"é".repeat(50));
+        assert!(name.len() <= 32);
+        assert_eq!(full_name, None);
+    }
+
+    #[test]
+    fn suite_name_is_tagged_on_every_test() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic())
+            .with_suite_name(Some("integration".to_string()));
+
+        payload.push(Event::Suite {
+            event: SuiteEvent::Started { test_count: 1 },
+        });
+        payload.push(Event::Test {
+            event: TestEvent::Started {
+                name: "api::test::submit".to_string(),
+            },
+            nextest: None,
+            extra: Default::default(),
+        });
+
+        let test_data = payload
+            .data
+            .get(&(0, "api::test::submit".to_string()))
+            .unwrap();
+        assert_eq!(test_data.tag("suite.name"), Some("integration"));
+    }
+
+    #[test]
+    fn duration_source_is_recorded() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+
+        payload.push(Event::Suite {
+            event: SuiteEvent::Started { test_count: 1 },
+        });
+        payload.push(Event::Test {
+            event: TestEvent::Started {
+                name: "api::test::submit".to_string(),
+            },
+            nextest: None,
+            extra: Default::default(),
+        });
+        payload.push(Event::Test {
+            event: TestEvent::Ok {
+                name: "api::test::submit".to_string(),
+                exec_time: Some(0.25),
+                stdout: None,
+            },
+            nextest: None,
+            extra: Default::default(),
+        });
+
+        let test_data = payload
+            .data
+            .get(&(0, "api::test::submit".to_string()))
+            .unwrap();
+        assert_eq!(test_data.history.duration, Some(0.25));
+        assert_eq!(test_data.tag("duration_source"), Some("libtest"));
+        assert_eq!(
+            test_data.history.end_at,
+            test_data.history.start_at.map(|start_at| start_at + 0.25)
+        );
+    }
+
+    #[test]
+    fn nextest_events_are_recognised() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+        let nextest =
+            r#""nextest": { "crate": "my-crate", "test_binary": "my_crate", "kind": "lib" }"#;
+
+        for line in [
+            format!(
+                r#"{{ "type": "suite", "event": "started", "test_count": 1, {} }}"#,
+                nextest
+            ),
+            format!(
+                r#"{{ "type": "test", "event": "started", "name": "my-crate$tests::flaky", {} }}"#,
+                nextest
+            ),
+            format!(
+                r#"{{ "type": "test", "event": "failed", "name": "my-crate$tests::flaky", "exec_time": 0.1, {} }}"#,
+                nextest
+            ),
+            format!(
+                r#"{{ "type": "test", "event": "started", "name": "my-crate$tests::flaky", {} }}"#,
+                nextest
+            ),
+            format!(
+                r#"{{ "type": "test", "event": "ok", "name": "my-crate$tests::flaky", "exec_time": 0.1, {} }}"#,
+                nextest
+            ),
+        ] {
+            crate::input::parse_line(&line, &mut payload).unwrap();
+        }
+
+        let test_data = payload
+            .data
+            .get(&(0, "my-crate$tests::flaky".to_string()))
+            .unwrap();
+        assert_eq!(&*test_data.scope, "my-crate::tests");
+        assert_eq!(test_data.name, "flaky");
+        assert_eq!(test_data.result, TestResult::Passed);
+
+        for (key, value) in [
+            ("nextest.binary_id", "my-crate"),
+            ("nextest.crate", "my-crate"),
+            ("nextest.test_binary", "my_crate"),
+            ("nextest.kind", "lib"),
+            ("attempt", "2"),
+            ("flaky", "true"),
+        ] {
+            assert_eq!(test_data.tag(key), Some(value));
+        }
+    }
+
+    #[test]
+    fn retries_are_uploaded_as_executions_of_their_own() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+
+        for event in ["failed", "failed", "ok"] {
+            for line in [
+                r#"{ "type": "test", "event": "started", "name": "my-crate$tests::flaky" }"#
+                    .to_string(),
+                format!(
+                    r#"{{ "type": "test", "event": "{}", "name": "my-crate$tests::flaky" }}"#,
+                    event
+                ),
+            ] {
+                crate::input::parse_line(&line, &mut payload).unwrap();
+            }
+        }
+
+        // Only the final attempt counts.
+        assert_eq!(payload.closed_data().len(), 1);
+        assert_eq!(payload.completeness(), None);
+
+        let json = serde_json::to_value(&payload).unwrap();
+        let data = json["data"].as_array().unwrap();
+        let field = |field: &str| {
+            data.iter()
+                .map(|test| test[field].clone())
+                .collect::<Vec<serde_json::Value>>()
+        };
+        assert_eq!(field("result"), ["failed", "failed", "passed"]);
+        assert_eq!(
+            data.iter()
+                .map(|test| test["tags"]["attempt"].clone())
+                .collect::<Vec<serde_json::Value>>(),
+            ["1", "2", "3"]
+        );
+        let mut ids = field("id");
+        ids.dedup();
+        assert_eq!(ids.len(), 3);
+
+        // Attempts stay with the final one in a batch.
+        let batches = payload.batchify(2, usize::MAX);
+        assert_eq!(batches.len(), 1);
+        assert_eq!(
+            serde_json::to_value(&batches[0]).unwrap()["data"]
+                .as_array()
+                .unwrap()
+                .len(),
+            3
+        );
+    }
+
+    #[test]
+    fn suite_results_are_totalled_as_run_tags() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+        assert!(serde_json::to_value(&payload)
+            .unwrap()
+            .get("tags")
+            .is_none());
+
+        for line in [
+            r#"{ "type": "suite", "event": "started", "test_count": 3 }"#,
+            r#"{ "type": "suite", "event": "ok", "passed": 2, "failed": 0, "ignored": 1, "measured": 0, "filtered_out": 4, "exec_time": 0.5 }"#,
+            r#"{ "type": "suite", "event": "started", "test_count": 1 }"#,
+            r#"{ "type": "suite", "event": "failed", "passed": 0, "failed": 1, "ignored": 0, "measured": 0, "filtered_out": 0, "exec_time": 0.25 }"#,
+        ] {
+            crate::input::parse_line(line, &mut payload).unwrap();
+        }
+
+        let tags = &serde_json::to_value(&payload).unwrap()["tags"];
+        assert_eq!(tags["libtest.passed"], "2");
+        assert_eq!(tags["libtest.failed"], "1");
+        assert_eq!(tags["libtest.ignored"], "1");
+        assert_eq!(tags["libtest.measured"], "0");
+        assert_eq!(tags["libtest.filtered_out"], "4");
+        assert_eq!(tags["libtest.exec_time"], "0.75");
+    }
+
+    #[test]
+    fn scopes_are_prefixed() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic())
+            .with_scope_prefix(Some("integration".to_string()))
+            .with_tag_rules(TagRules::parse("api::* team=api"));
+
+        for line in [
+            r#"{ "type": "test", "event": "started", "name": "api::tests::works" }"#,
+            r#"{ "type": "test", "event": "started", "name": "top_level" }"#,
+        ] {
+            crate::input::parse_line(line, &mut payload).unwrap();
+        }
+        let unprefixed =
+            Payload::new(RuntimeEnvironment::generic()).new_test_data("api::tests", "works", 0.0);
+
+        let mut tests = payload.unfinished_data();
+        tests.sort_by_key(|test_data| test_data.full_name());
+        assert_eq!(tests[0].full_name(), "integration::api::tests::works");
+        assert_eq!(tests[0].tag("team"), Some("api"));
+        assert_ne!(tests[0].id, unprefixed.id);
+        assert_eq!(tests[1].full_name(), "integration::top_level");
+    }
+
+    #[test]
+    fn huge_failure_output_is_trimmed() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic()).with_max_failure_bytes(1000);
+        let stdout = format!(
+            "thread 'tests::noisy' panicked at src/lib.rs:3:5:\\n{}",
+            "spam\\n".repeat(10_000)
+        );
+
+        for line in [
+            r#"{ "type": "test", "event": "started", "name": "tests::noisy" }"#.to_string(),
+            format!(
+                r#"{{ "type": "test", "name": "tests::noisy", "event": "failed", "stdout": "{}" }}"#,
+                stdout
+            ),
+        ] {
+            crate::input::parse_line(&line, &mut payload).unwrap();
+        }
+
+        let test_data = payload.closed_data()[0];
+        let reason = test_data.failure_reason().unwrap();
+        assert!(reason.len() <= 1000);
+        assert!(reason.contains("bytes trimmed"));
+        let expanded = &test_data.failure_expanded[0].expanded;
+        assert_eq!(
+            expanded[0],
+            "thread 'tests::noisy' panicked at src/lib.rs:3:5:"
+        );
+        assert!(expanded.iter().map(|line| line.len() + 1).sum::<usize>() <= 1000);
+    }
+
+    #[test]
+    fn failures_without_a_panic_use_libtests_message() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+
+        for line in [
+            r#"{ "type": "test", "event": "started", "name": "tests::aborts" }"#,
+            r#"{ "type": "test", "name": "tests::aborts", "event": "failed", "stdout": "", "message": "child process exited with signal 6" }"#,
+        ] {
+            crate::input::parse_line(line, &mut payload).unwrap();
+        }
+
+        let test_data = payload.closed_data()[0];
+        assert_eq!(
+            test_data.failure_reason(),
+            Some("child process exited with signal 6")
+        );
+        assert!(!test_data.extra.contains_key("message"));
+    }
+
+    #[test]
+    fn failures_include_stderr() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+
+        for line in [
+            r#"{ "type": "test", "event": "started", "name": "tests::asserts" }"#,
+            r#"{ "type": "test", "name": "tests::asserts", "event": "failed", "stdout": "", "stderr": "expected 1, got 2\n" }"#,
+            r#"{ "type": "test", "event": "started", "name": "tests::both" }"#,
+            r#"{ "type": "test", "name": "tests::both", "event": "failed", "stdout": "setting up\n", "stderr": "thread 'tests::both' panicked at src/lib.rs:7:5:\nboom\n" }"#,
+        ] {
+            crate::input::parse_line(line, &mut payload).unwrap();
+        }
+
+        let mut tests = payload.closed_data();
+        tests.sort_by_key(|test_data| test_data.name().to_string());
+        assert_eq!(tests[0].failure_reason(), Some("expected 1, got 2"));
+        assert_eq!(tests[1].failure_reason(), Some("boom"));
+        assert_eq!(tests[1].file_name(), Some("src/lib.rs"));
+        assert_eq!(
+            tests[1].failure_expanded[0].expanded[..2],
+            ["setting up", "---- stderr ----"]
+        );
+    }
+
+    #[test]
+    fn libtest_mimic_events_are_recognised() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+
+        for line in [
+            r#"{ "type": "suite", "event": "started", "test_count": 4 }"#,
+            r#"{ "type": "test", "event": "started", "name": "[fixture] parses::empty" }"#,
+            r#"{ "type": "test", "name": "[fixture] parses::empty", "event": "ok" }"#,
+            r#"{ "type": "test", "event": "started", "name": "parses::broken" }"#,
+            r#"{ "type": "test", "name": "parses::broken", "event": "failed", "stdout": "Error: \"unexpected token\"\n" }"#,
+            r#"{ "type": "test", "name": "parses::slow", "event": "ignored" }"#,
+            r#"{ "type": "bench", "name": "parses::large", "median": 1500, "deviation": 30 }"#,
+            r#"{ "type": "suite", "event": "failed", "passed": 1, "failed": 1, "ignored": 1, "measured": 1 }"#,
+        ] {
+            crate::input::parse_line(line, &mut payload).unwrap();
+        }
+
+        let mut tests = payload.closed_data();
+        tests.sort_by_key(|test_data| test_data.name().to_string());
+
+        assert_eq!(tests.len(), 4);
+        assert_eq!(tests[0].name(), "broken");
+        assert_eq!(
+            tests[0].failure_reason(),
+            Some("Error: \"unexpected token\"")
+        );
+        assert_eq!(tests[1].name(), "empty");
+        assert_eq!(tests[1].tag("kind"), Some("fixture"));
+        assert_eq!(tests[2].name(), "large");
+        assert_eq!(tests[2].tag("bench.median_ns"), Some("1500"));
+        assert_eq!(tests[3].name(), "slow");
+        assert_eq!(tests[3].result, TestResult::Skipped);
+        assert_eq!(payload.unfinished_data().len(), 0);
+    }
+
+    #[test]
+    fn parameterised_cases_are_tagged_when_enabled() {
+        for case_tags in [false, true] {
+            let mut payload = Payload::new(RuntimeEnvironment::generic()).with_case_tags(case_tags);
+            payload.push(Event::Test {
+                event: TestEvent::Started {
+                    name: "tests::parses::case_3_negative_input".to_string(),
+                },
+                nextest: None,
+                extra: Default::default(),
+            });
+
+            let test_data = payload.data.values().next().unwrap();
+            assert_eq!(test_data.name, "case_3_negative_input");
+            if case_tags {
+                assert_eq!(test_data.tag("case.test"), Some("tests::parses"));
+                assert_eq!(test_data.tag("case.index"), Some("3"));
+                assert_eq!(test_data.tag("case.description"), Some("negative_input"));
+            } else {
+                assert_eq!(test_data.tag("case.test"), None);
+            }
+        }
+    }
+
+    #[test]
+    fn nextest_binaries_are_part_of_the_scope() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+
+        for name in [
+            "my-crate::api$submits",
+            "my-crate::api$client::retries",
+            "flaky",
+        ] {
+            payload.push(Event::Test {
+                event: TestEvent::Started {
+                    name: name.to_string(),
+                },
+                nextest: None,
+                extra: Default::default(),
+            });
+        }
+
+        let mut scopes = payload
+            .data
+            .values()
+            .map(|test_data| (test_data.scope.to_string(), test_data.name.clone()))
+            .collect::<Vec<_>>();
+        scopes.sort();
+
+        assert_eq!(
+            scopes,
+            vec![
+                ("".to_string(), "flaky".to_string()),
+                ("my-crate::api".to_string(), "submits".to_string()),
+                ("my-crate::api::client".to_string(), "retries".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn cargo_binaries_are_part_of_the_scope() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+
+        for (binary, name) in [
+            ("crate_a", "tests::it_works"),
+            ("crate_b", "tests::it_works"),
+            ("crate_b", "src/lib.rs - add (line 3)"),
+        ] {
+            payload.set_binary(binary);
+            payload.push(Event::Test {
+                event: TestEvent::Started {
+                    name: name.to_string(),
+                },
+                nextest: None,
+                extra: Default::default(),
+            });
+        }
+
+        let mut scopes = payload
+            .data
+            .values()
+            .map(|test_data| test_data.scope.to_string())
+            .collect::<Vec<_>>();
+        scopes.sort();
+
+        assert_eq!(
+            scopes,
+            vec!["crate_a::tests", "crate_b::src/lib.rs", "crate_b::tests"]
+        );
+    }
+
+    #[test]
+    fn bench_events_are_recorded_with_their_median() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+
+        for line in [
+            r#"{ "type": "suite", "event": "started", "test_count": 1 }"#,
+            r#"{ "type": "test", "event": "started", "name": "parser::bench_parse" }"#,
+            r#"{ "type": "bench", "name": "parser::bench_parse", "median": 1500000, "deviation": 2500, "mib_per_second": 64 }"#,
+        ] {
+            crate::input::parse_line(line, &mut payload).unwrap();
+        }
+
+        let test_data = payload
+            .data
+            .get(&(0, "parser::bench_parse".to_string()))
+            .unwrap();
+        assert!(test_data.is_finished());
+        assert_eq!(&*test_data.scope, "parser");
+        assert_eq!(test_data.name, "bench_parse");
+        assert_eq!(test_data.result, TestResult::Passed);
+        assert_eq!(test_data.duration(), Some(0.0015));
+
+        for (key, value) in [
+            ("bench.median_ns", "1500000"),
+            ("bench.deviation_ns", "2500"),
+            ("bench.mib_per_second", "64"),
+            ("duration_source", "libtest"),
+            ("kind", "bench"),
+        ] {
+            assert_eq!(test_data.tag(key), Some(value));
+        }
+
+        let history = &serde_json::to_value(test_data).unwrap()["history"];
+        assert_eq!(history["detail"]["median_ns"], "1500000");
+        assert_eq!(history["detail"]["deviation_ns"], "2500");
+        assert_eq!(history["detail"]["mib_per_second"], "64");
+    }
+
+    #[test]
+    fn tests_from_separate_suites_do_not_collide() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+
+        for (result, exec_time) in [("ok", 0.1), ("failed", 0.2)] {
+            for line in [
+                r#"{ "type": "suite", "event": "started", "test_count": 1 }"#.to_string(),
+                r#"{ "type": "test", "event": "started", "name": "tests::it_works" }"#.to_string(),
+                format!(
+                    r#"{{ "type": "test", "name": "tests::it_works", "event": "{}", "exec_time": {} }}"#,
+                    result, exec_time
+                ),
+                format!(
+                    r#"{{ "type": "suite", "event": "{}", "passed": 0, "failed": 0, "ignored": 0, "measured": 0, "filtered_out": 0, "exec_time": {} }}"#,
+                    result, exec_time
+                ),
+            ] {
+                crate::input::parse_line(&line, &mut payload).unwrap();
+            }
+        }
+
+        assert_eq!(payload.closed_data().len(), 2);
+
+        let first = payload
+            .data
+            .get(&(0, "tests::it_works".to_string()))
+            .unwrap();
+        let second = payload
+            .data
+            .get(&(1, "tests::it_works".to_string()))
+            .unwrap();
+        assert_eq!(first.result, TestResult::Passed);
+        assert!(second.is_failed());
+        assert!(!second.tags.contains_key("attempt"));
+        assert!(second.history.start_at >= first.history.start_at);
+    }
+
+    #[test]
+    fn concatenated_runs_are_merged_into_one() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+        let mut input = crate::input::InputParser::new(crate::input::InputFormat::Auto, false);
+
+        // Eg `cargo test -p api; cargo test -p web` piped through one collector,
+        // each running a unit test binary and a doctest binary.
+        for run in ["api", "web"] {
+            for binary in ["unit", "doc"] {
+                for line in [
+                    String::new(),
+                    format!("     Running {} ({})", binary, run),
+                    r#"{ "type": "suite", "event": "started", "test_count": 1 }"#.to_string(),
+                    r#"{ "type": "test", "event": "started", "name": "tests::it_works" }"#
+                        .to_string(),
+                    r#"{ "type": "test", "name": "tests::it_works", "event": "ok", "exec_time": 0.1 }"#
+                        .to_string(),
+                    r#"{ "type": "suite", "event": "ok", "passed": 1, "failed": 0, "ignored": 0, "measured": 0, "filtered_out": 0, "exec_time": 0.1 }"#
+                        .to_string(),
+                ] {
+                    input.parse_line(&line, &mut payload);
+                }
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        input.finish(&mut payload);
+
+        assert_eq!(payload.closed_data().len(), 4);
+        assert_eq!(
+            payload.completeness(),
+            Some(Completeness {
+                expected: 4,
+                finished: 4,
+            })
+        );
+
+        let history = |suite: usize| {
+            let (_, test_data) = payload
+                .data
+                .iter()
+                .find(|((index, _), _)| *index == suite)
+                .unwrap();
+            (
+                test_data.history.start_at.unwrap(),
+                test_data.history.end_at.unwrap(),
+            )
+        };
+        // The end_at of each is derived from its (made up) exec_time, so only
+        // the start_at reflects when it ran.
+        for suite in 1..4 {
+            assert!(history(suite).0 >= history(suite - 1).0);
+        }
+        assert!(
+            history(2).0 > history(1).0,
+            "the second run starts after the first"
+        );
+    }
+
+    #[test]
+    fn tests_can_be_built_directly() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic())
+            .with_scope_prefix(Some("tool".to_string()))
+            .with_default_tags(vec![("team".to_string(), "core".to_string())]);
+        payload.push_test(
+            TestData::builder()
+                .scope("checks")
+                .name("lints")
+                .failed(Some("3 warnings"))
+                .start_at(1.5)
+                .duration(0.5),
+        );
+
+        let test_data = &payload.closed_data()[0];
+        assert_eq!(test_data.full_name(), "tool::checks::lints");
+        assert!(test_data.is_failed());
+        assert_eq!(test_data.tag("team"), Some("core"));
+        assert_eq!(test_data.tag("duration_source"), Some("collector"));
+        assert_eq!(test_data.duration(), Some(0.5));
+        assert_eq!(
+            test_data.history.end_at,
+            test_data.history.start_at.map(|start_at| start_at + 0.5)
+        );
+        assert_eq!(
+            test_data.id,
+            test_id(None, "tool::checks", "lints").to_string()
+        );
+    }
+
+    #[test]
+    fn duplicate_names_are_told_apart() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+        for line in [
+            r#"{ "type": "suite", "event": "started", "test_count": 1 }"#,
+            r#"{ "type": "test", "event": "started", "name": "tests::works" }"#,
+            r#"{ "type": "test", "name": "tests::works", "event": "ok" }"#,
+            r#"{ "type": "suite", "event": "ok", "passed": 1, "failed": 0, "ignored": 0, "measured": 0, "filtered_out": 0 }"#,
+            r#"{ "type": "suite", "event": "started", "test_count": 1 }"#,
+            r#"{ "type": "test", "event": "started", "name": "tests::works" }"#,
+            r#"{ "type": "test", "name": "tests::works", "event": "failed" }"#,
+            r#"{ "type": "suite", "event": "failed", "passed": 0, "failed": 1, "ignored": 0, "measured": 0, "filtered_out": 0 }"#,
+        ] {
+            crate::input::parse_line(line, &mut payload).unwrap();
+        }
+        for result in [TestResult::Passed, TestResult::Skipped] {
+            payload.push_finished_test("report", "case", result, 0.0, 0.1, DurationSource::Junit);
+        }
+
+        let mut names = payload
+            .closed_data()
+            .iter()
+            .map(|test_data| (test_data.full_name(), test_data.id.clone()))
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(
+            names
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>(),
+            [
+                "report::case",
+                "report::case (2)",
+                "tests::works",
+                "tests::works (suite 2)"
+            ]
+        );
+        let ids = names
+            .iter()
+            .map(|(_, id)| id)
+            .collect::<std::collections::HashSet<_>>();
+        assert_eq!(ids.len(), 4, "each test has an id of its own");
+    }
+
+    #[test]
+    fn ids_are_derived_from_the_suite_scope_and_name() {
+        let ids = |payload: Payload| {
+            let mut payload = payload;
+            for name in ["tests::adds", "tests::adds", "tests::divides"] {
+                payload.push(Event::Test {
+                    event: TestEvent::Started {
+                        name: name.to_string(),
+                    },
+                    nextest: None,
+                    extra: Default::default(),
+                });
+                payload.push(Event::Suite {
+                    event: SuiteEvent::Ok {
+                        results: Default::default(),
+                    },
+                });
+            }
+            let mut data = payload.data.into_iter().collect::<Vec<_>>();
+            data.sort_by(|(a, _), (b, _)| a.cmp(b));
+            data.into_iter()
+                .map(|(_, test_data)| test_data.id)
+                .collect::<Vec<String>>()
+        };
+
+        let first = ids(Payload::new(RuntimeEnvironment::generic()));
+        assert_ne!(first[0], first[2]);
+        assert_eq!(first[0], test_id(None, "tests", "adds").to_string());
+        // The same test in another suite is told apart.
+        assert_eq!(
+            first[1],
+            test_id(None, "tests", "adds (suite 2)").to_string()
+        );
+        assert_eq!(ids(Payload::new(RuntimeEnvironment::generic())), first);
+
+        let suite =
+            ids(Payload::new(RuntimeEnvironment::generic())
+                .with_suite_name(Some("unit".to_string())));
+        assert_ne!(suite[0], first[0]);
+
+        let random = ids(Payload::new(RuntimeEnvironment::generic()).with_random_ids(true));
+        assert_ne!(random[0], random[1]);
+    }
+
+    #[test]
+    fn times_are_seconds_since_the_epoch() {
+        let epoch = || {
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs_f64()
+        };
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+
+        let before = epoch();
+        for line in [
+            r#"{ "type": "test", "event": "started", "name": "tests::sleeps" }"#,
+            r#"{ "type": "test", "name": "tests::sleeps", "event": "ok" }"#,
+        ] {
+            crate::input::parse_line(line, &mut payload).unwrap();
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        let after = epoch();
+
+        let history = &payload.closed_data()[0].history;
+        let start_at = history.start_at.unwrap();
+        let end_at = history.end_at.unwrap();
+        assert!(before <= start_at && start_at <= end_at && end_at <= after);
+        assert!(history.duration.unwrap() >= 0.02);
+    }
+
+    #[test]
+    fn offsets_are_never_negative() {
+        let mut rng = rand::thread_rng();
+
+        for _ in 0..50 {
+            let mut payload = Payload::new(RuntimeEnvironment::generic());
+            let tests = rng.gen_range(1..20);
+
+            for test in 0..tests {
+                let name = format!("tests::test_{}", test);
+
+                if rng.gen_bool(0.2) {
+                    payload.push(Event::Suite {
+                        event: SuiteEvent::Started { test_count: tests },
+                    });
+                }
+                if rng.gen_bool(0.9) {
+                    payload.push(Event::Test {
+                        event: TestEvent::Started { name: name.clone() },
+                        nextest: None,
+                        extra: Default::default(),
+                    });
+                }
+                if rng.gen_bool(0.1) {
+                    payload.push(Event::Suite {
+                        event: SuiteEvent::Ok {
+                            results: serde_json::from_str(
+                                r#"{ "passed": 0, "failed": 0, "ignored": 0, "measured": 0, "filtered_out": 0, "exec_time": 0.0 }"#,
+                            )
+                            .unwrap(),
+                        },
+                    });
+                }
+                payload.push(Event::Test {
+                    event: TestEvent::Ok {
+                        name,
+                        exec_time: Some(rng.gen_range(0.0..1.0)),
+                        stdout: None,
+                    },
+                    nextest: None,
+                    extra: Default::default(),
+                });
+            }
+
+            for test_data in payload.data.values() {
+                let start_at = test_data.history.start_at.unwrap();
+                assert!(start_at >= 0.0);
+                if let Some(end_at) = test_data.history.end_at {
+                    assert!(end_at >= start_at);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn tests_started_before_their_suite_are_kept() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+
+        for line in [
+            r#"{ "type": "test", "event": "started", "name": "tests::early" }"#,
+            r#"{ "type": "suite", "event": "started", "test_count": 1 }"#,
+            r#"{ "type": "test", "name": "tests::early", "event": "ok", "exec_time": 0.1 }"#,
+        ] {
+            crate::input::parse_line(line, &mut payload).unwrap();
+        }
+
+        assert_eq!(payload.closed_data().len(), 1);
+    }
+
+    #[test]
+    fn failures_are_split_into_reason_and_expanded_output() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+
+        for line in [
+            r#"{ "type": "suite", "event": "started", "test_count": 1 }"#,
+            r#"{ "type": "test", "event": "started", "name": "tests::it_fails" }"#,
+            r#"{ "type": "test", "name": "tests::it_fails", "event": "failed", "exec_time": 0.1, "stdout": "thread 'tests::it_fails' panicked at src/lib.rs:10:5:\nboom\nnote: run with `RUST_BACKTRACE=1` environment variable to display a backtrace\n" }"#,
+        ] {
+            crate::input::parse_line(line, &mut payload).unwrap();
+        }
+
+        let json = serde_json::to_value(&payload).unwrap();
+        let test = &json["data"][0];
+
+        assert_eq!(test["failure_reason"], "boom");
+        assert_eq!(test["location"], "src/lib.rs:10");
+        assert_eq!(test["file_name"], "src/lib.rs");
+        assert_eq!(
+            test["failure_expanded"][0]["expanded"][0],
+            "thread 'tests::it_fails' panicked at src/lib.rs:10:5:"
+        );
+        assert_eq!(
+            test["failure_expanded"][0]["backtrace"],
+            serde_json::json!([])
+        );
+    }
+
+    #[test]
+    fn tests_are_tagged_from_markers_in_their_output() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+
+        for line in [
+            r#"{ "type": "suite", "event": "started", "test_count": 2 }"#,
+            r#"{ "type": "test", "event": "started", "name": "tests::passes" }"#,
+            r#"{ "type": "test", "event": "started", "name": "tests::fails" }"#,
+            r#"{ "type": "test", "name": "tests::passes", "event": "ok", "exec_time": 0.1, "stdout": "buildkite-test-collector: tag team=search priority=p1\n" }"#,
+            r#"{ "type": "test", "name": "tests::fails", "event": "failed", "exec_time": 0.1, "stdout": "buildkite-test-collector: tag team=ads\nboom\n" }"#,
+        ] {
+            crate::input::parse_line(line, &mut payload).unwrap();
+        }
+
+        let passes = &payload.data[&(0, "tests::passes".to_string())];
+        assert_eq!(passes.tag("team"), Some("search"));
+        assert_eq!(passes.tag("priority"), Some("p1"));
+
+        let fails = &payload.data[&(0, "tests::fails".to_string())];
+        assert_eq!(fails.tag("team"), Some("ads"));
+        assert_eq!(fails.failure_reason(), Some("boom"));
+    }
+
+    #[test]
+    fn spans_are_recorded_as_history_children() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+
+        for line in [
+            r#"{ "type": "test", "event": "started", "name": "tests::saves" }"#,
+            r#"{ "type": "test", "name": "tests::saves", "event": "ok", "exec_time": 0.5, "stdout": "buildkite-test-collector: span start name=db_setup at=0.1\nbuildkite-test-collector: span end at=0.25\n" }"#,
+        ] {
+            crate::input::parse_line(line, &mut payload).unwrap();
+        }
+
+        let test_data = &payload.data[&(0, "tests::saves".to_string())];
+        let start_at = test_data.history.start_at.unwrap();
+        let span = &test_data.history.children[0];
+
+        assert_eq!(&*span.section, "annotation");
+        assert_eq!(span.detail["name"], "db_setup");
+        assert_eq!(span.start_at, Some(start_at + 0.1));
+        assert_eq!(span.end_at, Some(start_at + 0.25));
+        assert_eq!(span.duration, Some(0.15));
+    }
+
+    #[test]
+    fn scopes_and_sections_are_shared_between_tests() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+
+        for line in [
+            r#"{ "type": "test", "event": "started", "name": "tests::saves" }"#,
+            r#"{ "type": "test", "name": "tests::saves", "event": "ok", "stdout": "buildkite-test-collector: span start at=0.1\nbuildkite-test-collector: span end at=0.2\n" }"#,
+            r#"{ "type": "test", "event": "started", "name": "tests::loads" }"#,
+            r#"{ "type": "test", "name": "tests::loads", "event": "ok", "stdout": "buildkite-test-collector: span start at=0.1\nbuildkite-test-collector: span end at=0.2\n" }"#,
+        ] {
+            crate::input::parse_line(line, &mut payload).unwrap();
+        }
+
+        let saves = &payload.data[&(0, "tests::saves".to_string())];
+        let loads = &payload.data[&(0, "tests::loads".to_string())];
+        assert!(Arc::ptr_eq(&saves.scope, &loads.scope));
+        assert!(Arc::ptr_eq(&saves.history.section, &loads.history.section));
+        assert!(Arc::ptr_eq(
+            &saves.history.children[0].section,
+            &loads.history.children[0].section
+        ));
+    }
+
+    #[test]
+    fn unknown_fields_are_passed_through_when_enabled() {
+        let lines = [
+            r#"{ "type": "test", "event": "started", "name": "tests::it_fails" }"#,
+            r#"{ "type": "test", "name": "tests::it_fails", "event": "failed", "exec_time": 0.1, "failure": { "kind": "panic" } }"#,
+        ];
+
+        for extra_fields in [false, true] {
+            let mut payload =
+                Payload::new(RuntimeEnvironment::generic()).with_extra_fields(extra_fields);
+            for line in lines {
+                crate::input::parse_line(line, &mut payload).unwrap();
+            }
+
+            let json = serde_json::to_value(&payload).unwrap();
+            let extra = &json["data"][0]["extra"];
+            if extra_fields {
+                assert_eq!(
+                    extra,
+                    &serde_json::json!({ "failure": { "kind": "panic" } })
+                );
+            } else {
+                assert!(extra.is_null());
+            }
+        }
+    }
+
+    #[test]
+    fn completeness_is_measured_against_the_test_count() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+        assert_eq!(payload.completeness(), None);
+
+        for line in [
+            r#"{ "type": "suite", "event": "started", "test_count": 4 }"#,
+            r#"{ "type": "test", "event": "started", "name": "a::one" }"#,
+            r#"{ "type": "test", "name": "a::one", "event": "ok", "exec_time": 0.1 }"#,
+            r#"{ "type": "test", "event": "started", "name": "a::ignored" }"#,
+            r#"{ "type": "test", "name": "a::ignored", "event": "ignored" }"#,
+        ] {
+            crate::input::parse_line(line, &mut payload).unwrap();
+        }
+
+        // Tests handed off early still count.
+        payload.take_finished();
+
+        for line in [
+            r#"{ "type": "test", "event": "started", "name": "a::two" }"#,
+            r#"{ "type": "test", "name": "a::two", "event": "ok", "exec_time": 0.1 }"#,
+            r#"{ "type": "test", "event": "started", "name": "a::crashed" }"#,
+        ] {
+            crate::input::parse_line(line, &mut payload).unwrap();
+        }
+
+        // The ignored test finished, as skipped.
+        let completeness = payload.completeness().unwrap();
+        assert_eq!(
+            completeness,
+            Completeness {
+                expected: 4,
+                finished: 3,
+            }
+        );
+        assert!(!completeness.is_complete());
+        assert!((completeness.ratio() - 3.0 / 4.0).abs() < f64::EPSILON);
+
+        // Only the crashed test was cut short; the ignored one never ran.
+        assert_eq!(
+            payload.fail_unfinished(|_| Failure::with_reason(String::new())),
+            1
+        );
+    }
+
+    #[test]
+    fn the_expected_test_count_can_be_given() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic()).with_expected_tests(Some(1));
+
+        for line in [
+            r#"{ "type": "suite", "event": "started", "test_count": 4 }"#,
+            r#"{ "type": "test", "event": "started", "name": "a::one" }"#,
+            r#"{ "type": "test", "name": "a::one", "event": "ok", "exec_time": 0.1 }"#,
+        ] {
+            crate::input::parse_line(line, &mut payload).unwrap();
+        }
+        let early = payload.take_finished();
+        for line in [
+            r#"{ "type": "test", "event": "started", "name": "a::two" }"#,
+            r#"{ "type": "test", "name": "a::two", "event": "ok", "exec_time": 0.1 }"#,
+        ] {
+            crate::input::parse_line(line, &mut payload).unwrap();
+        }
+
+        let completeness = payload.completeness().unwrap();
+        assert_eq!(
+            completeness,
+            Completeness {
+                expected: 1,
+                finished: 2,
+            }
+        );
+        assert!(completeness.is_complete());
+        assert!(completeness.is_exceeded());
+        assert_eq!(early.completeness(), None);
+    }
+
+    #[test]
+    fn timed_out_tests_are_failed_until_they_finish() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+
+        for line in [
+            r#"{ "type": "suite", "event": "started", "test_count": 2 }"#,
+            r#"{ "type": "test", "event": "started", "name": "tests::hangs" }"#,
+            r#"{ "type": "test", "event": "started", "name": "tests::slow" }"#,
+            r#"{ "type": "test", "event": "timeout", "name": "tests::hangs" }"#,
+            r#"{ "type": "test", "event": "timeout", "name": "tests::slow" }"#,
+            r#"{ "type": "test", "name": "tests::slow", "event": "ok", "exec_time": 61.0 }"#,
+        ] {
+            crate::input::parse_line(line, &mut payload).unwrap();
+        }
+
+        let hangs = payload.data.get(&(0, "tests::hangs".to_string())).unwrap();
+        assert!(hangs.is_finished());
+        assert_eq!(
+            hangs.result,
+            TestResult::Failed {
+                failure_reason: Some("test exceeded time limit".into())
+            }
+        );
+        assert_eq!(hangs.tag("timeout"), Some("true"));
+
+        let slow = payload.data.get(&(0, "tests::slow".to_string())).unwrap();
+        assert_eq!(slow.result, TestResult::Passed);
+        assert_eq!(slow.duration(), Some(61.0));
+        assert_eq!(slow.tag("timeout"), Some("true"));
+    }
+
+    #[test]
+    fn results_without_exec_time_use_the_measured_duration() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+
+        for line in [
+            r#"{ "type": "suite", "event": "started", "test_count": 2 }"#,
+            r#"{ "type": "test", "event": "started", "name": "tests::passes" }"#,
+            r#"{ "type": "test", "event": "started", "name": "tests::fails" }"#,
+            r#"{ "type": "test", "name": "tests::passes", "event": "ok" }"#,
+            r#"{ "type": "test", "name": "tests::fails", "event": "failed", "stdout": "boom" }"#,
+            r#"{ "type": "suite", "event": "failed", "passed": 1, "failed": 1, "ignored": 0, "measured": 0, "filtered_out": 0 }"#,
+        ] {
+            crate::input::parse_line(line, &mut payload).unwrap();
+        }
+
+        assert!(payload.warned_exec_time);
+        assert_eq!(payload.closed_data().len(), 2);
+        for test_data in payload.closed_data() {
+            assert!(test_data.duration().is_some());
+            assert_eq!(test_data.tag("duration_source"), Some("collector"));
+        }
+    }
+
+    #[test]
+    fn doctests_are_scoped_by_file() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+
+        payload.push(Event::Suite {
+            event: SuiteEvent::Started { test_count: 1 },
+        });
+        payload.push(Event::Test {
+            event: TestEvent::Started {
+                name: "src/lib.rs - module::func (line 42)".to_string(),
+            },
+            nextest: None,
+            extra: Default::default(),
+        });
+
+        let test_data = payload
+            .data
+            .get(&(0, "src/lib.rs - module::func (line 42)".to_string()))
+            .unwrap();
+        assert_eq!(&*test_data.scope, "src/lib.rs");
+        assert_eq!(test_data.name, "module::func (line 42)");
+        assert_eq!(test_data.location, Some("src/lib.rs:42".to_string()));
+        assert_eq!(test_data.file_name, Some("src/lib.rs".to_string()));
     }
 
     fn stub_test_data(finished: bool) -> TestData {
@@ -301,10 +3087,15 @@ mod test {
 
         TestData {
             id: uuid.clone(),
-            scope: uuid.clone(),
+            scope: Arc::from(uuid.as_str()),
             name: uuid.clone(),
+            location: None,
+            file_name: None,
             result: stub_test_result(),
+            failure_expanded: Vec::new(),
             history: stub_test_history(finished),
+            tags: HashMap::new(),
+            extra: serde_json::Map::new(),
         }
     }
 
@@ -329,18 +3120,20 @@ mod test {
             let end_at = rng.gen::<f64>() + start_at;
 
             TestHistory {
-                section: "top".to_string(),
+                section: Arc::from("top"),
                 start_at: Some(start_at),
                 end_at: Some(end_at),
                 duration: Some(end_at - start_at),
+                detail: HashMap::new(),
                 children: vec![],
             }
         } else {
             TestHistory {
-                section: "top".to_string(),
+                section: Arc::from("top"),
                 start_at: Some(start_at),
                 end_at: None,
                 duration: None,
+                detail: HashMap::new(),
                 children: vec![],
             }
         }
@@ -0,0 +1,177 @@
+//! # preflight
+//!
+//! Estimates the size of the upload before it's sent, so that platform
+//! owners get early warning of runaway tests before they blow through egress
+//! or API limits.
+
+use crate::payload::{parse_var, Payload};
+
+/// Tests smaller than this are never reported as dominating the upload,
+/// however small the upload is.
+const MIN_REPORTED_TEST_BYTES: usize = 64 * 1024;
+
+/// # Estimate
+///
+/// The estimated size of an upload.
+#[derive(Debug, Default, PartialEq)]
+pub struct Estimate {
+    total_bytes: usize,
+    batches: usize,
+    largest_test: Option<(String, usize)>,
+}
+
+/// # Limits
+///
+/// When to warn about the size of an upload.
+#[derive(Debug, PartialEq)]
+pub struct Limits {
+    max_test_percent: f64,
+    budget_bytes: Option<usize>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_test_percent: 25.0,
+            budget_bytes: None,
+        }
+    }
+}
+
+impl Limits {
+    /// Read the limits from `BUILDKITE_ANALYTICS_MAX_TEST_PERCENT` and
+    /// `BUILDKITE_ANALYTICS_UPLOAD_BUDGET` (in bytes).
+    pub fn from_env() -> Self {
+        let default = Limits::default();
+
+        Limits {
+            max_test_percent: parse_var("BUILDKITE_ANALYTICS_MAX_TEST_PERCENT")
+                .unwrap_or(default.max_test_percent),
+            budget_bytes: parse_var("BUILDKITE_ANALYTICS_UPLOAD_BUDGET"),
+        }
+    }
+}
+
+/// Estimate the serialised size of the `batches`.
+pub fn estimate(batches: &[Payload]) -> Estimate {
+    let mut estimate = Estimate {
+        batches: batches.len(),
+        ..Estimate::default()
+    };
+
+    for batch in batches {
+        estimate.total_bytes += serialized_len(batch);
+
+        for test_data in batch.closed_data() {
+            let bytes = serialized_len(test_data);
+
+            if estimate
+                .largest_test
+                .as_ref()
+                .is_none_or(|(_, largest)| bytes > *largest)
+            {
+                let name = format!("{}::{}", test_data.scope(), test_data.name());
+                estimate.largest_test = Some((name, bytes));
+            }
+        }
+    }
+
+    estimate
+}
+
+/// The warnings, if any, to show for `estimate`.
+pub fn warnings(estimate: &Estimate, limits: &Limits) -> Vec<String> {
+    let mut warnings = Vec::new();
+
+    if let Some((name, bytes)) = &estimate.largest_test {
+        let percent = *bytes as f64 * 100.0 / estimate.total_bytes.max(1) as f64;
+
+        if *bytes >= MIN_REPORTED_TEST_BYTES && percent > limits.max_test_percent {
+            warnings.push(format!(
+                "{} contributes {} bytes ({:.0}%) of the {} byte upload.",
+                name, bytes, percent, estimate.total_bytes
+            ));
+        }
+    }
+
+    if let Some(budget) = limits.budget_bytes {
+        if estimate.total_bytes > budget {
+            warnings.push(format!(
+                "The upload is {} bytes in {} batches, which exceeds the budget of {} bytes.",
+                estimate.total_bytes, estimate.batches, budget
+            ));
+        }
+    }
+
+    warnings
+}
+
+/// Estimate the size of the `batches` and warn about anything unusual.
+///
+/// ## Emits warnings
+///  - If a single test contributes too much of the upload.
+///  - If the upload exceeds the budget.
+pub fn check(batches: &[Payload], limits: &Limits) {
+    for warning in warnings(&estimate(batches), limits) {
+        eprintln!("{}", warning);
+    }
+}
+
+/// The length of `value` serialised as JSON, without keeping the JSON.
+pub fn serialized_len<T: serde::Serialize>(value: &T) -> usize {
+    let mut counter = Counter(0);
+    match serde_json::to_writer(&mut counter, value) {
+        Ok(()) => counter.0,
+        Err(_) => 0,
+    }
+}
+
+/// Counts the bytes written to it.
+struct Counter(usize);
+
+impl std::io::Write for Counter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn warns_about_dominant_tests_and_budgets() {
+        let estimate = Estimate {
+            total_bytes: 200 * 1024,
+            batches: 2,
+            largest_test: Some(("api::test::huge".to_string(), 100 * 1024)),
+        };
+
+        assert_eq!(warnings(&estimate, &Limits::default()).len(), 1);
+        assert!(warnings(&estimate, &Limits::default())[0].starts_with("api::test::huge"));
+
+        let limits = Limits {
+            max_test_percent: 60.0,
+            budget_bytes: Some(100 * 1024),
+        };
+        let warnings = warnings(&estimate, &limits);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("exceeds the budget"));
+    }
+
+    #[test]
+    fn small_tests_are_never_reported() {
+        let estimate = Estimate {
+            total_bytes: 1000,
+            batches: 1,
+            largest_test: Some(("api::test::small".to_string(), 900)),
+        };
+
+        assert!(warnings(&estimate, &Limits::default()).is_empty());
+    }
+}
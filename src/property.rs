@@ -0,0 +1,133 @@
+//! # property
+//!
+//! Finds how to reproduce a failed property test in its output, so that
+//! Test Engine can show (and be searched by) the failing case.
+//!
+//! [proptest](https://github.com/proptest-rs/proptest) reports the minimal
+//! failing input in its panic message, and the seed of the failing case when
+//! saving it to its regressions file:
+//!
+//! ```text
+//! proptest: Saving this and future failures in /src/proptest-regressions/lib.txt
+//! proptest: If this test was run on a CI system, you may wish to add the following line to your copy of the file. (You may need to create it.)
+//! cc 5b2c67bb0de49a3c5bd8c2e8a0a3bb64d5cd3a2d # shrinks to x = 100
+//! thread 'tests::doubles' panicked at src/lib.rs:10:5:
+//! Test failed: assertion failed: x < 100; minimal failing input: x = 100
+//! ```
+//!
+//! [quickcheck](https://github.com/BurntSushi/quickcheck) reports the
+//! shrunk arguments, eg `[quickcheck] TEST FAILED. Arguments: (100)`.
+
+use crate::failure;
+
+/// The most bytes of a failing input to keep, since a tag is no place for a
+/// huge generated value.
+const MAX_INPUT_BYTES: usize = 1024;
+
+/// The tags describing the failing case of a property test, from its
+/// output, eg `proptest.seed`.  Empty if it isn't a property test.
+pub fn tags(output: &str) -> Vec<(&'static str, String)> {
+    let mut tags = Vec::new();
+    let mut lines = output.lines().peekable();
+
+    while let Some(line) = lines.next() {
+        let line = line.trim();
+        if let Some(path) = line.strip_prefix("proptest: Saving this and future failures in ") {
+            tags.push(("proptest.regressions_file", path.to_string()));
+        } else if let Some(seed) = line
+            .strip_prefix("cc ")
+            .and_then(|rest| rest.split_whitespace().next())
+            .filter(|seed| seed.len() >= 32 && seed.bytes().all(|byte| byte.is_ascii_hexdigit()))
+        {
+            tags.push(("proptest.seed", seed.to_string()));
+        } else if let Some((_, input)) = line.split_once("minimal failing input: ") {
+            // Inputs with a multi-line `Debug` continue until proptest's
+            // counts of successes and rejects.
+            let mut input = input.to_string();
+            while let Some(next) = lines.next_if(|next| {
+                let next = next.trim();
+                !next.is_empty() && !next.starts_with("successes:") && !next.starts_with("thread '")
+            }) {
+                input.push('\n');
+                input.push_str(next.trim_end());
+            }
+            tags.push(("proptest.minimal_input", input));
+        } else if let Some((_, arguments)) = line
+            .strip_prefix("[quickcheck] TEST FAILED")
+            .and_then(|rest| rest.split_once("Arguments: "))
+        {
+            tags.push(("quickcheck.arguments", arguments.to_string()));
+        }
+    }
+
+    for (_, value) in &mut tags {
+        failure::truncate(value, MAX_INPUT_BYTES);
+    }
+    tags
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn finds_proptest_seeds_and_minimal_inputs() {
+        let output = "proptest: Saving this and future failures in /src/proptest-regressions/lib.txt
+proptest: If this test was run on a CI system, you may wish to add the following line to your copy of the file. (You may need to create it.)
+cc 5b2c67bb0de49a3c5bd8c2e8a0a3bb64d5cd3a2d # shrinks to x = 100
+thread 'tests::doubles' panicked at src/lib.rs:10:5:
+Test failed: assertion failed: x < 100; minimal failing input: x = 100
+\tsuccesses: 12
+\tlocal rejects: 0
+\tglobal rejects: 0
+";
+
+        assert_eq!(
+            tags(output),
+            [
+                (
+                    "proptest.regressions_file",
+                    "/src/proptest-regressions/lib.txt".to_string()
+                ),
+                (
+                    "proptest.seed",
+                    "5b2c67bb0de49a3c5bd8c2e8a0a3bb64d5cd3a2d".to_string()
+                ),
+                ("proptest.minimal_input", "x = 100".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_multi_line_inputs_and_quickcheck_arguments() {
+        let output = "thread 'tests::parses' panicked at src/lib.rs:20:5:
+Test failed: unexpected token.
+minimal failing input: input = Config {
+    name: \"\",
+}
+\tsuccesses: 3
+";
+        assert_eq!(
+            tags(output),
+            [(
+                "proptest.minimal_input",
+                "input = Config {\n    name: \"\",\n}".to_string()
+            )]
+        );
+
+        let output = "thread 'tests::reverses' panicked at src/lib.rs:3:5:
+[quickcheck] TEST FAILED. Arguments: ([0, 1])";
+        assert_eq!(
+            tags(output),
+            [("quickcheck.arguments", "([0, 1])".to_string())]
+        );
+    }
+
+    #[test]
+    fn ignores_other_failures() {
+        assert!(
+            tags("thread 'tests::boom' panicked at src/lib.rs:3:5:\nboom\ncc me on the fix")
+                .is_empty()
+        );
+    }
+}
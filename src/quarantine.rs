@@ -0,0 +1,141 @@
+//! # quarantine
+//!
+//! Respects a team's list of known-flaky tests.  A quarantine file lists test
+//! name patterns, one per line, eg:
+//!
+//! ```text
+//! # Flaky since the search cluster upgrade
+//! search::tests::reindexes_*
+//! api::client::test::retries_after_timeout
+//! ```
+//!
+//! Patterns match the test's full name (`scope::name`), with `*` and `?`
+//! wildcards.  Matching tests are tagged `quarantined`, so their failures are
+//! uploaded but flagged.  If `BUILDKITE_ANALYTICS_QUARANTINE_IGNORE_FAILURES`
+//! is `true` their failures don't fail the run either, unless they're
+//! blockers (see `severity`).
+
+use crate::codeowners::match_glob;
+use crate::payload::Payload;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+/// Where the quarantine file is conventionally kept.
+const PATH: &str = ".buildkite/test-quarantine";
+
+/// # Quarantine
+///
+/// The test name patterns from a quarantine file.
+#[derive(Debug, PartialEq, Default)]
+pub struct Quarantine {
+    patterns: Vec<String>,
+}
+
+impl Quarantine {
+    /// Find and parse the quarantine file.
+    ///
+    /// Taken from `BUILDKITE_ANALYTICS_QUARANTINE_FILE`, or
+    /// `.buildkite/test-quarantine` if it exists.
+    ///
+    /// ## Emits warnings
+    ///  - If the file cannot be read.
+    pub fn discover() -> Option<Self> {
+        let path = match env::var_os("BUILDKITE_ANALYTICS_QUARANTINE_FILE") {
+            Some(path) => PathBuf::from(path),
+            None => Some(PathBuf::from(PATH)).filter(|path| path.is_file())?,
+        };
+
+        match fs::read_to_string(&path) {
+            Ok(contents) => Some(Self::parse(&contents)),
+            Err(err) => {
+                eprintln!("Unable to read {}: {}", path.display(), err);
+                None
+            }
+        }
+    }
+
+    /// Parse the contents of a quarantine file.
+    pub fn parse(contents: &str) -> Self {
+        let patterns = contents
+            .lines()
+            .map(|line| {
+                line.split_once('#')
+                    .map_or(line, |(pattern, _)| pattern)
+                    .trim()
+            })
+            .filter(|pattern| !pattern.is_empty())
+            .map(String::from)
+            .collect();
+
+        Quarantine { patterns }
+    }
+
+    /// Is the test with the given full name quarantined?
+    pub fn contains(&self, full_name: &str) -> bool {
+        self.patterns
+            .iter()
+            .any(|pattern| match_glob(pattern.as_bytes(), full_name.as_bytes()))
+    }
+
+    /// Tag every quarantined test in `payload` as `quarantined`.
+    pub fn tag(&self, payload: &mut Payload) {
+        for test_data in payload.data_mut() {
+            if self.contains(&test_data.full_name()) {
+                test_data.insert_tag("quarantined", "true");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::run_env::RuntimeEnvironment;
+
+    #[test]
+    fn patterns_match_full_names() {
+        let quarantine = Quarantine::parse(
+            "# Known flaky
+search::tests::reindexes_*   # since the upgrade
+
+api::client::test::retries
+",
+        );
+
+        assert!(quarantine.contains("search::tests::reindexes_users"));
+        assert!(quarantine.contains("api::client::test::retries"));
+        assert!(!quarantine.contains("api::client::test::retries_twice"));
+        assert!(!quarantine.contains("search::tests::searches"));
+        assert!(!Quarantine::default().contains("search::tests::searches"));
+    }
+
+    #[test]
+    fn quarantined_tests_are_tagged() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+        for line in [
+            r#"{ "type": "test", "event": "started", "name": "search::tests::reindexes_users" }"#,
+            r#"{ "type": "test", "name": "search::tests::reindexes_users", "event": "failed", "exec_time": 0.1 }"#,
+            r#"{ "type": "test", "event": "started", "name": "search::tests::searches" }"#,
+            r#"{ "type": "test", "name": "search::tests::searches", "event": "ok", "exec_time": 0.1 }"#,
+        ] {
+            crate::input::parse_line(line, &mut payload).unwrap();
+        }
+
+        Quarantine::parse("search::tests::reindexes_*").tag(&mut payload);
+
+        let reindexes = payload
+            .closed_data()
+            .into_iter()
+            .find(|test_data| test_data.name() == "reindexes_users")
+            .unwrap();
+        assert_eq!(reindexes.tag("quarantined"), Some("true"));
+        assert!(reindexes.is_failed());
+        let searches = payload
+            .closed_data()
+            .into_iter()
+            .find(|test_data| test_data.name() == "searches")
+            .unwrap();
+        assert_eq!(searches.tag("quarantined"), None);
+    }
+}
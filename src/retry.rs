@@ -0,0 +1,254 @@
+//! # retry
+//!
+//! Retries uploads which fail for reasons which are likely to pass, ie
+//! connection errors, 5xx responses and being rate limited (429), so that a
+//! network blip at the end of CI doesn't lose a batch.  Each retry waits twice
+//! as long as the last, less a random jitter so that jobs failing together
+//! don't retry together, unless the response said how long to wait in its
+//! `Retry-After` header.
+//!
+//! Configured with `BUILDKITE_ANALYTICS_RETRY_ATTEMPTS` (the most times to
+//! try each upload, default 3), `BUILDKITE_ANALYTICS_RETRY_DELAY_SECONDS` (the
+//! wait before the first retry, default 1) and
+//! `BUILDKITE_ANALYTICS_RETRY_JITTER` (the most of each wait to take off at
+//! random, from 0 to 1, default 0.5).
+
+use crate::http::HttpError;
+use crate::payload::{parse_var, seconds_var};
+use reqwest::Response;
+use std::future::Future;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// No retry waits longer than this, however many attempts are allowed.
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// The longest `Retry-After` we'll wait, so a confused server can't hold up
+/// the pipeline indefinitely.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+/// # RetryPolicy
+///
+/// How many times to try an upload, and how long to wait between tries.
+#[derive(Debug, PartialEq, Clone)]
+pub struct RetryPolicy {
+    attempts: u32,
+    base_delay: Duration,
+    jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 3,
+            base_delay: Duration::from_secs(1),
+            jitter: 0.5,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Read the policy from the environment.
+    ///
+    /// ## Emits warnings
+    ///  - If any of its variables cannot be parsed.
+    pub fn from_env() -> Self {
+        let default = RetryPolicy::default();
+
+        RetryPolicy {
+            attempts: parse_var("BUILDKITE_ANALYTICS_RETRY_ATTEMPTS")
+                .unwrap_or(default.attempts)
+                .max(1),
+            base_delay: seconds_var("BUILDKITE_ANALYTICS_RETRY_DELAY_SECONDS")
+                .unwrap_or(default.base_delay),
+            jitter: parse_var::<f64>("BUILDKITE_ANALYTICS_RETRY_JITTER")
+                .filter(|jitter| jitter.is_finite())
+                .unwrap_or(default.jitter)
+                .clamp(0.0, 1.0),
+        }
+    }
+
+    /// How long to wait before the `retry`th retry (counting from 1), given
+    /// a `random` number between 0 and 1.
+    fn delay(&self, retry: u32, random: f64) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)))
+            .min(MAX_DELAY);
+
+        delay.mul_f64(1.0 - self.jitter * random)
+    }
+
+    /// Make the request with `send`, retrying it if it fails with a
+    /// connection error, a 5xx response or a 429, until it succeeds or runs
+    /// out of attempts.
+    ///
+    /// Returns the last response or error.
+    ///
+    /// ## Emits warnings
+    ///  - Before each retry, with why.
+    pub async fn send<F, Fut>(&self, mut send: F) -> Result<Response, HttpError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<Response, HttpError>>,
+    {
+        let mut attempt = 1;
+        loop {
+            let result = send().await;
+            match &result {
+                Err(err) if is_retryable(err) && attempt < self.attempts => {
+                    let delay = retry_after(err).unwrap_or_else(|| self.delay(attempt, random()));
+                    if matches!(err, HttpError::Status(429, _)) {
+                        eprintln!(
+                            "Upload attempt {} of {} was rate limited, retrying in {:.1}s.",
+                            attempt,
+                            self.attempts,
+                            delay.as_secs_f64()
+                        );
+                    } else {
+                        eprintln!(
+                            "Upload attempt {} of {} failed ({}), retrying in {:.1}s.",
+                            attempt,
+                            self.attempts,
+                            err,
+                            delay.as_secs_f64()
+                        );
+                    }
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                _ => return result,
+            }
+        }
+    }
+}
+
+/// Might the request succeed if it's made again?
+fn is_retryable(err: &HttpError) -> bool {
+    match err {
+        HttpError::Status(status, _) => *status == 429 || (500..600).contains(status),
+        HttpError::Transport(_) => true,
+    }
+}
+
+/// How long the response asked us to wait before retrying, if it had a
+/// `Retry-After` header in seconds.  (HTTP dates aren't understood, so fall
+/// back to the usual backoff.)
+fn retry_after(err: &HttpError) -> Option<Duration> {
+    let HttpError::Status(_, response) = err else {
+        return None;
+    };
+    let seconds = response
+        .headers()
+        .get("Retry-After")?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+
+    Some(Duration::from_secs(seconds).min(MAX_RETRY_AFTER))
+}
+
+/// A random number between 0 and 1, from the low bits of a random UUID
+/// (its version and variant bits are higher up), which is plenty for jitter.
+fn random() -> f64 {
+    const BITS: u32 = f64::MANTISSA_DIGITS;
+    let bits = Uuid::new_v4().as_u128() as u64 & ((1 << BITS) - 1);
+
+    bits as f64 / (1u64 << BITS) as f64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::http::block_on;
+
+    fn response(status: u16, retry_after: Option<&str>) -> Response {
+        let mut response = ::http::Response::builder().status(status);
+        if let Some(retry_after) = retry_after {
+            response = response.header("Retry-After", retry_after);
+        }
+
+        response.body("{}").unwrap().into()
+    }
+
+    fn status(status: u16) -> Result<Response, HttpError> {
+        if status >= 400 {
+            Err(HttpError::Status(status, Box::new(response(status, None))))
+        } else {
+            Ok(response(status, None))
+        }
+    }
+
+    /// Send with `policy`, taking each response from the end of `responses`.
+    fn send(
+        policy: &RetryPolicy,
+        responses: &mut Vec<Result<Response, HttpError>>,
+    ) -> Result<Response, HttpError> {
+        block_on(policy.send(|| {
+            let response = responses.pop().unwrap();
+            async { response }
+        }))
+    }
+
+    #[test]
+    fn delays_double_less_jitter() {
+        let policy = RetryPolicy::default();
+
+        assert_eq!(policy.delay(1, 0.0), Duration::from_secs(1));
+        assert_eq!(policy.delay(2, 0.0), Duration::from_secs(2));
+        assert_eq!(policy.delay(3, 1.0), Duration::from_secs(2));
+        assert_eq!(policy.delay(20, 0.0), MAX_DELAY);
+        for _ in 0..100 {
+            let random = random();
+            assert!((0.0..1.0).contains(&random), "{}", random);
+        }
+    }
+
+    #[test]
+    fn retries_only_what_might_pass() {
+        let policy = RetryPolicy {
+            attempts: 3,
+            base_delay: Duration::ZERO,
+            jitter: 0.5,
+        };
+
+        let mut responses = vec![status(202), status(503), status(502)];
+        let result = send(&policy, &mut responses);
+        assert_eq!(result.unwrap().status(), 202);
+        assert!(responses.is_empty());
+
+        let mut responses = vec![status(500), status(500), status(500), status(500)];
+        let result = send(&policy, &mut responses);
+        assert!(matches!(result.err(), Some(HttpError::Status(500, _))));
+        assert_eq!(responses.len(), 1, "gives up after 3 attempts");
+
+        let mut responses = vec![status(202), status(422)];
+        let result = send(&policy, &mut responses);
+        assert!(matches!(result.err(), Some(HttpError::Status(422, _))));
+        assert_eq!(responses.len(), 1, "client errors aren't retried");
+
+        let mut responses = vec![status(202), status(429)];
+        let result = send(&policy, &mut responses);
+        assert_eq!(result.unwrap().status(), 202);
+    }
+
+    #[test]
+    fn rate_limits_say_how_long_to_wait() {
+        let rate_limited =
+            |retry_after| HttpError::Status(429, Box::new(response(429, Some(retry_after))));
+
+        assert_eq!(
+            retry_after(&rate_limited("7")),
+            Some(Duration::from_secs(7))
+        );
+        assert_eq!(retry_after(&rate_limited("86400")), Some(MAX_RETRY_AFTER));
+        assert_eq!(
+            retry_after(&rate_limited("Wed, 21 Oct 2015 07:28:00 GMT")),
+            None
+        );
+        let unavailable = HttpError::Status(503, Box::new(response(503, None)));
+        assert_eq!(retry_after(&unavailable), None);
+    }
+}
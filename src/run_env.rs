@@ -8,6 +8,19 @@ use uuid::Uuid;
 static VERSION: &str = env!("CARGO_PKG_VERSION");
 static COLLECTOR_NAME: &str = env!("CARGO_PKG_NAME");
 
+/// The environment variables which must be present for each supported CI
+/// environment to be detected, in detection order.
+pub static REQUIRED_VARS: &[(&str, &[&str])] = &[
+    ("buildkite", &["BUILDKITE_BUILD_ID"]),
+    (
+        "github_actions",
+        &["GITHUB_ACTION", "GITHUB_RUN_NUMBER", "GITHUB_RUN_ATTEMPT"],
+    ),
+    ("circleci", &["CIRCLE_BUILD_NUM", "CIRCLE_WORKFLOW_ID"]),
+    ("sourcehut", &["JOB_ID", "JOB_URL", "BUILD_SUBMITTER"]),
+    ("generic", &["CI"]),
+];
+
 /// # RuntimeEnvironment
 ///
 /// A data structure containing information about the detected CI environment.
@@ -25,6 +38,17 @@ pub struct RuntimeEnvironment {
     version: String,
 }
 
+/// # RunReference
+///
+/// A runtime environment reduced to the fields which identify its run.
+#[derive(serde::Serialize, Debug, PartialEq)]
+pub struct RunReference<'a> {
+    ci: &'a str,
+    key: &'a str,
+    collector: &'a str,
+    version: &'a str,
+}
+
 impl RuntimeEnvironment {
     /// Detect the runtime environment
     ///
@@ -34,9 +58,36 @@ impl RuntimeEnvironment {
         buildkite_env()
             .or_else(github_actions_env)
             .or_else(circle_ci_env)
+            .or_else(sourcehut_env)
             .or_else(generic_env)
     }
 
+    /// The name of the detected CI environment.
+    pub fn ci(&self) -> &str {
+        &self.ci
+    }
+
+    /// The key identifying the run, which each of its uploads is sent with.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// A link to the CI build, if known.
+    pub fn url(&self) -> Option<&str> {
+        self.url.as_deref()
+    }
+
+    /// The fields which identify the run, to send in place of the whole
+    /// environment once the API has it (see `RunState::send_batches`).
+    pub fn reference(&self) -> RunReference<'_> {
+        RunReference {
+            ci: &self.ci,
+            key: &self.key,
+            collector: &self.collector,
+            version: &self.version,
+        }
+    }
+
     #[cfg(test)]
     pub fn generic() -> RuntimeEnvironment {
         RuntimeEnvironment {
@@ -48,7 +99,7 @@ impl RuntimeEnvironment {
             commit_sha: None,
             message: None,
             url: None,
-            collector: format!("rust-{}", COLLECTOR_NAME.to_string()),
+            collector: format!("rust-{}", COLLECTOR_NAME),
             version: VERSION.to_string(),
         }
     }
@@ -66,7 +117,7 @@ fn buildkite_env() -> Option<RuntimeEnvironment> {
         number: maybe_var("BUILDKITE_BUILD_NUMBER"),
         job_id: maybe_var("BUILDKITE_JOB_ID"),
         message: maybe_var("BUILDKITE_MESSAGE"),
-        collector: format!("rust-{}", COLLECTOR_NAME.to_string()),
+        collector: format!("rust-{}", COLLECTOR_NAME),
         version: VERSION.to_string(),
     })
 }
@@ -87,7 +138,7 @@ fn github_actions_env() -> Option<RuntimeEnvironment> {
         number: Some(run_number),
         job_id: None,
         message: None,
-        collector: format!("rust-{}", COLLECTOR_NAME.to_string()),
+        collector: format!("rust-{}", COLLECTOR_NAME),
         version: VERSION.to_string(),
     })
 }
@@ -105,7 +156,37 @@ fn circle_ci_env() -> Option<RuntimeEnvironment> {
         number: Some(build_num),
         job_id: None,
         message: None,
-        collector: format!("rust-{}", COLLECTOR_NAME.to_string()),
+        collector: format!("rust-{}", COLLECTOR_NAME),
+        version: VERSION.to_string(),
+    })
+}
+
+/// builds.sr.ht only provides the job id and URL, so the commit, branch and
+/// message can be provided by the build manifest's `environment` using
+/// `BUILDKITE_ANALYTICS_SHA`, `BUILDKITE_ANALYTICS_BRANCH` and
+/// `BUILDKITE_ANALYTICS_MESSAGE`.
+///
+/// `JOB_ID` and `JOB_URL` are common names, so it's only detected with
+/// `BUILD_SUBMITTER`, which builds.sr.ht sets too, and a job on its host.
+fn sourcehut_env() -> Option<RuntimeEnvironment> {
+    maybe_var("BUILD_SUBMITTER")?;
+    let job_id = maybe_var("JOB_ID")?;
+    let job_url = maybe_var("JOB_URL")?;
+    let host = job_url.strip_prefix("https://")?.split('/').next();
+    if host != Some("builds.sr.ht") {
+        return None;
+    }
+
+    Some(RuntimeEnvironment {
+        ci: "sourcehut".to_string(),
+        key: job_id.clone(),
+        url: Some(job_url),
+        branch: maybe_var("BUILDKITE_ANALYTICS_BRANCH"),
+        commit_sha: maybe_var("BUILDKITE_ANALYTICS_SHA"),
+        number: Some(job_id),
+        job_id: None,
+        message: maybe_var("BUILDKITE_ANALYTICS_MESSAGE"),
+        collector: format!("rust-{}", COLLECTOR_NAME),
         version: VERSION.to_string(),
     })
 }
@@ -122,7 +203,7 @@ fn generic_env() -> Option<RuntimeEnvironment> {
         commit_sha: None,
         message: None,
         url: None,
-        collector: format!("rust-{}", COLLECTOR_NAME.to_string()),
+        collector: format!("rust-{}", COLLECTOR_NAME),
         version: VERSION.to_string(),
     })
 }
@@ -131,12 +212,54 @@ fn maybe_var(key: &str) -> Option<String> {
     env::var(key).ok()
 }
 
+/// The `owner/name` slug of the repository being tested.
+///
+/// Taken from `BUILDKITE_ANALYTICS_REPOSITORY`, or the CI environment.
+pub fn repository_slug() -> Option<String> {
+    maybe_var("BUILDKITE_ANALYTICS_REPOSITORY")
+        .or_else(|| maybe_var("GITHUB_REPOSITORY"))
+        .or_else(|| {
+            let owner = maybe_var("CIRCLE_PROJECT_USERNAME")?;
+            let name = maybe_var("CIRCLE_PROJECT_REPONAME")?;
+            Some(format!("{}/{}", owner, name))
+        })
+        .or_else(|| maybe_var("BUILDKITE_REPO").and_then(|url| slug_from_url(&url)))
+}
+
+/// The `owner/name` slug of a git remote URL, eg
+/// `git@github.com:buildkite/test-collector-rust.git`.
+fn slug_from_url(url: &str) -> Option<String> {
+    let path = match url.split_once("://") {
+        Some((_, rest)) => rest.split_once('/')?.1,
+        None => url.split_once(':')?.1,
+    };
+    let path = path.trim_matches('/');
+    let path = path.strip_suffix(".git").unwrap_or(path);
+
+    Some(path.to_string()).filter(|path| path.contains('/'))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use rand::Rng;
     use std::collections::HashMap;
 
+    #[test]
+    fn repository_slugs_are_taken_from_remote_urls() {
+        for url in [
+            "git@github.com:buildkite/test-collector-rust.git",
+            "https://github.com/buildkite/test-collector-rust.git",
+            "ssh://git@gitlab.example.com/buildkite/test-collector-rust",
+        ] {
+            assert_eq!(
+                slug_from_url(url).as_deref(),
+                Some("buildkite/test-collector-rust")
+            );
+        }
+        assert_eq!(slug_from_url("/srv/git/collector.git"), None);
+    }
+
     #[test]
     #[serial]
     fn detects_buildkite_environment() {
@@ -170,7 +293,7 @@ mod test {
             assert_eq!(env.job_id, Some(job_id));
             assert_eq!(env.message, Some(message));
             assert_eq!(env.version, VERSION);
-            assert_eq!(env.collector, format!("rust-{}", COLLECTOR_NAME.to_string()));
+            assert_eq!(env.collector, format!("rust-{}", COLLECTOR_NAME));
         });
     }
 
@@ -216,7 +339,7 @@ mod test {
             assert_eq!(env.job_id, None);
             assert_eq!(env.message, None);
             assert_eq!(env.version, VERSION);
-            assert_eq!(env.collector, format!("rust-{}", COLLECTOR_NAME.to_string()));
+            assert_eq!(env.collector, format!("rust-{}", COLLECTOR_NAME));
         })
     }
 
@@ -249,7 +372,58 @@ mod test {
             assert_eq!(env.job_id, None);
             assert_eq!(env.message, None);
             assert_eq!(env.version, VERSION);
-            assert_eq!(env.collector, format!("rust-{}", COLLECTOR_NAME.to_string()));
+            assert_eq!(env.collector, format!("rust-{}", COLLECTOR_NAME));
+        });
+    }
+
+    #[test]
+    #[serial]
+    fn detect_sourcehut_environment() {
+        let mut rng = rand::thread_rng();
+
+        with_clean_environment(|| {
+            let job_id = rng.gen_range(0..999999).to_string();
+            let url = format!("https://builds.sr.ht/~marty/job/{}", job_id);
+            let branch = "marty".to_string();
+            let commit_sha = Uuid::new_v4().to_string().replace('-', "");
+            let message = "Be excellent to each other".to_string();
+
+            env::set_var("BUILD_SUBMITTER", "git.sr.ht");
+            env::set_var("JOB_ID", &job_id);
+            env::set_var("JOB_URL", &url);
+            env::set_var("BUILDKITE_ANALYTICS_BRANCH", &branch);
+            env::set_var("BUILDKITE_ANALYTICS_SHA", &commit_sha);
+            env::set_var("BUILDKITE_ANALYTICS_MESSAGE", &message);
+
+            let env = RuntimeEnvironment::detect().unwrap();
+
+            assert_eq!(env.ci, "sourcehut");
+            assert_eq!(env.key, job_id);
+            assert_eq!(env.url, Some(url));
+            assert_eq!(env.branch, Some(branch));
+            assert_eq!(env.commit_sha, Some(commit_sha));
+            assert_eq!(env.number, Some(job_id));
+            assert_eq!(env.job_id, None);
+            assert_eq!(env.message, Some(message));
+            assert_eq!(env.version, VERSION);
+            assert_eq!(env.collector, format!("rust-{}", COLLECTOR_NAME));
+        });
+    }
+
+    #[test]
+    #[serial]
+    fn ignore_other_jobs_with_an_id_and_url() {
+        with_clean_environment(|| {
+            env::set_var("JOB_ID", "42");
+            env::set_var("JOB_URL", "https://jenkins.example.com/job/42");
+            assert_eq!(RuntimeEnvironment::detect(), None);
+
+            // Even with the variable builds.sr.ht sets, the job must be on it.
+            env::set_var("BUILD_SUBMITTER", "git.sr.ht");
+            assert_eq!(RuntimeEnvironment::detect(), None);
+
+            env::set_var("JOB_URL", "https://builds.sr.ht/~marty/job/42");
+            assert_eq!(RuntimeEnvironment::detect().unwrap().ci, "sourcehut");
         });
     }
 
@@ -271,7 +445,7 @@ mod test {
             assert_eq!(env.message, None);
             assert_eq!(env.url, None);
             assert_eq!(env.version, VERSION);
-            assert_eq!(env.collector, format!("rust-{}", COLLECTOR_NAME.to_string()));
+            assert_eq!(env.collector, format!("rust-{}", COLLECTOR_NAME));
         });
     }
 
@@ -314,6 +488,8 @@ mod test {
         key.starts_with("BUILDKITE")
             || key.starts_with("GITHUB")
             || key.starts_with("CIRCLE")
+            || key.starts_with("JOB_")
+            || key.starts_with("BUILD_")
             || key.starts_with("CI")
     }
 }
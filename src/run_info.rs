@@ -0,0 +1,148 @@
+//! # run_info
+//!
+//! Machine-readable information about the uploaded run, so that later
+//! pipeline steps can link to or query it, and a line in the build log
+//! linking to it in Test Engine.
+//!
+//! The API's response usually includes the run's URL.  If it doesn't, the URL
+//! is built from the run's id, given `BUILDKITE_ORGANIZATION_SLUG` and
+//! `BUILDKITE_ANALYTICS_SUITE_SLUG`.
+
+use crate::api::ApiResponse;
+use std::env;
+use std::fs;
+use std::io;
+
+/// # RunInfo
+///
+/// The identifiers returned by the API for each uploaded batch.
+#[derive(serde::Serialize, Debug, Default, PartialEq)]
+pub struct RunInfo {
+    run_id: Option<String>,
+    run_url: Option<String>,
+    upload_ids: Vec<String>,
+    /// The number of tests queued for processing, across every upload.
+    #[serde(skip)]
+    queued: usize,
+    /// The number of tests the API skipped, across every upload.
+    #[serde(skip)]
+    skipped: usize,
+    /// The fraction of the tests the suites expected to run which finished.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    completeness: Option<f64>,
+}
+
+impl RunInfo {
+    /// Record the response to a successful upload.
+    pub fn record(&mut self, response: &ApiResponse) {
+        self.run_id.get_or_insert_with(|| response.run_id.clone());
+        if self.run_url.is_none() {
+            self.run_url = response.run_url.clone().or_else(|| {
+                run_url(
+                    env::var("BUILDKITE_ORGANIZATION_SLUG").ok()?,
+                    env::var("BUILDKITE_ANALYTICS_SUITE_SLUG").ok()?,
+                    &response.run_id,
+                )
+            });
+        }
+        self.upload_ids.push(response.id.clone());
+        self.queued += response.queued;
+        self.skipped += response.skipped;
+    }
+
+    /// Record how complete the uploaded results are.
+    pub fn set_completeness(&mut self, completeness: Option<f64>) {
+        self.completeness = completeness;
+    }
+
+    /// Has any upload succeeded?
+    pub fn is_empty(&self) -> bool {
+        self.upload_ids.is_empty()
+    }
+
+    /// A line for the build log saying how many tests were uploaded, and
+    /// where to see them.  `None` if nothing was uploaded.
+    pub fn message(&self) -> Option<String> {
+        let run = self.run_url.as_ref().or(self.run_id.as_ref())?;
+        let mut message = format!("Test Engine queued {} tests", self.queued);
+        if self.skipped > 0 {
+            message.push_str(&format!(" ({} skipped)", self.skipped));
+        }
+
+        Some(format!("{} for run {}", message, run))
+    }
+
+    /// Write the run information to `path` as JSON.
+    pub fn write(&self, path: &str) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        fs::write(path, json)
+    }
+}
+
+/// The URL of run `run_id` of the `suite` in the `org`, if both are known.
+fn run_url(org: String, suite: String, run_id: &str) -> Option<String> {
+    if org.is_empty() || suite.is_empty() {
+        return None;
+    }
+
+    Some(format!(
+        "https://buildkite.com/organizations/{}/analytics/suites/{}/runs/{}",
+        org, suite, run_id
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn records_every_upload_of_the_run() {
+        let mut run_info = RunInfo::default();
+
+        for id in ["upload-1", "upload-2"] {
+            run_info.record(&ApiResponse {
+                id: id.to_string(),
+                run_id: "run-1".to_string(),
+                queued: 1,
+                skipped: 0,
+                errors: vec![],
+                run_url: Some("https://buildkite.com/runs/run-1".to_string()),
+            });
+        }
+
+        assert_eq!(
+            serde_json::to_value(&run_info).unwrap(),
+            serde_json::json!({
+                "run_id": "run-1",
+                "run_url": "https://buildkite.com/runs/run-1",
+                "upload_ids": ["upload-1", "upload-2"],
+            })
+        );
+        assert_eq!(
+            run_info.message().as_deref(),
+            Some("Test Engine queued 2 tests for run https://buildkite.com/runs/run-1")
+        );
+    }
+
+    #[test]
+    fn links_to_the_run_without_a_url_from_the_api() {
+        assert_eq!(RunInfo::default().message(), None);
+
+        let mut run_info = RunInfo::default();
+        run_info.record(&ApiResponse {
+            id: "upload-1".to_string(),
+            run_id: "run-1".to_string(),
+            queued: 498,
+            skipped: 2,
+            errors: vec![],
+            run_url: None,
+        });
+        run_info.run_url = run_url("acme".to_string(), "api".to_string(), "run-1");
+        assert_eq!(
+            run_info.message().as_deref(),
+            Some("Test Engine queued 498 tests (2 skipped) for run https://buildkite.com/organizations/acme/analytics/suites/api/runs/run-1")
+        );
+
+        assert_eq!(run_url(String::new(), "api".to_string(), "run-1"), None);
+    }
+}
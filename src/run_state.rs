@@ -0,0 +1,291 @@
+//! # run_state
+//!
+//! What's known about the run across all of its uploads.  The first batch,
+//! streamed batches, periodic and `serve` flushes, spilled batches and the
+//! final upload each `finish` a payload of their own, but they share one
+//! `RunState`, so that the run information and the annotation cover every
+//! upload, and, if batches may only refer to the run, its full runtime
+//! environment is sent before any batch which does.
+
+use crate::annotate::{self, AnnotateStyle, Results};
+use crate::api::ApiResponse;
+use crate::payload::{Completeness, Payload};
+use crate::run_info::RunInfo;
+use futures_util::stream::{self, StreamExt};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// # Sent
+///
+/// What became of a batch passed to `send`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sent {
+    /// The API accepted it.
+    Accepted,
+    /// The API refused it, so it wasn't kept to send again.
+    Rejected,
+    /// It couldn't be sent, eg because the API is down, and was spooled.
+    Failed,
+}
+
+/// # RunState
+///
+/// The state shared by every upload of the run.
+#[derive(Debug, Default)]
+pub struct RunState {
+    run_info: Mutex<RunInfo>,
+    results: Mutex<Results>,
+    /// Whether batches after the first may send only the fields of the
+    /// runtime environment which identify the run.
+    run_env_references: bool,
+    /// Held while the full runtime environment is sent, and true once the
+    /// API has accepted it.
+    run_env_sent: tokio::sync::Mutex<bool>,
+    /// Whether the API has refused a batch which only referred to the run.
+    references_rejected: AtomicBool,
+}
+
+impl RunState {
+    /// Send only a reference to the run with each batch once the API has
+    /// accepted its full runtime environment.
+    ///
+    /// Off by default, as the upload API doesn't document keeping the
+    /// metadata of a run when a later upload for it leaves it out.
+    pub fn with_run_env_references(mut self, run_env_references: bool) -> Self {
+        self.run_env_references = run_env_references;
+        self
+    }
+
+    /// Record the response to a successful upload.
+    pub fn record(&self, response: &ApiResponse) {
+        self.run_info.lock().unwrap().record(response);
+    }
+
+    /// Tally the tests which have finished in `payload`, for the annotation.
+    pub fn record_results(&self, payload: &Payload) {
+        self.results.lock().unwrap().record(payload);
+    }
+
+    /// Annotate the build with the failures of every test tallied so far,
+    /// and the suite name and build URL of `payload`.
+    pub fn annotate(&self, payload: &Payload, style: &AnnotateStyle) {
+        annotate::annotate(&self.results.lock().unwrap(), payload, style);
+    }
+
+    /// Send `batches` with `send`, up to `workers` at once.
+    ///
+    /// If batches may refer to the run (see `with_run_env_references`), then
+    /// until the API has accepted the full runtime environment, for this or
+    /// an earlier call, batches are sent one at a time in full, and the rest
+    /// wait.  Then the rest only refer to the run, unless the API refuses
+    /// one, which is sent again in full, as are all after it.
+    ///
+    /// ## Emits warnings
+    ///  - If the API refuses a batch which only refers to the run.
+    pub async fn send_batches<F>(&self, batches: Vec<Payload>, workers: usize, send: F)
+    where
+        F: AsyncFn(&Payload) -> Sent,
+    {
+        let mut batches = batches.into_iter();
+
+        if self.run_env_references {
+            // Other uploads for the run wait on the lock too.
+            let mut run_env_sent = self.run_env_sent.lock().await;
+            while !*run_env_sent {
+                let Some(batch) = batches.next() else {
+                    return;
+                };
+                *run_env_sent = send(&batch).await == Sent::Accepted;
+            }
+        }
+
+        let send = &send;
+        stream::iter(batches)
+            .for_each_concurrent(workers.max(1), |mut batch| async move {
+                if self.run_env_references && !self.references_rejected.load(Ordering::Relaxed) {
+                    batch.set_refer_to_run(true);
+                    if send(&batch).await != Sent::Rejected {
+                        return;
+                    }
+                    if !self.references_rejected.swap(true, Ordering::Relaxed) {
+                        eprintln!("Sending the full runtime environment with each batch instead.");
+                    }
+                    batch.set_refer_to_run(false);
+                }
+                send(&batch).await;
+            })
+            .await;
+    }
+
+    /// Once the last upload is done, print how many tests were uploaded and
+    /// where to see them, and write the run information, including the
+    /// `completeness` of the results, to `run_info_file`.  Does nothing if
+    /// no upload was accepted.
+    ///
+    /// ## Emits warnings
+    ///  - If the run information cannot be written.
+    pub fn finish(&self, run_info_file: Option<&str>, completeness: Option<Completeness>) {
+        let mut run_info = self.run_info.lock().unwrap();
+        run_info.set_completeness(completeness.map(|c| c.ratio()));
+
+        if let Some(message) = run_info.message() {
+            eprintln!("{}", message);
+        }
+
+        if let Some(path) = run_info_file.filter(|_| !run_info.is_empty()) {
+            if let Err(err) = run_info.write(path) {
+                eprintln!("Unable to write run information to {}: {}", path, err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::http::block_on;
+    use crate::run_env::RuntimeEnvironment;
+    use std::env;
+    use std::fs;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn response(id: &str) -> ApiResponse {
+        ApiResponse {
+            id: id.to_string(),
+            run_id: "run-1".to_string(),
+            queued: 1,
+            skipped: 0,
+            errors: vec![],
+            run_url: None,
+        }
+    }
+
+    #[test]
+    fn run_information_covers_every_upload() {
+        let run = Arc::new(RunState::default());
+        // Eg the first batch, uploaded on its own thread.
+        let early = run.clone();
+        thread::spawn(move || early.record(&response("upload-1")))
+            .join()
+            .unwrap();
+        run.record(&response("upload-2"));
+
+        let path = env::temp_dir().join(format!("run-info-{}.json", uuid::Uuid::new_v4()));
+        run.finish(path.to_str(), None);
+        let json: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(
+            json["upload_ids"],
+            serde_json::json!(["upload-1", "upload-2"])
+        );
+    }
+
+    fn batches(count: usize) -> Vec<Payload> {
+        let run_env = RuntimeEnvironment::generic();
+        (0..count).map(|_| Payload::new(run_env.clone())).collect()
+    }
+
+    #[test]
+    fn batches_are_sent_in_full_by_default() {
+        let run = RunState::default();
+        let sent = Mutex::new(Vec::new());
+
+        block_on(run.send_batches(batches(4), 2, async |batch: &Payload| {
+            sent.lock().unwrap().push(batch.refers_to_run());
+            Sent::Accepted
+        }));
+
+        assert_eq!(sent.into_inner().unwrap(), [false, false, false, false]);
+    }
+
+    #[test]
+    fn batches_are_sent_up_to_workers_at_once() {
+        let run = RunState::default();
+        let sending = AtomicUsize::new(0);
+        let most = AtomicUsize::new(0);
+
+        block_on(run.send_batches(batches(8), 3, async |_: &Payload| {
+            let now = sending.fetch_add(1, Ordering::SeqCst) + 1;
+            most.fetch_max(now, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            sending.fetch_sub(1, Ordering::SeqCst);
+            Sent::Accepted
+        }));
+
+        assert_eq!(most.into_inner(), 3);
+    }
+
+    #[test]
+    fn full_run_env_is_accepted_before_references_are_sent() {
+        let run = RunState::default().with_run_env_references(true);
+        let accepted = AtomicBool::new(false);
+        let sent = Mutex::new(Vec::new());
+
+        block_on(run.send_batches(batches(6), 3, async |batch: &Payload| {
+            let full = !batch.refers_to_run();
+            if full {
+                // Slow, so a reference sent early would be caught.
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                accepted.store(true, Ordering::SeqCst);
+            } else {
+                assert!(accepted.load(Ordering::SeqCst));
+            }
+            sent.lock().unwrap().push(full);
+            Sent::Accepted
+        }));
+        assert_eq!(
+            sent.lock().unwrap()[..],
+            [true, false, false, false, false, false]
+        );
+
+        // A later upload for the run only refers to it.
+        sent.lock().unwrap().clear();
+        block_on(run.send_batches(batches(2), 3, async |batch: &Payload| {
+            sent.lock().unwrap().push(!batch.refers_to_run());
+            Sent::Accepted
+        }));
+        assert_eq!(sent.into_inner().unwrap(), [false, false]);
+    }
+
+    #[test]
+    fn full_run_env_is_sent_until_accepted() {
+        let run = RunState::default().with_run_env_references(true);
+        let sent = Mutex::new(Vec::new());
+
+        block_on(run.send_batches(batches(4), 1, async |batch: &Payload| {
+            let mut sent = sent.lock().unwrap();
+            sent.push(!batch.refers_to_run());
+            // Eg the API is down for the first.
+            if sent.len() == 1 {
+                Sent::Failed
+            } else {
+                Sent::Accepted
+            }
+        }));
+
+        assert_eq!(sent.into_inner().unwrap(), [true, true, false, false]);
+    }
+
+    #[test]
+    fn full_run_env_is_sent_if_references_are_rejected() {
+        let run = RunState::default().with_run_env_references(true);
+        let sent = Mutex::new(Vec::new());
+
+        block_on(run.send_batches(batches(4), 1, async |batch: &Payload| {
+            let full = !batch.refers_to_run();
+            sent.lock().unwrap().push(full);
+            if full {
+                Sent::Accepted
+            } else {
+                Sent::Rejected
+            }
+        }));
+
+        assert_eq!(sent.into_inner().unwrap(), [true, false, true, true, true]);
+    }
+}
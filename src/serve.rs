@@ -0,0 +1,417 @@
+//! # serve
+//!
+//! `serve` mode collects libtest JSON from `stdin`, like the default mode,
+//! while listening on a TCP socket so that tools (eg IDEs and dashboards) can
+//! follow the run as it happens.
+//!
+//! Connections send newline delimited lines.  [JSON-RPC 2.0] requests are
+//! answered on the same connection, and any other line is parsed as libtest
+//! JSON, so test output can be streamed over the socket too.
+//!
+//! | Method                 | Params            | Result                                   |
+//! | ---------------------- | ----------------- | ---------------------------------------- |
+//! | `status`               |                   | Counts of tests so far                   |
+//! | `flush`                |                   | Uploads the finished tests now           |
+//! | `set_tags`             | `{"key": "value"}`| Tags the run                             |
+//! | `get_current_failures` |                   | The tests which have failed so far       |
+//!
+//! [JSON-RPC 2.0]: https://www.jsonrpc.org/specification
+
+use crate::exit::Summary;
+use crate::input::{is_json_object, ParseErrors};
+use crate::payload::Payload;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// The address listened on when `--listen` isn't given.  Port `0` picks a
+/// free port, which is printed on start up.
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:0";
+
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INVALID_REQUEST: i64 = -32600;
+
+/// # State
+///
+/// Everything collected so far, shared between `stdin` and the connections.
+#[derive(Debug)]
+pub struct State {
+    pub payload: Payload,
+    pub parse_errors: ParseErrors,
+    /// The totals of any uploads made with `flush`.
+    pub flushed: Summary,
+}
+
+impl State {
+    pub fn new(payload: Payload, parse_errors: ParseErrors) -> Self {
+        State {
+            payload,
+            parse_errors,
+            flushed: Summary::default(),
+        }
+    }
+}
+
+type Shared = Arc<Mutex<State>>;
+
+/// Uploads a payload, as `finish` does at the end of the run.
+pub type Flush = Arc<dyn Fn(Payload) -> Summary + Send + Sync>;
+
+#[derive(Deserialize)]
+struct Request {
+    jsonrpc: String,
+    method: String,
+    #[serde(default)]
+    params: Value,
+    #[serde(default)]
+    id: Option<Value>,
+}
+
+/// Listen on `address`, then collect `stdin` until it closes.
+///
+/// Returns the state at the end of the run, for the final upload.
+///
+/// ## Emits warnings
+///  - With the address being listened on.
+pub fn serve(address: &str, state: State, flush: Flush) -> io::Result<State> {
+    let state = Arc::new(Mutex::new(state));
+    let address = listen(address, state.clone(), flush)?;
+    eprintln!("Listening for JSON-RPC requests on {}", address);
+
+    for line in io::stdin().lock().lines().map_while(Result::ok) {
+        {
+            let mut state = state.lock().unwrap();
+            let State {
+                payload,
+                parse_errors,
+                ..
+            } = &mut *state;
+            parse_errors.parse_line(&line, payload);
+        }
+        println!("{}", line);
+    }
+
+    let mut state = state.lock().unwrap();
+    Ok(State {
+        payload: state.payload.take(),
+        parse_errors: std::mem::take(&mut state.parse_errors),
+        flushed: std::mem::take(&mut state.flushed),
+    })
+}
+
+/// Accept connections on `address` in the background.
+///
+/// Returns the address actually bound, eg the port picked for port `0`.
+/// Paths are refused, rather than looked up as host names, as they're what
+/// `--input-socket` takes.
+fn listen(address: &str, state: Shared, flush: Flush) -> io::Result<SocketAddr> {
+    if address.contains('/') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "not a TCP address (to read test output from a named pipe or Unix socket, pass --input-socket without `serve`)",
+        ));
+    }
+    let listener = TcpListener::bind(address)?;
+    let address = listener.local_addr()?;
+
+    thread::spawn(move || {
+        for stream in listener.incoming().map_while(Result::ok) {
+            let state = state.clone();
+            let flush = flush.clone();
+            thread::spawn(move || {
+                if let Err(err) = connection(stream, &state, &flush) {
+                    eprintln!("JSON-RPC connection closed: {}", err);
+                }
+            });
+        }
+    });
+
+    Ok(address)
+}
+
+fn connection(stream: TcpStream, state: &Shared, flush: &Flush) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+
+    for line in BufReader::new(stream).lines() {
+        if let Some(response) = handle_line(&line?, state, flush) {
+            writeln!(writer, "{}", response)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Answer `line` if it's a JSON-RPC request, otherwise collect it as libtest
+/// JSON.
+///
+/// Returns the response, if one is due (notifications don't get one).
+fn handle_line(line: &str, state: &Shared, flush: &Flush) -> Option<Value> {
+    let value = Some(line)
+        .filter(|line| is_json_object(line))
+        .and_then(|line| serde_json::from_str::<Value>(line).ok())
+        .filter(|value| value.get("jsonrpc").is_some());
+
+    let Some(value) = value else {
+        let mut state = state.lock().unwrap();
+        let State {
+            payload,
+            parse_errors,
+            ..
+        } = &mut *state;
+        parse_errors.parse_line(line, payload);
+        return None;
+    };
+
+    let id = value.get("id").cloned().unwrap_or(Value::Null);
+    let request = match serde_json::from_value::<Request>(value) {
+        Ok(request) if request.jsonrpc == "2.0" => request,
+        _ => return Some(error(id, INVALID_REQUEST, "Invalid request")),
+    };
+
+    let result = call(&request.method, request.params, state, flush);
+    let id = request.id?;
+
+    Some(match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err((code, message)) => error(id, code, &message),
+    })
+}
+
+fn call(
+    method: &str,
+    params: Value,
+    state: &Shared,
+    flush: &Flush,
+) -> Result<Value, (i64, String)> {
+    match method {
+        "status" => {
+            let state = state.lock().unwrap();
+            let closed_data = state.payload.closed_data();
+            Ok(json!({
+                "finished": closed_data.len(),
+                "failed": closed_data.iter().filter(|test_data| test_data.is_failed()).count(),
+                "running": state.payload.unfinished_data().len(),
+                "flushed": state.flushed.tests,
+                "parse_errors": state.parse_errors.count,
+            }))
+        }
+        "flush" => {
+            // Don't hold the lock while uploading, so collection carries on.
+            let finished = state.lock().unwrap().payload.take_finished();
+            let summary = flush(finished);
+            let result = json!({
+                "tests": summary.tests,
+                "batches": summary.batches,
+                "uploaded": summary.uploaded,
+                "spooled": summary.spooled,
+            });
+            state.lock().unwrap().flushed.merge(&summary);
+            Ok(result)
+        }
+        "set_tags" => {
+            let Some(tags) = params.as_object() else {
+                return Err((INVALID_PARAMS, "Expected an object of tags".to_string()));
+            };
+            let mut state = state.lock().unwrap();
+            for (key, value) in tags {
+                let Some(value) = value.as_str() else {
+                    return Err((INVALID_PARAMS, format!("Tag {:?} must be a string", key)));
+                };
+                state.payload.insert_tag(key, value);
+            }
+            Ok(json!(tags.len()))
+        }
+        "get_current_failures" => {
+            let state = state.lock().unwrap();
+            let failures = state
+                .payload
+                .closed_data()
+                .into_iter()
+                .filter(|test_data| test_data.is_failed())
+                .map(|test_data| {
+                    json!({
+                        "scope": test_data.scope(),
+                        "name": test_data.name(),
+                        "file_name": test_data.file_name(),
+                        "failure_reason": test_data.failure_reason(),
+                    })
+                })
+                .collect::<Vec<Value>>();
+            Ok(Value::Array(failures))
+        }
+        _ => Err((METHOD_NOT_FOUND, format!("Unknown method {:?}", method))),
+    }
+}
+
+fn error(id: Value, code: i64, message: &str) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message },
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::run_env::RuntimeEnvironment;
+
+    fn shared() -> Shared {
+        let state = State::new(
+            Payload::new(RuntimeEnvironment::generic()),
+            ParseErrors::default(),
+        );
+        Arc::new(Mutex::new(state))
+    }
+
+    fn counting_flush() -> Flush {
+        Arc::new(|payload: Payload| Summary {
+            configured: true,
+            tests: payload.closed_data().len(),
+            batches: 1,
+            uploaded: 1,
+            ..Summary::default()
+        })
+    }
+
+    fn feed(state: &Shared, flush: &Flush) {
+        for line in [
+            r#"{ "type": "suite", "event": "started", "test_count": 3 }"#,
+            r#"{ "type": "test", "event": "started", "name": "a::passes" }"#,
+            r#"{ "type": "test", "event": "started", "name": "a::fails" }"#,
+            r#"{ "type": "test", "event": "started", "name": "a::runs" }"#,
+            r#"{ "type": "test", "name": "a::passes", "event": "ok", "exec_time": 0.1 }"#,
+            r#"{ "type": "test", "name": "a::fails", "event": "failed", "exec_time": 0.1, "stdout": "boom" }"#,
+        ] {
+            assert_eq!(handle_line(line, state, flush), None);
+        }
+    }
+
+    fn request(state: &Shared, flush: &Flush, request: Value) -> Value {
+        handle_line(&request.to_string(), state, flush).unwrap()
+    }
+
+    #[test]
+    fn reports_status_and_failures() {
+        let (state, flush) = (shared(), counting_flush());
+        feed(&state, &flush);
+
+        let status = request(
+            &state,
+            &flush,
+            json!({ "jsonrpc": "2.0", "id": 1, "method": "status" }),
+        );
+        assert_eq!(
+            status,
+            json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "result": { "finished": 2, "failed": 1, "running": 1, "flushed": 0, "parse_errors": 0 },
+            })
+        );
+
+        let failures = request(
+            &state,
+            &flush,
+            json!({ "jsonrpc": "2.0", "id": "f", "method": "get_current_failures" }),
+        );
+        assert_eq!(
+            failures["result"],
+            json!([{ "scope": "a", "name": "fails", "file_name": null, "failure_reason": "boom" }])
+        );
+    }
+
+    #[test]
+    fn flushes_the_finished_tests() {
+        let (state, flush) = (shared(), counting_flush());
+        feed(&state, &flush);
+
+        let response = request(
+            &state,
+            &flush,
+            json!({ "jsonrpc": "2.0", "id": 1, "method": "flush" }),
+        );
+
+        assert_eq!(response["result"]["tests"], 2);
+        let state = state.lock().unwrap();
+        assert_eq!(state.payload.closed_data().len(), 0);
+        assert_eq!(state.payload.unfinished_data().len(), 1);
+        assert_eq!(state.flushed.tests, 2);
+    }
+
+    #[test]
+    fn tags_the_run() {
+        let (state, flush) = (shared(), counting_flush());
+
+        let response = request(
+            &state,
+            &flush,
+            json!({ "jsonrpc": "2.0", "id": 1, "method": "set_tags", "params": { "branch.kind": "feature" } }),
+        );
+        assert_eq!(response["result"], 1);
+
+        let json = serde_json::to_value(&state.lock().unwrap().payload).unwrap();
+        assert_eq!(json["tags"]["branch.kind"], "feature");
+
+        let response = request(
+            &state,
+            &flush,
+            json!({ "jsonrpc": "2.0", "id": 2, "method": "set_tags", "params": ["feature"] }),
+        );
+        assert_eq!(response["error"]["code"], INVALID_PARAMS);
+    }
+
+    #[test]
+    fn rejects_unknown_methods_and_answers_no_notifications() {
+        let (state, flush) = (shared(), counting_flush());
+
+        let response = request(
+            &state,
+            &flush,
+            json!({ "jsonrpc": "2.0", "id": 1, "method": "explode" }),
+        );
+        assert_eq!(response["error"]["code"], METHOD_NOT_FOUND);
+
+        let response = request(&state, &flush, json!({ "jsonrpc": "1.0", "id": 2 }));
+        assert_eq!(response["error"]["code"], INVALID_REQUEST);
+
+        let notification = json!({ "jsonrpc": "2.0", "method": "status" }).to_string();
+        assert_eq!(handle_line(&notification, &state, &flush), None);
+    }
+
+    #[test]
+    fn answers_requests_over_tcp() {
+        let (state, flush) = (shared(), counting_flush());
+        let address = listen(DEFAULT_ADDRESS, state, flush).unwrap();
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        writeln!(
+            stream,
+            r#"{{ "type": "test", "event": "started", "name": "a::b" }}"#
+        )
+        .unwrap();
+        writeln!(
+            stream,
+            r#"{{ "jsonrpc": "2.0", "id": 1, "method": "status" }}"#
+        )
+        .unwrap();
+
+        let mut response = String::new();
+        BufReader::new(stream).read_line(&mut response).unwrap();
+        let response: Value = serde_json::from_str(&response).unwrap();
+        assert_eq!(response["result"]["running"], 1);
+    }
+
+    #[test]
+    fn refuses_socket_paths() {
+        let (state, flush) = (shared(), counting_flush());
+        let err = listen("/tmp/collector.sock", state, flush).unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("--input-socket"));
+    }
+}
@@ -0,0 +1,175 @@
+//! # severity
+//!
+//! Lets platform teams decide which test failures matter, without editing the
+//! tests.  A severity file maps test name patterns to a severity, one rule per
+//! line, eg:
+//!
+//! ```text
+//! # Later rules take precedence
+//! *                      normal
+//! smoke::*               blocker
+//! experimental::*        informational
+//! ```
+//!
+//! Patterns match the test's full name (`scope::name`), with `*` and `?`
+//! wildcards.  Every test is tagged with its `severity`, and failures of
+//! informational tests are uploaded but don't fail the run.  Blocker failures
+//! always fail the run, whatever other policies say.
+
+use crate::codeowners::match_glob;
+use crate::payload::Payload;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+/// Where the severity file is conventionally kept.
+const PATH: &str = ".buildkite/test-severity";
+
+/// # Severity
+///
+/// How much a test's failure matters.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum Severity {
+    /// Always fails the run.
+    Blocker,
+    #[default]
+    Normal,
+    /// Uploaded, but never fails the run.
+    Informational,
+}
+
+impl Severity {
+    pub fn parse(value: &str) -> Option<Severity> {
+        match value {
+            "blocker" => Some(Severity::Blocker),
+            "normal" => Some(Severity::Normal),
+            "informational" => Some(Severity::Informational),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Blocker => "blocker",
+            Severity::Normal => "normal",
+            Severity::Informational => "informational",
+        }
+    }
+}
+
+/// # SeverityRules
+///
+/// The rules from a severity file.  Later rules take precedence.
+#[derive(Debug, PartialEq, Default)]
+pub struct SeverityRules {
+    rules: Vec<(String, Severity)>,
+}
+
+impl SeverityRules {
+    /// Find and parse the severity file.
+    ///
+    /// Taken from `BUILDKITE_ANALYTICS_SEVERITY_FILE`, or
+    /// `.buildkite/test-severity` if it exists.
+    ///
+    /// ## Emits warnings
+    ///  - If the file cannot be read.
+    pub fn discover() -> Option<Self> {
+        let path = match env::var_os("BUILDKITE_ANALYTICS_SEVERITY_FILE") {
+            Some(path) => PathBuf::from(path),
+            None => Some(PathBuf::from(PATH)).filter(|path| path.is_file())?,
+        };
+
+        match fs::read_to_string(&path) {
+            Ok(contents) => Some(Self::parse(&contents)),
+            Err(err) => {
+                eprintln!("Unable to read {}: {}", path.display(), err);
+                None
+            }
+        }
+    }
+
+    /// Parse the contents of a severity file.
+    ///
+    /// ## Emits warnings
+    ///  - For each rule with an unknown severity, which is ignored.
+    pub fn parse(contents: &str) -> Self {
+        let rules = contents
+            .lines()
+            .map(|line| line.split_once('#').map_or(line, |(rule, _)| rule).trim())
+            .filter_map(|line| {
+                let (pattern, severity) = line.rsplit_once(char::is_whitespace)?;
+                match Severity::parse(severity) {
+                    Some(severity) => Some((pattern.trim().to_string(), severity)),
+                    None => {
+                        eprintln!("Ignoring the rule {:?}: unknown severity.", line);
+                        None
+                    }
+                }
+            })
+            .collect();
+
+        SeverityRules { rules }
+    }
+
+    /// The severity of the test with the given full name.
+    pub fn severity(&self, full_name: &str) -> Severity {
+        self.rules
+            .iter()
+            .rev()
+            .find(|(pattern, _)| match_glob(pattern.as_bytes(), full_name.as_bytes()))
+            .map(|(_, severity)| *severity)
+            .unwrap_or_default()
+    }
+
+    /// Tag every test in `payload` with its `severity`.
+    pub fn tag(&self, payload: &mut Payload) {
+        for test_data in payload.data_mut() {
+            let severity = self.severity(&test_data.full_name());
+            test_data.insert_tag("severity", severity.as_str());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn later_rules_take_precedence() {
+        let rules = SeverityRules::parse(
+            "# Everything matters a bit
+*                 normal
+smoke::*          blocker
+smoke::flaky_*    informational  # until it's fixed
+broken::*         sometimes
+",
+        );
+
+        assert_eq!(rules.severity("payload::test::a"), Severity::Normal);
+        assert_eq!(rules.severity("smoke::logs_in"), Severity::Blocker);
+        assert_eq!(
+            rules.severity("smoke::flaky_search"),
+            Severity::Informational
+        );
+        assert_eq!(rules.severity("broken::thing"), Severity::Normal);
+        assert_eq!(SeverityRules::default().severity("a"), Severity::Normal);
+    }
+
+    #[test]
+    fn tests_are_tagged_with_their_severity() {
+        use crate::run_env::RuntimeEnvironment;
+
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+        for line in [
+            r#"{ "type": "test", "event": "started", "name": "smoke::flaky_search" }"#,
+            r#"{ "type": "test", "name": "smoke::flaky_search", "event": "failed", "exec_time": 0.1 }"#,
+        ] {
+            crate::input::parse_line(line, &mut payload).unwrap();
+        }
+
+        SeverityRules::parse("smoke::flaky_* informational").tag(&mut payload);
+
+        let json = serde_json::to_value(&payload).unwrap();
+        assert_eq!(json["data"][0]["tags"]["severity"], "informational");
+    }
+}
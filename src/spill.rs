@@ -0,0 +1,220 @@
+//! # spill
+//!
+//! Keeps memory bounded on giant suites by writing each test to a temporary
+//! NDJSON file as soon as it finishes, rather than holding every result (and
+//! its failure output) until the end of the run.  When the run is over the
+//! file is read back a batch at a time, and each batch is uploaded before the
+//! next is read.
+//!
+//! Enabled by setting `BUILDKITE_ANALYTICS_SPILL=true`.  The file is written
+//! to `BUILDKITE_ANALYTICS_SPILL_DIR`, or the system temporary directory.
+
+use crate::exit::Summary;
+use crate::payload::{parse_var, Payload, TestData};
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// # Spill
+///
+/// The temporary file finished tests are written to.
+#[derive(Debug)]
+pub struct Spill {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    spilled: usize,
+}
+
+impl Spill {
+    /// Create the spill file, if spilling is enabled.
+    ///
+    /// ## Emits warnings
+    ///  - If the file cannot be created, in which case results are kept in
+    ///    memory as usual.
+    pub fn from_env() -> Option<Self> {
+        if !parse_var::<bool>("BUILDKITE_ANALYTICS_SPILL").unwrap_or(false) {
+            return None;
+        }
+
+        let dir = env::var_os("BUILDKITE_ANALYTICS_SPILL_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(env::temp_dir);
+        match Self::create(&dir) {
+            Ok(spill) => Some(spill),
+            Err(err) => {
+                eprintln!(
+                    "Unable to create a spill file in {}: {}.  Results will be kept in memory.",
+                    dir.display(),
+                    err
+                );
+                None
+            }
+        }
+    }
+
+    /// Create a new spill file in `dir`.
+    pub fn create(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(format!(
+            "buildkite-test-collector-spill-{}.ndjson",
+            Uuid::new_v4()
+        ));
+        let writer = BufWriter::new(File::create(&path)?);
+
+        Ok(Spill {
+            path,
+            writer,
+            spilled: 0,
+        })
+    }
+
+    /// Move the tests which have finished in `payload` to the spill file.
+    ///
+    /// ## Emits warnings
+    ///  - If they cannot be written, in which case they're left in `payload`.
+    pub fn poll(&mut self, payload: &mut Payload) {
+        if payload.executions().is_empty() {
+            return;
+        }
+
+        let finished = payload.take_finished();
+        if let Err(err) = self.write(&finished) {
+            eprintln!("Unable to write to {}: {}", self.path.display(), err);
+            for test_data in finished.executions() {
+                payload.push_execution(test_data.clone());
+            }
+        }
+    }
+
+    fn write(&mut self, payload: &Payload) -> io::Result<()> {
+        for test_data in payload.executions() {
+            serde_json::to_writer(&mut self.writer, test_data)?;
+            self.writer.write_all(b"\n")?;
+            self.spilled += 1;
+        }
+        self.writer.flush()
+    }
+
+    /// Spill any tests which have finished in `payload`, then read them all
+    /// back, `batch_size` at a time, into payloads with its settings, passing
+    /// each to `finish`.  The tests which haven't finished are left in
+    /// `payload`.  The file is removed afterwards.
+    ///
+    /// Returns the combined summary of the batches.
+    ///
+    /// ## Emits warnings
+    ///  - If the file cannot be read, or a test in it cannot be parsed.
+    pub fn finish<F>(mut self, payload: &mut Payload, batch_size: usize, mut finish: F) -> Summary
+    where
+        F: FnMut(Payload) -> Summary,
+    {
+        self.poll(payload);
+        let mut summary = Summary::default();
+        if self.spilled == 0 {
+            let _ = fs::remove_file(&self.path);
+            return summary;
+        }
+        drop(self.writer);
+
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(err) => {
+                eprintln!("Unable to read {}: {}", self.path.display(), err);
+                return summary;
+            }
+        };
+
+        // Taken while `payload` has no finished tests, so that each batch has
+        // the run's settings but none of its tests.
+        let mut empty = || payload.take_finished();
+        let mut batch = empty();
+        let mut count = 0;
+        for line in BufReader::new(file).lines() {
+            let test_data = line.map_err(|err| err.to_string()).and_then(|line| {
+                serde_json::from_str::<TestData>(&line).map_err(|err| err.to_string())
+            });
+            match test_data {
+                Ok(test_data) => batch.push_execution(test_data),
+                Err(err) => {
+                    eprintln!(
+                        "Unable to read a test from {}: {}",
+                        self.path.display(),
+                        err
+                    );
+                    continue;
+                }
+            }
+
+            count += 1;
+            if count == batch_size {
+                summary.merge(&finish(std::mem::replace(&mut batch, empty())));
+                count = 0;
+            }
+        }
+        if count > 0 {
+            summary.merge(&finish(batch));
+        }
+
+        if let Err(err) = fs::remove_file(&self.path) {
+            eprintln!("Unable to remove {}: {}", self.path.display(), err);
+        }
+        summary
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::input::parse_line;
+    use crate::run_env::RuntimeEnvironment;
+
+    #[test]
+    fn spilled_tests_are_read_back_in_batches() {
+        let dir = env::temp_dir().join(format!("spill-test-{}", Uuid::new_v4()));
+        let mut spill = Spill::create(&dir).unwrap();
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+
+        for line in [
+            r#"{ "type": "test", "event": "started", "name": "a::one" }"#,
+            r#"{ "type": "test", "name": "a::one", "event": "failed", "stdout": "thread 'a::one' panicked at src/a.rs:1:5:\nboom\n" }"#,
+            r#"{ "type": "test", "event": "started", "name": "a::two" }"#,
+            r#"{ "type": "test", "name": "a::two", "event": "ok" }"#,
+            r#"{ "type": "test", "event": "started", "name": "a::three" }"#,
+        ] {
+            parse_line(line, &mut payload).unwrap();
+            spill.poll(&mut payload);
+        }
+        assert!(payload.executions().is_empty());
+        let contents = fs::read_to_string(&spill.path).unwrap();
+        let spilled: serde_json::Value =
+            serde_json::from_str(contents.lines().next().unwrap()).unwrap();
+        parse_line(
+            r#"{ "type": "test", "name": "a::three", "event": "ok" }"#,
+            &mut payload,
+        )
+        .unwrap();
+
+        let path = spill.path.clone();
+        let mut batches = Vec::new();
+        let summary = spill.finish(&mut payload, 2, |batch| {
+            batches.push(serde_json::to_value(&batch).unwrap());
+            Summary {
+                tests: batch.executions().len(),
+                ..Summary::default()
+            }
+        });
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(summary.tests, 3);
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0]["data"].as_array().unwrap().len(), 2);
+        assert_eq!(batches[1]["data"].as_array().unwrap().len(), 1);
+        assert!(batches[0]["data"].as_array().unwrap().contains(&spilled));
+        assert_eq!(spilled["name"], "one");
+        assert_eq!(spilled["failure_reason"], "boom");
+        assert!(!path.exists());
+        assert!(payload.executions().is_empty());
+    }
+}
@@ -0,0 +1,175 @@
+//! # spool
+//!
+//! Stores payloads on disk when they can't be uploaded right now, so that
+//! they can be sent later with the `flush` subcommand.
+
+use crate::api;
+use crate::compress::{self, Compression};
+use serde::Serialize;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The directory payloads are spooled into.
+///
+/// Taken from `BUILDKITE_ANALYTICS_SPOOL_DIR`, or a directory within the
+/// system temporary directory if that isn't set.
+pub fn dir() -> PathBuf {
+    env::var_os("BUILDKITE_ANALYTICS_SPOOL_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| env::temp_dir().join("buildkite-test-collector"))
+}
+
+/// Write the serialised `payload` into the spool at `dir`, compressed with
+/// `compression`, named after its `idempotency_key` so that it's sent with the
+/// same key when it's flushed.
+pub fn write<T: Serialize>(
+    dir: &Path,
+    payload: &T,
+    idempotency_key: &str,
+    compression: Compression,
+) -> io::Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+
+    let path = dir.join(format!("{}.json", idempotency_key));
+    let json = serde_json::to_vec(payload)?;
+
+    compression.write(&path, &json)
+}
+
+/// The paths of all the payloads in the spool at `dir`, oldest first.
+pub fn entries(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut entries = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            compress::uncompressed_path(&entry.path())
+                .extension()
+                .is_some_and(|ext| ext == "json")
+        })
+        .map(|entry| {
+            let modified = entry.metadata().and_then(|meta| meta.modified()).ok();
+            (modified, entry.path())
+        })
+        .collect::<Vec<_>>();
+
+    entries.sort();
+
+    Ok(entries.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Upload every spooled payload, removing those which were accepted.
+///
+/// ## Emits warnings
+///  - If the spool cannot be read.
+///  - If a spooled payload cannot be parsed or uploaded.
+pub fn flush(endpoint: &str) {
+    let dir = dir();
+
+    let entries = match entries(&dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(err) => {
+            eprintln!("Unable to read the spool at {}: {}", dir.display(), err);
+            return;
+        }
+    };
+
+    let mut sent = 0;
+
+    for path in entries.iter() {
+        let payload = compress::read(path)
+            .ok()
+            .and_then(|json| serde_json::from_slice::<serde_json::Value>(&json).ok());
+
+        let Some(payload) = payload else {
+            eprintln!("Unable to read spooled payload {}", path.display());
+            continue;
+        };
+
+        // The key the payload was first sent with.
+        let idempotency_key = compress::uncompressed_path(path)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        match api::submit(&payload, endpoint, &idempotency_key) {
+            Ok(_) => {
+                sent += 1;
+                if let Err(err) = fs::remove_file(path) {
+                    eprintln!("Unable to remove {}: {}", path.display(), err);
+                }
+            }
+            // No other payload would be accepted either.
+            Err(err) if err.is_configuration() => {
+                eprintln!("Unable to flush the spool: {}", err);
+                break;
+            }
+            Err(err) => eprintln!(
+                "Unable to upload spooled payload {}: {}",
+                path.display(),
+                err
+            ),
+        }
+    }
+
+    eprintln!(
+        "Flushed {} of {} spooled payloads from {}",
+        sent,
+        entries.len(),
+        dir.display()
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn written_payloads_are_listed_in_the_spool() {
+        let dir = env::temp_dir().join(format!("spool-test-{}", Uuid::new_v4()));
+
+        let first = write(
+            &dir,
+            &serde_json::json!({ "batch": 1 }),
+            "run-0",
+            Compression::None,
+        )
+        .unwrap();
+        let second = write(
+            &dir,
+            &serde_json::json!({ "batch": 2 }),
+            "run-1",
+            Compression::None,
+        )
+        .unwrap();
+
+        let listed = entries(&dir).unwrap();
+        assert_eq!(listed.len(), 2);
+        assert!(listed.contains(&first));
+        assert!(listed.contains(&second));
+        assert_eq!(fs::read_to_string(&first).unwrap(), r#"{"batch":1}"#);
+        assert_eq!(first, dir.join("run-0.json"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn compressed_payloads_are_listed_and_read() {
+        let dir = env::temp_dir().join(format!("spool-test-{}", Uuid::new_v4()));
+
+        let path = write(
+            &dir,
+            &serde_json::json!({ "batch": 1 }),
+            "run-0",
+            Compression::Zstd { level: 3 },
+        )
+        .unwrap();
+
+        assert_eq!(entries(&dir).unwrap(), vec![path.clone()]);
+        assert_eq!(compress::read(&path).unwrap(), br#"{"batch":1}"#);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,231 @@
+//! # tags
+//!
+//! Tags every test with the team or component it belongs to, so results can
+//! be sliced in Test Engine, without editing the tests.  A tag rules file maps
+//! test name patterns to tags, one rule per line, eg:
+//!
+//! ```text
+//! # Later rules take precedence
+//! *                team=platform
+//! search::*        team=search component=indexer
+//! search::api::*   component=api
+//! ```
+//!
+//! Patterns match the test's full name (`scope::name`), with `*` and `?`
+//! wildcards.  Tags from rules take precedence over the defaults given with
+//! `--tag` or `BUILDKITE_ANALYTICS_TAGS`, and tags printed by the test itself
+//! (see `marker`) over both.
+
+use crate::codeowners::match_glob;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+/// Where the tag rules file is conventionally kept.
+const PATH: &str = ".buildkite/test-tags";
+
+/// # TagRules
+///
+/// The rules from a tag rules file.  Later rules take precedence.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct TagRules {
+    rules: Vec<(String, Vec<(String, String)>)>,
+}
+
+impl TagRules {
+    /// Find and parse the tag rules file.
+    ///
+    /// Taken from `BUILDKITE_ANALYTICS_TAGS_FILE`, or `.buildkite/test-tags`
+    /// if it exists.
+    ///
+    /// ## Emits warnings
+    ///  - If the file cannot be read.
+    pub fn discover() -> Option<Self> {
+        let path = match env::var_os("BUILDKITE_ANALYTICS_TAGS_FILE") {
+            Some(path) => PathBuf::from(path),
+            None => Some(PathBuf::from(PATH)).filter(|path| path.is_file())?,
+        };
+
+        match fs::read_to_string(&path) {
+            Ok(contents) => Some(Self::parse(&contents)),
+            Err(err) => {
+                eprintln!("Unable to read {}: {}", path.display(), err);
+                None
+            }
+        }
+    }
+
+    /// Parse the contents of a tag rules file.
+    ///
+    /// Each rule is a pattern followed by `key=value` pairs, so patterns (eg
+    /// of doctest names) may contain spaces but not `=`.
+    ///
+    /// ## Emits warnings
+    ///  - For each rule without any tags, which is ignored.
+    pub fn parse(contents: &str) -> Self {
+        let rules = contents
+            .lines()
+            .map(|line| line.split_once('#').map_or(line, |(rule, _)| rule).trim())
+            .filter(|line| !line.is_empty())
+            .filter_map(|line| {
+                let (pattern, tags) = line.split_at(
+                    line.split_whitespace()
+                        .find(|word| word.contains('='))
+                        .and_then(|word| line.find(word))
+                        .unwrap_or(line.len()),
+                );
+                let tags = parse_pairs(tags.split_whitespace());
+                if pattern.trim().is_empty() || tags.is_empty() {
+                    eprintln!(
+                        "Ignoring the rule {:?}: expected a pattern and key=value tags.",
+                        line
+                    );
+                    return None;
+                }
+                Some((pattern.trim().to_string(), tags))
+            })
+            .collect();
+
+        TagRules { rules }
+    }
+
+    /// The tags of the test with the given full name, in order of precedence
+    /// (lowest first).
+    pub fn tags<'a>(&'a self, full_name: &'a str) -> impl Iterator<Item = (&'a str, &'a str)> {
+        self.rules
+            .iter()
+            .filter(move |(pattern, _)| match_glob(pattern.as_bytes(), full_name.as_bytes()))
+            .flat_map(|(_, tags)| tags)
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+}
+
+/// The tags given to every test: the comma separated `key=value` pairs in
+/// `BUILDKITE_ANALYTICS_TAGS` (eg `os=linux,profile=release`), then the
+/// `--tag` options, which take precedence.
+pub fn default_tags(options: &[String]) -> Vec<(String, String)> {
+    let from_env = env::var("BUILDKITE_ANALYTICS_TAGS").unwrap_or_default();
+
+    parse_pairs(
+        from_env
+            .split(',')
+            .filter(|pair| !pair.trim().is_empty())
+            .chain(options.iter().map(String::as_str)),
+    )
+}
+
+/// Parse `key=value` pairs, eg from `--tag` options.
+///
+/// ## Emits warnings
+///  - For each pair without an `=` or with an empty key, which is ignored.
+pub fn parse_pairs<'a>(pairs: impl IntoIterator<Item = &'a str>) -> Vec<(String, String)> {
+    pairs
+        .into_iter()
+        .filter_map(|pair| match pair.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => {
+                Some((key.trim().to_string(), value.trim().to_string()))
+            }
+            _ => {
+                eprintln!("Ignoring the tag {:?}: expected key=value.", pair);
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::input::{Event, TestEvent};
+    use crate::payload::Payload;
+    use crate::run_env::RuntimeEnvironment;
+    use serial_test::serial;
+
+    #[test]
+    fn later_rules_take_precedence() {
+        let rules = TagRules::parse(
+            "# Everyone owns something
+*                          team=platform
+search::*                  team=search component=indexer
+search::api::*             component=api  # split out in Q3
+src/lib.rs - add (line 3)  component=docs
+orphaned::*
+",
+        );
+
+        let tags = |name: &str| {
+            rules.tags(name).fold(
+                Vec::new(),
+                |mut tags: Vec<(String, String)>, (key, value)| {
+                    tags.retain(|(existing, _)| existing != key);
+                    tags.push((key.to_string(), value.to_string()));
+                    tags
+                },
+            )
+        };
+
+        assert_eq!(tags("payload::a"), [("team".into(), "platform".into())]);
+        assert_eq!(
+            tags("search::api::queries"),
+            [
+                ("team".into(), "search".into()),
+                ("component".into(), "api".into()),
+            ]
+        );
+        assert_eq!(
+            tags("src/lib.rs - add (line 3)"),
+            [
+                ("team".into(), "platform".into()),
+                ("component".into(), "docs".into()),
+            ]
+        );
+        assert_eq!(rules.rules.len(), 4);
+    }
+
+    #[test]
+    #[serial]
+    fn default_tags_come_from_the_environment_then_options() {
+        env::set_var("BUILDKITE_ANALYTICS_TAGS", "os=linux, profile=release,,");
+        let tags = default_tags(&["profile=debug".to_string()]);
+        env::remove_var("BUILDKITE_ANALYTICS_TAGS");
+
+        assert_eq!(
+            tags,
+            [
+                ("os".to_string(), "linux".to_string()),
+                ("profile".to_string(), "release".to_string()),
+                ("profile".to_string(), "debug".to_string()),
+            ]
+        );
+        assert!(default_tags(&[]).is_empty());
+    }
+
+    #[test]
+    fn markers_beat_rules_which_beat_defaults() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic())
+            .with_default_tags(parse_pairs(["team=unknown", "os=linux", "broken"]))
+            .with_tag_rules(TagRules::parse("search::* team=search component=indexer"));
+
+        for event in [
+            TestEvent::Started {
+                name: "search::ranks".to_string(),
+            },
+            TestEvent::Ok {
+                name: "search::ranks".to_string(),
+                exec_time: None,
+                stdout: Some("buildkite-test-collector: tag component=ranking".to_string()),
+            },
+        ] {
+            payload.push(Event::Test {
+                event,
+                nextest: None,
+                extra: Default::default(),
+            });
+        }
+
+        let test_data = payload.closed_data()[0];
+        assert_eq!(test_data.tag("os"), Some("linux"));
+        assert_eq!(test_data.tag("team"), Some("search"));
+        assert_eq!(test_data.tag("component"), Some("ranking"));
+    }
+}
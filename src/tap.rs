@@ -0,0 +1,277 @@
+//! # tap
+//!
+//! Deserialisation of Test Anything Protocol (TAP) output, as produced by
+//! some embedded and FFI test runners.
+
+use crate::duration::DurationSource;
+use crate::payload::{Payload, TestResult};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// The scope given to TAP tests, which don't have one of their own.
+const SCOPE: &str = "tap";
+
+#[derive(Debug)]
+struct PendingTest {
+    name: String,
+    result: TestResult,
+    start_at: f64,
+    duration: f64,
+}
+
+/// # TapParser
+///
+/// TAP is line oriented, but a test's YAML diagnostics block follows its
+/// `ok`/`not ok` line, so tests are only pushed into the payload once the
+/// next test starts or the stream ends.
+#[derive(Debug)]
+pub struct TapParser {
+    started_at: Instant,
+    last_test_at: f64,
+    pending: Option<PendingTest>,
+    yaml: Option<Vec<String>>,
+}
+
+impl Default for TapParser {
+    fn default() -> Self {
+        TapParser {
+            started_at: Instant::now(),
+            last_test_at: 0.0,
+            pending: None,
+            yaml: None,
+        }
+    }
+}
+
+impl TapParser {
+    /// Parse a single line of TAP output.
+    pub fn parse_line(&mut self, line: &str, payload: &mut Payload) {
+        if let Some(yaml) = self.yaml.as_mut() {
+            match line.trim() {
+                "..." => self.finish_yaml(),
+                _ => yaml.push(line.to_string()),
+            }
+            return;
+        }
+
+        if line.trim() == "---" && self.pending.is_some() {
+            self.yaml = Some(Vec::new());
+            return;
+        }
+
+        let (ok, rest) = if let Some(rest) = strip_status(line, "not ok") {
+            (false, rest)
+        } else if let Some(rest) = strip_status(line, "ok") {
+            (true, rest)
+        } else {
+            return;
+        };
+
+        self.finish(payload);
+
+        let (description, directive) = match rest.split_once('#') {
+            Some((description, directive)) => (description, Some(directive.trim())),
+            None => (rest, None),
+        };
+
+        let description = description.trim();
+        let number_len = description
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(description.len());
+        let (number, description) = description.split_at(number_len);
+        let description = description.trim_start().trim_start_matches('-').trim();
+
+        // Tests without a description are named after their number.
+        let name = if description.is_empty() {
+            number
+        } else {
+            description
+        };
+
+        let directive = directive.map(|directive| directive.to_ascii_uppercase());
+        let skipped = directive.as_deref().is_some_and(|directive| {
+            directive.starts_with("SKIP") || directive.starts_with("TODO")
+        });
+
+        let result = if skipped {
+            TestResult::Skipped
+        } else if ok {
+            TestResult::Passed
+        } else {
+            TestResult::Failed {
+                failure_reason: None,
+            }
+        };
+
+        let now = self.started_at.elapsed().as_secs_f64();
+        self.pending = Some(PendingTest {
+            name: name.to_string(),
+            result,
+            start_at: self.last_test_at,
+            duration: now - self.last_test_at,
+        });
+        self.last_test_at = now;
+    }
+
+    /// Push the final test into the payload once the stream has ended.
+    pub fn finish(&mut self, payload: &mut Payload) {
+        self.finish_yaml();
+
+        if let Some(test) = self.pending.take() {
+            payload.push_finished_test(
+                SCOPE,
+                &test.name,
+                test.result,
+                test.start_at,
+                test.duration,
+                DurationSource::Collector,
+            );
+        }
+    }
+
+    /// Attach the YAML diagnostics to a failed test as its failure reason.
+    fn finish_yaml(&mut self) {
+        let Some(yaml) = self.yaml.take() else {
+            return;
+        };
+
+        if let Some(PendingTest {
+            result: TestResult::Failed { failure_reason },
+            ..
+        }) = self.pending.as_mut()
+        {
+            let indent = yaml
+                .iter()
+                .filter(|line| !line.trim().is_empty())
+                .map(|line| line.len() - line.trim_start().len())
+                .min()
+                .unwrap_or(0);
+
+            let diagnostics = yaml
+                .iter()
+                .map(|line| line.get(indent..).unwrap_or_default())
+                .collect::<Vec<&str>>()
+                .join("\n");
+
+            *failure_reason = Some(Arc::from(diagnostics));
+        }
+    }
+}
+
+/// The rest of a test line starting with `status`, eg `ok 1` but not `okay`.
+fn strip_status<'a>(line: &'a str, status: &str) -> Option<&'a str> {
+    let rest = line.strip_prefix(status)?;
+    (rest.is_empty() || rest.starts_with([' ', '#'])).then_some(rest)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::run_env::RuntimeEnvironment;
+
+    #[test]
+    fn parses_results_directives_and_diagnostics() {
+        let tap = "TAP version 13
+1..4
+ok 1 - boots
+not ok 2 - talks to the sensor
+  ---
+  message: timed out
+  severity: fail
+  ...
+ok 3 - calibrates # SKIP no hardware
+not ok 4 # TODO not implemented
+# all done";
+
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+        let mut parser = TapParser::default();
+        for line in tap.lines() {
+            parser.parse_line(line, &mut payload);
+        }
+        parser.finish(&mut payload);
+
+        let json = serde_json::to_value(&payload).unwrap();
+        let mut data = json["data"].as_array().unwrap().clone();
+        data.sort_by_key(|test| test["name"].as_str().unwrap().to_string());
+
+        assert_eq!(data.len(), 4);
+        assert_eq!(data[0]["name"], "4");
+        assert_eq!(data[0]["result"], "skipped");
+        assert_eq!(data[1]["name"], "boots");
+        assert_eq!(data[1]["scope"], "tap");
+        assert_eq!(data[1]["result"], "passed");
+        assert_eq!(data[2]["name"], "calibrates");
+        assert_eq!(data[2]["result"], "skipped");
+        assert_eq!(data[3]["name"], "talks to the sensor");
+        assert_eq!(data[3]["result"], "failed");
+        assert_eq!(
+            data[3]["failure_reason"],
+            "message: timed out\nseverity: fail"
+        );
+    }
+
+    #[test]
+    fn ignores_lines_which_only_start_like_results() {
+        let tap = "okay, starting up
+oki
+not okay either
+ok
+not ok#crashed";
+
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+        let mut parser = TapParser::default();
+        for line in tap.lines() {
+            parser.parse_line(line, &mut payload);
+        }
+        parser.finish(&mut payload);
+
+        let json = serde_json::to_value(&payload).unwrap();
+        let results: Vec<_> = json["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|test| test["result"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(results.len(), 2);
+        assert!(results.contains(&"passed".to_string()));
+        assert!(results.contains(&"failed".to_string()));
+    }
+
+    mod properties {
+        use super::*;
+        use crate::run_env::RuntimeEnvironment;
+        use proptest::prelude::*;
+
+        proptest! {
+            #[test]
+            fn arbitrary_lines_never_panic(lines in prop::collection::vec(".*", 0..20)) {
+                let mut payload = Payload::new(RuntimeEnvironment::generic());
+                let mut parser = TapParser::default();
+                for line in lines {
+                    parser.parse_line(&line, &mut payload);
+                }
+                parser.finish(&mut payload);
+            }
+
+            #[test]
+            fn every_test_line_is_recorded(
+                results in prop::collection::vec((any::<bool>(), prop::option::of("[A-Z ]{0,10}")), 0..30)
+            ) {
+                let mut payload = Payload::new(RuntimeEnvironment::generic());
+                let mut parser = TapParser::default();
+
+                parser.parse_line(&format!("1..{}", results.len()), &mut payload);
+                for (index, (ok, directive)) in results.iter().enumerate() {
+                    let mut line = format!("{} {} - test {}", if *ok { "ok" } else { "not ok" }, index + 1, index);
+                    if let Some(directive) = directive {
+                        line.push_str(&format!(" # {}", directive));
+                    }
+                    parser.parse_line(&line, &mut payload);
+                }
+                parser.finish(&mut payload);
+
+                prop_assert_eq!(payload.closed_data().len(), results.len());
+            }
+        }
+    }
+}
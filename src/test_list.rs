@@ -0,0 +1,222 @@
+//! # test_list
+//!
+//! Lists the tests `cargo test` would run.  Listing means building and
+//! running every test binary, so the list is cached by a fingerprint of the
+//! build and repeated CI steps can reuse it.
+
+use crate::payload::fnv1a;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::UNIX_EPOCH;
+
+/// The directory test lists are cached in.
+///
+/// Taken from `BUILDKITE_ANALYTICS_CACHE_DIR`, or a directory within cargo's
+/// target directory if that isn't set.
+pub fn cache_dir() -> PathBuf {
+    env::var_os("BUILDKITE_ANALYTICS_CACHE_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| {
+            env::var_os("CARGO_TARGET_DIR")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| PathBuf::from("target"))
+                .join("buildkite-test-collector")
+        })
+}
+
+/// The names of the tests `cargo test <cargo_args>` would run.
+///
+/// Reuses a cached list if the build's fingerprint hasn't changed, unless
+/// `refresh` is set.
+///
+/// ## Emits warnings
+///  - If cargo cannot be run, or fails to list the tests.
+///  - If the list cannot be cached.
+pub fn list(cargo_args: &[String], refresh: bool) -> Option<Vec<String>> {
+    let path = fingerprint_build(cargo_args)
+        .map(|fingerprint| cache_dir().join(format!("test-list-{:016x}.txt", fingerprint)));
+
+    if let Some(path) = path.as_ref().filter(|_| !refresh) {
+        if let Ok(cached) = fs::read_to_string(path) {
+            return Some(cached.lines().map(String::from).collect());
+        }
+    }
+
+    let output = cargo(
+        &["test"]
+            .into_iter()
+            .map(String::from)
+            .chain(cargo_args.iter().cloned())
+            .chain(["--", "--list", "--format", "terse"].map(String::from))
+            .collect::<Vec<String>>(),
+    )?;
+    let names = parse_list(&output);
+
+    if let Some(path) = path {
+        if let Err(err) = write_cache(&path, &names) {
+            eprintln!(
+                "Unable to cache the test list in {}: {}",
+                path.display(),
+                err
+            );
+        }
+    }
+
+    Some(names)
+}
+
+/// Parse the output of `cargo test -- --list --format terse`, eg:
+///
+/// ```text
+/// payload::test::batchify_works_as_expected: test
+/// parser::bench_parse: benchmark
+/// ```
+pub fn parse_list(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter_map(|line| line.strip_suffix(": test"))
+        .map(String::from)
+        .collect()
+}
+
+fn write_cache(path: &Path, names: &[String]) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let mut contents = names.join("\n");
+    contents.push('\n');
+    fs::write(path, contents)
+}
+
+/// Fingerprint the build from `cargo metadata` and the modification times of
+/// every file in the workspace's packages.
+fn fingerprint_build(cargo_args: &[String]) -> Option<u64> {
+    let metadata = cargo(&["metadata", "--no-deps", "--format-version", "1"].map(String::from))?;
+    let json: serde_json::Value = serde_json::from_str(&metadata).ok()?;
+
+    let dirs = json["packages"]
+        .as_array()?
+        .iter()
+        .filter_map(|package| package["manifest_path"].as_str())
+        .filter_map(|manifest| Path::new(manifest).parent().map(Path::to_path_buf))
+        .collect::<Vec<PathBuf>>();
+
+    Some(fingerprint(
+        &format!("{}\n{}", cargo_args.join(" "), metadata),
+        &dirs,
+    ))
+}
+
+/// Hash `seed` along with the path, size and modification time of every file
+/// within `dirs`, skipping hidden directories and cargo's `target`.
+pub fn fingerprint(seed: &str, dirs: &[PathBuf]) -> u64 {
+    let mut files = Vec::new();
+    for dir in dirs {
+        collect_files(dir, &mut files);
+    }
+    files.sort();
+
+    let mut input = seed.to_string();
+    for (path, len, modified) in files {
+        input.push_str(&format!("\n{}:{}:{}", path.display(), len, modified));
+    }
+
+    fnv1a(&input)
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<(PathBuf, u64, u128)>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+
+        if metadata.is_dir() {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name != "target" && !name.starts_with('.') {
+                collect_files(&path, files);
+            }
+        } else {
+            let modified = metadata
+                .modified()
+                .ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map(|modified| modified.as_nanos())
+                .unwrap_or_default();
+            files.push((path, metadata.len(), modified));
+        }
+    }
+}
+
+fn cargo(args: &[String]) -> Option<String> {
+    let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+
+    match Command::new(&cargo).args(args).output() {
+        Ok(output) if output.status.success() => {
+            Some(String::from_utf8_lossy(&output.stdout).to_string())
+        }
+        Ok(output) => {
+            eprintln!(
+                "Unable to run cargo {}: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            None
+        }
+        Err(err) => {
+            eprintln!("Unable to run {}: {}", cargo, err);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_terse_test_lists() {
+        let output = "payload::test::batchify_works_as_expected: test
+parser::bench_parse: benchmark
+src/lib.rs - module::func (line 42): test
+";
+
+        assert_eq!(
+            parse_list(output),
+            vec![
+                "payload::test::batchify_works_as_expected",
+                "src/lib.rs - module::func (line 42)"
+            ]
+        );
+    }
+
+    #[test]
+    fn fingerprint_changes_with_the_source() {
+        let dir = env::temp_dir().join(format!("test-list-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::create_dir_all(dir.join("target")).unwrap();
+        fs::write(dir.join("src/lib.rs"), "fn a() {}").unwrap();
+
+        let dirs = vec![dir.clone()];
+        let before = fingerprint("metadata", &dirs);
+        assert_eq!(before, fingerprint("metadata", &dirs));
+        assert_ne!(before, fingerprint("other metadata", &dirs));
+
+        fs::write(dir.join("target/build.log"), "ignored").unwrap();
+        assert_eq!(before, fingerprint("metadata", &dirs));
+
+        fs::write(dir.join("src/lib.rs"), "fn a() { b() }").unwrap();
+        assert_ne!(before, fingerprint("metadata", &dirs));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,363 @@
+//! # text
+//!
+//! A fallback parser for libtest's default, human-readable output, for when
+//! the tests weren't run with `--format json`.  It understands the
+//! `test foo ... ok` result lines and the captured output printed under
+//! `failures:`, which is enough for results but not for precise timing.
+//!
+//! wasm-bindgen-test's runner (eg under `wasm-pack test`) prints much the same
+//! format, with `FAIL` results and `---- foo output ----` sections, so is
+//! parsed here too.
+
+use crate::input::{is_cargo_message, is_json_object, Event, SuiteEvent, SuiteResults, TestEvent};
+use crate::payload::Payload;
+
+/// # TextParser
+///
+/// libtest prints the captured output of failed tests after every test has
+/// finished, so failures are held back until their output has been read, and
+/// pushed into the payload when the suite's `test result:` line arrives (or
+/// the stream ends).
+#[derive(Debug, Default)]
+pub struct TextParser {
+    /// A JSON event has been seen, so this isn't text output after all.
+    json: bool,
+    warned: bool,
+    failures: Vec<PendingFailure>,
+    /// The output section currently being read, and its lines.
+    section: Option<(String, Vec<String>)>,
+}
+
+#[derive(Debug)]
+struct PendingFailure {
+    name: String,
+    exec_time: Option<f64>,
+    stdout: Option<String>,
+}
+
+impl TextParser {
+    /// Parse a single line of libtest's human-readable output.
+    ///
+    /// Does nothing once a line of JSON (other than cargo's own messages) has
+    /// been seen, so it's safe to feed every line of libtest's output through
+    /// both this and the JSON parser.
+    ///
+    /// ## Emits warnings
+    ///  - The first time a test result is parsed from text.
+    pub fn parse_line(&mut self, line: &str, payload: &mut Payload) {
+        if is_cargo_message(line) {
+            return;
+        }
+        if self.json || is_json_object(line) {
+            self.json = true;
+            return;
+        }
+
+        if let Some(name) = line.strip_prefix("---- ").and_then(|rest| {
+            rest.strip_suffix(" stdout ----")
+                .or_else(|| rest.strip_suffix(" output ----"))
+        }) {
+            self.finish_section();
+            self.section = Some((name.to_string(), Vec::new()));
+            return;
+        }
+
+        if let Some((_, lines)) = self.section.as_mut() {
+            if line != "failures:" {
+                lines.push(line.to_string());
+                return;
+            }
+            self.finish_section();
+        }
+
+        if let Some(count) = line
+            .strip_prefix("running ")
+            .and_then(|rest| rest.strip_suffix(" tests").or(rest.strip_suffix(" test")))
+            .and_then(|count| count.parse().ok())
+        {
+            self.finish(payload);
+            payload.push(Event::Suite {
+                event: SuiteEvent::Started { test_count: count },
+            });
+        } else if let Some(result) = line.strip_prefix("test result: ") {
+            self.finish(payload);
+            let results = summary_results(result);
+            payload.push(Event::Suite {
+                event: if result.starts_with("ok") {
+                    SuiteEvent::Ok { results }
+                } else {
+                    SuiteEvent::Failed { results }
+                },
+            });
+        } else if let Some((name, result)) = line
+            .strip_prefix("test ")
+            .and_then(|rest| rest.rsplit_once(" ... "))
+        {
+            self.parse_result(name, result, payload);
+        }
+    }
+
+    /// Push any failures still waiting for their output into the payload.
+    pub fn finish(&mut self, payload: &mut Payload) {
+        self.finish_section();
+
+        for failure in self.failures.drain(..) {
+            payload.push(started(&failure.name));
+            payload.push(test_event(TestEvent::Failed {
+                name: failure.name,
+                exec_time: failure.exec_time,
+                stdout: failure.stdout,
+                stderr: None,
+                message: None,
+            }));
+        }
+    }
+
+    fn parse_result(&mut self, name: &str, result: &str, payload: &mut Payload) {
+        // With `--report-time`, results end with the test's time, eg `<0.012s>`.
+        let (result, exec_time) = match result.trim_end().rsplit_once(" <") {
+            Some((result, time)) => (
+                result,
+                time.strip_suffix("s>").and_then(|time| time.parse().ok()),
+            ),
+            None => (result.trim_end(), None),
+        };
+
+        let name = name.to_string();
+        if result == "ok" {
+            self.warn();
+            payload.push(started(&name));
+            payload.push(test_event(TestEvent::Ok {
+                name,
+                exec_time,
+                stdout: None,
+            }));
+        } else if result.starts_with("FAIL") {
+            self.warn();
+            self.failures.push(PendingFailure {
+                name,
+                exec_time,
+                stdout: None,
+            });
+        } else if result.starts_with("ignored") {
+            self.warn();
+            payload.push(test_event(TestEvent::Ignored { name }));
+        }
+    }
+
+    /// Attach the output section just read to its failure.
+    fn finish_section(&mut self) {
+        let Some((name, mut lines)) = self.section.take() else {
+            return;
+        };
+
+        while lines.last().is_some_and(|line| line.trim().is_empty()) {
+            lines.pop();
+        }
+
+        // wasm-bindgen-test indents its output sections.
+        let indent = lines
+            .iter()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| line.len() - line.trim_start().len())
+            .min()
+            .unwrap_or(0);
+        let lines = lines
+            .iter()
+            .map(|line| line.get(indent..).unwrap_or_default())
+            .collect::<Vec<&str>>();
+
+        if let Some(failure) = self
+            .failures
+            .iter_mut()
+            .find(|failure| failure.name == name)
+        {
+            failure.stdout = Some(lines.join("\n"));
+        }
+    }
+
+    fn warn(&mut self) {
+        if !self.warned {
+            self.warned = true;
+            eprintln!("Parsing libtest's human-readable output, so durations will be approximate.  Pass `-Z unstable-options --format json --report-time` to libtest for full results.");
+        }
+    }
+}
+
+fn started(name: &str) -> Event {
+    test_event(TestEvent::Started {
+        name: name.to_string(),
+    })
+}
+
+fn test_event(event: TestEvent) -> Event {
+    Event::Test {
+        event,
+        nextest: None,
+        extra: Default::default(),
+    }
+}
+
+/// The counts in libtest's summary, eg `ok. 2 passed; 1 failed; 1 ignored; 0
+/// measured; 0 filtered out; finished in 0.01s`.
+fn summary_results(summary: &str) -> SuiteResults {
+    let mut results = SuiteResults::default();
+    let counts = summary.split_once(". ").map_or("", |(_, counts)| counts);
+
+    for count in counts.split("; ") {
+        if let Some(seconds) = count
+            .strip_prefix("finished in ")
+            .and_then(|time| time.strip_suffix('s'))
+        {
+            results.exec_time = seconds.parse().ok();
+            continue;
+        }
+        let Some((number, label)) = count.split_once(' ') else {
+            continue;
+        };
+        let Ok(number) = number.parse() else {
+            continue;
+        };
+        match label {
+            "passed" => results.passed = number,
+            "failed" => results.failed = number,
+            "ignored" => results.ignored = number,
+            "measured" => results.measured = number,
+            "filtered out" => results.filtered_out = number,
+            _ => {}
+        }
+    }
+
+    results
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::run_env::RuntimeEnvironment;
+
+    #[test]
+    fn parses_results_and_failure_output() {
+        let output = "
+running 4 tests
+test tests::adds ... ok
+test tests::divides ... FAILED
+test tests::slow ... ignored, takes an hour
+test src/lib.rs - add (line 3) ... ok <0.250s>
+
+failures:
+
+---- tests::divides stdout ----
+thread 'tests::divides' panicked at src/lib.rs:20:9:
+attempt to divide by zero
+note: run with `RUST_BACKTRACE=1` environment variable to display a backtrace
+
+
+failures:
+    tests::divides
+
+test result: FAILED. 2 passed; 1 failed; 1 ignored; 0 measured; 0 filtered out; finished in 0.01s
+";
+
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+        let mut parser = TextParser::default();
+        for line in output.lines() {
+            parser.parse_line(line, &mut payload);
+        }
+        parser.finish(&mut payload);
+
+        let mut tests = payload.closed_data();
+        tests.sort_by_key(|test_data| test_data.full_name());
+        let results = tests
+            .iter()
+            .map(|test_data| (test_data.full_name(), test_data.failure_reason()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(tests.len(), 4);
+        assert!(results[0].0.ends_with("add (line 3)"));
+        assert_eq!(results[1], ("tests::adds".to_string(), None));
+        assert_eq!(results[2].0, "tests::divides");
+        assert!(results[2]
+            .1
+            .is_some_and(|reason| reason.contains("divide by zero")));
+        assert_eq!(results[3], ("tests::slow".to_string(), None));
+        assert_eq!(serde_json::to_value(tests[3]).unwrap()["result"], "skipped");
+        let completeness = payload.completeness().unwrap();
+        assert_eq!(completeness.expected, 4);
+        assert_eq!(completeness.finished, 4);
+
+        let json = serde_json::to_value(&payload).unwrap();
+        assert_eq!(json["tags"]["libtest.passed"], "2");
+        assert_eq!(json["tags"]["libtest.failed"], "1");
+        assert_eq!(json["tags"]["libtest.ignored"], "1");
+        assert_eq!(json["tags"]["libtest.exec_time"], "0.01");
+    }
+
+    #[test]
+    fn parses_wasm_bindgen_test_output() {
+        let output = "Executing bindgen...
+
+running 2 tests
+test app::tests::adds ... ok
+test app::tests::fails ... FAIL
+
+failures:
+
+---- app::tests::fails output ----
+    error output:
+        panicked at src/lib.rs:12:9:
+        assertion `left == right` failed
+          left: 1
+         right: 2
+
+        Stack:
+
+        Error
+            at http://127.0.0.1:8000/wasm-bindgen-test:1:100
+
+    JS exception that was thrown:
+        RuntimeError: unreachable executed
+
+failures:
+
+    app::tests::fails
+
+test result: FAILED. 1 passed; 1 failed; 0 ignored; 0 filtered out; finished in 0.02s
+";
+
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+        let mut parser = TextParser::default();
+        for line in output.lines() {
+            parser.parse_line(line, &mut payload);
+        }
+        parser.finish(&mut payload);
+
+        let json = serde_json::to_value(&payload).unwrap();
+        let mut data = json["data"].as_array().unwrap().clone();
+        data.sort_by_key(|test| test["name"].as_str().unwrap().to_string());
+
+        assert_eq!(data.len(), 2);
+        assert_eq!(data[0]["name"], "adds");
+        assert_eq!(data[1]["name"], "fails");
+        assert_eq!(data[1]["result"], "failed");
+        assert_eq!(
+            data[1]["failure_reason"],
+            "assertion `left == right` failed\n  left: 1\n right: 2"
+        );
+        assert_eq!(data[1]["location"], "src/lib.rs:12");
+    }
+
+    #[test]
+    fn ignores_json_output() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+        let mut parser = TextParser::default();
+        for line in [
+            r#"{ "type": "suite", "event": "started", "test_count": 1 }"#,
+            "test tests::adds ... ok",
+        ] {
+            parser.parse_line(line, &mut payload);
+        }
+
+        assert!(payload.closed_data().is_empty());
+    }
+}
@@ -0,0 +1,177 @@
+//! # tracing
+//!
+//! A [`tracing-subscriber`](https://docs.rs/tracing-subscriber) layer which
+//! records the spans entered during each test as span markers (see
+//! `marker`), so that they become nested sections of the test's history in
+//! Test Engine, eg:
+//!
+//! ```ignore
+//! use tracing_subscriber::prelude::*;
+//!
+//! #[test]
+//! fn saves_a_user() {
+//!     let _ = tracing_subscriber::registry()
+//!         .with(buildkite_test_collector::tracing::TestSpans::default())
+//!         .try_init();
+//!
+//!     let _span = tracing::info_span!("db_setup", table = "users").entered();
+//!     // ...
+//! }
+//! ```
+//!
+//! libtest only captures output printed on the test's own thread, so spans
+//! entered on other threads (eg by a multi-threaded async runtime) aren't
+//! recorded.  Captured output has no timestamps, so each span's times are
+//! measured from the first span entered on the test's thread.
+
+use crate::marker::PREFIX;
+use std::fmt::{self, Write};
+use std::time::Instant;
+use tracing_subscriber::field::Visit;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+thread_local! {
+    /// When the test running on this thread (libtest runs each test on a
+    /// thread of its own) entered its first span.
+    static STARTED: Instant = Instant::now();
+}
+
+/// # TestSpans
+///
+/// Prints a `span start` marker as each span is entered and a `span end`
+/// marker as it's exited, with the span's name and fields as its detail.
+#[derive(Debug, Clone, Copy)]
+pub struct TestSpans {
+    print: fn(String),
+}
+
+impl Default for TestSpans {
+    fn default() -> Self {
+        TestSpans {
+            // `println!`, rather than writing to `stdout`, so that libtest
+            // captures it.
+            print: |line| println!("{}", line),
+        }
+    }
+}
+
+/// The `key=value` pairs of a span's fields, as they'll appear in its marker.
+struct Fields(String);
+
+impl Visit for Fields {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn fmt::Debug) {
+        let value = format!("{:?}", value);
+        let _ = write!(self.0, " {}={}", field.name(), marker_value(&value));
+    }
+
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        let _ = write!(self.0, " {}={}", field.name(), marker_value(value));
+    }
+}
+
+/// `value` without whitespace, which would end it in a marker.
+fn marker_value(value: &str) -> String {
+    value.split_whitespace().collect::<Vec<&str>>().join("_")
+}
+
+fn seconds_since_start() -> f64 {
+    STARTED.with(|started| started.elapsed().as_secs_f64())
+}
+
+impl<S> Layer<S> for TestSpans
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(
+        &self,
+        attrs: &tracing::span::Attributes<'_>,
+        id: &tracing::span::Id,
+        ctx: Context<'_, S>,
+    ) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut fields = Fields(format!(" name={}", marker_value(span.name())));
+        attrs.record(&mut fields);
+        span.extensions_mut().insert(fields);
+    }
+
+    fn on_record(
+        &self,
+        id: &tracing::span::Id,
+        values: &tracing::span::Record<'_>,
+        ctx: Context<'_, S>,
+    ) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(fields) = span.extensions_mut().get_mut::<Fields>() {
+                values.record(fields);
+            }
+        }
+    }
+
+    fn on_enter(&self, id: &tracing::span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let at = seconds_since_start();
+        let extensions = span.extensions();
+        let fields = extensions
+            .get::<Fields>()
+            .map_or("", |fields| fields.0.as_str());
+
+        (self.print)(format!("{} span start{} at={:.6}", PREFIX, fields, at));
+    }
+
+    fn on_exit(&self, _id: &tracing::span::Id, _ctx: Context<'_, S>) {
+        (self.print)(format!(
+            "{} span end at={:.6}",
+            PREFIX,
+            seconds_since_start()
+        ));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::marker;
+    use std::cell::RefCell;
+    use tracing_subscriber::prelude::*;
+
+    thread_local! {
+        static PRINTED: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    }
+
+    #[test]
+    fn spans_become_nested_markers() {
+        let layer = TestSpans {
+            print: |line| PRINTED.with(|printed| printed.borrow_mut().push(line)),
+        };
+        let subscriber = tracing_subscriber::registry().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            let setup = tracing::info_span!("db setup", table = "users", rows = 3);
+            setup.in_scope(|| {
+                tracing::info_span!("insert", query = "INSERT INTO users").in_scope(|| {});
+            });
+            tracing::info_span!("assert").in_scope(|| {});
+        });
+
+        let output = PRINTED.with(|printed| printed.borrow().join("\n"));
+        let spans = marker::spans(&output);
+
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0].section, "annotation");
+        assert_eq!(spans[0].detail["name"], "db_setup");
+        assert_eq!(spans[0].detail["table"], "users");
+        assert_eq!(spans[0].detail["rows"], "3");
+        assert_eq!(spans[0].children.len(), 1);
+        assert_eq!(spans[0].children[0].detail["query"], "INSERT_INTO_users");
+        assert_eq!(spans[1].detail["name"], "assert");
+        assert!(spans
+            .iter()
+            .all(|span| span.start.is_some_and(|start| span.end >= Some(start))));
+    }
+}
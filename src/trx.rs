@@ -0,0 +1,187 @@
+//! # trx
+//!
+//! Deserialisation of Visual Studio test results (`.trx` files), as produced
+//! by `vstest` and `dotnet test --logger trx`.
+
+use crate::duration::DurationSource;
+use crate::payload::{Payload, TestResult};
+use roxmltree::{Document, Node};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Does `xml` look like a TRX file rather than a JUnit report?
+pub fn is_trx(xml: &str) -> bool {
+    xml.contains("<TestRun")
+}
+
+/// Parse a TRX file and push each `UnitTestResult` into `payload`.
+///
+/// Results are scoped by the class of their test method, from the matching
+/// `UnitTest` definition, or else by everything before the last `.` in their
+/// `testName`.
+///
+/// Returns the number of results found.
+pub fn parse(xml: &str, payload: &mut Payload) -> Result<usize, roxmltree::Error> {
+    let document = Document::parse(xml)?;
+
+    let class_names = document
+        .descendants()
+        .filter(|node| node.has_tag_name("UnitTest"))
+        .filter_map(|test| {
+            let class_name = test
+                .children()
+                .find(|node| node.has_tag_name("TestMethod"))?
+                .attribute("className")?;
+            Some((test.attribute("id")?, class_name))
+        })
+        .collect::<HashMap<&str, &str>>();
+
+    let mut offset = 0.0;
+    let mut count = 0;
+
+    for result in document
+        .descendants()
+        .filter(|node| node.has_tag_name("UnitTestResult"))
+    {
+        let test_name = result.attribute("testName").unwrap_or_default();
+        let (scope, name) = match result
+            .attribute("testId")
+            .and_then(|id| class_names.get(id))
+        {
+            // Parameterised tests are named `Method(1, 2)`, so only strip the
+            // class when it's really there.
+            Some(class_name) => (
+                *class_name,
+                test_name
+                    .strip_prefix(class_name)
+                    .and_then(|name| name.strip_prefix('.'))
+                    .unwrap_or(test_name),
+            ),
+            None => test_name.rsplit_once('.').unwrap_or(("", test_name)),
+        };
+        let duration = result
+            .attribute("duration")
+            .and_then(parse_duration)
+            .unwrap_or_default();
+
+        payload.push_finished_test(
+            scope,
+            name,
+            test_result(result),
+            offset,
+            duration,
+            DurationSource::Trx,
+        );
+
+        offset += duration;
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+/// Parse a TRX duration, eg `00:01:02.5000000`, into seconds.
+fn parse_duration(duration: &str) -> Option<f64> {
+    let mut parts = duration.splitn(3, ':');
+    let hours = parts.next()?.parse::<f64>().ok()?;
+    let minutes = parts.next()?.parse::<f64>().ok()?;
+    let seconds = parts.next()?.parse::<f64>().ok()?;
+
+    Some(hours * 3600.0 + minutes * 60.0 + seconds)
+}
+
+fn test_result(result: Node) -> TestResult {
+    match result.attribute("outcome").unwrap_or_default() {
+        "Passed" | "PassedButRunAborted" | "Completed" | "Warning" => TestResult::Passed,
+        "Failed" | "Error" | "Timeout" | "Aborted" => TestResult::Failed {
+            failure_reason: failure_reason(result).map(Arc::from),
+        },
+        // NotExecuted, Inconclusive, NotRunnable and friends.
+        _ => TestResult::Skipped,
+    }
+}
+
+/// The error message and stack trace from the result's `ErrorInfo`.
+fn failure_reason(result: Node) -> Option<String> {
+    let error_info = result
+        .descendants()
+        .find(|node| node.has_tag_name("ErrorInfo"))?;
+    let text = |tag: &str| {
+        error_info
+            .children()
+            .find(|node| node.has_tag_name(tag))
+            .and_then(|node| node.text())
+            .map(str::trim)
+            .filter(|text| !text.is_empty())
+    };
+
+    match (text("Message"), text("StackTrace")) {
+        (Some(message), Some(stack_trace)) => Some(format!("{}\n{}", message, stack_trace)),
+        (message, stack_trace) => message.or(stack_trace).map(String::from),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::run_env::RuntimeEnvironment;
+
+    #[test]
+    fn parses_unit_test_results_into_the_payload() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<TestRun id="1" name="build" xmlns="http://microsoft.com/schemas/VisualStudio/TeamTest/2010">
+  <Results>
+    <UnitTestResult executionId="a" testId="t1" testName="Api.Tests.Submits" duration="00:00:00.5000000" outcome="Passed" />
+    <UnitTestResult executionId="b" testId="t2" testName="Api.Tests.Retries(2)" duration="00:01:01.2500000" outcome="Failed">
+      <Output>
+        <ErrorInfo>
+          <Message>Assert.Equal() Failure</Message>
+          <StackTrace>   at Api.Tests.Retries(Int32 attempts)</StackTrace>
+        </ErrorInfo>
+      </Output>
+    </UnitTestResult>
+    <UnitTestResult executionId="c" testId="t3" testName="Orphan.Flaky" duration="00:00:00" outcome="NotExecuted" />
+  </Results>
+  <TestDefinitions>
+    <UnitTest name="Submits" id="t1"><TestMethod className="Api.Tests" name="Submits" /></UnitTest>
+    <UnitTest name="Retries(2)" id="t2"><TestMethod className="Api.Tests" name="Retries" /></UnitTest>
+  </TestDefinitions>
+</TestRun>"#;
+
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+
+        assert!(is_trx(xml));
+        assert_eq!(parse(xml, &mut payload).unwrap(), 3);
+
+        let json = serde_json::to_value(&payload).unwrap();
+        let mut data = json["data"].as_array().unwrap().clone();
+        data.sort_by_key(|test| test["name"].as_str().unwrap().to_string());
+
+        assert_eq!(data[0]["name"], "Flaky");
+        assert_eq!(data[0]["scope"], "Orphan");
+        assert_eq!(data[0]["result"], "skipped");
+
+        assert_eq!(data[1]["name"], "Retries(2)");
+        assert_eq!(data[1]["scope"], "Api.Tests");
+        assert_eq!(data[1]["result"], "failed");
+        assert_eq!(
+            data[1]["failure_reason"],
+            "Assert.Equal() Failure\nat Api.Tests.Retries(Int32 attempts)"
+        );
+        // Start times are measured from the first test's.
+        let start_at = |test: &serde_json::Value| test["history"]["start_at"].as_f64().unwrap();
+        assert!((start_at(&data[1]) - start_at(&data[2]) - 0.5).abs() < 1e-6);
+        assert_eq!(data[1]["history"]["duration"], 61.25);
+        assert_eq!(data[1]["tags"]["duration_source"], "trx");
+
+        assert_eq!(data[2]["name"], "Submits");
+        assert_eq!(data[2]["result"], "passed");
+    }
+
+    #[test]
+    fn rejects_malformed_xml() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+
+        assert!(parse("<TestRun>", &mut payload).is_err());
+    }
+}
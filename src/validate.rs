@@ -0,0 +1,214 @@
+//! # validate
+//!
+//! Checks each test against the upload format before it's sent, so that a
+//! test the API would reject (eg with a negative duration, or a tag key with
+//! spaces in it) is reported precisely, and left out, rather than the whole
+//! batch being rejected with an opaque list of errors.
+
+use crate::payload::{Payload, TestData};
+use serde_json::Value;
+use uuid::Uuid;
+
+/// How many problems to report individually before summarising the rest.
+const MAX_REPORTED: usize = 10;
+
+/// The results a test may have.
+const RESULTS: [&str; 3] = ["passed", "failed", "skipped"];
+
+/// Remove the tests and run tags in `payload` which don't match the upload
+/// format.
+///
+/// Returns how many tests were removed.
+///
+/// ## Emits warnings
+///  - For each test or run tag removed, up to `MAX_REPORTED`, then a count of
+///    the rest.
+pub fn check(payload: &mut Payload) -> usize {
+    let mut reported = Vec::new();
+
+    let invalid_tags = payload
+        .run_tags()
+        .keys()
+        .filter(|key| !is_valid_tag_key(key))
+        .cloned()
+        .collect::<Vec<String>>();
+    for key in invalid_tags {
+        reported.push(format!(
+            "Not uploading the run tag {:?}: {}",
+            key, TAG_KEY_PROBLEM
+        ));
+        payload.remove_tag(&key);
+    }
+
+    let mut removed = 0;
+    payload.retain_tests(|test_data| {
+        let problems = problems(test_data);
+        if problems.is_empty() {
+            return true;
+        }
+        reported.push(format!(
+            "Not uploading {}: {}",
+            test_data.full_name(),
+            problems.join("; ")
+        ));
+        removed += 1;
+        false
+    });
+
+    for problem in reported.iter().take(MAX_REPORTED) {
+        eprintln!("{}", problem);
+    }
+    if reported.len() > MAX_REPORTED {
+        eprintln!(
+            "…and {} more tests or tags which don't match the upload format.",
+            reported.len() - MAX_REPORTED
+        );
+    }
+
+    removed
+}
+
+const TAG_KEY_PROBLEM: &str =
+    "tag keys must be non-empty, without whitespace or control characters";
+
+fn is_valid_tag_key(key: &str) -> bool {
+    !key.is_empty() && !key.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+/// What's wrong with `test_data`, as it would be uploaded.
+fn problems(test_data: &TestData) -> Vec<String> {
+    let test = match serde_json::to_value(test_data) {
+        Ok(test) => test,
+        Err(err) => return vec![format!("it can't be serialised: {}", err)],
+    };
+    let mut problems = Vec::new();
+
+    if test["id"]
+        .as_str()
+        .is_none_or(|id| Uuid::parse_str(id).is_err())
+    {
+        problems.push(format!("id {} isn't a UUID", test["id"]));
+    }
+    if !test["scope"].is_string() {
+        problems.push("scope is missing".to_string());
+    }
+    if test["name"].as_str().is_none_or(str::is_empty) {
+        problems.push("name is missing".to_string());
+    }
+    if !test["result"]
+        .as_str()
+        .is_some_and(|result| RESULTS.contains(&result))
+    {
+        problems.push(format!(
+            "result {} isn't one of {:?}",
+            test["result"], RESULTS
+        ));
+    }
+    if let Some(tags) = test["tags"].as_object() {
+        for key in tags.keys().filter(|key| !is_valid_tag_key(key)) {
+            problems.push(format!("tag {:?}: {}", key, TAG_KEY_PROBLEM));
+        }
+    }
+
+    history_problems(&test["history"], "history", true, &mut problems);
+
+    problems
+}
+
+/// Add what's wrong with `history` (at `path`, eg `history.children[0]`) to
+/// `problems`.  Only the top level history must have a start time.
+fn history_problems(history: &Value, path: &str, top: bool, problems: &mut Vec<String>) {
+    if !history.is_object() {
+        problems.push(format!("{} is missing", path));
+        return;
+    }
+
+    if history["section"].as_str().is_none_or(str::is_empty) {
+        problems.push(format!("{}.section is missing", path));
+    }
+
+    let start_at = history["start_at"].as_f64();
+    if top && start_at.is_none() {
+        problems.push(format!("{}.start_at is missing", path));
+    }
+    for field in ["start_at", "end_at", "duration"] {
+        match &history[field] {
+            Value::Null => {}
+            Value::Number(number) if number.as_f64().is_some_and(|n| n >= 0.0) => {}
+            Value::Number(number) => {
+                problems.push(format!("{}.{} is negative ({})", path, field, number))
+            }
+            value => problems.push(format!("{}.{} isn't a number ({})", path, field, value)),
+        }
+    }
+    if let (Some(start_at), Some(end_at)) = (start_at, history["end_at"].as_f64()) {
+        if end_at < start_at {
+            problems.push(format!("{}.end_at is before its start_at", path));
+        }
+    }
+
+    for (index, child) in history["children"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .enumerate()
+    {
+        history_problems(
+            child,
+            &format!("{}.children[{}]", path, index),
+            false,
+            problems,
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::input::parse_line;
+    use crate::run_env::RuntimeEnvironment;
+
+    #[test]
+    fn invalid_tests_and_tags_are_removed() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+        payload.insert_tag("build type", "nightly");
+        payload.insert_tag("os", "linux");
+        for line in [
+            r#"{ "type": "test", "event": "started", "name": "tests::fine" }"#,
+            r#"{ "type": "test", "name": "tests::fine", "event": "ok", "stdout": "buildkite-test-collector: span start name=setup at=0.1\nbuildkite-test-collector: span end at=0.2" }"#,
+            r#"{ "type": "test", "event": "started", "name": "tests::tagged" }"#,
+            r#"{ "type": "test", "name": "tests::tagged", "event": "ok", "stdout": "buildkite-test-collector: tag \u0007bell=rung" }"#,
+            r#"{ "type": "test", "event": "started", "name": "tests::travelled" }"#,
+            r#"{ "type": "test", "name": "tests::travelled", "event": "ok", "stdout": "buildkite-test-collector: span start name=setup at=0.5\nbuildkite-test-collector: span end at=0.2" }"#,
+        ] {
+            parse_line(line, &mut payload).unwrap();
+        }
+
+        assert_eq!(check(&mut payload), 2);
+
+        let json = serde_json::to_value(&payload).unwrap();
+        assert_eq!(json["data"].as_array().unwrap().len(), 1);
+        assert_eq!(json["data"][0]["name"], "fine");
+        assert!(json["tags"].get("build type").is_none());
+        assert_eq!(json["tags"]["os"], "linux");
+    }
+
+    #[test]
+    fn problems_are_described_precisely() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+        for line in [
+            r#"{ "type": "test", "event": "started", "name": "tests::travelled" }"#,
+            r#"{ "type": "test", "name": "tests::travelled", "event": "ok", "stdout": "buildkite-test-collector: span start name=setup at=0.5\nbuildkite-test-collector: span end at=0.2" }"#,
+        ] {
+            parse_line(line, &mut payload).unwrap();
+        }
+
+        assert_eq!(
+            problems(payload.closed_data()[0]),
+            [
+                "history.children[0].duration is negative (-0.3)",
+                "history.children[0].end_at is before its start_at"
+            ]
+        );
+    }
+}
@@ -0,0 +1,339 @@
+//! # wrapper
+//!
+//! Runs the test command ourselves, rather than reading its output from
+//! `stdin`, so that we can tell when the test binary crashes.
+
+use crate::failure::Failure;
+use crate::payload::{Payload, TestData};
+use std::collections::VecDeque;
+use std::io::{self, BufRead, BufReader};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// How many lines of output to keep for crash diagnostics.
+const LAST_LINES: usize = 50;
+
+/// The scope given to the synthetic execution recording a crash.
+const SCOPE: &str = "crash";
+
+/// # Crash
+///
+/// Diagnostics about a test process which was killed by a signal.
+#[derive(Debug, PartialEq)]
+pub struct Crash {
+    pub signal: String,
+    pub core_dumped: bool,
+    pub last_lines: Vec<String>,
+}
+
+/// Run `command`, passing each line of its `stdout` to `on_line`.
+///
+/// The command's `stderr` is echoed to our `stderr`, and each line passed to
+/// `on_stderr` (on another thread).  Returns the command's exit status, and
+/// crash diagnostics if it (or a test binary run by cargo) was killed by a
+/// signal.
+pub fn run<F, G>(
+    command: &[String],
+    mut on_line: F,
+    mut on_stderr: G,
+) -> io::Result<(ExitStatus, Option<Crash>)>
+where
+    F: FnMut(&str),
+    G: FnMut(&str) + Send + 'static,
+{
+    let (program, args) = command
+        .split_first()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no command given"))?;
+
+    let mut child = Command::new(program)
+        .args(args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let last_lines = Arc::new(Mutex::new(VecDeque::with_capacity(LAST_LINES)));
+
+    let stderr = child.stderr.take().map(|stderr| {
+        let last_lines = last_lines.clone();
+        thread::spawn(move || {
+            for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                eprintln!("{}", line);
+                on_stderr(&line);
+                remember(&last_lines, line);
+            }
+        })
+    });
+
+    if let Some(stdout) = child.stdout.take() {
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            on_line(&line);
+            remember(&last_lines, line);
+        }
+    }
+
+    if let Some(stderr) = stderr {
+        let _ = stderr.join();
+    }
+
+    let status = child.wait()?;
+    let last_lines = last_lines
+        .lock()
+        .map(|lines| lines.iter().cloned().collect::<Vec<String>>())
+        .unwrap_or_default();
+
+    let crash = status_crash(&status)
+        .or_else(|| last_lines.iter().rev().find_map(|line| cargo_crash(line)))
+        .map(|(signal, core_dumped)| Crash {
+            signal,
+            core_dumped,
+            last_lines,
+        });
+
+    Ok((status, crash))
+}
+
+/// Record a crash in `payload`, as a synthetic failed execution and run
+/// tags, alongside the tests which completed before it.
+///
+/// Tests which started but never finished are the likely culprits, so they
+/// are listed in the failure reason, and failed themselves.  The test whose
+/// thread panicked (eg under `panic = "abort"`), or the only test running, is
+/// given the last lines of output.
+pub fn record(crash: &Crash, payload: &mut Payload) {
+    let mut failure_reason = format!("Test binary was killed by {}", crash.signal);
+    if crash.core_dumped {
+        failure_reason.push_str(" (core dumped)");
+    }
+
+    let mut running = payload
+        .unfinished_data()
+        .into_iter()
+        .map(TestData::full_name)
+        .collect::<Vec<String>>();
+    running.sort();
+
+    if !running.is_empty() {
+        failure_reason.push_str("\n\nStill running:\n");
+        failure_reason.push_str(&running.join("\n"));
+    }
+
+    if !crash.last_lines.is_empty() {
+        failure_reason.push_str("\n\nLast output:\n");
+        failure_reason.push_str(&crash.last_lines.join("\n"));
+    }
+
+    let only_running = match running.as_slice() {
+        [name] => Some(name.as_str()),
+        _ => None,
+    };
+    let culprit = crash
+        .last_lines
+        .iter()
+        .rev()
+        .find_map(|line| panicked_thread(line))
+        .and_then(|name| {
+            // The scope may start with the test binary, which the thread's
+            // name doesn't include.
+            running.iter().find(|running| {
+                running.as_str() == name || running.ends_with(&format!("::{}", name))
+            })
+        })
+        .map(String::as_str)
+        .or(only_running)
+        .map(String::from);
+    payload.fail_unfinished(|test_data| {
+        Failure::with_reason(
+            if culprit.as_deref() == Some(test_data.full_name().as_str()) {
+                format!(
+                    "Test binary was killed by {} while running this test\n\nLast output:\n{}",
+                    crash.signal,
+                    crash.last_lines.join("\n")
+                )
+            } else {
+                format!(
+                    "Test binary was killed by {} while running this test",
+                    crash.signal
+                )
+            },
+        )
+    });
+
+    payload.push_test(
+        TestData::builder()
+            .scope(SCOPE)
+            .name(&format!("test binary crashed with {}", crash.signal))
+            .failed(Some(&failure_reason)),
+    );
+    payload.insert_tag("crash.signal", &crash.signal);
+    payload.insert_tag("crash.core_dumped", &crash.core_dumped.to_string());
+}
+
+/// The name of the thread in a panic message, eg `thread 'tests::boom'
+/// panicked at src/lib.rs:3:5:`.  libtest names each test's thread after it.
+fn panicked_thread(line: &str) -> Option<&str> {
+    let (name, _) = line.strip_prefix("thread '")?.split_once("' panicked")?;
+    Some(name)
+}
+
+fn remember(last_lines: &Mutex<VecDeque<String>>, line: String) {
+    if let Ok(mut last_lines) = last_lines.lock() {
+        if last_lines.len() == LAST_LINES {
+            last_lines.pop_front();
+        }
+        last_lines.push_back(line);
+    }
+}
+
+#[cfg(unix)]
+fn status_crash(status: &ExitStatus) -> Option<(String, bool)> {
+    use std::os::unix::process::ExitStatusExt;
+
+    let signal = status.signal()?;
+    Some((signal_name(signal), status.core_dumped()))
+}
+
+#[cfg(not(unix))]
+fn status_crash(_status: &ExitStatus) -> Option<(String, bool)> {
+    None
+}
+
+#[cfg(unix)]
+fn signal_name(signal: i32) -> String {
+    match signal {
+        4 => "SIGILL".to_string(),
+        6 => "SIGABRT".to_string(),
+        7 => "SIGBUS".to_string(),
+        8 => "SIGFPE".to_string(),
+        9 => "SIGKILL".to_string(),
+        11 => "SIGSEGV".to_string(),
+        15 => "SIGTERM".to_string(),
+        signal => format!("signal {}", signal),
+    }
+}
+
+/// cargo doesn't pass on the signal which killed a test binary, but it does
+/// report it, eg:
+///
+/// ```text
+/// process didn't exit successfully: `...` (signal: 11, SIGSEGV: invalid memory reference)
+/// ```
+fn cargo_crash(line: &str) -> Option<(String, bool)> {
+    let (_, rest) = line.rsplit_once("(signal: ")?;
+    let (_, rest) = rest.split_once(", ")?;
+    let signal = rest.split([':', ')']).next()?.trim();
+
+    Some((signal.to_string(), line.contains("core dumped")))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::run_env::RuntimeEnvironment;
+
+    #[test]
+    fn cargo_crash_reports_are_recognised() {
+        assert_eq!(
+            cargo_crash("error: test failed, to rerun pass `--lib`\n\nCaused by:\n  process didn't exit successfully: `/target/debug/deps/crate-abc` (signal: 11, SIGSEGV: invalid memory reference)"),
+            Some(("SIGSEGV".to_string(), false))
+        );
+        assert_eq!(
+            cargo_crash("process didn't exit successfully: `crate-abc` (signal: 6, SIGABRT: process abort signal) (core dumped)"),
+            Some(("SIGABRT".to_string(), true))
+        );
+        assert_eq!(cargo_crash("test result: ok. 1 passed"), None);
+    }
+
+    #[test]
+    fn crashes_are_recorded_as_failed_executions() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+        for event in [
+            r#"{ "type": "suite", "event": "started", "test_count": 2 }"#,
+            r#"{ "type": "test", "event": "started", "name": "api::test::works" }"#,
+            r#"{ "type": "test", "name": "api::test::works", "event": "ok", "exec_time": 0.1 }"#,
+            r#"{ "type": "test", "event": "started", "name": "api::test::segfaults" }"#,
+        ] {
+            crate::input::parse_line(event, &mut payload).unwrap();
+        }
+
+        let crash = Crash {
+            signal: "SIGSEGV".to_string(),
+            core_dumped: true,
+            last_lines: vec!["running 2 tests".to_string()],
+        };
+        record(&crash, &mut payload);
+
+        let json = serde_json::to_value(&payload).unwrap();
+        let mut data = json["data"].as_array().unwrap().clone();
+        data.sort_by_key(|test| test["name"].as_str().unwrap().to_string());
+
+        assert_eq!(json["tags"]["crash.signal"], "SIGSEGV");
+        assert_eq!(json["tags"]["crash.core_dumped"], "true");
+        assert_eq!(data.len(), 3);
+        assert_eq!(data[0]["name"], "segfaults");
+        assert_eq!(data[0]["result"], "failed");
+        assert_eq!(data[0]["tags"]["incomplete"], "true");
+        assert_eq!(
+            data[0]["failure_reason"],
+            "Test binary was killed by SIGSEGV while running this test\n\nLast output:\nrunning 2 tests"
+        );
+        assert_eq!(data[1]["scope"], "crash");
+        assert_eq!(data[1]["name"], "test binary crashed with SIGSEGV");
+        assert_eq!(data[1]["result"], "failed");
+        assert_eq!(
+            data[1]["failure_reason"],
+            "Test binary was killed by SIGSEGV (core dumped)\n\nStill running:\napi::test::segfaults\n\nLast output:\nrunning 2 tests"
+        );
+        assert_eq!(data[2]["name"], "works");
+    }
+
+    #[test]
+    fn aborts_are_attributed_to_the_panicking_test() {
+        let mut payload = Payload::new(RuntimeEnvironment::generic());
+        for event in [
+            r#"{ "type": "test", "event": "started", "name": "tests::waits" }"#,
+            r#"{ "type": "test", "event": "started", "name": "tests::aborts" }"#,
+        ] {
+            crate::input::parse_line(event, &mut payload).unwrap();
+        }
+
+        let crash = Crash {
+            signal: "SIGABRT".to_string(),
+            core_dumped: false,
+            last_lines: vec![
+                "thread 'tests::aborts' panicked at src/lib.rs:3:5:".to_string(),
+                "boom".to_string(),
+            ],
+        };
+        record(&crash, &mut payload);
+
+        let reason = |name: &str| {
+            payload
+                .closed_data()
+                .into_iter()
+                .find(|test_data| test_data.name() == name)
+                .and_then(|test_data| test_data.failure_reason().map(String::from))
+                .unwrap()
+        };
+        assert!(reason("aborts").ends_with("boom"));
+        assert_eq!(
+            reason("waits"),
+            "Test binary was killed by SIGABRT while running this test"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn crashes_are_captured_with_the_last_lines() {
+        let command = ["sh", "-c", "echo before; kill -SEGV $$"].map(String::from);
+        let mut seen = Vec::new();
+
+        let (status, crash) = run(&command, |line| seen.push(line.to_string()), |_| {}).unwrap();
+        let crash = crash.unwrap();
+
+        assert!(!status.success());
+        assert_eq!(seen, vec!["before"]);
+        assert_eq!(crash.signal, "SIGSEGV");
+        assert_eq!(crash.last_lines, vec!["before"]);
+    }
+}